@@ -0,0 +1,52 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Feeds arbitrary packets to `deobfuscate_wg_packet`, as a remote peer or anyone on the
+//! path can.
+//!
+//! The first input byte picks the rule: bit 0 enables `auth=on`, bits 1-2 the cipher mode
+//! and bit 3 a previous key. The rest is the packet. Besides not panicking, the
+//! deobfuscator must either leave the packet untouched or shrink it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nf_wgobfs::config::{parse_config, FilterConfig};
+use nf_wgobfs::filter::obfuscator::deobfuscate_wg_packet;
+use std::sync::OnceLock;
+
+const CIPHERS: [&str; 4] = ["chacha20", "chacha12", "chacha8", "xchacha20"];
+
+/// The 16 rules, parsed once.
+fn rules() -> &'static [FilterConfig] {
+    static RULES: OnceLock<Vec<FilterConfig>> = OnceLock::new();
+    RULES.get_or_init(|| {
+        let lines: Vec<String> = (0..16u16)
+            .map(|i| {
+                let key = if i & 8 != 0 { "fuzzkey,oldkey" } else { "fuzzkey" };
+                let auth = if i & 1 != 0 { "on" } else { "off" };
+                let cipher = CIPHERS[(i as usize >> 1) & 3];
+                format!("{}:in:fuzz:{}:1500:auth={}:cipher={}:keycheck=off", i, key, auth, cipher)
+            })
+            .collect();
+        parse_config(&lines).expect("fuzz rules must parse")
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, packet)) = data.split_first() else {
+        return;
+    };
+    let rule = &rules()[(selector & 0x0f) as usize];
+    let mut buf = packet.to_vec();
+    match deobfuscate_wg_packet(&mut buf, rule) {
+        Some(len) if len == packet.len() => assert_eq!(buf, packet, "changed but not shrunk"),
+        Some(len) => assert!(len < packet.len(), "grew from {} to {}", packet.len(), len),
+        None => assert_eq!(buf, packet, "dropped packet was modified"),
+    }
+});
@@ -0,0 +1,127 @@
+/* SPDX-License-Identifier: MIT */
+
+#ifndef NF_WGOBFS_H
+#define NF_WGOBFS_H
+
+/* Generated from src/ffi.rs by cbindgen; do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// The call succeeded; `*out_len` holds the packet length.
+#define NF_WGOBFS_OK 0
+
+// The packet failed to deobfuscate under the key (wrong key or cipher, or chaff) and must
+// be dropped.
+#define NF_WGOBFS_DROP 1
+
+// A required pointer is NULL, `len` exceeds `cap`, or a passphrase is not UTF-8.
+#define NF_WGOBFS_EINVAL -1
+
+// The buffer is too small for the obfuscated packet.
+#define NF_WGOBFS_ENOSPC -2
+
+// The transform panicked; the buffer contents are unspecified.
+#define NF_WGOBFS_EPANIC -3
+
+// A packet of a batch for [`nf_wgobfs_obfuscate_batch`] and [`nf_wgobfs_deobfuscate_batch`].
+typedef struct NfWgobfsPacket {
+  // The packet, at the start of `cap` writable bytes.
+  uint8_t *buf;
+  // Length of the packet; its new length once transformed with [`NF_WGOBFS_OK`].
+  size_t len;
+  // Capacity of `buf`; only obfuscation uses it.
+  size_t cap;
+  // Outcome for this packet, the status the single-packet call would have returned.
+  int status;
+} NfWgobfsPacket;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Obfuscates the packet in `buf` in place.
+//
+// Packets that are not WireGuard over UDP or TCP, are too short to obfuscate or are longer
+// than `mtu` are left unchanged, with `*out_len` set to `len`. An obfuscated packet is at
+// most `max(mtu, len + 14)` bytes long, so a capacity of `mtu + 14` always suffices.
+//
+// Returns [`NF_WGOBFS_OK`], [`NF_WGOBFS_EINVAL`], [`NF_WGOBFS_ENOSPC`] or
+// [`NF_WGOBFS_EPANIC`]; `*out_len` is only written on success.
+//
+// # Safety
+// `buf` must point to `cap` initialised, writable bytes, `key` to the 32-byte key (see
+// [`nf_wgobfs_derive_key`]) and `out_len` to a writable `size_t`. None of them may be
+// accessed by another thread during the call.
+int nf_wgobfs_obfuscate(uint8_t *buf,
+                        size_t len,
+                        size_t cap,
+                        const uint8_t *key,
+                        size_t mtu,
+                        size_t *out_len);
+
+// Deobfuscates the packet in `buf` in place, reversing [`nf_wgobfs_obfuscate`].
+//
+// Packets that are not WireGuard over UDP or TCP or are too short to have been obfuscated
+// are left unchanged, with `*out_len` set to `len`. The packet only ever shrinks.
+//
+// Returns [`NF_WGOBFS_OK`], [`NF_WGOBFS_DROP`], [`NF_WGOBFS_EINVAL`] or
+// [`NF_WGOBFS_EPANIC`]; `*out_len` is only written on success.
+//
+// # Safety
+// `buf` must point to `len` initialised, writable bytes, `key` to the 32-byte key and
+// `out_len` to a writable `size_t`. None of them may be accessed by another thread during
+// the call.
+int nf_wgobfs_deobfuscate(uint8_t *buf, size_t len, const uint8_t *key, size_t *out_len);
+
+// Obfuscates `count` packets in place, each as [`nf_wgobfs_obfuscate`] would, setting up
+// the rule once for all of them, e.g. for the packets of one batched receive.
+//
+// Each packet gets its own status, and its new length on success: a packet with a NULL
+// `buf` or a `len` over `cap` gets [`NF_WGOBFS_EINVAL`] without stopping the others.
+//
+// Returns [`NF_WGOBFS_OK`] once every packet has its status, [`NF_WGOBFS_EINVAL`] if
+// `packets` or `key` is NULL, or [`NF_WGOBFS_EPANIC`], after which the statuses are
+// unspecified.
+//
+// # Safety
+// `packets` must point to `count` writable [`NfWgobfsPacket`]s, each `buf` to `cap`
+// initialised, writable bytes not shared with another packet, and `key` to the 32-byte
+// key. None of them may be accessed by another thread during the call.
+int nf_wgobfs_obfuscate_batch(struct NfWgobfsPacket *packets,
+                              size_t count,
+                              const uint8_t *key,
+                              size_t mtu);
+
+// Deobfuscates `count` packets in place, each as [`nf_wgobfs_deobfuscate`] would, like
+// [`nf_wgobfs_obfuscate_batch`]. `cap` is not used; a packet with a NULL `buf` gets
+// [`NF_WGOBFS_EINVAL`].
+//
+// Returns [`NF_WGOBFS_OK`] once every packet has its status, [`NF_WGOBFS_EINVAL`] if
+// `packets` or `key` is NULL, or [`NF_WGOBFS_EPANIC`].
+//
+// # Safety
+// `packets` must point to `count` writable [`NfWgobfsPacket`]s, each `buf` to `len`
+// initialised, writable bytes not shared with another packet, and `key` to the 32-byte
+// key. None of them may be accessed by another thread during the call.
+int nf_wgobfs_deobfuscate_batch(struct NfWgobfsPacket *packets, size_t count, const uint8_t *key);
+
+// Derives the 32-byte key from a passphrase as the configuration does for `SECRET_KEY`,
+// under the `salt=` option if `salt` is not NULL, and writes it to `key`.
+//
+// Returns [`NF_WGOBFS_OK`], [`NF_WGOBFS_EINVAL`] or [`NF_WGOBFS_EPANIC`].
+//
+// # Safety
+// `passphrase` must point to `passphrase_len` readable bytes, `salt` (unless NULL) to
+// `salt_len` readable bytes, and `key` to 32 writable bytes.
+int nf_wgobfs_derive_key(const uint8_t *passphrase,
+                         size_t passphrase_len,
+                         const uint8_t *salt,
+                         size_t salt_len,
+                         uint8_t *key);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NF_WGOBFS_H */
@@ -0,0 +1,204 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Portable ChaCha core with a configurable number of rounds.
+//!
+//! Implements the RFC 8439 block function (32-bit counter, 96-bit nonce) in plain Rust.
+//! The round count is a parameter, so the same core serves ChaCha8, ChaCha12 and ChaCha20.
+//! [`hchacha`] derives the XChaCha20 subkey from the first 16 bytes of a 24-byte nonce.
+
+use zeroize::Zeroize;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// ChaCha keystream generator.
+pub struct ChaCha {
+    state: [u32; 16],
+    rounds: usize,
+    /// Keystream of the current block and how much of it has been used.
+    block: [u8; 64],
+    used: usize,
+}
+
+impl ChaCha {
+    /// Creates a keystream for `key` and `nonce`, starting at block counter 0.
+    ///
+    /// `rounds` must be even (8, 12 or 20).
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12], rounds: usize) -> Self {
+        debug_assert!(rounds.is_multiple_of(2), "ChaCha rounds must be even");
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CONSTANTS);
+        for (word, chunk) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        for (word, chunk) in state[13..16].iter_mut().zip(nonce.chunks_exact(4)) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Self { state, rounds, block: [0u8; 64], used: 64 }
+    }
+
+    /// XORs `data` with the next bytes of the keystream.
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == 64 {
+                self.refill();
+            }
+            *byte ^= self.block[self.used];
+            self.used += 1;
+        }
+    }
+
+    /// Computes the keystream block at the current counter and advances the counter.
+    fn refill(&mut self) {
+        let mut x = self.state;
+        permute(&mut x, self.rounds);
+        for (i, chunk) in self.block.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&x[i].wrapping_add(self.state[i]).to_le_bytes());
+        }
+        x.zeroize();
+        self.state[12] = self.state[12].wrapping_add(1);
+        self.used = 0;
+    }
+}
+
+impl Drop for ChaCha {
+    fn drop(&mut self) {
+        self.state.zeroize();
+        self.block.zeroize();
+    }
+}
+
+/// HChaCha20: derives a subkey from `key` and a 16-byte nonce prefix (XChaCha20 draft,
+/// section 2.2).
+pub fn hchacha(key: &[u8; 32], nonce: &[u8; 16]) -> [u8; 32] {
+    let mut x = [0u32; 16];
+    x[..4].copy_from_slice(&CONSTANTS);
+    for (word, chunk) in x[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    for (word, chunk) in x[12..16].iter_mut().zip(nonce.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    permute(&mut x, 20);
+    let mut subkey = [0u8; 32];
+    for (chunk, word) in subkey.chunks_exact_mut(4).zip(x[..4].iter().chain(&x[12..])) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    x.zeroize();
+    subkey
+}
+
+/// Applies `rounds` ChaCha rounds (alternating column and diagonal rounds) to `x`.
+#[inline(always)]
+fn permute(x: &mut [u32; 16], rounds: usize) {
+    for _ in 0..rounds / 2 {
+        quarter_round(x, 0, 4, 8, 12);
+        quarter_round(x, 1, 5, 9, 13);
+        quarter_round(x, 2, 6, 10, 14);
+        quarter_round(x, 3, 7, 11, 15);
+        quarter_round(x, 0, 5, 10, 15);
+        quarter_round(x, 1, 6, 11, 12);
+        quarter_round(x, 2, 7, 8, 13);
+        quarter_round(x, 3, 4, 9, 14);
+    }
+}
+
+#[inline(always)]
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystream(key: &[u8; 32], nonce: &[u8; 12], rounds: usize, len: usize) -> String {
+        let mut data = vec![0u8; len];
+        ChaCha::new(key, nonce, rounds).apply_keystream(&mut data);
+        hex::encode(data)
+    }
+
+    /// Tests the first keystream block under an all-zero key and nonce for each round count.
+    #[test]
+    fn test_zero_key_vectors() {
+        let (key, nonce) = ([0u8; 32], [0u8; 12]);
+        assert_eq!(
+            keystream(&key, &nonce, 8, 64),
+            "3e00ef2f895f40d67f5bb8e81f09a5a12c840ec3ce9a7f3b181be188ef711a1e\
+             984ce172b9216f419f445367456d5619314a42a3da86b001387bfdb80e0cfe42"
+        );
+        assert_eq!(
+            keystream(&key, &nonce, 12, 64),
+            "9bf49a6a0755f953811fce125f2683d50429c3bb49e074147e0089a52eae155f\
+             0564f879d27ae3c02ce82834acfa8c793a629f2ca0de6919610be82f411326be"
+        );
+        assert_eq!(
+            keystream(&key, &nonce, 20, 64),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+        );
+    }
+
+    /// Tests a keystream crossing a block boundary under the RFC 8439 key and nonce.
+    #[test]
+    fn test_sequential_key_vectors() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        assert_eq!(
+            keystream(&key, &nonce, 8, 80),
+            "62bf060f2e45c3450b4bc17eefed59cb0c18d2d23ca4bcfc1ced6ba76ed35c95\
+             097b81d752cdc05a91ba6891185c191b110c36cad34db95ec3ea12314329d70e\
+             eead9dfbbc60443e9d6811bab8e60a3a"
+        );
+        assert_eq!(
+            keystream(&key, &nonce, 12, 80),
+            "15a783e5ad5e601c5a1d3e3db3e8d2405509bcf1c986967825888dc7fd4b991e\
+             e5c8dfb889e3b1ecbe1e474122f60742a28322c85f8390fa8418df439fd50d66\
+             7f8b136677c73799e3e7777d16e6d8cc"
+        );
+        assert_eq!(
+            keystream(&key, &nonce, 20, 80),
+            "8adc91fd9ff4f0f51b0fad50ff15d637e40efda206cc52c783a74200503c1582\
+             cd9833367d0a54d57d3c9e998f490ee69ca34c1ff9e939a75584c52d690a35d4\
+             10f1e7e4d13b5915500fdd1fa32071c4"
+        );
+    }
+
+    /// Tests HChaCha20 against the XChaCha20 draft, section 2.2.1.
+    #[test]
+    fn test_hchacha_vector() {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0, 0x31, 0x41, 0x59, 0x27];
+        assert_eq!(
+            hex::encode(hchacha(&key, &nonce)),
+            "82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc"
+        );
+    }
+
+    /// Tests that splitting the input across calls does not change the keystream.
+    #[test]
+    fn test_split_calls() {
+        let (key, nonce) = ([7u8; 32], [9u8; 12]);
+        let mut whole = [0u8; 150];
+        ChaCha::new(&key, &nonce, 12).apply_keystream(&mut whole);
+        let mut split = [0u8; 150];
+        let mut cipher = ChaCha::new(&key, &nonce, 12);
+        for chunk in split.chunks_mut(35) {
+            cipher.apply_keystream(chunk);
+        }
+        assert_eq!(whole, split);
+    }
+}
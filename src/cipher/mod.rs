@@ -0,0 +1,337 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Stream ciphers used by the obfuscator.
+//!
+//! The obfuscator encrypts a short block of every packet with ChaCha. The variant is
+//! selected per rule ([`CipherMode`]): ChaCha20 and XChaCha20 run on the assembly of
+//! `fast_chacha` where the CPU supports it, and everything else runs on the portable core
+//! in [`chacha`]. [`active_impl_name`] reports which one a mode runs on. Built without the
+//! default `fast-chacha` feature, every variant runs on the portable core; the keystream is
+//! the same. The C library needs this, as the `fast_chacha` assembly cannot be linked into
+//! a shared object. XChaCha20 takes a 24-byte nonce, so random nonces do not risk colliding
+//! however many packets are sent under one key.
+//!
+//! `NF_WGOBFS_CIPHER=fallback` runs ChaCha20 and XChaCha20 on the portable core even where
+//! `fast_chacha` is available, e.g. to reproduce a peer built without it when debugging
+//! interoperability; `fast` (or leaving it unset) keeps the automatic choice. Before the
+//! assembly is used, it is checked once against the portable core on this CPU: should they
+//! ever disagree, the portable core is used, with a warning, so that the keystream never
+//! depends on the CPU of the peer.
+
+pub mod chacha;
+
+#[cfg(feature = "fast-chacha")]
+use fast_chacha::FastChaCha20;
+#[cfg(feature = "fast-chacha")]
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+/// Environment variable overriding the ChaCha20 implementation (`fallback` or `fast`).
+pub const CIPHER_ENV: &str = "NF_WGOBFS_CIPHER";
+
+/// Interprets a [`CIPHER_ENV`] value: true if it forces the portable core.
+///
+/// Unknown values are reported and leave the automatic choice in place.
+#[cfg(feature = "fast-chacha")]
+fn parse_override(value: Option<&str>) -> bool {
+    match value.map(str::trim) {
+        None | Some("") => false,
+        Some(value) if value.eq_ignore_ascii_case("fallback") => true,
+        Some(value) if value.eq_ignore_ascii_case("fast") => false,
+        Some(value) => {
+            log::warn!("Ignoring {}={:?} (expected fallback or fast)", CIPHER_ENV, value);
+            false
+        }
+    }
+}
+
+/// Whether [`CIPHER_ENV`] forces ChaCha20 onto the portable core; read on first use.
+#[cfg(feature = "fast-chacha")]
+fn force_fallback() -> bool {
+    static FORCE: OnceLock<bool> = OnceLock::new();
+    *FORCE.get_or_init(|| parse_override(std::env::var(CIPHER_ENV).ok().as_deref()))
+}
+
+/// Whether the assembly of `fast_chacha` gives the keystream of the portable core on this
+/// CPU, across block boundaries; logs a warning if not.
+#[cfg(feature = "fast-chacha")]
+fn fast_agrees() -> bool {
+    let key: [u8; 32] = core::array::from_fn(|i| (i * 29 + 5) as u8);
+    let nonce: [u8; 12] = core::array::from_fn(|i| (i * 17 + 9) as u8);
+    let mut fast = [0u8; 300];
+    FastChaCha20::new(&key, &nonce).apply_keystream(&mut fast);
+    let mut portable = [0u8; 300];
+    chacha::ChaCha::new(&key, &nonce, 20).apply_keystream(&mut portable);
+    if fast != portable {
+        log::warn!(
+            "fast_chacha disagrees with the portable ChaCha20 on this CPU; using the portable \
+            core so that peers still interoperate"
+        );
+    }
+    fast == portable
+}
+
+/// Whether ChaCha20 runs on the assembly of `fast_chacha`: built with the `fast-chacha`
+/// feature, supported by the CPU, not overridden by [`CIPHER_ENV`] and in agreement with
+/// the portable core; checked on first use.
+#[cfg(feature = "fast-chacha")]
+pub fn fast_available() -> bool {
+    static FAST: OnceLock<bool> = OnceLock::new();
+    *FAST.get_or_init(|| !force_fallback() && fast_chacha::is_asm_available() && fast_agrees())
+}
+
+/// Whether ChaCha20 runs on the assembly of `fast_chacha`; never without the `fast-chacha`
+/// feature.
+#[cfg(not(feature = "fast-chacha"))]
+pub fn fast_available() -> bool {
+    false
+}
+
+/// Name of the implementation `mode` runs on in this process, e.g. `chacha20-fast` or
+/// `chacha20-portable`, as logged at startup and reported by `--version` and `status`.
+///
+/// Peers produce the same keystream either way; a name that differs from the peer's
+/// narrows a broken round-trip down to the configuration or the build.
+pub fn active_impl_name(mode: CipherMode) -> &'static str {
+    match (mode, fast_available()) {
+        (CipherMode::ChaCha8, _) => "chacha8-portable",
+        (CipherMode::ChaCha12, _) => "chacha12-portable",
+        (CipherMode::ChaCha20, true) => "chacha20-fast",
+        (CipherMode::ChaCha20, false) => "chacha20-portable",
+        (CipherMode::XChaCha20, true) => "xchacha20-fast",
+        (CipherMode::XChaCha20, false) => "xchacha20-portable",
+    }
+}
+
+/// ChaCha variant used to encrypt the obfuscated block
+/// (`cipher=chacha8|chacha12|chacha20|xchacha20`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CipherMode {
+    ChaCha8,
+    ChaCha12,
+    #[default]
+    ChaCha20,
+    XChaCha20,
+}
+
+impl CipherMode {
+    /// All modes, fastest first.
+    pub const ALL: [CipherMode; 4] =
+        [CipherMode::ChaCha8, CipherMode::ChaCha12, CipherMode::ChaCha20, CipherMode::XChaCha20];
+
+    /// Number of ChaCha rounds.
+    pub fn rounds(self) -> usize {
+        match self {
+            CipherMode::ChaCha8 => 8,
+            CipherMode::ChaCha12 => 12,
+            CipherMode::ChaCha20 | CipherMode::XChaCha20 => 20,
+        }
+    }
+
+    /// Length of the nonce in bytes.
+    pub fn nonce_len(self) -> usize {
+        match self {
+            CipherMode::XChaCha20 => 24,
+            _ => 12,
+        }
+    }
+
+    /// Name used in the configuration file.
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherMode::ChaCha8 => "chacha8",
+            CipherMode::ChaCha12 => "chacha12",
+            CipherMode::ChaCha20 => "chacha20",
+            CipherMode::XChaCha20 => "xchacha20",
+        }
+    }
+
+    /// Whether the mode may run on `fast_chacha` (see [`fast_available`]).
+    pub fn has_fast_path(self) -> bool {
+        matches!(self, CipherMode::ChaCha20 | CipherMode::XChaCha20)
+    }
+
+    /// Parses a mode from its configuration name (case-insensitive).
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A keystream generator for one packet.
+pub enum CipherImpl {
+    /// ChaCha20 on the assembly of `fast_chacha` (also XChaCha20, under the derived subkey).
+    #[cfg(feature = "fast-chacha")]
+    Fast(FastChaCha20),
+    /// The portable core, for any round count.
+    Fallback(chacha::ChaCha),
+}
+
+impl CipherImpl {
+    /// Creates the keystream for `mode` under `key` and `nonce`, starting at block counter 0.
+    ///
+    /// # Panics
+    /// If `nonce` is not [`CipherMode::nonce_len`] bytes long.
+    pub fn new(mode: CipherMode, key: &[u8; 32], nonce: &[u8]) -> Self {
+        if mode == CipherMode::XChaCha20 {
+            let (prefix, suffix) = nonce.split_at(16);
+            let subkey = Zeroizing::new(chacha::hchacha(key, prefix.try_into().unwrap()));
+            let mut inner = [0u8; 12];
+            inner[4..].copy_from_slice(suffix);
+            return Self::chacha20(&subkey, &inner);
+        }
+        let nonce: &[u8; 12] = nonce.try_into().expect("ChaCha takes a 12-byte nonce");
+        match mode {
+            CipherMode::ChaCha20 => Self::chacha20(key, nonce),
+            _ => CipherImpl::Fallback(chacha::ChaCha::new(key, nonce, mode.rounds())),
+        }
+    }
+
+    /// ChaCha20 on `fast_chacha` where [`fast_available`], else on the portable core.
+    #[cfg(feature = "fast-chacha")]
+    fn chacha20(key: &[u8; 32], nonce: &[u8; 12]) -> Self {
+        if !fast_available() {
+            return CipherImpl::Fallback(chacha::ChaCha::new(key, nonce, 20));
+        }
+        CipherImpl::Fast(FastChaCha20::new(key, nonce))
+    }
+
+    /// ChaCha20 on the portable core, without the `fast-chacha` feature.
+    #[cfg(not(feature = "fast-chacha"))]
+    fn chacha20(key: &[u8; 32], nonce: &[u8; 12]) -> Self {
+        CipherImpl::Fallback(chacha::ChaCha::new(key, nonce, 20))
+    }
+
+    /// XORs `data` with the next bytes of the keystream.
+    #[inline(always)]
+    pub fn apply_keystream(&mut self, data: &mut [u8]) {
+        match self {
+            #[cfg(feature = "fast-chacha")]
+            CipherImpl::Fast(cipher) => cipher.apply_keystream(data),
+            CipherImpl::Fallback(cipher) => cipher.apply_keystream(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that mode names round-trip and unknown names are rejected.
+    #[test]
+    fn test_mode_names() {
+        for mode in CipherMode::ALL {
+            assert_eq!(CipherMode::parse(mode.name()), Some(mode));
+        }
+        assert_eq!(CipherMode::parse("ChaCha12"), Some(CipherMode::ChaCha12));
+        assert_eq!(CipherMode::parse("chacha6"), None);
+        assert_eq!(CipherMode::default(), CipherMode::ChaCha20);
+    }
+
+    /// Tests that the portable core produces the same ChaCha20 keystream as `fast_chacha`.
+    #[test]
+    #[cfg(feature = "fast-chacha")]
+    fn test_portable_matches_fast_chacha20() {
+        let key: [u8; 32] = core::array::from_fn(|i| (i * 7 + 3) as u8);
+        let nonce: [u8; 12] = core::array::from_fn(|i| (i * 13 + 1) as u8);
+        for len in [1, 35, 64, 65, 200] {
+            let mut fast = vec![0u8; len];
+            CipherImpl::new(CipherMode::ChaCha20, &key, &nonce).apply_keystream(&mut fast);
+            let mut portable = vec![0u8; len];
+            chacha::ChaCha::new(&key, &nonce, 20).apply_keystream(&mut portable);
+            assert_eq!(fast, portable, "length {len}");
+        }
+    }
+
+    /// Tests that the fast and portable paths that `CipherImpl::new` chooses between give
+    /// the same keystream in both 20-round modes, so peers on different CPUs or builds
+    /// interoperate.
+    #[test]
+    #[cfg(feature = "fast-chacha")]
+    fn test_fast_matches_fallback() {
+        let key: [u8; 32] = core::array::from_fn(|i| (i * 5 + 11) as u8);
+        for mode in [CipherMode::ChaCha20, CipherMode::XChaCha20] {
+            let nonce: Vec<u8> = (0..mode.nonce_len()).map(|i| (i * 3 + 2) as u8).collect();
+            let fallback = || match mode {
+                CipherMode::XChaCha20 => {
+                    let subkey = chacha::hchacha(&key, nonce[..16].try_into().unwrap());
+                    let mut inner = [0u8; 12];
+                    inner[4..].copy_from_slice(&nonce[16..]);
+                    chacha::ChaCha::new(&subkey, &inner, 20)
+                }
+                _ => chacha::ChaCha::new(&key, nonce[..].try_into().unwrap(), 20),
+            };
+            let fast = || CipherImpl::new(mode, &key, &nonce);
+            if fast_available() {
+                assert!(
+                    matches!(fast(), CipherImpl::Fast(_)),
+                    "{} not on fast_chacha",
+                    mode.name()
+                );
+            }
+            for len in [1, 35, 63, 64, 65, 128, 1500] {
+                let mut expected: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let mut actual = expected.clone();
+                CipherImpl::Fallback(fallback()).apply_keystream(&mut expected);
+                fast().apply_keystream(&mut actual);
+                assert_eq!(actual, expected, "{} length {len}", mode.name());
+            }
+        }
+    }
+
+    /// Tests that the startup check passes where the assembly runs at all.
+    #[test]
+    #[cfg(feature = "fast-chacha")]
+    fn test_fast_agrees() {
+        if fast_chacha::is_asm_available() {
+            assert!(fast_agrees());
+        }
+        assert!(CipherMode::ChaCha20.has_fast_path() && CipherMode::XChaCha20.has_fast_path());
+        assert!(!CipherMode::ChaCha8.has_fast_path() && !CipherMode::ChaCha12.has_fast_path());
+    }
+
+    /// Tests the values of the cipher override.
+    #[test]
+    #[cfg(feature = "fast-chacha")]
+    fn test_parse_override() {
+        assert!(parse_override(Some("fallback")));
+        assert!(parse_override(Some(" Fallback ")));
+        assert!(!parse_override(Some("fast")));
+        assert!(!parse_override(Some("")));
+        assert!(!parse_override(Some("avx2")));
+        assert!(!parse_override(None));
+    }
+
+    /// Tests that the reported implementation names follow the mode and the fast path.
+    #[test]
+    fn test_active_impl_name() {
+        assert_eq!(active_impl_name(CipherMode::ChaCha8), "chacha8-portable");
+        assert_eq!(active_impl_name(CipherMode::ChaCha12), "chacha12-portable");
+        let suffix = if fast_available() { "-fast" } else { "-portable" };
+        for mode in [CipherMode::ChaCha20, CipherMode::XChaCha20] {
+            assert_eq!(active_impl_name(mode), format!("{}{}", mode.name(), suffix));
+        }
+        #[cfg(not(feature = "fast-chacha"))]
+        assert!(!fast_available());
+    }
+
+    /// Tests XChaCha20 against a keystream from the XChaCha20 draft's key and nonce.
+    #[test]
+    fn test_xchacha20_vector() {
+        let key: [u8; 32] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 24] = core::array::from_fn(|i| 0x40 + i as u8);
+        let mut data = [0u8; 80];
+        CipherImpl::new(CipherMode::XChaCha20, &key, &nonce).apply_keystream(&mut data);
+        assert_eq!(
+            hex::encode(data),
+            "7b191f80f361f099094f6f4b8fb97df847cc6873a8f2b190dd73807183f907d5\
+             a1cb27385b00329f7ddc127059d6882551a120e7631352e9b0381572e950155a\
+             f10c73f45bf0f45afb1277d3f6ae9d55"
+        );
+    }
+}
@@ -34,106 +34,399 @@
 //! - Helper functions for integration with systemd service management.
 
 use crate::config;
+use crate::filter::{obfuscator, queue};
+use crate::logger::LogFormat;
+use crate::stats;
+use clap::{Args, Parser, Subcommand};
+use std::ffi::OsString;
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+/// Command line of nf_wgobfs: an optional command plus the global [`Options`].
+#[derive(Debug, Parser)]
+#[command(
+    name = "nf_wgobfs",
+    about = "WireGuard obfuscation filter for Linux NFQUEUE",
+    after_help = "Without a command, all configured filters are run (same as `run`).",
+    disable_version_flag = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    options: Options,
+}
 
 /// Enum representing supported CLI commands for the application.
 ///
 /// Each variant corresponds to a specific mode of operation:
-/// - `Start(u16)`: Start the application for a specific queue number.
+/// - `Start { queue_num }`: Start the application for a specific queue number.
 /// - `RunAll`: Run all configured filters.
-/// - `GenerateUnits`: Generate systemd unit files for all configured filters.
+/// - `GenerateUnits { out }`: Generate systemd unit files for all configured filters.
+/// - `GenerateOpenrc { out }`: Generate OpenRC service scripts for all configured filters.
+/// - `GenerateNftables`: Generate an nftables ruleset for all configured filters.
+/// - `GenerateIptables`: Print iptables/ip6tables rules for all configured filters.
+/// - `GenerateDissector { queue, port }`: Print a Wireshark dissector for a rule's framing.
 /// - `Version`: Print version information.
-#[derive(Debug)]
+/// - `Status { path }`: Print the statistics served on the given socket.
+/// - `Pcap { .. }`: Transform the packets of a capture file offline.
+/// - `Overhead { queue }`: Print the obfuscation overhead of the rules.
+/// - `DumpConfig`: Print the configuration sources and the rules loaded from each.
+/// - `Completions { shell }`: Print a shell completion script.
+///
+/// The generators and `version` also accept their historical flag spelling
+/// (`--generate-units`, `--version`, ...).
+#[derive(Debug, Subcommand)]
 pub enum Command {
-    /// Start the application for a specific queue number.
-    Start(u16),
-    /// Run all configured filters.
+    /// Run the filter of one configured queue in the foreground.
+    #[command(name = "queue")]
+    Start {
+        /// NFQUEUE number of the rule to run.
+        #[arg(value_name = "NUM")]
+        queue_num: u16,
+    },
+    /// Run all configured filters in the foreground (the default).
+    #[command(name = "run")]
     RunAll,
-    /// Generate systemd unit files for all configured filters.
-    GenerateUnits,
+    /// Generate systemd units for all configured filters.
+    #[command(long_flag = "generate-units")]
+    GenerateUnits {
+        /// Directory to write to instead of /tmp/nf_wgobfs (e.g. /etc/systemd/system).
+        #[arg(long, value_name = "DIR")]
+        out: Option<String>,
+    },
+    /// Generate OpenRC service scripts for all configured filters.
+    #[command(name = "generate-openrc", long_flag = "generate-openrc")]
+    GenerateOpenrc {
+        /// Directory to write to instead of /tmp/nf_wgobfs (e.g. /etc/init.d).
+        #[arg(long, value_name = "DIR")]
+        out: Option<String>,
+    },
+    /// Generate an nftables ruleset steering traffic into the configured queues.
+    #[command(name = "generate-nft", long_flag = "generate-nft")]
+    GenerateNftables,
+    /// Print iptables/ip6tables rules steering traffic into the configured queues.
+    #[command(long_flag = "generate-iptables")]
+    GenerateIptables,
+    /// Print a Lua Wireshark dissector labelling the fields of a rule's packets.
+    #[command(name = "generate-dissector", long_flag = "generate-dissector")]
+    GenerateDissector {
+        /// Rule to take the cipher, auth and encrypt-bytes settings from (first rule if omitted).
+        #[arg(long, value_name = "NUM")]
+        queue: Option<u16>,
+        /// UDP port to register the dissector on (the WireGuard listen port).
+        #[arg(long, value_name = "PORT", default_value_t = crate::dissector::DEFAULT_PORT)]
+        port: u16,
+    },
     /// Print version information.
+    #[command(long_flag = "version", short_flag = 'V')]
     Version,
+    /// Print the per-queue statistics of a running instance.
+    Status {
+        /// Statistics socket (default: --stats-socket or /run/nf_wgobfs.sock).
+        path: Option<String>,
+    },
+    /// Obfuscate or deobfuscate the packets of a pcap file offline.
+    Pcap {
+        /// Capture to read.
+        input: String,
+        /// Capture to write.
+        output: String,
+        /// Transform to apply: `out` obfuscates, `in` deobfuscates.
+        #[arg(value_name = "in|out", value_parser = parse_direction)]
+        direction: config::Direction,
+        /// Rule to take the key and MTU from (first rule if omitted).
+        queue: Option<u16>,
+    },
+    /// Round-trip sample packets through every configured rule, without NFQUEUE.
+    #[command(name = "self-test")]
+    SelfTest,
+    /// Obfuscate and deobfuscate one packet given in hex, printing every stage.
+    #[command(name = "obfuscate-hex")]
+    ObfuscateHex {
+        /// Full IP packet in hex; whitespace and colons between bytes are ignored.
+        hex: String,
+        /// Rule to take the settings from (first rule if omitted).
+        #[arg(long, value_name = "NUM")]
+        queue: Option<u16>,
+    },
+    /// Print canonical test vectors of the wire format, to check another implementation against.
+    #[command(name = "test-vectors")]
+    TestVectors,
+    /// Print the bytes obfuscation adds to packets and the WireGuard MTU leaving room for them.
+    Overhead {
+        /// Rule to report on (all rules if omitted).
+        #[arg(long, value_name = "NUM")]
+        queue: Option<u16>,
+    },
+    /// Print the configuration sources in precedence order and the rules loaded from each.
+    #[command(name = "dump-config")]
+    DumpConfig,
+    /// Print a completion script for bash, zsh or fish.
+    Completions {
+        /// Shell to generate the script for.
+        shell: String,
+    },
+}
+
+/// Options that apply to every command.
+#[derive(Debug, Default, Args)]
+pub struct Options {
+    /// Configuration file replacing /etc/nf_wgobfs/config and NF_WGOBFS_CONF, `-` for stdin.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Unix socket to serve per-queue statistics on.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub stats_socket: Option<String>,
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100.
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub metrics: Option<String>,
+    /// User to switch to once the queues are bound.
+    #[arg(long, global = true, value_name = "NAME|UID")]
+    pub user: Option<String>,
+    /// Group to switch to, defaults to the user's primary group.
+    #[arg(long, global = true, value_name = "NAME|GID")]
+    pub group: Option<String>,
+    /// Receive buffer of each NFQUEUE socket in bytes, 0 for the kernel default.
+    #[arg(long, global = true, value_name = "BYTES", default_value_t = queue::DEFAULT_RCVBUF)]
+    pub rcvbuf: usize,
+    /// Fork into the background once the configuration is loaded (`run` and `queue`).
+    #[arg(long, global = true)]
+    pub daemonize: bool,
+    /// PID file to write with --daemonize (default /run/nf_wgobfs.pid).
+    #[arg(long, global = true, value_name = "PATH")]
+    pub pid_file: Option<String>,
+    /// File to append the log to with --daemonize (default: discarded).
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<String>,
+    /// Log line layout: `text`, or `json` for one object per event.
+    #[arg(long, global = true, value_name = "text|json", default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+/// Parses the direction argument of `pcap`.
+fn parse_direction(arg: &str) -> Result<config::Direction, String> {
+    match arg.to_lowercase().as_str() {
+        "in" => Ok(config::Direction::In),
+        "out" => Ok(config::Direction::Out),
+        _ => Err("expected `in` or `out`".to_string()),
+    }
 }
 
-/// Parses command-line arguments and returns the corresponding [`Command`].
+/// Parses `args` (including the program name) into a [`Command`] and [`Options`].
+fn parse_from<I, T>(args: I) -> Result<(Command, Options), clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let cli = Cli::try_parse_from(args)?;
+    Ok((cli.command.unwrap_or(Command::RunAll), cli.options))
+}
+
+/// Parses command-line arguments and returns the corresponding [`Command`] and [`Options`].
 ///
 /// # Returns
-/// * [`Command`] - The parsed command to execute.
+/// * ([`Command`], [`Options`]) - The parsed command to execute and the global options.
 ///
 /// # Behavior
-/// - `--generate-units`: Generates systemd unit files.
-/// - `--version` or `-V`: Prints version information.
+/// - `run` or no command at all: Runs all configured filters.
 /// - `queue <num>`: Starts the application for the specified queue number.
-/// - No arguments or unknown arguments: Runs all configured filters.
+/// - `generate-units [--out <dir>]`: Generates systemd unit files.
+/// - `generate-openrc [--out <dir>]`: Generates OpenRC service scripts.
+/// - `generate-nft`: Generates an nftables ruleset.
+/// - `generate-iptables`: Prints iptables and ip6tables rules.
+/// - `generate-dissector [--queue <num>] [--port <port>]`: Prints a Wireshark dissector.
+/// - `version`, `--version` or `-V`: Prints version information.
+/// - `status [path]`: Prints the statistics of a running instance.
+/// - `pcap <input> <output> <in|out> [queue]`: Transforms a capture file offline.
+/// - `self-test`: Checks that every rule round-trips sample packets.
+/// - `obfuscate-hex <hex> [--queue <num>]`: Previews the transform of one packet.
+/// - `test-vectors`: Prints deterministic test vectors of the wire format.
+/// - `overhead [--queue <num>]`: Prints the obfuscation overhead and matching WireGuard MTU.
+/// - `dump-config`: Prints the configuration sources and the rules loaded from each.
+/// - `completions <bash|zsh|fish>`: Prints a shell completion script.
+/// - `--config <path>` (anywhere): Reads the rules from `path` instead of the default file.
+/// - `--stats-socket <path>` (anywhere): Serves statistics on the given Unix socket.
+/// - `--metrics <addr>` (anywhere): Serves Prometheus metrics on the given TCP address.
+/// - `--user <name|uid>` / `--group <name|gid>` (anywhere): Drops root privileges once the
+///   queues are bound, keeping only `CAP_NET_ADMIN`.
+/// - `--log-format <text|json>` (anywhere): Writes log records as text lines or JSON objects.
+/// - `--daemonize [--pid-file <path>] [--log-file <path>]` (anywhere): Runs the filters in
+///   the background, detached from the terminal, with a PID file.
+///
+/// `--help` prints the usage. Unknown commands and invalid arguments (e.g. a queue
+/// number that is not a `u16`) print the usage and exit with status 2.
 ///
 /// # Example
-/// ```
-/// let cmd = parse_args();
+/// ```no_run
+/// # use nf_wgobfs::cli::{parse_args, Command};
+/// let (cmd, options) = parse_args();
 /// match cmd {
-///     Command::Start(q) => { /* start for queue q */ }
+///     Command::Start { queue_num } => { /* start for queue queue_num */ }
 ///     Command::RunAll => { /* run all filters */ }
-///     Command::GenerateUnits => { /* generate systemd units */ }
+///     Command::GenerateUnits { out } => { /* generate systemd units into out */ }
+///     Command::GenerateOpenrc { out } => { /* generate OpenRC scripts into out */ }
+///     Command::GenerateNftables => { /* generate nftables rules */ }
+///     Command::GenerateIptables => { /* print iptables rules */ }
+///     Command::GenerateDissector { queue, port } => { /* print a Wireshark dissector */ }
 ///     Command::Version => { /* print version */ }
+///     Command::Status { path } => { /* print statistics served on path */ }
+///     Command::Pcap { .. } => { /* transform a capture file */ }
+///     Command::SelfTest => { /* round-trip sample packets through every rule */ }
+///     Command::ObfuscateHex { hex, queue } => { /* preview one packet */ }
+///     Command::TestVectors => { /* print test vectors of the wire format */ }
+///     Command::Overhead { queue } => { /* print the obfuscation overhead */ }
+///     Command::DumpConfig => { /* print the configuration sources and rules */ }
+///     Command::Completions { shell } => { /* print a completion script */ }
 /// }
 /// ```
-pub fn parse_args() -> Command {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "--generate-units" => Command::GenerateUnits,
-            "--version" | "-V" => Command::Version,
-            "queue" if args.len() > 2 => Command::Start(args[2].parse().unwrap_or(0)),
-            _ => Command::RunAll,
+pub fn parse_args() -> (Command, Options) {
+    parse_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+}
+
+/// Fetches the statistics snapshot from a running instance and prints it.
+///
+/// # Arguments
+/// * `path` - Path of the Unix socket the instance serves statistics on.
+///
+/// # Returns
+/// * `std::io::Result<()>` - Error if the socket cannot be reached.
+pub fn print_status(path: &str) -> std::io::Result<()> {
+    let json = stats::fetch(path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot read statistics from {}: {}", path, e))
+    })?;
+    println!("{}", stats::pretty_json(&json));
+    Ok(())
+}
+
+/// Bytes WireGuard adds around an inner packet over IPv6 endpoints: IPv6 and UDP headers and
+/// its own 32-byte transport header and tag. IPv4 endpoints take 20 bytes less.
+const WG_ENCAP_IPV6: usize = 40 + 8 + 32;
+
+/// Describes the obfuscation overhead of `filter` and the WireGuard MTU it leaves room for.
+///
+/// The MTU of the rule bounds the obfuscated packets on the wire, so a WireGuard interface
+/// MTU of up to the first value gives every packet its full random ballast, and up to the
+/// second keeps every packet within the MTU with less ballast on the largest ones.
+pub fn overhead_report(filter: &config::FilterConfig) -> String {
+    let overhead = obfuscator::overhead(filter);
+    let wg_mtu = |added: usize| filter.mtu.saturating_sub(WG_ENCAP_IPV6 + added);
+    format!(
+        "queue {} ({}, {:?}): MTU {}\n  \
+         overhead:      {} bytes minimum, {} maximum, {} typical\n  \
+         WireGuard MTU: {} for full ballast, at most {} (IPv6 endpoints; 20 more over IPv4)\n",
+        filter.queue_num,
+        filter.iface,
+        filter.direction,
+        filter.mtu,
+        overhead.min,
+        overhead.max,
+        overhead.typical,
+        wg_mtu(overhead.max),
+        wg_mtu(overhead.min)
+    )
+}
+
+/// Prints [`overhead_report`] for the rule of `queue`, or for every rule.
+pub fn print_overhead(configs: &[config::FilterConfig], queue: Option<u16>) -> std::io::Result<()> {
+    let rules: Vec<_> =
+        configs.iter().filter(|f| queue.is_none_or(|queue_num| f.queue_num == queue_num)).collect();
+    if rules.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Queue not found in config"));
+    }
+    for filter in rules {
+        print!("{}", overhead_report(filter));
+    }
+    Ok(())
+}
+
+/// Describes the loaded configuration: each source in precedence order, followed by one
+/// line per rule. Keys are shown by fingerprint only.
+pub fn config_dump(layers: &[config::ConfigLayer]) -> String {
+    let mut out = String::new();
+    for layer in layers {
+        out.push_str(&format!("# {}\n", layer.source));
+        for filter in &layer.rules {
+            let queues = match filter.workers {
+                1 => filter.queue_num.to_string(),
+                _ => format!("{}-{}", filter.queue_num, filter.queues().end - 1),
+            };
+            out.push_str(&format!(
+                "queue {} ({}, {:?}): MTU {}{}, cipher {:?}, key {}{}\n",
+                queues,
+                filter.iface,
+                filter.direction,
+                filter.mtu,
+                if filter.mtu_auto { " (auto)" } else { "" },
+                filter.cipher,
+                filter.key_fingerprints(),
+                if filter.enabled { "" } else { ", disabled" }
+            ));
         }
-    } else {
-        Command::RunAll
     }
+    out
 }
 
 /// Generates systemd unit files for each filter configuration and a target unit.
 ///
-/// This function creates a directory `/tmp/nf_wgobfs/` and writes a systemd
-/// service unit file for each filter configuration. It also generates a target
-/// unit that depends on all generated service units. After generation, it prints
-/// instructions for installing and activating the units.
+/// This function creates the output directory (`/tmp/nf_wgobfs/` by default) and
+/// writes a systemd service unit file for each filter configuration. It also
+/// generates a target unit that depends on all generated service units. After
+/// generation, it prints instructions for installing and activating the units.
 ///
 /// # Arguments
 /// * `configs` - A slice of [`config::FilterConfig`] containing filter configurations.
+/// * `out_dir` - Directory to write to, e.g. `/etc/systemd/system`. When given, the
+///   units are installed in place and the printed instructions skip the copy step.
 ///
 /// # Returns
-/// * `std::io::Result<()>` - Result indicating success or failure.
+/// * `std::io::Result<()>` - Result indicating success or failure; errors name the
+///   directory or file that could not be written.
 ///
 /// # Side Effects
-/// - Writes unit files to `/tmp/nf_wgobfs/`.
+/// - Writes unit files to the output directory.
 /// - Prints instructions for installing and activating the generated units.
 ///
 /// # Example
+/// ```no_run
+/// # use nf_wgobfs::cli::generate_systemd_units;
+/// # fn main() -> std::io::Result<()> {
+/// # let configs = nf_wgobfs::config::read_config()?;
+/// generate_systemd_units(&configs, Some("/etc/systemd/system"))?;
+/// # Ok(())
+/// # }
 /// ```
-/// generate_systemd_units(&configs)?;
-/// ```
-pub fn generate_systemd_units(configs: &[config::FilterConfig]) -> std::io::Result<()> {
-    let out_dir = "/tmp/nf_wgobfs";
-    fs::create_dir_all(out_dir)?;
+pub fn generate_systemd_units(
+    configs: &[config::FilterConfig],
+    out_dir: Option<&str>,
+) -> std::io::Result<()> {
+    let installed = out_dir.is_some();
+    let out_dir = out_dir.unwrap_or("/tmp/nf_wgobfs");
+    fs::create_dir_all(out_dir).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot create directory {}: {}", out_dir, e))
+    })?;
     let mut unit_names = Vec::new();
     for filter in configs {
         // Generate a systemd service unit for each queue
         let unit = format!(
             r#"[Unit]
-Description=NFQUEUE WireGuard Obfuscator queue {queue}
+Description=NFQUEUE WireGuard Obfuscator queue {queue} ({iface})
 After=network.target
 
 [Service]
 Type=simple
 ExecStart=/usr/bin/nf_wgobfs queue {queue}
+ExecReload=/bin/kill -HUP $MAINPID
 Restart=on-failure
 
 [Install]
 WantedBy=multi-user.target
 "#,
-            queue = filter.queue_num
+            queue = filter.queue_num,
+            iface = filter.iface
         );
         let filename = format!("{}/nf_wgobfs@{}.service", out_dir, filter.queue_num);
-        fs::write(&filename, unit)?;
+        write_file(&filename, unit)?;
         println!("Generated {}", filename);
         unit_names.push(format!("nf_wgobfs@{}.service", filter.queue_num));
     }
@@ -152,15 +445,451 @@ WantedBy=multi-user.target
         wants = wants
     );
     let target_filename = format!("{}/nf_wgobfs.target", out_dir);
-    fs::write(&target_filename, target)?;
+    write_file(&target_filename, target)?;
     println!("Generated {}", target_filename);
 
     // Print instructions for installing and activating the units
-    println!("\nTo install and activate these units, run:");
-    println!("  sudo cp /tmp/nf_wgobfs/nf_wgobfs@*.service /etc/systemd/system/");
-    println!("  sudo cp /tmp/nf_wgobfs/nf_wgobfs.target /etc/systemd/system/");
+    if installed {
+        println!("\nTo activate these units, run:");
+    } else {
+        println!("\nTo install and activate these units, run:");
+        println!("  sudo cp /tmp/nf_wgobfs/nf_wgobfs@*.service /etc/systemd/system/");
+        println!("  sudo cp /tmp/nf_wgobfs/nf_wgobfs.target /etc/systemd/system/");
+    }
     println!("  sudo systemctl daemon-reload");
     println!("  sudo systemctl enable nf_wgobfs.target");
     println!("  sudo systemctl start nf_wgobfs.target");
     Ok(())
 }
+
+/// Builds the OpenRC service script of one rule.
+///
+/// The script runs `nf_wgobfs queue <num> --daemonize` with a PID file and a log file of
+/// its own, so that `start-stop-daemon` tracks the detached process and stops it with
+/// SIGTERM.
+pub fn openrc_script(filter: &config::FilterConfig) -> String {
+    format!(
+        r#"#!/sbin/openrc-run
+# Generated by nf_wgobfs generate-openrc
+
+description="NFQUEUE WireGuard Obfuscator queue {queue} ({iface})"
+command="/usr/bin/nf_wgobfs"
+pidfile="/run/nf_wgobfs.{queue}.pid"
+command_args="queue {queue} --daemonize --pid-file ${{pidfile}} --log-file /var/log/nf_wgobfs.{queue}.log"
+
+depend() {{
+	need net
+	after firewall
+}}
+"#,
+        queue = filter.queue_num,
+        iface = filter.iface
+    )
+}
+
+/// Generates an OpenRC service script for each filter configuration.
+///
+/// Works like [`generate_systemd_units`] for systems without systemd, such as Alpine: the
+/// scripts `nf_wgobfs.<queue>` (see [`openrc_script`]) are written to `out_dir`, or to
+/// `/tmp/nf_wgobfs/` by default, made executable, and the commands to enable them are
+/// printed.
+///
+/// # Returns
+/// * `std::io::Result<()>` - Result indicating success or failure; errors name the
+///   directory or file that could not be written.
+pub fn generate_openrc_scripts(
+    configs: &[config::FilterConfig],
+    out_dir: Option<&str>,
+) -> std::io::Result<()> {
+    let installed = out_dir.is_some();
+    let out_dir = out_dir.unwrap_or("/tmp/nf_wgobfs");
+    fs::create_dir_all(out_dir).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot create directory {}: {}", out_dir, e))
+    })?;
+    let mut services = Vec::new();
+    for filter in configs {
+        let filename = format!("{}/nf_wgobfs.{}", out_dir, filter.queue_num);
+        write_file(&filename, openrc_script(filter))?;
+        fs::set_permissions(&filename, fs::Permissions::from_mode(0o755)).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Cannot make {} executable: {}", filename, e))
+        })?;
+        println!("Generated {}", filename);
+        services.push(format!("nf_wgobfs.{}", filter.queue_num));
+    }
+
+    // Print instructions for installing and activating the services
+    if installed {
+        println!("\nTo activate these services, run:");
+    } else {
+        println!("\nTo install and activate these services, run:");
+        println!("  sudo cp /tmp/nf_wgobfs/nf_wgobfs.* /etc/init.d/");
+    }
+    for service in &services {
+        println!("  sudo rc-update add {} default", service);
+    }
+    for service in &services {
+        println!("  sudo rc-service {} start", service);
+    }
+    Ok(())
+}
+
+/// Writes a generated file, naming it in the error on failure.
+fn write_file(path: &str, contents: String) -> std::io::Result<()> {
+    fs::write(path, contents)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Cannot write {}: {}", path, e)))
+}
+
+/// Builds an nftables ruleset that queues each rule's traffic to its NFQUEUE.
+///
+/// Inbound rules match UDP arriving on the rule's interface with destination port
+/// `$wg_port` in prerouting; outbound rules match UDP leaving it with source port
+/// `$wg_port` in postrouting. Rules with a `port=` list match its ports instead.
+/// Rules with several workers fan packets out over their queues by CPU. The table is
+/// deleted and recreated, so the file can be applied repeatedly.
+pub fn nftables_ruleset(configs: &[config::FilterConfig]) -> String {
+    let mut inbound = String::new();
+    let mut outbound = String::new();
+    for filter in configs {
+        let queue = match filter.workers {
+            1 => filter.queue_num.to_string(),
+            _ => format!("{}-{} fanout", filter.queue_num, filter.queues().end - 1),
+        };
+        let ports = match filter.ports.as_slice() {
+            [] => "$wg_port".to_string(),
+            ports => format!("{{ {} }}", port_list(ports, "-", ", ")),
+        };
+        match filter.direction {
+            config::Direction::In => inbound.push_str(&format!(
+                "        iifname \"{}\" udp dport {} queue num {}\n",
+                filter.iface, ports, queue
+            )),
+            config::Direction::Out => outbound.push_str(&format!(
+                "        oifname \"{}\" udp sport {} queue num {}\n",
+                filter.iface, ports, queue
+            )),
+        }
+    }
+    format!(
+        r#"#!/usr/sbin/nft -f
+# Generated by nf_wgobfs: steers WireGuard traffic into the obfuscator queues.
+# Set wg_port to the WireGuard listen port before applying.
+define wg_port = 51820
+
+table inet nf_wgobfs
+delete table inet nf_wgobfs
+
+table inet nf_wgobfs {{
+    chain in_chain {{
+        type filter hook prerouting priority 0; policy accept;
+{inbound}    }}
+
+    chain out_chain {{
+        type filter hook postrouting priority 0; policy accept;
+{outbound}    }}
+}}
+"#
+    )
+}
+
+/// Writes the nftables ruleset for all filter configurations to `/tmp/nf_wgobfs`.
+///
+/// # Arguments
+/// * `configs` - Slice of filter configurations.
+///
+/// # Returns
+/// * `std::io::Result<()>` - Ok on success, or an error if file operations fail.
+pub fn generate_nftables(configs: &[config::FilterConfig]) -> std::io::Result<()> {
+    let out_dir = "/tmp/nf_wgobfs";
+    fs::create_dir_all(out_dir)?;
+    let filename = format!("{}/nf_wgobfs.nft", out_dir);
+    fs::write(&filename, nftables_ruleset(configs))?;
+    println!("Generated {}", filename);
+
+    // Print instructions for applying the ruleset
+    println!("\nSet wg_port in the file, then apply the ruleset with:");
+    println!("  sudo nft -f /tmp/nf_wgobfs/nf_wgobfs.nft");
+    println!("To load it at boot, run:");
+    println!("  sudo cp /tmp/nf_wgobfs/nf_wgobfs.nft /etc/nftables.d/");
+    println!("and include \"/etc/nftables.d/nf_wgobfs.nft\" from /etc/nftables.conf");
+    Ok(())
+}
+
+/// Joins `ports` with `separator`, each range written as its first and last port around
+/// `dash`.
+fn port_list(ports: &[config::PortRange], dash: &str, separator: &str) -> String {
+    ports
+        .iter()
+        .map(|range| {
+            if range.first == range.last {
+                range.first.to_string()
+            } else {
+                format!("{}{}{}", range.first, dash, range.last)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Most entries of a `port=` list in one iptables rule: the multiport match takes up to 15
+/// ports, a range counting as two.
+const MULTIPORT_ENTRIES: usize = 7;
+
+/// Builds a shell script of iptables and ip6tables rules queueing each rule's traffic.
+///
+/// Inbound rules go to the mangle PREROUTING chain (`-i <iface> --dport $WG_PORT`),
+/// outbound rules to mangle POSTROUTING (`-o <iface> --sport $WG_PORT`), once for
+/// IPv4 and once for IPv6. Rules with a `port=` list match its ports with the multiport
+/// match instead, over several rules for long lists. Rules with several workers balance
+/// over their queues by CPU.
+pub fn iptables_rules(configs: &[config::FilterConfig]) -> String {
+    let mut script = String::from(
+        "#!/bin/sh\n\
+         # Generated by nf_wgobfs: steers WireGuard traffic into the obfuscator queues.\n\
+         # Set WG_PORT to the WireGuard listen port before running.\n\
+         WG_PORT=51820\n",
+    );
+    for tool in ["iptables", "ip6tables"] {
+        script.push('\n');
+        for filter in configs {
+            let (chain, iface_flag, port_flag, ports_flag) = match filter.direction {
+                config::Direction::In => ("PREROUTING", "-i", "--dport", "--dports"),
+                config::Direction::Out => ("POSTROUTING", "-o", "--sport", "--sports"),
+            };
+            let queue = match filter.workers {
+                1 => format!("--queue-num {}", filter.queue_num),
+                _ => format!(
+                    "--queue-balance {}:{} --queue-cpu-fanout",
+                    filter.queue_num,
+                    filter.queues().end - 1
+                ),
+            };
+            let port_matches = match filter.ports.as_slice() {
+                [] => vec![format!("{port_flag} \"$WG_PORT\"")],
+                ports => ports
+                    .chunks(MULTIPORT_ENTRIES)
+                    .map(|chunk| {
+                        format!("-m multiport {ports_flag} {}", port_list(chunk, ":", ","))
+                    })
+                    .collect(),
+            };
+            for port_match in port_matches {
+                script.push_str(&format!(
+                    "{tool} -t mangle -A {chain} {iface_flag} {iface} -p udp {port_match} \
+                     -j NFQUEUE {queue}\n",
+                    iface = filter.iface
+                ));
+            }
+        }
+    }
+    script
+}
+
+/// Prints the iptables/ip6tables rules for all filter configurations to stdout.
+///
+/// # Arguments
+/// * `configs` - Slice of filter configurations.
+pub fn generate_iptables(configs: &[config::FilterConfig]) {
+    print!("{}", iptables_rules(configs));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    /// Tests that no command runs all filters and that global options go anywhere.
+    #[test]
+    fn test_parse_default_and_options() {
+        let (cmd, options) = parse_from(["nf_wgobfs", "--user", "nobody"]).unwrap();
+        assert!(matches!(cmd, Command::RunAll));
+        assert_eq!(options.user.as_deref(), Some("nobody"));
+
+        let (cmd, options) =
+            parse_from(["nf_wgobfs", "queue", "3", "--stats-socket", "/tmp/s"]).unwrap();
+        assert!(matches!(cmd, Command::Start { queue_num: 3 }));
+        assert_eq!(options.stats_socket.as_deref(), Some("/tmp/s"));
+        assert_eq!(options.log_format, LogFormat::Text);
+
+        let (_, options) = parse_from(["nf_wgobfs", "run", "--log-format", "json"]).unwrap();
+        assert_eq!(options.log_format, LogFormat::Json);
+        assert!(!options.daemonize);
+
+        let args = ["nf_wgobfs", "--daemonize", "queue", "2", "--pid-file", "/run/q2.pid"];
+        let (cmd, options) = parse_from(args).unwrap();
+        assert!(matches!(cmd, Command::Start { queue_num: 2 }));
+        assert!(options.daemonize);
+        assert_eq!(options.pid_file.as_deref(), Some("/run/q2.pid"));
+        assert_eq!(options.log_file, None);
+    }
+
+    /// Tests that the historical flag spellings still select their commands.
+    #[test]
+    fn test_parse_legacy_flags() {
+        let (cmd, _) = parse_from(["nf_wgobfs", "--generate-units", "--out", "/x"]).unwrap();
+        assert!(matches!(cmd, Command::GenerateUnits { out: Some(ref d) } if d == "/x"));
+        let (cmd, _) = parse_from(["nf_wgobfs", "--generate-nft"]).unwrap();
+        assert!(matches!(cmd, Command::GenerateNftables));
+        let (cmd, _) = parse_from(["nf_wgobfs", "-V"]).unwrap();
+        assert!(matches!(cmd, Command::Version));
+        let (cmd, _) = parse_from(["nf_wgobfs", "self-test"]).unwrap();
+        assert!(matches!(cmd, Command::SelfTest));
+        let (cmd, _) = parse_from(["nf_wgobfs", "test-vectors"]).unwrap();
+        assert!(matches!(cmd, Command::TestVectors));
+    }
+
+    /// Tests that `--config` goes anywhere and that `dump-config` parses.
+    #[test]
+    fn test_parse_config_option() {
+        let (cmd, options) = parse_from(["nf_wgobfs", "dump-config", "--config", "/x"]).unwrap();
+        assert!(matches!(cmd, Command::DumpConfig));
+        assert_eq!(options.config.as_deref(), Some("/x"));
+        let (cmd, options) = parse_from(["nf_wgobfs", "--config", "-", "run"]).unwrap();
+        assert!(matches!(cmd, Command::RunAll));
+        assert_eq!(options.config.as_deref(), Some("-"));
+    }
+
+    /// Tests that the dump names each source and its rules without revealing the keys.
+    #[test]
+    fn test_config_dump() {
+        let layer = |source, line: &str| config::ConfigLayer {
+            source,
+            rules: parse_config(&[line.to_string()]).unwrap(),
+        };
+        let layers = [
+            layer(
+                config::ConfigSource::File("/etc/x".into()),
+                "0:in:eth0:secret:workers=3:enabled=off",
+            ),
+            layer(config::ConfigSource::Stdin, "5:out:ppp0:secret:1400"),
+        ];
+        let dump = config_dump(&layers);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 4, "{dump}");
+        assert_eq!(lines[0], "# /etc/x");
+        assert!(lines[1].starts_with("queue 0-2 (eth0, In): MTU 1500, cipher "), "{dump}");
+        assert!(lines[1].ends_with(", disabled"), "{dump}");
+        assert_eq!(lines[2], "# standard input");
+        assert!(lines[3].starts_with("queue 5 (ppp0, Out): MTU 1400, cipher "), "{dump}");
+        assert!(lines[3].ends_with(&layers[1].rules[0].key_fingerprints()), "{dump}");
+        assert!(!dump.contains("secret"), "{dump}");
+    }
+
+    /// Tests the overhead report: fixed part, largest ballast and the WireGuard MTUs.
+    #[test]
+    fn test_overhead_report() {
+        let configs = parse_config(&["3:out:eth0:secret:1500:auth=on".to_string()]).unwrap();
+        let report = overhead_report(&configs[0]);
+        assert!(report.starts_with("queue 3 (eth0, Out): MTU 1500\n"), "{report}");
+        assert!(report.contains("overhead:      30 bytes minimum, 95 maximum, 64 typical\n"));
+        assert!(report.contains("WireGuard MTU: 1325 for full ballast, at most 1390 "));
+
+        let (cmd, _) = parse_from(["nf_wgobfs", "overhead", "--queue", "3"]).unwrap();
+        assert!(matches!(cmd, Command::Overhead { queue: Some(3) }));
+        assert!(print_overhead(&configs, Some(4)).is_err());
+    }
+
+    /// Tests the dissector generator options and their defaults.
+    #[test]
+    fn test_parse_generate_dissector() {
+        let (cmd, _) = parse_from(["nf_wgobfs", "--generate-dissector"]).unwrap();
+        assert!(matches!(cmd, Command::GenerateDissector { queue: None, port: 51820 }));
+        let args = ["nf_wgobfs", "generate-dissector", "--queue", "3", "--port", "443"];
+        let (cmd, _) = parse_from(args).unwrap();
+        assert!(matches!(cmd, Command::GenerateDissector { queue: Some(3), port: 443 }));
+        assert!(parse_from(["nf_wgobfs", "generate-dissector", "--port", "65536"]).is_err());
+    }
+
+    /// Tests that invalid queue numbers and unknown commands are rejected.
+    #[test]
+    fn test_parse_rejects_invalid_input() {
+        for args in [
+            &["nf_wgobfs", "queue", "abc"][..],
+            &["nf_wgobfs", "queue", "70000"],
+            &["nf_wgobfs", "queue"],
+            &["nf_wgobfs", "frobnicate"],
+            &["nf_wgobfs", "pcap", "a.pcap", "b.pcap", "sideways"],
+            &["nf_wgobfs", "--log-format", "xml"],
+        ] {
+            assert!(parse_from(args).is_err(), "{args:?}");
+        }
+    }
+
+    /// Tests that the OpenRC script daemonizes the rule's queue with its own PID file.
+    #[test]
+    fn test_openrc_script() {
+        let configs = parse_config(&["7:out:wg0:secret".to_string()]).unwrap();
+        let script = openrc_script(&configs[0]);
+        assert!(script.starts_with("#!/sbin/openrc-run\n"));
+        assert!(script.contains("description=\"NFQUEUE WireGuard Obfuscator queue 7 (wg0)\"\n"));
+        assert!(script.contains("pidfile=\"/run/nf_wgobfs.7.pid\"\n"));
+        assert!(script.contains("command_args=\"queue 7 --daemonize --pid-file ${pidfile} "));
+
+        // The generated invocation parses
+        let (cmd, options) = parse_from(["nf_wgobfs", "queue", "7", "--daemonize"]).unwrap();
+        assert!(matches!(cmd, Command::Start { queue_num: 7 }) && options.daemonize);
+        let (cmd, _) = parse_from(["nf_wgobfs", "--generate-openrc", "--out", "/x"]).unwrap();
+        assert!(matches!(cmd, Command::GenerateOpenrc { out: Some(ref d) } if d == "/x"));
+    }
+
+    /// Tests that each rule is queued from the chain matching its direction and interface.
+    #[test]
+    fn test_nftables_ruleset() {
+        let lines =
+            ["0:in:eth0:secret", "1:out:eth0:secret", "2:out:ppp0:secret", "4:in:ppp0:k:workers=4"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).unwrap();
+        let ruleset = nftables_ruleset(&configs);
+        assert!(ruleset.contains("iifname \"ppp0\" udp dport $wg_port queue num 4-7 fanout\n"));
+
+        let (inbound, outbound) = ruleset.split_once("chain out_chain").unwrap();
+        assert!(inbound.contains("iifname \"eth0\" udp dport $wg_port queue num 0\n"));
+        assert!(outbound.contains("oifname \"eth0\" udp sport $wg_port queue num 1\n"));
+        assert!(outbound.contains("oifname \"ppp0\" udp sport $wg_port queue num 2\n"));
+        assert!(!inbound.contains("queue num 1"));
+        assert!(ruleset.contains("delete table inet nf_wgobfs"));
+
+        // A port list replaces $wg_port
+        let configs = parse_config(&["3:out:eth0:k:port=51820,51821-51830".to_string()]).unwrap();
+        let ruleset = nftables_ruleset(&configs);
+        assert!(ruleset.contains(
+            "oifname \"eth0\" udp sport { 51820, 51821-51830 } \
+            queue num 3\n"
+        ));
+    }
+
+    /// Tests that iptables rules pick the chain by direction and cover IPv4 and IPv6.
+    #[test]
+    fn test_iptables_rules() {
+        let lines = ["0:in:eth0:secret", "1:out:ppp0:secret", "2:out:eth0:secret:workers=2"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).unwrap();
+        let rules = iptables_rules(&configs);
+        assert!(rules.contains("-j NFQUEUE --queue-balance 2:3 --queue-cpu-fanout\n"));
+
+        for tool in ["iptables", "ip6tables"] {
+            assert!(rules.contains(&format!(
+                "{tool} -t mangle -A PREROUTING -i eth0 -p udp --dport \"$WG_PORT\" \
+                 -j NFQUEUE --queue-num 0\n"
+            )));
+            assert!(rules.contains(&format!(
+                "{tool} -t mangle -A POSTROUTING -o ppp0 -p udp --sport \"$WG_PORT\" \
+                 -j NFQUEUE --queue-num 1\n"
+            )));
+        }
+
+        // A port list goes to multiport matches of at most 15 ports each
+        let ports = (0..8).map(|i| format!("{}-{}", 51820 + i * 10, 51825 + i * 10));
+        let line = format!("5:in:eth0:k:port={}", ports.collect::<Vec<_>>().join(","));
+        let rules = iptables_rules(&parse_config(&[line]).unwrap());
+        assert!(rules.contains(
+            "iptables -t mangle -A PREROUTING -i eth0 -p udp -m multiport --dports \
+             51820:51825,51830:51835,51840:51845,51850:51855,51860:51865,51870:51875,\
+             51880:51885 -j NFQUEUE --queue-num 5\n"
+        ));
+        assert!(rules.contains(
+            "iptables -t mangle -A PREROUTING -i eth0 -p udp -m multiport --dports \
+             51890:51895 -j NFQUEUE --queue-num 5\n"
+        ));
+        assert!(!rules.contains("$WG_PORT\" -j"));
+    }
+}
@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Shell completion scripts.
+//!
+//! [`script`] renders a bash, zsh or fish completion script for the commands and
+//! options accepted by [`crate::cli::parse_args`] (the historical `--generate-*` and
+//! `--version` spellings still work but are not offered). Queue numbers are baked into the
+//! script from the configuration when it can be read at generation time.
+
+/// Commands accepted as the first argument, with their descriptions.
+const COMMANDS: &[(&str, &str)] = &[
+    ("run", "Run all configured filters"),
+    ("queue", "Run one queue in the foreground"),
+    ("status", "Print the statistics of a running instance"),
+    ("pcap", "Obfuscate or deobfuscate a capture file offline"),
+    ("self-test", "Round-trip sample packets through every rule"),
+    ("obfuscate-hex", "Preview the transform of a packet given in hex"),
+    ("test-vectors", "Print test vectors of the wire format"),
+    ("overhead", "Print the obfuscation overhead for MTU planning"),
+    ("dump-config", "Print the loaded configuration sources and rules"),
+    ("completions", "Print a shell completion script"),
+    ("generate-units", "Generate systemd units"),
+    ("generate-openrc", "Generate OpenRC service scripts"),
+    ("generate-nft", "Generate an nftables ruleset"),
+    ("generate-iptables", "Print iptables/ip6tables rules"),
+    ("generate-dissector", "Print a Wireshark dissector"),
+    ("version", "Print the version"),
+    ("help", "Print the usage"),
+];
+
+/// Options accepted anywhere on the command line; each takes a value.
+const OPTIONS: &[(&str, &str)] = &[
+    ("--config", "Configuration file to read instead of the default"),
+    ("--stats-socket", "Serve statistics on a Unix socket"),
+    ("--metrics", "Serve Prometheus metrics on an address"),
+    ("--user", "User to switch to after binding the queues"),
+    ("--group", "Group to switch to after binding the queues"),
+    ("--rcvbuf", "Receive buffer of each NFQUEUE socket in bytes"),
+    ("--log-format", "Log line layout (text or json)"),
+    ("--pid-file", "PID file to write with --daemonize"),
+    ("--log-file", "File to append the log to with --daemonize"),
+];
+
+/// Options accepted anywhere on the command line that take no value.
+const FLAGS: &[(&str, &str)] = &[("--daemonize", "Fork into the background")];
+
+/// Shells a completion script can be generated for.
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+/// Renders the completion script for `shell`, offering `queues` as queue numbers.
+///
+/// # Returns
+/// * `Some(script)` - The script to source or install.
+/// * `None` - If `shell` is not one of [`SHELLS`].
+pub fn script(shell: &str, queues: &[u16]) -> Option<String> {
+    let queues = queues.iter().map(u16::to_string).collect::<Vec<_>>().join(" ");
+    match shell {
+        "bash" => Some(bash(&queues)),
+        "zsh" => Some(zsh(&queues)),
+        "fish" => Some(fish(&queues)),
+        _ => None,
+    }
+}
+
+fn names(table: &[(&str, &str)]) -> String {
+    table.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(" ")
+}
+
+fn bash(queues: &str) -> String {
+    r#"# bash completion for nf_wgobfs
+_nf_wgobfs() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "$prev" in
+        queue|--queue) COMPREPLY=($(compgen -W "@QUEUES@" -- "$cur")); return ;;
+        completions) COMPREPLY=($(compgen -W "@SHELLS@" -- "$cur")); return ;;
+        status|--config|--stats-socket|--pid-file|--log-file) COMPREPLY=($(compgen -f -- "$cur")); return ;;
+        --out) COMPREPLY=($(compgen -d -- "$cur")); return ;;
+        --user) COMPREPLY=($(compgen -u -- "$cur")); return ;;
+        --group) COMPREPLY=($(compgen -g -- "$cur")); return ;;
+        --metrics|--rcvbuf|--port) return ;;
+        --log-format) COMPREPLY=($(compgen -W "text json" -- "$cur")); return ;;
+        help) COMPREPLY=($(compgen -W "@COMMANDS@" -- "$cur")); return ;;
+    esac
+    if [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "@COMMANDS@ @OPTIONS@" -- "$cur"))
+        return
+    fi
+    case "${COMP_WORDS[1]}" in
+        pcap)
+            case $COMP_CWORD in
+                2|3) COMPREPLY=($(compgen -f -- "$cur")) ;;
+                4) COMPREPLY=($(compgen -W "in out" -- "$cur")) ;;
+                5) COMPREPLY=($(compgen -W "@QUEUES@" -- "$cur")) ;;
+            esac ;;
+        generate-units|--generate-units|generate-openrc|--generate-openrc) COMPREPLY=($(compgen -W "--out @OPTIONS@" -- "$cur")) ;;
+        obfuscate-hex|overhead) COMPREPLY=($(compgen -W "--queue @OPTIONS@" -- "$cur")) ;;
+        generate-dissector|--generate-dissector) COMPREPLY=($(compgen -W "--queue --port @OPTIONS@" -- "$cur")) ;;
+        *) COMPREPLY=($(compgen -W "@OPTIONS@" -- "$cur")) ;;
+    esac
+}
+complete -F _nf_wgobfs nf_wgobfs
+"#
+    .replace("@QUEUES@", queues)
+    .replace("@SHELLS@", &SHELLS.join(" "))
+    .replace("@COMMANDS@", &names(COMMANDS))
+    .replace("@OPTIONS@", &format!("{} {}", names(OPTIONS), names(FLAGS)))
+}
+
+fn zsh(queues: &str) -> String {
+    let commands = COMMANDS
+        .iter()
+        .map(|(name, desc)| format!("{}\\:'{}'", name, desc))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let options = OPTIONS
+        .iter()
+        .map(|(name, desc)| {
+            let action = match *name {
+                "--config" | "--stats-socket" | "--pid-file" | "--log-file" => "_files",
+                "--user" => "_users",
+                "--group" => "_groups",
+                "--log-format" => "(text json)",
+                _ => "",
+            };
+            format!("    '{}[{}]:value:{}' \\\n", name, desc, action)
+        })
+        .chain(FLAGS.iter().map(|(name, desc)| format!("    '{}[{}]' \\\n", name, desc)))
+        .collect::<String>();
+    r#"#compdef nf_wgobfs
+
+_nf_wgobfs() {
+    local -a queues=(@QUEUES@)
+    local state
+    _arguments \
+@OPTIONS@    '1:command:((@COMMANDS@))' \
+        '*::argument:->args'
+    [[ $state == args ]] || return
+    case $words[1] in
+        queue) _describe queue queues ;;
+        status) _files ;;
+        completions) _values shell @SHELLS@ ;;
+        generate-units|--generate-units|generate-openrc|--generate-openrc) _arguments '--out[output directory]:directory:_files -/' ;;
+        obfuscate-hex|overhead) _arguments "--queue[rule to take the settings from]:queue:($queues)" ;;
+        generate-dissector|--generate-dissector) _arguments "--queue[rule to take the settings from]:queue:($queues)" '--port[UDP port to register on]:port:' ;;
+        pcap)
+            case $CURRENT in
+                2|3) _files ;;
+                4) _values direction in out ;;
+                5) _describe queue queues ;;
+            esac ;;
+    esac
+}
+
+_nf_wgobfs "$@"
+"#
+    .replace("@QUEUES@", queues)
+    .replace("@SHELLS@", &SHELLS.join(" "))
+    .replace("@COMMANDS@", &commands)
+    .replace("@OPTIONS@", &options)
+}
+
+fn fish(queues: &str) -> String {
+    let mut script = String::from("# fish completion for nf_wgobfs\ncomplete -c nf_wgobfs -f\n");
+    let subcommands = names(COMMANDS);
+    for (name, desc) in COMMANDS {
+        script.push_str(&format!(
+            "complete -c nf_wgobfs -n 'not __fish_seen_subcommand_from {}' -a {} -d '{}'\n",
+            subcommands, name, desc
+        ));
+    }
+    for (name, desc) in OPTIONS {
+        let values = match *name {
+            "--config" | "--stats-socket" | "--pid-file" | "--log-file" => " -F",
+            "--user" => " -a '(__fish_complete_users)'",
+            "--group" => " -a '(__fish_complete_groups)'",
+            "--log-format" => " -a 'text json'",
+            _ => "",
+        };
+        script.push_str(&format!(
+            "complete -c nf_wgobfs -l {} -r{} -d '{}'\n",
+            name.trim_start_matches("--"),
+            values,
+            desc
+        ));
+    }
+    for (name, desc) in FLAGS {
+        script.push_str(&format!(
+            "complete -c nf_wgobfs -l {} -d '{}'\n",
+            name.trim_start_matches("--"),
+            desc
+        ));
+    }
+    script.push_str(&format!(
+        "complete -c nf_wgobfs -n '__fish_seen_subcommand_from queue pcap' -a '{queues}'\n\
+         complete -c nf_wgobfs -n '__fish_seen_subcommand_from pcap' -a 'in out' -F\n\
+         complete -c nf_wgobfs -n '__fish_seen_subcommand_from status' -F\n\
+         complete -c nf_wgobfs -n '__fish_seen_subcommand_from completions' -a '{}'\n\
+         complete -c nf_wgobfs -n '__fish_seen_subcommand_from generate-units generate-openrc' -l out -r \
+         -a '(__fish_complete_directories)' -d 'Output directory'\n\
+         complete -c nf_wgobfs -n '__fish_seen_subcommand_from obfuscate-hex overhead generate-dissector' -l queue -r \
+         -a '{queues}' -d 'Rule to take the settings from'\n\
+         complete -c nf_wgobfs -n '__fish_seen_subcommand_from generate-dissector' -l port -r \
+         -d 'UDP port to register on'\n",
+        SHELLS.join(" ")
+    ));
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that every shell gets a script listing the commands and configured queues.
+    #[test]
+    fn test_scripts_cover_commands_and_queues() {
+        for shell in SHELLS {
+            let script = script(shell, &[0, 7]).unwrap();
+            for (name, _) in COMMANDS.iter().chain(OPTIONS).chain(FLAGS) {
+                assert!(script.contains(name.trim_start_matches("--")), "{shell}: {name}");
+            }
+            assert!(script.contains("0 7"), "{shell}: queues");
+        }
+        assert!(script("powershell", &[]).is_none());
+    }
+
+    /// Tests that the bash script is syntactically valid.
+    #[test]
+    fn test_bash_script_parses() {
+        let path = std::env::temp_dir().join("nf_wgobfs_completion_test.bash");
+        std::fs::write(&path, script("bash", &[0, 1]).unwrap()).unwrap();
+        match std::process::Command::new("bash").arg("-n").arg(&path).status() {
+            Ok(status) => assert!(status.success()),
+            Err(_) => eprintln!("bash not available, skipping"),
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
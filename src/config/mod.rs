@@ -0,0 +1,858 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ * SPDX-License-Identifier: MIT
+ *
+ * This module provides configuration parsing and management for the nf_wgobfs application.
+ * It defines the configuration structures, parsing logic, and utility functions for
+ * handling filter rules, including queue numbers, directions, interface names, keys, and MTU.
+ *
+ * The rule type, its builder and its checks need no more than `alloc`; reading rules from
+ * config files, the environment and key commands lives in `parse` and needs `std`.
+ */
+
+use crate::cipher::CipherMode;
+use crate::filter::ballast::BallastProfile;
+use crate::filter::keepalive::{DEFAULT_IDLE_THRESHOLD, PEER_IDLE_TIMEOUT};
+use crate::filter::obfuscator::{
+    fixed_overhead, ENCRYPT_BYTES_MAX, ENCRYPT_BYTES_MIN, MIN_WG_LEN, OBFUSCATION_OVERHEAD,
+};
+use crate::netutils::cidr::Cidr;
+use crate::netutils::common::MAX_STACK_UDP;
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+#[cfg(feature = "std")]
+mod parse;
+#[cfg(feature = "std")]
+pub use parse::*;
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind};
+
+/// Error of a rule that cannot run, without `std`: a stand-in for the `std::io::Error`
+/// that [`FilterConfig::validate`] and the builder return with it, built the same way.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+/// Kind of an [`Error`] without `std`, named as in `std::io::ErrorKind`.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A setting is missing.
+    InvalidInput,
+    /// A setting is out of range or at odds with another.
+    InvalidData,
+}
+
+#[cfg(not(feature = "std"))]
+impl Error {
+    /// Creates an error of `kind` with `message`.
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        Error { kind, message }
+    }
+
+    /// Returns the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl core::error::Error for Error {}
+
+/// Represents the direction of the filter rule (incoming or outgoing).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Timing jitter applied to outbound packets (`jitter=<percent>,<max_ms>`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Jitter {
+    /// Share of packets delayed, in percent (0-100).
+    pub percent: u8,
+    /// Upper bound of the delay added to a packet.
+    pub max_delay: Duration,
+}
+
+/// Dummy packets sent toward the peer of an outbound rule (`chaff=<interval_ms>,<min>,<max>`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chaff {
+    /// Mean interval between chaff packets; intervals are exponentially distributed.
+    pub interval: Duration,
+    /// Smallest WireGuard payload of a chaff packet, before obfuscation.
+    pub min_len: usize,
+    /// Largest WireGuard payload of a chaff packet, before obfuscation.
+    pub max_len: usize,
+}
+
+/// How many keepalives in a row a thinning burst drops (`keepalive-drop=<min>[,<max>]`), see
+/// [`crate::filter::keepalive::KeepaliveDropper`].
+///
+/// A burst starts at the first keepalive of an idle peer, which is dropped, and then drops
+/// a random number from `min` to `max` of the keepalives that follow, and any that come
+/// within a few seconds of the first; the next one goes out. Of a steady stream of
+/// keepalives, one in 2 + N is sent, N drawn anew for every burst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveDrop {
+    /// Fewest keepalives dropped after the first of a burst, at least 1.
+    pub min: u8,
+    /// Most keepalives dropped after the first of a burst.
+    pub max: u8,
+}
+
+impl Default for KeepaliveDrop {
+    /// Between 1 and 9 keepalives after the first.
+    fn default() -> Self {
+        KeepaliveDrop { min: 1, max: 9 }
+    }
+}
+
+/// Debugging capture of the packets of a rule (`tap=<packets>[,<seconds>]`), see
+/// [`crate::filter::tap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tap {
+    /// Packets captured before the capture stops; each is recorded before and after the
+    /// transform.
+    pub packets: usize,
+    /// Time after which the capture stops.
+    pub duration: Duration,
+}
+
+impl Default for Tap {
+    /// Limits of a capture started by SIGUSR2 on a rule without `tap=`.
+    fn default() -> Self {
+        Tap { packets: 1000, duration: Duration::from_secs(60) }
+    }
+}
+
+/// Padding of outbound packets to the largest recent size of their flow
+/// (`hide-length=<ceiling>[,<seconds>]`), see [`crate::filter::keepalive::SizeWindow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthHiding {
+    /// Largest on-wire packet size padded to; the MTU of the rule if lower.
+    pub ceiling: usize,
+    /// How long a size counts as recent.
+    pub window: Duration,
+}
+
+/// How long a size counts as recent for `hide-length=` by default.
+pub const HIDE_LENGTH_WINDOW: Duration = Duration::from_secs(30);
+/// Longest `hide-length=` window: the size state of a peer expires with it.
+pub const HIDE_LENGTH_WINDOW_MAX: Duration = PEER_IDLE_TIMEOUT;
+
+/// Token-bucket limit of a queue (`ratelimit=<rate>[,<burst>]`, `bytelimit=<rate>[,<burst>]`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Tokens added per second: packets or bytes.
+    pub rate: u64,
+    /// Bucket size, the most tokens spent at once after an idle period.
+    pub burst: u64,
+}
+
+/// Range of UDP or TCP ports, both ends included (`port=51820,51821-51830`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    /// First port of the range.
+    pub first: u16,
+    /// Last port of the range, equal to `first` for a single port.
+    pub last: u16,
+}
+
+impl PortRange {
+    /// Returns true if `port` lies in the range.
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+/// A 32-byte obfuscation key, wiped from memory when dropped.
+pub type Key = Zeroizing<[u8; 32]>;
+
+/// Holds the configuration for a single filter rule.
+#[derive(Clone)]
+pub struct FilterConfig {
+    /// Netfilter queue number.
+    pub queue_num: u16,
+    /// Direction of the filter (inbound or outbound).
+    pub direction: Direction,
+    /// Interface the WireGuard UDP traffic goes through (third field). Scopes generated
+    /// firewall rules and identifies the queue in logs.
+    pub iface: String,
+    /// 32-byte key derived from ASCII input, salted if `salt=` is set.
+    pub key: Key,
+    /// Previous key, still accepted when deobfuscating during a key rotation (`key,oldkey`).
+    pub prev_key: Option<Key>,
+    /// Maximum Transmission Unit for this rule.
+    pub mtu: usize,
+    /// Whether the MTU follows the interface (`auto` in the MTU field): read by
+    /// [`read_config`] and again by the filter threads on SIGHUP.
+    pub mtu_auto: bool,
+    /// Whether WireGuard keepalives are thinned out (`keepalive=off` disables it).
+    pub keepalive: bool,
+    /// Whether obfuscated packets carry an integrity tag (`auth=on`, must match the peer).
+    pub auth: bool,
+    /// On-wire packet sizes to pad obfuscated packets up to, ascending (`buckets=512,1024`).
+    /// Empty for random ballast.
+    pub buckets: Vec<usize>,
+    /// Random delay for a share of outbound packets (`jitter=25,10`), off if `None`.
+    pub jitter: Option<Jitter>,
+    /// Dummy packets injected toward the peer (`chaff=1000,32,512`), off if `None`.
+    pub chaff: Option<Chaff>,
+    /// ChaCha variant encrypting the obfuscated block (`cipher=xchacha20`, must match the peer).
+    pub cipher: CipherMode,
+    /// Worker threads (`workers=4`), bound to consecutive queues from `queue_num` on that
+    /// the kernel fans packets out to.
+    pub workers: u16,
+    /// Keep the DSCP bits of outbound packets (`keep-dscp=on`) instead of clearing them.
+    pub keep_dscp: bool,
+    /// Zero the IPv6 Flow Label of outbound packets (`flowlabel=zero`).
+    pub zero_flow_label: bool,
+    /// WireGuard messages shorter than this many bytes are not obfuscated (`min-size=`,
+    /// must match the peer). 0 obfuscates every message.
+    pub min_size: usize,
+    /// Packets per second each queue of the rule passes (`ratelimit=`), unlimited if `None`.
+    pub packet_limit: Option<RateLimit>,
+    /// Bytes per second each queue of the rule passes (`bytelimit=`), unlimited if `None`.
+    pub byte_limit: Option<RateLimit>,
+    /// Source prefixes whose packets the rule transforms (`allow=`); empty allows all.
+    pub allow: Vec<Cidr>,
+    /// Source prefixes whose packets the rule passes unchanged (`deny=`), even if allowed.
+    pub deny: Vec<Cidr>,
+    /// WireGuard ports whose packets the rule transforms (`port=`), matched against either
+    /// port of a packet; empty allows all.
+    pub ports: Vec<PortRange>,
+    /// Leading bytes of each WireGuard message encrypted in place (`encrypt-bytes=`, must
+    /// match the peer), from [`ENCRYPT_BYTES_MIN`] to [`ENCRYPT_BYTES_MAX`].
+    pub encrypt_bytes: usize,
+    /// Time without data after which a peer's keepalives are thinned (`keepalive-idle=`).
+    pub keepalive_idle: Duration,
+    /// Keepalives a thinning burst drops in a row (`keepalive-drop=`).
+    pub keepalive_drop: KeepaliveDrop,
+    /// Content of the ballast (`ballast=random|zero|text`); the peer need not match it.
+    pub ballast: BallastProfile,
+    /// Capture started with the queue (`tap=`), off if `None`; SIGUSR2 toggles one anyway.
+    pub tap: Option<Tap>,
+    /// Time without WireGuard data on any queue of the rule after which the tunnel is
+    /// reported as idle (`watchdog=`), off if `None`.
+    pub watchdog: Option<Duration>,
+    /// Padding of outbound packets to the largest recent size of their flow
+    /// (`hide-length=`), off if `None`.
+    pub hide_length: Option<LengthHiding>,
+    /// Whether the rule transforms packets (`enabled=off` passes them all unchanged, while
+    /// its queues stay bound). Read again on SIGHUP.
+    pub enabled: bool,
+    /// Upper edges of the packet size histograms of the queues, ascending (`histogram=`).
+    /// Borrows [`DEFAULT_SIZE_EDGES`] unless set, so that building a rule does not allocate.
+    pub size_edges: Cow<'static, [usize]>,
+}
+
+impl FilterConfig {
+    /// Queue numbers served by this rule: one per worker, starting at `queue_num`.
+    pub fn queues(&self) -> core::ops::Range<u16> {
+        self.queue_num..self.queue_num + self.workers
+    }
+
+    /// Fingerprints of the keys of this rule for diagnostics: that of the key, followed by
+    /// that of the previous key after a comma during a rotation, as in the config.
+    pub fn key_fingerprints(&self) -> String {
+        match &self.prev_key {
+            Some(prev) => format!("{},{}", key_fingerprint(&self.key), key_fingerprint(prev)),
+            None => key_fingerprint(&self.key),
+        }
+    }
+
+    /// Starts a rule with the defaults of a config line that sets nothing but its key:
+    /// queue 0, outbound, MTU 1500, keepalive thinning on, no options.
+    ///
+    /// ```
+    /// # use nf_wgobfs::config::{Direction, FilterConfig};
+    /// let filter = FilterConfig::builder()
+    ///     .queue(0)
+    ///     .direction(Direction::Out)
+    ///     .key_from_ascii("secret")
+    ///     .mtu(1420)
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(filter.mtu, 1420);
+    /// ```
+    pub fn builder() -> FilterConfigBuilder {
+        FilterConfigBuilder {
+            config: FilterConfig {
+                queue_num: 0,
+                direction: Direction::Out,
+                iface: String::new(),
+                key: Zeroizing::new([0; 32]),
+                prev_key: None,
+                mtu: DEFAULT_MTU,
+                mtu_auto: false,
+                keepalive: true,
+                auth: false,
+                buckets: Vec::new(),
+                jitter: None,
+                chaff: None,
+                cipher: CipherMode::default(),
+                workers: 1,
+                keep_dscp: false,
+                zero_flow_label: false,
+                min_size: 0,
+                packet_limit: None,
+                byte_limit: None,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                ports: Vec::new(),
+                encrypt_bytes: ENCRYPT_BYTES_MIN,
+                keepalive_idle: DEFAULT_IDLE_THRESHOLD,
+                keepalive_drop: KeepaliveDrop::default(),
+                ballast: BallastProfile::default(),
+                tap: None,
+                watchdog: None,
+                hide_length: None,
+                enabled: true,
+                size_edges: Cow::Borrowed(&DEFAULT_SIZE_EDGES),
+            },
+            has_key: false,
+        }
+    }
+
+    /// Checks the settings of this rule against each other and against their ranges, as
+    /// [`parse_config`] does for every rule it reads.
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the rule can run.
+    /// * `Err` - Naming the first setting out of range or at odds with another one.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidData, message));
+        let queue_num = self.queue_num;
+        if !(MTU_MIN..=MTU_MAX).contains(&self.mtu) {
+            return invalid(format!(
+                "Invalid MTU for queue {}: {} (expected {}-{})",
+                queue_num, self.mtu, MTU_MIN, MTU_MAX
+            ));
+        }
+        check_mtus(core::slice::from_ref(self))?;
+        if !(1..=MAX_WORKERS).contains(&self.workers)
+            || queue_num.checked_add(self.workers - 1).is_none()
+        {
+            return invalid(format!("Invalid workers for queue {}: {}", queue_num, self.workers));
+        }
+        if !(ENCRYPT_BYTES_MIN..=ENCRYPT_BYTES_MAX).contains(&self.encrypt_bytes) {
+            return invalid(format!(
+                "Invalid encrypt-bytes for queue {}: {} (expected {}-{})",
+                queue_num, self.encrypt_bytes, ENCRYPT_BYTES_MIN, ENCRYPT_BYTES_MAX
+            ));
+        }
+        if self.keepalive_idle > PEER_IDLE_TIMEOUT {
+            return invalid(format!(
+                "Invalid keepalive-idle for queue {}: {:?} (expected at most {:?})",
+                queue_num, self.keepalive_idle, PEER_IDLE_TIMEOUT
+            ));
+        }
+        let drop = self.keepalive_drop;
+        if drop.min == 0 || drop.min > drop.max {
+            return invalid(format!(
+                "Invalid keepalive-drop for queue {}: {},{} (expected 1 <= min <= max)",
+                queue_num, drop.min, drop.max
+            ));
+        }
+        let watchdog_range = Duration::from_secs(1)..=Duration::from_secs(WATCHDOG_MAX);
+        if let Some(watchdog) = self.watchdog.filter(|w| !watchdog_range.contains(w)) {
+            return invalid(format!("Invalid watchdog for queue {}: {:?}", queue_num, watchdog));
+        }
+        if self.buckets.windows(2).any(|pair| pair[0] >= pair[1]) || self.buckets.contains(&0) {
+            return invalid(format!("Bucket sizes of queue {} are not ascending", queue_num));
+        }
+        if self.size_edges.is_empty()
+            || self.size_edges.len() > MAX_SIZE_EDGES
+            || self.size_edges.windows(2).any(|pair| pair[0] >= pair[1])
+            || self.size_edges.contains(&0)
+        {
+            return invalid(format!(
+                "Histogram edges of queue {} must be 1-{} ascending sizes",
+                queue_num, MAX_SIZE_EDGES
+            ));
+        }
+        if let Some(hide) = self.hide_length {
+            let min = min_mtu(self);
+            let max = if self.mtu_auto { MTU_MAX } else { self.mtu };
+            if !(min..=max).contains(&hide.ceiling) {
+                return invalid(format!(
+                    "Length hiding ceiling {} of queue {} is out of range ({}-{})",
+                    hide.ceiling, queue_num, min, max
+                ));
+            }
+            if hide.window.is_zero() || hide.window > HIDE_LENGTH_WINDOW_MAX {
+                return invalid(format!(
+                    "Invalid length hiding window for queue {}: {:?}",
+                    queue_num, hide.window
+                ));
+            }
+        }
+        // Chaff below the minimum size would stand out among the unobfuscated messages
+        if let Some(chaff) = self.chaff.filter(|chaff| chaff.min_len < self.min_size) {
+            return invalid(format!(
+                "Chaff for queue {} starts at {} bytes, below min-size {}",
+                queue_num, chaff.min_len, self.min_size
+            ));
+        }
+        // A byte burst below the MTU would drop every full-sized packet
+        if let Some(limit) = self.byte_limit.filter(|limit| limit.burst < self.mtu as u64) {
+            return invalid(format!(
+                "Byte limit burst for queue {} is {} bytes, below the MTU {}",
+                queue_num, limit.burst, self.mtu
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`FilterConfig`] in code, see [`FilterConfig::builder`].
+///
+/// Every setting starts at the default of the config file, so code that sets only what it
+/// needs keeps compiling when rules gain options. [`build`](Self::build) checks the result
+/// as a config line would be checked.
+#[derive(Clone)]
+pub struct FilterConfigBuilder {
+    config: FilterConfig,
+    has_key: bool,
+}
+
+impl FilterConfigBuilder {
+    /// Sets the queue number (first field).
+    pub fn queue(mut self, queue_num: u16) -> Self {
+        self.config.queue_num = queue_num;
+        self
+    }
+
+    /// Sets the direction (second field).
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.config.direction = direction;
+        self
+    }
+
+    /// Sets the interface name (third field), empty by default.
+    pub fn iface(mut self, iface: impl Into<String>) -> Self {
+        self.config.iface = iface.into();
+        self
+    }
+
+    /// Sets the key.
+    pub fn key(mut self, key: Key) -> Self {
+        self.config.key = key;
+        self.has_key = true;
+        self
+    }
+
+    /// Sets the key derived from an ASCII passphrase, as an unsalted config line does.
+    pub fn key_from_ascii(self, passphrase: &str) -> Self {
+        self.key(ascii_to_key(passphrase))
+    }
+
+    /// Sets the previous key accepted inbound during a rotation.
+    pub fn prev_key(mut self, prev_key: Key) -> Self {
+        self.config.prev_key = Some(prev_key);
+        self
+    }
+
+    /// Sets the MTU (default 1500).
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.config.mtu = mtu;
+        self
+    }
+
+    /// Sets keepalive thinning (`keepalive=`, default on).
+    pub fn keepalive(mut self, keepalive: bool) -> Self {
+        self.config.keepalive = keepalive;
+        self
+    }
+
+    /// Sets the time without data before keepalives are thinned (`keepalive-idle=`).
+    pub fn keepalive_idle(mut self, idle: Duration) -> Self {
+        self.config.keepalive_idle = idle;
+        self
+    }
+
+    /// Sets how many keepalives a thinning burst drops in a row (`keepalive-drop=`,
+    /// default 1 to 9).
+    pub fn keepalive_drop(mut self, drop: KeepaliveDrop) -> Self {
+        self.config.keepalive_drop = drop;
+        self
+    }
+
+    /// Sets the integrity tag (`auth=`, default off).
+    pub fn auth(mut self, auth: bool) -> Self {
+        self.config.auth = auth;
+        self
+    }
+
+    /// Sets the padding bucket sizes (`buckets=`), in any order.
+    pub fn buckets(mut self, mut buckets: Vec<usize>) -> Self {
+        buckets.sort_unstable();
+        buckets.dedup();
+        self.config.buckets = buckets;
+        self
+    }
+
+    /// Sets the timing jitter (`jitter=`).
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.config.jitter = Some(jitter);
+        self
+    }
+
+    /// Sets the chaff (`chaff=`).
+    pub fn chaff(mut self, chaff: Chaff) -> Self {
+        self.config.chaff = Some(chaff);
+        self
+    }
+
+    /// Sets the cipher (`cipher=`, default ChaCha20).
+    pub fn cipher(mut self, cipher: CipherMode) -> Self {
+        self.config.cipher = cipher;
+        self
+    }
+
+    /// Sets the number of worker queues (`workers=`, default 1).
+    pub fn workers(mut self, workers: u16) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Keeps the DSCP marks (`keep-dscp=`, default off).
+    pub fn keep_dscp(mut self, keep_dscp: bool) -> Self {
+        self.config.keep_dscp = keep_dscp;
+        self
+    }
+
+    /// Zeroes the IPv6 Flow Label (`flowlabel=zero`, default off).
+    pub fn zero_flow_label(mut self, zero_flow_label: bool) -> Self {
+        self.config.zero_flow_label = zero_flow_label;
+        self
+    }
+
+    /// Sets the size below which messages are not obfuscated (`min-size=`, default 0).
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.config.min_size = min_size;
+        self
+    }
+
+    /// Sets the packet rate limit (`ratelimit=`).
+    pub fn packet_limit(mut self, limit: RateLimit) -> Self {
+        self.config.packet_limit = Some(limit);
+        self
+    }
+
+    /// Sets the byte rate limit (`bytelimit=`).
+    pub fn byte_limit(mut self, limit: RateLimit) -> Self {
+        self.config.byte_limit = Some(limit);
+        self
+    }
+
+    /// Sets the source prefixes the rule transforms (`allow=`).
+    pub fn allow(mut self, allow: Vec<Cidr>) -> Self {
+        self.config.allow = allow;
+        self
+    }
+
+    /// Sets the source prefixes the rule passes unchanged (`deny=`).
+    pub fn deny(mut self, deny: Vec<Cidr>) -> Self {
+        self.config.deny = deny;
+        self
+    }
+
+    /// Sets the ports the rule transforms (`port=`).
+    pub fn ports(mut self, ports: Vec<PortRange>) -> Self {
+        self.config.ports = ports;
+        self
+    }
+
+    /// Sets the bytes encrypted in place (`encrypt-bytes=`, default 16).
+    pub fn encrypt_bytes(mut self, encrypt_bytes: usize) -> Self {
+        self.config.encrypt_bytes = encrypt_bytes;
+        self
+    }
+
+    /// Sets the ballast content (`ballast=`, default random).
+    pub fn ballast(mut self, ballast: BallastProfile) -> Self {
+        self.config.ballast = ballast;
+        self
+    }
+
+    /// Sets the capture started with the queue (`tap=`).
+    pub fn tap(mut self, tap: Tap) -> Self {
+        self.config.tap = Some(tap);
+        self
+    }
+
+    /// Sets the idle watchdog timeout (`watchdog=`).
+    pub fn watchdog(mut self, timeout: Duration) -> Self {
+        self.config.watchdog = Some(timeout);
+        self
+    }
+
+    /// Sets length hiding (`hide-length=`).
+    pub fn hide_length(mut self, hide: LengthHiding) -> Self {
+        self.config.hide_length = Some(hide);
+        self
+    }
+
+    /// Switches the rule on or off (`enabled=`, on by default).
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.config.enabled = enabled;
+        self
+    }
+
+    /// Sets the edges of the packet size histograms (`histogram=`), in any order.
+    pub fn size_edges(mut self, mut edges: Vec<usize>) -> Self {
+        edges.sort_unstable();
+        edges.dedup();
+        self.config.size_edges = Cow::Owned(edges);
+        self
+    }
+
+    /// Checks the rule (see [`FilterConfig::validate`]) and returns it.
+    ///
+    /// # Returns
+    /// * `Ok(filter)` - The rule.
+    /// * `Err` - If no key was set, or a setting is out of range or at odds with another.
+    pub fn build(self) -> Result<FilterConfig, Error> {
+        if !self.has_key {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("No key set for queue {}", self.config.queue_num),
+            ));
+        }
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Converts an ASCII string to a 32-byte key using SHA-256 hash.
+/// Returns the resulting 32-byte key; the intermediate digest is wiped.
+pub fn ascii_to_key(s: &str) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(s.as_bytes());
+    let mut result = hasher.finalize();
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&result);
+    result.as_mut_slice().zeroize();
+    key
+}
+
+/// Info string of the salted key derivation.
+const KEY_INFO: &[u8] = b"nf_wgobfs key v1";
+
+/// Derives a 32-byte key from an ASCII passphrase and a salt with HKDF-SHA256.
+///
+/// Unlike [`ascii_to_key`], the same passphrase yields unrelated keys under different salts,
+/// so a passphrase reused across deployments does not reuse the key, and precomputed
+/// guesses do not carry over. Both ends must use the same salt.
+pub fn derive_key(s: &str, salt: &str) -> Key {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt.as_bytes()), s.as_bytes());
+    let mut key = Zeroizing::new([0u8; 32]);
+    hkdf.expand(KEY_INFO, &mut key[..]).expect("32 bytes is a valid HKDF-SHA256 length");
+    key
+}
+
+/// Returns a short fingerprint of a derived key: the first 8 hex digits of its SHA-256.
+///
+/// Two ends show the same fingerprint exactly when they derived the same key (typos,
+/// salts and all), so it can be compared across hosts and logged; the key cannot be
+/// recovered from it. Never log the key itself.
+pub fn key_fingerprint(key: &Key) -> String {
+    hex::encode(&Sha256::digest(&key[..])[..4])
+}
+
+/// Upper edges, in bytes, of the packet size histograms of a rule without `histogram=`.
+pub const DEFAULT_SIZE_EDGES: [usize; 8] = [64, 128, 256, 512, 768, 1024, 1280, 1500];
+/// Most edges of a packet size histogram (`histogram=`).
+pub const MAX_SIZE_EDGES: usize = 32;
+
+/// MTU of a rule that does not set one.
+const DEFAULT_MTU: usize = 1500;
+/// Smallest MTU accepted: the least every IPv4 link must carry.
+pub const MTU_MIN: usize = 68;
+/// Largest MTU accepted: jumbo frames. Packet buffers are sized from the MTU, so larger
+/// values are rejected rather than allocated.
+pub const MTU_MAX: usize = 9000;
+
+// An obfuscated packet of the largest MTU must still fit the IP length fields and the
+// packet buffer; a larger ballast or nonce in the future must keep it so
+const _: () = assert!(MTU_MAX + OBFUSCATION_OVERHEAD <= u16::MAX as usize);
+// and its UDP checksum must still be summed on the stack
+const _: () = assert!(MTU_MAX + OBFUSCATION_OVERHEAD <= MAX_STACK_UDP);
+
+/// Smallest packet WireGuard sends: a keepalive, IPv4 and UDP headers around the 32-byte
+/// message.
+const MIN_WG_PACKET: usize = 20 + 8 + MIN_WG_LEN;
+
+/// Smallest MTU `filter` works with: one that holds the smallest WireGuard packet once
+/// obfuscated under the rule's cipher and `auth` setting, without ballast.
+pub fn min_mtu(filter: &FilterConfig) -> usize {
+    MIN_WG_PACKET + fixed_overhead(filter)
+}
+
+/// Checks that the MTU of every rule leaves room for the obfuscation overhead (see
+/// [`min_mtu`]). Below that, every packet the rule obfuscates would exceed its MTU.
+///
+/// # Returns
+/// * `Ok(())` - If every MTU is large enough.
+/// * `Err` - Naming the first queue whose MTU is too small, and the least it needs.
+pub fn check_mtus(configs: &[FilterConfig]) -> Result<(), Error> {
+    match configs.iter().find(|filter| filter.mtu < min_mtu(filter)) {
+        Some(filter) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "MTU {} of queue {} leaves no room for the obfuscation overhead: at least {} \
+                 needed with cipher {} and auth={}",
+                filter.mtu,
+                filter.queue_num,
+                min_mtu(filter),
+                filter.cipher.name(),
+                if filter.auth { "on" } else { "off" }
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Longest `watchdog=` timeout, in seconds: a day.
+const WATCHDOG_MAX: u64 = 86_400;
+
+/// Largest number of workers per rule.
+const MAX_WORKERS: u16 = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that ascii_to_key produces consistent results for the same input.
+    #[test]
+    fn test_ascii_to_key_consistency() {
+        let key1 = ascii_to_key("testkey");
+        let key2 = ascii_to_key("testkey");
+        assert_eq!(key1, key2);
+    }
+
+    /// Tests that ascii_to_key produces different results for different inputs.
+    #[test]
+    fn test_ascii_to_key_differs() {
+        let key1 = ascii_to_key("testkey1");
+        let key2 = ascii_to_key("testkey2");
+        assert_ne!(key1, key2);
+    }
+
+    /// Tests that the salted derivation depends on the salt and differs from the unsalted one.
+    #[test]
+    fn test_derive_key_salt() {
+        let key = derive_key("testkey", "site-a");
+        assert_eq!(key, derive_key("testkey", "site-a"));
+        assert_ne!(key, derive_key("testkey", "site-b"));
+        assert_ne!(key, derive_key("otherkey", "site-a"));
+        assert_ne!(key, ascii_to_key("testkey"));
+    }
+
+    /// Tests the key fingerprint: a hash of the derived key rather than a part of it, and
+    /// both keys of a rotation in config order.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_key_fingerprint() {
+        let key = ascii_to_key("secretkey");
+        assert_eq!(key_fingerprint(&key), "b38ea80f");
+        assert!(!hex::encode(&key[..]).starts_with(&key_fingerprint(&key)));
+        assert_ne!(key_fingerprint(&derive_key("secretkey", "site-a")), "b38ea80f");
+
+        let lines = ["0:out:eth0:secretkey,oldkey".to_string(), "1:in:eth0:secretkey".to_string()];
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].key_fingerprints(), "b38ea80f,150d3c9a");
+        assert_eq!(configs[1].key_fingerprints(), "b38ea80f");
+    }
+
+    /// Tests that the builder starts from the defaults of a config line.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_builder() {
+        let lines = vec!["0:out:eth0:secretkey".to_string()];
+        let parsed = parse_config(&lines).unwrap().remove(0);
+        let built =
+            FilterConfig::builder().iface("eth0").key_from_ascii("secretkey").build().unwrap();
+        // The C interface builds a rule per call, which must not allocate
+        assert!(matches!(built.size_edges, Cow::Borrowed(_)));
+        assert_eq!(*built.key, *parsed.key);
+        assert_eq!(
+            (built.queue_num, built.direction, built.iface.as_str(), built.mtu, built.workers),
+            (parsed.queue_num, parsed.direction, parsed.iface.as_str(), parsed.mtu, parsed.workers)
+        );
+        assert_eq!(
+            (built.keepalive, built.keepalive_idle, built.auth, built.cipher, built.encrypt_bytes),
+            (
+                parsed.keepalive,
+                parsed.keepalive_idle,
+                parsed.auth,
+                parsed.cipher,
+                parsed.encrypt_bytes
+            )
+        );
+        assert_eq!((built.ballast, built.min_size), (parsed.ballast, parsed.min_size));
+        assert!(built.enabled && parsed.enabled);
+    }
+
+    /// Tests the builder's settings and that it refuses rules that cannot run, naming the
+    /// queue, with or without `std`.
+    #[test]
+    fn test_builder_validation() {
+        let built = FilterConfig::builder()
+            .queue(7)
+            .direction(Direction::In)
+            .key_from_ascii("secretkey")
+            .mtu(1420)
+            .buckets(vec![1024, 512, 1024])
+            .cipher(CipherMode::XChaCha20)
+            .build()
+            .unwrap();
+        assert_eq!((built.queue_num, built.direction, built.mtu), (7, Direction::In, 1420));
+        assert_eq!((built.buckets, built.cipher), (vec![512, 1024], CipherMode::XChaCha20));
+
+        let base = || FilterConfig::builder().key_from_ascii("secretkey");
+        let error = FilterConfig::builder().mtu(1420).build().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "No key set for queue 0");
+        for builder in [
+            base().mtu(MTU_MAX + 1),
+            base().mtu(80).auth(true).cipher(CipherMode::XChaCha20),
+            base().workers(0),
+            base().queue(u16::MAX).workers(2),
+            base().encrypt_bytes(ENCRYPT_BYTES_MAX + 1),
+            base().keepalive_idle(PEER_IDLE_TIMEOUT + Duration::from_secs(1)),
+            base().keepalive_drop(KeepaliveDrop { min: 0, max: 9 }),
+            base().keepalive_drop(KeepaliveDrop { min: 5, max: 4 }),
+            base().watchdog(Duration::ZERO),
+            base().min_size(100).chaff(Chaff {
+                interval: Duration::from_secs(1),
+                min_len: 32,
+                max_len: 256,
+            }),
+            base().byte_limit(RateLimit { rate: 1000, burst: 1000 }),
+        ] {
+            let error = builder.build().err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert!(error.to_string().contains("queue "), "{error}");
+        }
+    }
+}
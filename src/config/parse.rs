@@ -0,0 +1,2206 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ * SPDX-License-Identifier: MIT
+ */
+
+//! Reading rules: config files and fragments, the environment, key commands and the parser
+//! of config lines. Everything here is re-exported from [`crate::config`].
+
+use super::*;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, Read};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Instant;
+
+/// Bit of `CAP_NET_ADMIN` in the capability masks of /proc/self/status.
+const CAP_NET_ADMIN_BIT: u64 = 1 << 12;
+
+/// Checks a /proc/<pid>/status text for the right to use NFQUEUE.
+/// Returns true if UID is 0 or if `CapEff` contains `CAP_NET_ADMIN`.
+fn status_allows_nfqueue(status: &str) -> bool {
+    let field = |name: &str| {
+        status.lines().find(|l| l.starts_with(name)).and_then(|l| l.split_whitespace().nth(1))
+    };
+    let root = field("Uid:").map(|uid| uid == "0").unwrap_or(false);
+    let net_admin = field("CapEff:")
+        .and_then(|caps| u64::from_str_radix(caps, 16).ok())
+        .map(|caps| caps & CAP_NET_ADMIN_BIT != 0)
+        .unwrap_or(false);
+    root || net_admin
+}
+
+/// Checks if the current process may use NFQUEUE by reading /proc/self/status.
+/// Returns true if running as root or with `CAP_NET_ADMIN`, false otherwise.
+///
+/// Without /proc (e.g. in minimal containers), falls back to the effective user ID and the
+/// effective capabilities as the kernel reports them.
+fn has_net_admin() -> bool {
+    allows_nfqueue(fs::read_to_string("/proc/self/status"))
+}
+
+/// Decides [`has_net_admin`] from the result of reading /proc/self/status.
+fn allows_nfqueue(status: std::io::Result<String>) -> bool {
+    match status {
+        Ok(status) => status_allows_nfqueue(&status),
+        Err(_) => {
+            nix::unistd::geteuid().is_root()
+                || caps::has_cap(None, caps::CapSet::Effective, caps::Capability::CAP_NET_ADMIN)
+                    .unwrap_or(false)
+        }
+    }
+}
+
+/// Loads the filter configuration from `--config`, the default path or the NF_WGOBFS_CONF
+/// environment variable, plus the fragments in `/etc/nf_wgobfs/conf.d/` (see
+/// [`read_config_layers`] for the precedence).
+/// Exits the process without root or CAP_NET_ADMIN. Returns a vector of FilterConfig on success.
+pub fn load_config() -> std::io::Result<Vec<FilterConfig>> {
+    if !has_net_admin() {
+        eprintln!("This program needs CAP_NET_ADMIN (run it as root or grant the capability).");
+        std::process::exit(1);
+    }
+    read_config()
+}
+
+/// Default configuration file.
+const DEFAULT_CONFIG: &str = "/etc/nf_wgobfs/config";
+/// Directory of configuration fragments (`*.conf`), read after the main file.
+const CONFIG_DIR: &str = "/etc/nf_wgobfs/conf.d";
+
+/// Value of `NF_WGOBFS_CONF` that reads the configuration from standard input.
+const STDIN_CONFIG: &str = "-";
+
+/// Reads the rule lines of a configuration stream, skipping blank lines and comments.
+fn config_lines(reader: impl BufRead) -> Vec<String> {
+    reader
+        .lines()
+        .map_while(Result::ok)
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect()
+}
+
+/// Reads the rule lines of a configuration file, like [`config_lines`].
+fn read_lines(path: &Path) -> std::io::Result<Vec<String>> {
+    let file = fs::File::open(path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e))
+    })?;
+    Ok(config_lines(std::io::BufReader::new(file)))
+}
+
+/// Lists the `*.conf` fragments of `dir` in byte order of their names, so that the rules,
+/// and any duplicate-queue error, come out the same on every run.
+fn config_fragments(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|p| p.extension().is_some_and(|ext| ext == "conf") && p.is_file());
+    paths.sort();
+    Ok(paths)
+}
+
+/// Where a layer of the configuration was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// A configuration file or `conf.d` fragment.
+    File(PathBuf),
+    /// Standard input, read until it is closed.
+    Stdin,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+            ConfigSource::Stdin => write!(f, "standard input"),
+        }
+    }
+}
+
+/// The rules read from one configuration source.
+pub struct ConfigLayer {
+    pub source: ConfigSource,
+    pub rules: Vec<FilterConfig>,
+}
+
+/// Configuration file given with `--config`, if any.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set once the rules were read from standard input, which holds them only once.
+static STDIN_READ: AtomicBool = AtomicBool::new(false);
+
+/// Tells whether the rules were read from standard input (`--config -`), so that reading
+/// the configuration again, e.g. on SIGHUP, cannot succeed.
+pub fn config_from_stdin() -> bool {
+    STDIN_READ.load(Ordering::Relaxed)
+}
+
+/// Sets the configuration file given on the command line, `-` for standard input. It
+/// replaces the default file and `NF_WGOBFS_CONF`; only the first call has an effect.
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+/// Lists the configuration sources in the order their rules are read.
+///
+/// The base is `explicit` (`--config`) if given, otherwise `default` if it exists, otherwise
+/// `env` (`NF_WGOBFS_CONF`), if set. The `*.conf` fragments of `conf_dir`, if it exists, are
+/// appended to it. A base of `-` reads standard input instead, and only that.
+///
+/// # Returns
+/// * `Ok(sources)` - The sources, the base first.
+/// * `Err` - If there is no source at all or `conf_dir` cannot be listed.
+fn config_sources(
+    explicit: Option<&Path>,
+    default: &Path,
+    env: Option<&Path>,
+    conf_dir: &Path,
+) -> std::io::Result<Vec<ConfigSource>> {
+    let base = match explicit {
+        Some(path) => Some(path),
+        None if default.exists() => Some(default),
+        None => env,
+    };
+    if base.is_some_and(|path| path == Path::new(STDIN_CONFIG)) {
+        return Ok(vec![ConfigSource::Stdin]);
+    }
+    let mut sources =
+        base.map(|path| ConfigSource::File(path.to_path_buf())).into_iter().collect::<Vec<_>>();
+    if conf_dir.is_dir() {
+        sources.extend(config_fragments(conf_dir)?.into_iter().map(ConfigSource::File));
+    }
+    if sources.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "Config not found: {}, no {}/*.conf and NF_WGOBFS_CONF not set.",
+                default.display(),
+                conf_dir.display()
+            ),
+        ));
+    }
+    Ok(sources)
+}
+
+/// Reads and parses each source on its own, then checks that no queue, workers included,
+/// is configured by two of them.
+///
+/// # Returns
+/// * `Ok(layers)` - The rules of each source, in the order of `sources`.
+/// * `Err` - If a source cannot be read, its rules are invalid, or two sources share a queue;
+///   the message names the source, or both.
+fn read_layers(sources: Vec<ConfigSource>) -> std::io::Result<Vec<ConfigLayer>> {
+    let mut layers: Vec<ConfigLayer> = Vec::with_capacity(sources.len());
+    for source in sources {
+        let lines = match &source {
+            ConfigSource::File(path) => read_lines(path)?,
+            // A second read would block on an open stdin or find nothing on a closed one
+            ConfigSource::Stdin if STDIN_READ.swap(true, Ordering::Relaxed) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "The rules were read from standard input already and cannot be read again",
+                ));
+            }
+            ConfigSource::Stdin => config_lines(std::io::stdin().lock()),
+        };
+        let rules = parse_config(&lines)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", source, e)))?;
+        for queue in rules.iter().flat_map(FilterConfig::queues) {
+            let owner =
+                layers.iter().find(|layer| layer.rules.iter().any(|f| f.queues().contains(&queue)));
+            if let Some(owner) = owner {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Queue {} of {} is already configured in {}",
+                        queue, source, owner.source
+                    ),
+                ));
+            }
+        }
+        layers.push(ConfigLayer { source, rules });
+    }
+    Ok(layers)
+}
+
+/// Parses `NF_WGOBFS_QUEUE`, a base added to every configured queue number so that a second
+/// instance can serve the same rules on other queues; 0 if unset.
+fn queue_base(value: Option<&OsStr>) -> std::io::Result<u16> {
+    let Some(value) = value else {
+        return Ok(0);
+    };
+    value.to_str().and_then(|v| v.trim().parse::<u16>().ok()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid NF_WGOBFS_QUEUE: {:?} (expected a queue base, 0-65535)", value),
+        )
+    })
+}
+
+/// Moves the queues of `filter`, workers included, up by `base`.
+fn shift_queues(filter: &mut FilterConfig, base: u16) -> std::io::Result<()> {
+    let shifted = filter.queue_num.checked_add(base);
+    match shifted.filter(|queue| queue.checked_add(filter.workers - 1).is_some()) {
+        Some(queue) => {
+            filter.queue_num = queue;
+            Ok(())
+        }
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Queue {} shifted by NF_WGOBFS_QUEUE={}: queue numbers end at 65535",
+                filter.queue_num, base
+            ),
+        )),
+    }
+}
+
+/// Reads the configuration like [`read_config`], keeping the rules of each source apart,
+/// e.g. to show what was loaded from where.
+///
+/// The base is the file given with `--config`; without it `/etc/nf_wgobfs/config` if it
+/// exists, and `NF_WGOBFS_CONF` only otherwise. The `*.conf` files of
+/// `/etc/nf_wgobfs/conf.d/` are appended to the base, in name order; either may be absent. A
+/// base of `-` reads the rules from standard input until it is closed, and only from there.
+/// A queue may be configured by one source only. `NF_WGOBFS_QUEUE`, if set, is added to
+/// every queue number. Rules with an `auto` MTU get the MTU of their interface.
+pub fn read_config_layers() -> std::io::Result<Vec<ConfigLayer>> {
+    let base = queue_base(env::var_os("NF_WGOBFS_QUEUE").as_deref())?;
+    let env = env::var_os("NF_WGOBFS_CONF").map(PathBuf::from);
+    let sources = config_sources(
+        CONFIG_PATH.get().map(PathBuf::as_path),
+        Path::new(DEFAULT_CONFIG),
+        env.as_deref(),
+        Path::new(CONFIG_DIR),
+    )?;
+    let mut layers = read_layers(sources)?;
+    for filter in layers.iter_mut().flat_map(|layer| layer.rules.iter_mut()) {
+        shift_queues(filter, base)?;
+        resolve_auto_mtu(filter);
+    }
+    Ok(layers)
+}
+
+/// Reads and parses the configuration like [`load_config`], without the privilege check.
+/// See [`read_config_layers`] for the sources and their precedence.
+pub fn read_config() -> std::io::Result<Vec<FilterConfig>> {
+    Ok(read_config_layers()?.into_iter().flat_map(|layer| layer.rules).collect())
+}
+
+/// Parses an on/off style option value.
+fn parse_switch(name: &str, value: &str) -> std::io::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid value for {}: {}", name, value),
+        )),
+    }
+}
+
+/// Parses a comma-separated list of packet sizes, such as the padding buckets (`buckets=`)
+/// or the histogram edges (`histogram=`), returned ascending without duplicates. `what`
+/// names one size in the error.
+fn parse_sizes(queue_num: u16, what: &str, value: &str) -> std::io::Result<Vec<usize>> {
+    let mut sizes = value
+        .split(',')
+        .map(|size| match size.trim().parse::<u16>() {
+            Ok(size) if size > 0 => Ok(size as usize),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid {} for queue {}: {:?}", what, queue_num, size),
+            )),
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    sizes.sort_unstable();
+    sizes.dedup();
+    Ok(sizes)
+}
+
+/// Parses a `<percent>,<max_ms>` jitter setting; the delay is capped at one second.
+fn parse_jitter(queue_num: u16, value: &str) -> std::io::Result<Jitter> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid jitter for queue {}: {:?} (expected <percent>,<max_ms>)",
+                queue_num, value
+            ),
+        )
+    };
+    let (percent, max_ms) = value.split_once(',').ok_or_else(invalid)?;
+    let percent = percent.trim().parse::<u8>().ok().filter(|p| *p <= 100).ok_or_else(invalid)?;
+    let max_ms = max_ms.trim().parse::<u64>().ok().filter(|ms| (1..=1000).contains(ms));
+    let max_delay = Duration::from_millis(max_ms.ok_or_else(invalid)?);
+    Ok(Jitter { percent, max_delay })
+}
+
+/// Parses a `<interval_ms>,<min_len>,<max_len>` chaff setting.
+///
+/// The interval must be at least 10 ms and the sizes must hold a WireGuard transport
+/// header and tag (32 bytes).
+fn parse_chaff(queue_num: u16, value: &str) -> std::io::Result<Chaff> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid chaff for queue {}: {:?} (expected <interval_ms>,<min_len>,<max_len>)",
+                queue_num, value
+            ),
+        )
+    };
+    let fields = value.split(',').map(|f| f.trim().parse::<u16>().ok()).collect::<Vec<_>>();
+    let [Some(interval), Some(min_len), Some(max_len)] = fields[..] else {
+        return Err(invalid());
+    };
+    if interval < 10 || min_len < 32 || max_len < min_len {
+        return Err(invalid());
+    }
+    Ok(Chaff {
+        interval: Duration::from_millis(interval as u64),
+        min_len: min_len as usize,
+        max_len: max_len as usize,
+    })
+}
+
+/// Most packets one capture of `tap=` may hold.
+pub const TAP_PACKETS_MAX: usize = 100_000;
+/// Longest capture `tap=` may run.
+pub const TAP_DURATION_MAX: Duration = Duration::from_secs(3600);
+
+/// Parses the `tap=<packets>[,<seconds>]` setting; the duration defaults to that of
+/// [`Tap::default`].
+fn parse_tap(queue_num: u16, value: &str) -> std::io::Result<Tap> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid tap for queue {}: {:?} (expected <packets>[,<seconds>], up to {} \
+                packets and {} seconds)",
+                queue_num,
+                value,
+                TAP_PACKETS_MAX,
+                TAP_DURATION_MAX.as_secs()
+            ),
+        )
+    };
+    let (packets, seconds) = match value.split_once(',') {
+        Some((packets, seconds)) => (packets, Some(seconds)),
+        None => (value, None),
+    };
+    let packets = packets
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (1..=TAP_PACKETS_MAX).contains(n))
+        .ok_or_else(invalid)?;
+    let duration = match seconds {
+        Some(seconds) => seconds
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .map(Duration::from_secs)
+            .filter(|d| !d.is_zero() && *d <= TAP_DURATION_MAX)
+            .ok_or_else(invalid)?,
+        None => Tap::default().duration,
+    };
+    Ok(Tap { packets, duration })
+}
+
+/// Parses a `<ceiling>[,<seconds>]` length hiding setting; the ceiling is checked against
+/// the MTU once the rule is complete.
+fn parse_hide_length(queue_num: u16, value: &str) -> std::io::Result<LengthHiding> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid hide-length for queue {}: {:?} (expected <ceiling>[,<seconds>], up to \
+                {} seconds)",
+                queue_num,
+                value,
+                HIDE_LENGTH_WINDOW_MAX.as_secs()
+            ),
+        )
+    };
+    let (ceiling, seconds) = match value.split_once(',') {
+        Some((ceiling, seconds)) => (ceiling, Some(seconds)),
+        None => (value, None),
+    };
+    let ceiling = ceiling.trim().parse::<usize>().map_err(|_| invalid())?;
+    let window = match seconds {
+        Some(seconds) => seconds
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .map(Duration::from_secs)
+            .filter(|d| !d.is_zero() && *d <= HIDE_LENGTH_WINDOW_MAX)
+            .ok_or_else(invalid)?,
+        None => HIDE_LENGTH_WINDOW,
+    };
+    Ok(LengthHiding { ceiling, window })
+}
+
+/// Parses a `<rate>[,<burst>]` rate limit of option `name`; the burst defaults to the rate.
+fn parse_rate_limit(queue_num: u16, name: &str, value: &str) -> std::io::Result<RateLimit> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid {} for queue {}: {:?} (expected <rate>[,<burst>])",
+                name, queue_num, value
+            ),
+        )
+    };
+    let (rate, burst) = match value.split_once(',') {
+        Some((rate, burst)) => (rate, Some(burst)),
+        None => (value, None),
+    };
+    let parse = |s: &str| s.trim().parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(invalid);
+    let rate = parse(rate)?;
+    let burst = burst.map(parse).transpose()?.unwrap_or(rate);
+    Ok(RateLimit { rate, burst })
+}
+
+/// Characters that a backslash escapes in a configuration line: `\:` and `\#` stand for a
+/// colon and a `#` within a field, `\\` for a backslash. Other backslashes are kept as is.
+const ESCAPED: [char; 3] = [':', '#', '\\'];
+
+/// Removes the comment at the end of a configuration line. A `#` at the start of the line or
+/// after whitespace starts a comment, so a `#` within a key (`pa#ss`) is kept; `\#` stands
+/// for a `#` that belongs to the rule even after whitespace. Escapes are left in place for
+/// [`unescape`].
+fn strip_comment(line: &str) -> String {
+    let mut rule = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    let mut after_space = true;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(|next| ESCAPED.contains(next)) => {
+                rule.push(c);
+                rule.push(chars.next().expect("peeked"));
+            }
+            '#' if after_space => break,
+            _ => rule.push(c),
+        }
+        after_space = c.is_whitespace();
+    }
+    rule.truncate(rule.trim_end().len());
+    rule
+}
+
+/// Resolves the escapes of a configuration field (see [`ESCAPED`]).
+fn unescape(field: &str) -> Cow<'_, str> {
+    if !field.contains('\\') {
+        return Cow::Borrowed(field);
+    }
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '\\' && ESCAPED.contains(&next) => {
+                value.push(next);
+                chars.next();
+            }
+            _ => value.push(c),
+        }
+    }
+    Cow::Owned(value)
+}
+
+/// Splits a configuration line into its `:`-separated fields, escapes still in place. Colons
+/// inside square brackets do not separate fields, so IPv6 addresses can be written as in
+/// URLs: `[2001:db8::]/32`; nor do escaped ones (`\:`), so keys and interface names may
+/// contain colons.
+fn split_fields(line: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0u32;
+    let mut escaped = false;
+    line.split(move |c| {
+        if std::mem::take(&mut escaped) && ESCAPED.contains(&c) {
+            return false;
+        }
+        match c {
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        c == ':' && depth == 0
+    })
+}
+
+/// Expands a `tunnel:name:key:queues[:field...]` line into the outbound and inbound rule
+/// lines it stands for, both with the same name, key, MTU and options. `queues` is
+/// `out=N,in=M`, or just `N` for an inbound queue right after the outbound one.
+///
+/// # Returns
+/// * `Ok(Some(lines))` - The two rule lines of a tunnel line.
+/// * `Ok(None)` - If `line` is an ordinary rule line.
+/// * `Err(e)` - If the queues of a tunnel line are invalid or missing.
+fn expand_tunnel(line: &str) -> std::io::Result<Option<[String; 2]>> {
+    let mut parts = split_fields(line);
+    if !parts.next().is_some_and(|s| s.trim().eq_ignore_ascii_case("tunnel")) {
+        return Ok(None);
+    }
+    let (Some(name), Some(key), Some(queues)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Incomplete tunnel line: {:?}", line),
+        ));
+    };
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid queues for tunnel {}: {:?} (expected out=N,in=M or N)",
+                name.trim(),
+                queues
+            ),
+        )
+    };
+    let parse = |s: &str| s.trim().parse::<u16>().map_err(|_| invalid());
+    let (out, inbound) = match queues.split_once(',') {
+        None => {
+            let out = parse(queues)?;
+            (out, out.checked_add(1).ok_or_else(invalid)?)
+        }
+        Some((first, second)) => {
+            let (mut out, mut inbound) = (None, None);
+            for queue in [first, second] {
+                match queue.split_once('=').map(|(name, value)| (name.trim(), value)) {
+                    Some(("out", value)) if out.is_none() => out = Some(parse(value)?),
+                    Some(("in", value)) if inbound.is_none() => inbound = Some(parse(value)?),
+                    _ => return Err(invalid()),
+                }
+            }
+            (out.ok_or_else(invalid)?, inbound.ok_or_else(invalid)?)
+        }
+    };
+    let rest: String = parts.map(|field| format!(":{}", field)).collect();
+    Ok(Some([
+        format!("{}:out:{}:{}{}", out, name, key, rest),
+        format!("{}:in:{}:{}{}", inbound, name, key, rest),
+    ]))
+}
+
+/// Parses the MTU field of a rule.
+fn parse_mtu(queue_num: u16, value: &str) -> std::io::Result<usize> {
+    value.parse::<usize>().ok().filter(|n| (MTU_MIN..=MTU_MAX).contains(n)).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid MTU for queue {}: {} (expected {}-{})",
+                queue_num, value, MTU_MIN, MTU_MAX
+            ),
+        )
+    })
+}
+
+/// Reads the MTU of the network interface `iface` with the `SIOCGIFMTU` ioctl.
+pub fn interface_mtu(iface: &str) -> std::io::Result<usize> {
+    use nix::libc;
+
+    // SAFETY: `ifreq` is plain data, valid when zeroed.
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    if iface.is_empty() || iface.len() >= req.ifr_name.len() || iface.contains('\0') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid interface name: {:?}", iface),
+        ));
+    }
+    for (dst, src) in req.ifr_name.iter_mut().zip(iface.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    // SAFETY: plain socket call; the descriptor is owned and closed below.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: `req` is a valid `ifreq` with a NUL-terminated name, as SIOCGIFMTU expects.
+    let ret = unsafe { libc::ioctl(fd, libc::SIOCGIFMTU as _, &mut req) };
+    let result = match ret {
+        // SAFETY: SIOCGIFMTU filled in the MTU member of the union.
+        0 => Ok(unsafe { req.ifr_ifru.ifru_mtu }.max(0) as usize),
+        _ => Err(std::io::Error::last_os_error()),
+    };
+    // SAFETY: `fd` is open and not used afterwards.
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// Sets the MTU of a rule with `mtu_auto` from its interface, within [`min_mtu`] and
+/// [`MTU_MAX`]. An interface that cannot be queried leaves the default of 1500, with a
+/// warning. Returns true if the MTU changed.
+pub fn resolve_auto_mtu(filter: &mut FilterConfig) -> bool {
+    if !filter.mtu_auto {
+        return false;
+    }
+    let mtu = match interface_mtu(&filter.iface) {
+        Ok(mtu) if (min_mtu(filter)..=MTU_MAX).contains(&mtu) => mtu,
+        Ok(mtu) => {
+            let clamped = mtu.clamp(min_mtu(filter), MTU_MAX);
+            log::warn!(
+                "MTU {} of {} (queue {}) is out of range, using {}",
+                mtu,
+                filter.iface,
+                filter.queue_num,
+                clamped
+            );
+            clamped
+        }
+        Err(e) => {
+            log::warn!(
+                "Cannot read the MTU of {} (queue {}): {}; using {}",
+                filter.iface,
+                filter.queue_num,
+                e,
+                DEFAULT_MTU
+            );
+            DEFAULT_MTU
+        }
+    };
+    let changed = mtu != filter.mtu;
+    filter.mtu = mtu;
+    changed
+}
+
+/// Parses the `encrypt-bytes=` setting.
+fn parse_encrypt_bytes(queue_num: u16, value: &str) -> std::io::Result<usize> {
+    value
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (ENCRYPT_BYTES_MIN..=ENCRYPT_BYTES_MAX).contains(n))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid encrypt-bytes for queue {}: {:?} (expected {}-{})",
+                    queue_num, value, ENCRYPT_BYTES_MIN, ENCRYPT_BYTES_MAX
+                ),
+            )
+        })
+}
+
+/// Parses the `keepalive-idle=` option: whole seconds, up to the time after which an idle
+/// peer is forgotten anyway.
+fn parse_keepalive_idle(queue_num: u16, value: &str) -> std::io::Result<Duration> {
+    value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+        .filter(|idle| *idle <= PEER_IDLE_TIMEOUT)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid keepalive-idle for queue {}: {:?} (expected 0-{} seconds)",
+                    queue_num,
+                    value,
+                    PEER_IDLE_TIMEOUT.as_secs()
+                ),
+            )
+        })
+}
+
+/// Parses the `keepalive-drop=` option: `<min>,<max>`, or a single count for both.
+fn parse_keepalive_drop(queue_num: u16, value: &str) -> std::io::Result<KeepaliveDrop> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid keepalive-drop for queue {}: {:?} (expected <min>[,<max>], 1-{})",
+                queue_num,
+                value,
+                u8::MAX
+            ),
+        )
+    };
+    let (min, max) = value.split_once(',').unwrap_or((value, value));
+    let parse = |s: &str| s.trim().parse::<u8>().ok().filter(|n| *n > 0).ok_or_else(invalid);
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(invalid());
+    }
+    Ok(KeepaliveDrop { min, max })
+}
+
+/// Parses the `watchdog=` option: whole seconds from 1 to a day, or `off`.
+fn parse_watchdog(queue_num: u16, value: &str) -> std::io::Result<Option<Duration>> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|secs| (1..=WATCHDOG_MAX).contains(secs))
+        .map(|secs| Some(Duration::from_secs(secs)))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid watchdog for queue {}: {:?} (expected 1-{} seconds or off)",
+                    queue_num, value, WATCHDOG_MAX
+                ),
+            )
+        })
+}
+
+/// Parses a comma-separated list of CIDR prefixes of option `name`.
+fn parse_cidrs(queue_num: u16, name: &str, value: &str) -> std::io::Result<Vec<Cidr>> {
+    value
+        .split(',')
+        .map(|cidr| {
+            Cidr::parse(cidr).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid {} prefix for queue {}: {:?}", name, queue_num, cidr),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of ports and port ranges, e.g. `51820,51821-51830`.
+fn parse_ports(queue_num: u16, value: &str) -> std::io::Result<Vec<PortRange>> {
+    let port = |s: &str| s.trim().parse::<u16>().ok().filter(|&port| port > 0);
+    value
+        .split(',')
+        .map(|item| {
+            let (first, last) = item.split_once('-').unwrap_or((item, item));
+            match (port(first), port(last)) {
+                (Some(first), Some(last)) if first <= last => Ok(PortRange { first, last }),
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid port for queue {}: {:?} (expected a port or a range such as \
+                        51821-51830)",
+                        queue_num, item
+                    ),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Parses a `chacha8|chacha12|chacha20|xchacha20` cipher setting.
+fn parse_cipher(queue_num: u16, value: &str) -> std::io::Result<CipherMode> {
+    CipherMode::parse(value.trim()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid cipher for queue {}: {:?} (expected chacha8/12/20 or xchacha20)",
+                queue_num, value
+            ),
+        )
+    })
+}
+
+/// Parses a ballast profile name.
+fn parse_ballast(queue_num: u16, value: &str) -> std::io::Result<BallastProfile> {
+    BallastProfile::parse(value.trim()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid ballast for queue {}: {:?} (expected random, zero or text)",
+                queue_num, value
+            ),
+        )
+    })
+}
+
+/// Parses a worker count, or `auto` for one worker per CPU.
+fn parse_workers(queue_num: u16, value: &str) -> std::io::Result<u16> {
+    let workers = match value.trim() {
+        "auto" => std::thread::available_parallelism().map_or(1, |n| n.get()) as u16,
+        count => count.parse::<u16>().ok().filter(|n| (1..=MAX_WORKERS).contains(n)).ok_or_else(
+            || {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid workers for queue {}: {:?} (expected 1-{} or auto)",
+                        queue_num, value, MAX_WORKERS
+                    ),
+                )
+            },
+        )?,
+    };
+    let workers = workers.min(MAX_WORKERS);
+    if queue_num.checked_add(workers - 1).is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Too many workers for queue {}: queue numbers end at 65535", queue_num),
+        ));
+    }
+    Ok(workers)
+}
+
+/// What to do with a weak passphrase (`keycheck=`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum KeyCheck {
+    /// Accept it silently.
+    Off,
+    /// Log a warning and accept it.
+    #[default]
+    Warn,
+    /// Reject the configuration.
+    Strict,
+}
+
+/// Parses the `keycheck=` option.
+fn parse_keycheck(queue_num: u16, value: &str) -> std::io::Result<KeyCheck> {
+    match value.trim().to_lowercase().as_str() {
+        "off" => Ok(KeyCheck::Off),
+        "warn" => Ok(KeyCheck::Warn),
+        "strict" => Ok(KeyCheck::Strict),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid keycheck for queue {}: {:?} (expected warn, strict or off)",
+                queue_num, value
+            ),
+        )),
+    }
+}
+
+/// Parses the `flowlabel=` option; returns true for `zero`.
+fn parse_flowlabel(queue_num: u16, value: &str) -> std::io::Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "keep" => Ok(false),
+        "zero" => Ok(true),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid flowlabel for queue {}: {:?} (expected keep or zero)",
+                queue_num, value
+            ),
+        )),
+    }
+}
+
+/// Passphrases shorter than this many characters are reported as weak.
+const MIN_PASSPHRASE_LEN: usize = 16;
+/// Passphrases with a lower estimated entropy, in bits, are reported as weak.
+const MIN_PASSPHRASE_BITS: f64 = 48.0;
+/// Placeholders commonly left in configuration files, compared case-insensitively.
+const PLACEHOLDERS: &[&str] =
+    &["changeme", "change_me", "password", "passphrase", "secret", "secretkey", "key", "test"];
+
+/// Estimates the entropy of `passphrase` in bits from the frequency of its characters.
+///
+/// This is an upper bound for human-chosen passphrases (it cannot see words or patterns),
+/// so it only catches the obvious cases: repetitions and short alphabets.
+fn passphrase_bits(passphrase: &str) -> f64 {
+    let chars = passphrase.chars().collect::<Vec<_>>();
+    let mut counts = std::collections::HashMap::new();
+    for c in &chars {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let len = chars.len() as f64;
+    counts.values().map(|&n| -(n as f64) * (n as f64 / len).log2()).sum()
+}
+
+/// Returns why `passphrase` is weak, or `None` if it passes the checks. The reason never
+/// quotes the passphrase, as it ends up in logs.
+fn passphrase_weakness(passphrase: &str) -> Option<String> {
+    let len = passphrase.chars().count();
+    if len == 0 {
+        return Some("it is empty".to_string());
+    }
+    if PLACEHOLDERS.contains(&passphrase.to_lowercase().as_str()) {
+        return Some("it is a common placeholder".to_string());
+    }
+    if len < MIN_PASSPHRASE_LEN {
+        return Some(format!("it is shorter than {} characters", MIN_PASSPHRASE_LEN));
+    }
+    let bits = passphrase_bits(passphrase);
+    if bits < MIN_PASSPHRASE_BITS {
+        return Some(format!("its estimated entropy is only {:.0} bits", bits));
+    }
+    None
+}
+
+/// Checks the passphrase of a rule (`which` names it, e.g. "key") before it is hashed, and
+/// warns or fails according to `check`.
+fn check_passphrase(
+    queue_num: u16,
+    which: &str,
+    passphrase: &str,
+    check: KeyCheck,
+) -> std::io::Result<()> {
+    let Some(reason) = passphrase_weakness(passphrase).filter(|_| check != KeyCheck::Off) else {
+        return Ok(());
+    };
+    let message = format!(
+        "Weak {} for queue {}: {}; use a long random passphrase (e.g. openssl rand -base64 32)",
+        which, queue_num, reason
+    );
+    if check == KeyCheck::Strict {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+    }
+    log::warn!("{}", message);
+    Ok(())
+}
+
+/// Prefix of a key field holding a command that prints the key (`keycmd=<command>`).
+const KEYCMD_PREFIX: &str = "keycmd=";
+
+/// Longest a key command may run before its rule is refused.
+const KEYCMD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a running key command is checked for having exited.
+const KEYCMD_POLL: Duration = Duration::from_millis(10);
+
+/// Runs the key command of queue `queue_num` with `sh -c` and returns what it printed,
+/// trimmed: the key field it stands for, so `newkey,oldkey` works as well. Its standard
+/// error goes to ours. The command runs in a process group of its own, killed as a whole
+/// unless it has exited and closed its output within `timeout`, so a background child
+/// still holding the output counts as a timeout too.
+///
+/// # Returns
+/// * `Ok(output)` - The output, wiped when dropped.
+/// * `Err` - If the command cannot be started, fails, times out or prints nothing; the
+///   message names the queue but never the output.
+fn run_keycmd(
+    queue_num: u16,
+    command: &str,
+    timeout: Duration,
+) -> std::io::Result<Zeroizing<String>> {
+    let error = |reason: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Key command for queue {} {}", queue_num, reason),
+        )
+    };
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| error(format!("could not be started: {}", e)))?;
+    // The group is led by `sh`, so its id is that of the child
+    let group = Pid::from_raw(child.id() as i32);
+    let kill_group = |child: &mut Child| {
+        let _ = killpg(group, Signal::SIGKILL);
+        let _ = child.wait();
+    };
+    // Read on the side, so that a long output cannot fill the pipe and stall the command
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        let mut output = Zeroizing::new(Vec::with_capacity(4096));
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if reader.is_finished() => break status,
+            Ok(_) => {}
+            Err(e) => {
+                kill_group(&mut child);
+                return Err(error(format!("could not be waited for: {}", e)));
+            }
+        }
+        if Instant::now() >= deadline {
+            // The reader ends once the killed group no longer holds the pipe
+            kill_group(&mut child);
+            return Err(error(format!("timed out after {:?}", timeout)));
+        }
+        thread::sleep(KEYCMD_POLL);
+    };
+    if !status.success() {
+        return Err(error(format!("failed ({})", status)));
+    }
+    let output = reader.join().map_err(|_| error("output was lost".to_string()))??;
+    let output = std::str::from_utf8(&output)
+        .map_err(|_| error("printed a key that is not valid UTF-8".to_string()))?
+        .trim();
+    if output.is_empty() {
+        return Err(error("printed no key".to_string()));
+    }
+    Ok(Zeroizing::new(output.to_string()))
+}
+
+/// Parses a list of configuration lines into a vector of FilterConfig.
+/// Each line should be in the format: queue_num:direction:name:key\[:mtu\]\[:option=value...\]
+/// The key may be given as `newkey,oldkey` to keep accepting the old key during a rotation.
+/// A key field `keycmd=<command>` runs the command with `sh -c` (colons escaped as `\:`)
+/// and takes the key field from what it prints, trimmed, e.g. to fetch the key from a
+/// secret manager instead of keeping it on disk. The command must finish within 10 seconds;
+/// each distinct command runs once per call.
+/// The MTU defaults to 1500 and must lie between 68 and 9000 (jumbo frames), and hold the
+/// smallest obfuscated packet of the rule (see [`min_mtu`]). `auto` sets `mtu_auto`,
+/// leaving 1500 in `mtu` until [`resolve_auto_mtu`] reads the interface.
+///
+/// A line `tunnel:name:key:out=N,in=M[:mtu][:option=value...]` declares both directions of
+/// a tunnel at once: it stands for an `out` rule on queue N and an `in` rule on queue M with
+/// the same name, key, MTU and options. `out=N,in=M` may be shortened to `N` for M = N + 1.
+/// Returns an error if the format is invalid or if there are duplicate queue numbers.
+///
+/// A `#` at the start of a line or after whitespace starts a comment running to the end of
+/// the line, e.g. `0:out:wg0:key:1420  # main tunnel`; write `\#` for a `#` that belongs to
+/// the rule there. Lines left empty are skipped. Within a field, `\:` stands for a colon and
+/// `\\` for a backslash, so keys and interface names may contain colons: `0:out:eth0:a\:b`.
+///
+/// Supported options:
+/// - `enabled=on|off` - transform packets (default `on`); `off` keeps the queues bound but
+///   accepts every packet unchanged, e.g. to roll obfuscation out one tunnel at a time.
+///   SIGHUP reads it again.
+/// - `keepalive=on|off` - thin out WireGuard keepalives (default `on`).
+/// - `keepalive-idle=<seconds>` - only thin the keepalives of peers that sent no data for this
+///   long (default 10, up to 180; 0 thins them regardless of traffic).
+/// - `keepalive-drop=<min>[,<max>]` - keepalives dropped in a row after the first of a
+///   thinning burst, a random count in this range (default `1,9`).
+/// - `auth=on|off` - append and verify an integrity tag (default `off`, must match the peer).
+/// - `buckets=<size>[,<size>...]` - pad packets up to the nearest of these sizes (default none).
+/// - `jitter=<percent>,<max_ms>` - delay this share of outbound packets by up to `max_ms`.
+/// - `chaff=<interval_ms>,<min_len>,<max_len>` - send dummy packets the peer discards.
+/// - `cipher=chacha8|chacha12|chacha20|xchacha20` - ChaCha variant (default `chacha20`, must
+///   match the peer).
+/// - `salt=<string>` - derive the key with HKDF under this salt (default: unsalted SHA-256,
+///   must match the peer).
+/// - `workers=<n>|auto` - spread the rule over n worker queues from `queue_num` on (default 1,
+///   `auto`: one per CPU).
+/// - `keycheck=warn|strict|off` - warn about (default), reject or accept short, low-entropy
+///   and placeholder passphrases.
+/// - `keep-dscp=on|off` - keep the DSCP marks of obfuscated packets (default `off`). Clearing
+///   them hides per-application QoS classes that would otherwise tell the tunnelled flows
+///   apart; keeping them lets the local network prioritise the tunnel by its inner marks.
+///   Applies to the IPv4 DiffServ field and the IPv6 Traffic Class.
+/// - `flowlabel=keep|zero` - keep (default) or zero the IPv6 Flow Label of obfuscated packets.
+/// - `min-size=N` - pass WireGuard messages shorter than N bytes through unobfuscated
+///   (default 0: obfuscate all). Both ends must use the same value, as the receiver relies
+///   on it to tell such messages from obfuscated ones. Chaff must not be smaller.
+/// - `ratelimit=<rate>[,<burst>]` - pass at most `rate` packets per second on each queue of the
+///   rule, with bursts of up to `burst` (default: the rate); the rest are dropped.
+/// - `bytelimit=<rate>[,<burst>]` - the same in bytes; the burst must hold an MTU-sized packet.
+/// - `allow=<cidr>[,<cidr>...]` - only transform packets whose source address lies in one of
+///   these IPv4 or IPv6 prefixes; others are passed unchanged (default: all sources). IPv6
+///   addresses go in square brackets, e.g. `[2001:db8::]/32`, as colons separate fields.
+/// - `deny=<cidr>[,<cidr>...]` - pass packets from these prefixes unchanged, even if allowed.
+/// - `port=<port>[-<port>][,...]` - only transform packets with a source or destination port
+///   in this list of ports and ranges, e.g. `51820,51821-51830` (default: all ports).
+/// - `encrypt-bytes=N` - encrypt the first N bytes of each WireGuard message in place, 16
+///   (default) to 128, stopping short of MAC2 on smaller messages (must match the peer).
+/// - `ballast=random|zero|text` - fill the ballast with random bytes (default), zero bytes or
+///   printable ASCII, to mimic the padding of another protocol. The ballast goes on the wire
+///   unencrypted: other profiles than `random` let it stand out from the ciphertext.
+/// - `tap=<packets>[,<seconds>]` - capture this many packets before and after the transform
+///   to a pcap file when the queue starts, for at most `seconds` (default 60); debugging only.
+/// - `hide-length=<ceiling>[,<seconds>]` - pad each outbound packet up to the largest size
+///   its flow sent in the last `seconds` (default 30, up to 180), plus a few random bytes,
+///   but never beyond `ceiling` bytes or the MTU. Flattens the size distribution at the
+///   cost of bandwidth; the peer need not set it.
+/// - `histogram=<size>,...` - upper edges of the per-queue histograms of packet sizes before
+///   and after the transform, as shown by `status` and `--metrics` (default
+///   64,128,256,512,768,1024,1280,1500; up to 32 edges).
+/// - `watchdog=<seconds>|off` - log a warning when no WireGuard data (keepalives aside) crossed
+///   any queue of the rule for this long, and a notice when it resumes (default `off`).
+pub fn parse_config(input: &[String]) -> std::io::Result<Vec<FilterConfig>> {
+    let mut lines = Vec::with_capacity(input.len());
+    for line in input.iter().map(|line| strip_comment(line)) {
+        if line.is_empty() {
+            continue;
+        }
+        match expand_tunnel(&line)? {
+            Some(tunnel) => lines.extend(tunnel),
+            None => lines.push(line),
+        }
+    }
+    let mut configs = Vec::with_capacity(lines.len());
+    let mut seen_queues = HashSet::with_capacity(lines.len());
+    // Output of each key command, so that both rules of a tunnel share one run
+    let mut key_outputs: HashMap<String, Zeroizing<String>> = HashMap::new();
+    for line in &lines {
+        let fields: Vec<Cow<str>> = split_fields(line).map(unescape).collect();
+        let mut parts = fields.iter().map(|field| field.as_ref());
+        let queue_num = parts
+            .next()
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or(std::io::ErrorKind::InvalidData)?;
+        if !seen_queues.insert(queue_num) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Duplicate queue number {}", queue_num),
+            ));
+        }
+        let direction = match parts.next().map(|s| s.to_lowercase()) {
+            Some(ref s) if s == "in" => Direction::In,
+            Some(_) => Direction::Out,
+            None => return Err(std::io::ErrorKind::InvalidData.into()),
+        };
+        let iface = parts.next().map(str::trim).ok_or(std::io::ErrorKind::InvalidData)?;
+        if iface.is_empty() || iface.contains(char::is_whitespace) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid interface name for queue {}: {:?}", queue_num, iface),
+            ));
+        }
+        let iface = iface.to_string();
+        let mut key_ascii = parts.next().ok_or(std::io::ErrorKind::InvalidData)?;
+        if let Some(command) = key_ascii.trim_start().strip_prefix(KEYCMD_PREFIX) {
+            let command = command.trim();
+            if !key_outputs.contains_key(command) {
+                let output = run_keycmd(queue_num, command, KEYCMD_TIMEOUT)?;
+                key_outputs.insert(command.to_string(), output);
+            }
+            key_ascii = key_outputs[command].as_str();
+        }
+        // `newkey,oldkey` stages a key rotation: the old key is still accepted inbound
+        let (key_ascii, prev_ascii) = match key_ascii.split_once(',') {
+            Some((key, prev)) => (key, Some(prev)),
+            None => (key_ascii, None),
+        };
+
+        // Remaining fields: a numeric MTU or `auto` (default 1500) and `name=value` options in
+        // any order. Other bare fields (such as the legacy cipher mode) are ignored.
+        let mut mtu = DEFAULT_MTU;
+        let mut mtu_auto = false;
+        let mut keepalive = true;
+        let mut auth = false;
+        let mut buckets = Vec::new();
+        let mut jitter = None;
+        let mut chaff = None;
+        let mut cipher = CipherMode::default();
+        let mut salt = None;
+        let mut workers = 1;
+        let mut keycheck = KeyCheck::default();
+        let mut keep_dscp = false;
+        let mut zero_flow_label = false;
+        let mut min_size = 0;
+        let mut packet_limit = None;
+        let mut byte_limit = None;
+        let mut allow = Vec::new();
+        let mut deny = Vec::new();
+        let mut ports = Vec::new();
+        let mut encrypt_bytes = ENCRYPT_BYTES_MIN;
+        let mut keepalive_idle = DEFAULT_IDLE_THRESHOLD;
+        let mut keepalive_drop = KeepaliveDrop::default();
+        let mut ballast = BallastProfile::default();
+        let mut tap = None;
+        let mut watchdog = None;
+        let mut hide_length = None;
+        let mut enabled = true;
+        let mut size_edges = Cow::Borrowed(&DEFAULT_SIZE_EDGES[..]);
+        for field in parts.map(str::trim) {
+            if !field.is_empty() && field.bytes().all(|b| b.is_ascii_digit()) {
+                mtu = parse_mtu(queue_num, field)?;
+                mtu_auto = false;
+                continue;
+            }
+            if field.eq_ignore_ascii_case("auto") {
+                // Read from the interface by `read_config`, not while parsing
+                mtu = DEFAULT_MTU;
+                mtu_auto = true;
+                continue;
+            }
+            match field.split_once('=') {
+                Some(("enabled", value)) => enabled = parse_switch("enabled", value)?,
+                Some(("histogram", value)) => {
+                    size_edges = Cow::Owned(parse_sizes(queue_num, "histogram edge", value)?)
+                }
+                Some(("keepalive", value)) => keepalive = parse_switch("keepalive", value)?,
+                Some(("keepalive-idle", value)) => {
+                    keepalive_idle = parse_keepalive_idle(queue_num, value)?;
+                }
+                Some(("keepalive-drop", value)) => {
+                    keepalive_drop = parse_keepalive_drop(queue_num, value)?;
+                }
+                Some(("auth", value)) => auth = parse_switch("auth", value)?,
+                Some(("buckets", value)) => buckets = parse_sizes(queue_num, "bucket size", value)?,
+                Some(("jitter", value)) => jitter = Some(parse_jitter(queue_num, value)?),
+                Some(("chaff", value)) => chaff = Some(parse_chaff(queue_num, value)?),
+                Some(("cipher", value)) => cipher = parse_cipher(queue_num, value)?,
+                Some(("salt", "")) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Empty salt for queue {}", queue_num),
+                    ));
+                }
+                Some(("salt", value)) => salt = Some(value),
+                Some(("workers", value)) => workers = parse_workers(queue_num, value)?,
+                Some(("keycheck", value)) => keycheck = parse_keycheck(queue_num, value)?,
+                Some(("keep-dscp", value)) => keep_dscp = parse_switch("keep-dscp", value)?,
+                Some(("flowlabel", value)) => {
+                    zero_flow_label = parse_flowlabel(queue_num, value)?;
+                }
+                Some(("min-size", value)) => {
+                    min_size = value.trim().parse::<u16>().map_err(|_| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Invalid min-size for queue {}: {:?}", queue_num, value),
+                        )
+                    })? as usize;
+                }
+                Some(("ratelimit", value)) => {
+                    packet_limit = Some(parse_rate_limit(queue_num, "ratelimit", value)?);
+                }
+                Some(("bytelimit", value)) => {
+                    byte_limit = Some(parse_rate_limit(queue_num, "bytelimit", value)?);
+                }
+                Some(("allow", value)) => allow = parse_cidrs(queue_num, "allow", value)?,
+                Some(("deny", value)) => deny = parse_cidrs(queue_num, "deny", value)?,
+                Some(("port", value)) => ports = parse_ports(queue_num, value)?,
+                Some(("ballast", value)) => ballast = parse_ballast(queue_num, value)?,
+                Some(("tap", value)) => tap = Some(parse_tap(queue_num, value)?),
+                Some(("watchdog", value)) => watchdog = parse_watchdog(queue_num, value)?,
+                Some(("hide-length", value)) => {
+                    hide_length = Some(parse_hide_length(queue_num, value)?);
+                }
+                Some(("encrypt-bytes", value)) => {
+                    encrypt_bytes = parse_encrypt_bytes(queue_num, value)?;
+                }
+                Some((name, _)) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unknown option for queue {}: {}", queue_num, name),
+                    ));
+                }
+                None => {}
+            }
+        }
+
+        // Worker queues follow the rule's queue number and must not overlap other rules
+        for worker_queue in queue_num + 1..queue_num + workers {
+            if !seen_queues.insert(worker_queue) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Workers of queue {} overlap queue {}", queue_num, worker_queue),
+                ));
+            }
+        }
+
+        // Passphrases are checked and keys derived once all options are known: `keycheck=`
+        // and `salt=` may follow the key
+        check_passphrase(queue_num, "key", key_ascii.trim(), keycheck)?;
+        if let Some(prev) = prev_ascii {
+            check_passphrase(queue_num, "previous key", prev.trim(), keycheck)?;
+        }
+        let to_key = |ascii: &str| match salt {
+            Some(salt) => derive_key(ascii.trim(), salt),
+            None => ascii_to_key(ascii.trim()),
+        };
+        let key = to_key(key_ascii);
+        let prev_key = prev_ascii.map(to_key);
+
+        let config = FilterConfig {
+            queue_num,
+            direction,
+            iface,
+            key,
+            prev_key,
+            mtu,
+            mtu_auto,
+            keepalive,
+            auth,
+            buckets,
+            jitter,
+            chaff,
+            cipher,
+            workers,
+            keep_dscp,
+            zero_flow_label,
+            min_size,
+            packet_limit,
+            byte_limit,
+            allow,
+            deny,
+            ports,
+            encrypt_bytes,
+            keepalive_idle,
+            keepalive_drop,
+            ballast,
+            tap,
+            watchdog,
+            hide_length,
+            enabled,
+            size_edges,
+        };
+        config.validate()?;
+        configs.push(config);
+    }
+    Ok(configs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that root or CAP_NET_ADMIN in CapEff grant NFQUEUE access, and that root is
+    /// still recognized without /proc.
+    #[test]
+    fn test_status_allows_nfqueue() {
+        let status = |uid: &str, cap_eff: &str| {
+            format!("Name:\tnf_wgobfs\nUid:\t{uid}\t{uid}\t{uid}\t{uid}\nCapEff:\t{cap_eff}\n")
+        };
+        assert!(status_allows_nfqueue(&status("0", "000001ffffffffff")));
+        assert!(status_allows_nfqueue(&status("0", "0000000000000000")));
+        assert!(status_allows_nfqueue(&status("1000", "0000000000001000")));
+        assert!(!status_allows_nfqueue(&status("1000", "0000000000000000")));
+        assert!(!status_allows_nfqueue(&status("1000", "0000000000002000")));
+        assert!(!status_allows_nfqueue(""));
+
+        // Without /proc, root still passes
+        let missing = || Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+        if nix::unistd::geteuid().is_root() {
+            assert!(allows_nfqueue(missing()));
+        }
+        assert!(!allows_nfqueue(Ok(status("1000", "0000000000000000"))));
+    }
+
+    /// Tests parsing a full config line with all fields present.
+    #[test]
+    fn test_parse_config_line_full() {
+        let line = ["1:in:wg_in:abcdef0123456789abcdef0123456789:F:1350"];
+        let line: Vec<String> = line.iter().map(|s| s.to_string()).collect();
+        if let Ok(config) = parse_config(&line) {
+            assert_eq!(config[0].queue_num, 1);
+            assert_eq!(config[0].direction, Direction::In);
+            assert_eq!(config[0].key, ascii_to_key("abcdef0123456789abcdef0123456789"));
+            assert_eq!(config[0].mtu, 1350);
+        } else {
+            panic!("Failed to parse config line");
+        }
+    }
+
+    /// Tests parsing multiple config lines, including default MTU handling.
+    #[test]
+    fn test_parse_config_line_full_and_defaults() {
+        let lines = [
+            "0:out:wg_out:abcdef6760123456789abcdef0123456789:1350",
+            "1:in:wg_in:fjklabcdef0123456789abcdef0123456789",
+            "2:in:wg_in:mnopf0123456789abcdef0123456789",
+            "3:in:wg_in:mnopf0123456789abcdef0123456789",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        if let Ok(configs) = parse_config(&lines) {
+            assert_eq!(configs[0].queue_num, 0);
+            assert_eq!(configs[0].direction, Direction::Out);
+            assert_eq!(configs[0].key, ascii_to_key("abcdef6760123456789abcdef0123456789"));
+            assert_eq!(configs[0].mtu, 1350);
+
+            assert_eq!(configs[1].queue_num, 1);
+            assert_eq!(configs[1].direction, Direction::In);
+            assert_eq!(configs[1].key, ascii_to_key("fjklabcdef0123456789abcdef0123456789"));
+            assert_eq!(configs[1].mtu, 1500); // Default MTU
+            assert_eq!(configs[2].queue_num, 2);
+            assert_eq!(configs[2].direction, Direction::In);
+            assert_eq!(configs[2].key, ascii_to_key("mnopf0123456789abcdef0123456789"));
+            assert_eq!(configs[2].mtu, 1500); // Default MTU
+        } else {
+            panic!("Failed to parse config lines");
+        }
+    }
+
+    /// Tests that duplicate queue numbers in the config cause an error.
+    #[test]
+    fn test_parse_config_duplicate_queue_num() {
+        let lines = [
+            "1:in:wg_in:abcdef0123456789abcdef0123456789:1350",
+            "1:out:wg_out:abcdef0123456789abcdef0123456789:1400", // duplicate queue_num
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let result = parse_config(&lines);
+        assert!(result.is_err(), "Duplicate queue numbers should cause an error");
+    }
+
+    /// Tests the keepalive option and its default.
+    #[test]
+    fn test_parse_config_keepalive_option() {
+        let lines = [
+            "0:out:wg_out:secret:1350:keepalive=off",
+            "1:out:wg_out:secret:keepalive=on",
+            "2:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert!(!configs[0].keepalive);
+        assert_eq!(configs[0].mtu, 1350);
+        assert!(configs[1].keepalive);
+        assert!(configs[2].keepalive);
+
+        let bad = vec!["0:out:wg_out:secret:keepalive=maybe".to_string()];
+        assert!(parse_config(&bad).is_err());
+        let unknown = vec!["0:out:wg_out:secret:bogus=1".to_string()];
+        assert!(parse_config(&unknown).is_err());
+    }
+
+    /// Tests the keepalive-idle option, its default and its range.
+    #[test]
+    fn test_parse_config_keepalive_idle() {
+        let lines = [
+            "0:out:wg_out:secret:keepalive-idle=0",
+            "1:out:wg_out:secret:keepalive-idle=180",
+            "2:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].keepalive_idle, Duration::ZERO);
+        assert_eq!(configs[1].keepalive_idle, Duration::from_secs(180));
+        assert_eq!(configs[2].keepalive_idle, DEFAULT_IDLE_THRESHOLD);
+
+        for bad in ["181", "-1", "1.5", ""] {
+            let lines = vec![format!("0:out:wg_out:secret:keepalive-idle={}", bad)];
+            assert!(parse_config(&lines).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Tests the keepalive-drop option, its default and its range.
+    #[test]
+    fn test_parse_config_keepalive_drop() {
+        let lines = [
+            "0:out:wg_out:secret:keepalive-drop=2,5",
+            "1:out:wg_out:secret:keepalive-drop=3",
+            "2:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].keepalive_drop, KeepaliveDrop { min: 2, max: 5 });
+        assert_eq!(configs[1].keepalive_drop, KeepaliveDrop { min: 3, max: 3 });
+        assert_eq!(configs[2].keepalive_drop, KeepaliveDrop { min: 1, max: 9 });
+
+        for bad in ["0,9", "5,4", "1,256", "-1", "", "1,", "a,b"] {
+            let lines = vec![format!("0:out:wg_out:secret:keepalive-drop={}", bad)];
+            assert!(parse_config(&lines).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Tests the hide-length option, its default window and its range.
+    #[test]
+    fn test_parse_config_hide_length() {
+        let lines = [
+            "0:out:wg_out:secret:1400:hide-length=1400",
+            "1:out:wg_out:secret:hide-length=1000,5",
+            "2:out:wg_out:secret:auto:hide-length=9000",
+            "3:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let hide =
+            |ceiling, secs| Some(LengthHiding { ceiling, window: Duration::from_secs(secs) });
+        assert_eq!(configs[0].hide_length, hide(1400, 30));
+        assert_eq!(configs[1].hide_length, hide(1000, 5));
+        assert_eq!(configs[2].hide_length, hide(9000, 30));
+        assert_eq!(configs[3].hide_length, None);
+
+        for bad in ["1401", "73", "1000,0", "1000,181", "", "1000,", "x"] {
+            let lines = vec![format!("0:out:wg_out:secret:1400:hide-length={}", bad)];
+            assert!(parse_config(&lines).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Tests the watchdog option, its default and its range.
+    #[test]
+    fn test_parse_config_watchdog() {
+        let lines = [
+            "0:out:wg_out:secret:watchdog=300",
+            "1:out:wg_out:secret:watchdog=OFF",
+            "2:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].watchdog, Some(Duration::from_secs(300)));
+        assert_eq!(configs[1].watchdog, None);
+        assert_eq!(configs[2].watchdog, None);
+
+        for bad in ["0", "86401", "-1", "1.5", "", "on"] {
+            let lines = vec![format!("0:out:wg_out:secret:watchdog={}", bad)];
+            assert!(parse_config(&lines).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Tests the ballast option and its default.
+    #[test]
+    fn test_parse_config_ballast() {
+        let lines = [
+            "0:out:wg_out:secret:ballast=zero",
+            "1:out:wg_out:secret:ballast=Text",
+            "2:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].ballast, BallastProfile::Zero);
+        assert_eq!(configs[1].ballast, BallastProfile::Text);
+        assert_eq!(configs[2].ballast, BallastProfile::Random);
+
+        let bad = vec!["0:out:wg_out:secret:ballast=quic".to_string()];
+        assert!(parse_config(&bad).is_err());
+    }
+
+    /// Tests the tap option: packet counts, durations and their limits.
+    #[test]
+    fn test_parse_config_tap() {
+        let lines = [
+            "0:out:wg_out:secret:tap=500",
+            "1:out:wg_out:secret:tap=100000,3600",
+            "2:out:wg_out:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let tap = |packets, secs| Some(Tap { packets, duration: Duration::from_secs(secs) });
+        assert_eq!(configs[0].tap, tap(500, 60));
+        assert_eq!(configs[1].tap, tap(TAP_PACKETS_MAX, TAP_DURATION_MAX.as_secs()));
+        assert_eq!(configs[2].tap, None);
+
+        for bad in ["tap=0", "tap=100001", "tap=10,0", "tap=10,3601", "tap=on", "tap=10,"] {
+            let line = format!("0:out:wg_out:secret:{}", bad);
+            let err = parse_config(&[line]).err().expect(bad);
+            assert!(err.to_string().contains("Invalid tap"), "{}: {}", bad, err);
+        }
+    }
+
+    /// Tests the port option: single ports, ranges, and invalid entries.
+    #[test]
+    fn test_parse_config_ports() {
+        let lines = [
+            "0:out:wg_out:secret:port=51820,51821-51830, 443".to_string(),
+            "1:out:wg_out:secret".to_string(),
+        ];
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let range = |first, last| PortRange { first, last };
+        assert_eq!(
+            configs[0].ports,
+            vec![range(51820, 51820), range(51821, 51830), range(443, 443)]
+        );
+        assert!(configs[0].ports[1].contains(51821) && configs[0].ports[1].contains(51830));
+        assert!(!configs[0].ports[1].contains(51831));
+        assert!(configs[1].ports.is_empty());
+
+        for bad in ["", "0", "65536", "51830-51821", "51820-", "-51820", "1-2-3", "wg", "80,"] {
+            let lines = vec![format!("0:out:wg_out:secret:port={}", bad)];
+            assert!(parse_config(&lines).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Tests that a key command stands for the key field it prints, and runs once for both
+    /// rules of a tunnel.
+    #[test]
+    fn test_keycmd() {
+        let dir = std::env::temp_dir().join(format!("nf_wgobfs-keycmd-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let runs = dir.join("runs");
+        let lines = [
+            format!("tunnel:eth0:keycmd=echo run >> {}; echo ' secretkey ':0", runs.display()),
+            "5:in:eth0:keycmd=printf 'new\\:key,secretkey'".to_string(),
+        ];
+        let configs = parse_config(&lines).unwrap();
+        assert_eq!(*configs[0].key, *ascii_to_key("secretkey"));
+        assert_eq!(*configs[1].key, *configs[0].key);
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+        assert_eq!(*configs[2].key, *ascii_to_key("new:key"));
+        assert_eq!(configs[2].prev_key.as_deref(), Some(&*ascii_to_key("secretkey")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that failing, silent and slow key commands are refused naming the queue.
+    #[test]
+    fn test_keycmd_errors() {
+        for (command, reason) in [
+            ("exit 3", "failed"),
+            ("true", "printed no key"),
+            ("printf '\\377'", "not valid UTF-8"),
+        ] {
+            let err = parse_config(&[format!("7:out:eth0:keycmd={}", command)]).err().unwrap();
+            let msg = err.to_string();
+            assert!(msg.starts_with("Key command for queue 7 "), "{msg}");
+            assert!(msg.contains(reason), "{msg}");
+        }
+        // A background child holding the output times out like a slow command
+        for command in ["sleep 5", "sleep 5 & echo key", "sleep 5 & exit 3"] {
+            let started = Instant::now();
+            let err = run_keycmd(8, command, Duration::from_millis(100)).unwrap_err();
+            assert!(err.to_string().contains("queue 8 timed out"), "{command}: {err}");
+            assert!(started.elapsed() < Duration::from_secs(3), "{command}");
+        }
+    }
+
+    /// Tests the histogram option, its default and its limits.
+    #[test]
+    fn test_parse_config_histogram() {
+        let lines = ["0:out:eth0:secret:histogram=1500,100,100,9000", "1:out:eth0:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].size_edges[..], [100, 1500, 9000]);
+        assert_eq!(configs[1].size_edges[..], DEFAULT_SIZE_EDGES);
+
+        let many = (1..=33).map(|i| (i * 10).to_string()).collect::<Vec<_>>().join(",");
+        for bad in ["", "0", "64,x", "70000", many.as_str()] {
+            let lines = vec![format!("0:out:eth0:secret:histogram={}", bad)];
+            assert!(parse_config(&lines).is_err(), "{:?}", bad);
+        }
+    }
+
+    /// Tests the enabled option and its default.
+    #[test]
+    fn test_parse_config_enabled_option() {
+        let lines = ["0:out:eth0:secret:enabled=off", "1:out:eth0:secret:enabled=on"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert!(!configs[0].enabled);
+        assert!(configs[1].enabled);
+        assert!(parse_config(&["0:out:eth0:secret:enabled=later".to_string()]).is_err());
+    }
+
+    /// Tests the keep-dscp option and its default.
+    #[test]
+    fn test_parse_config_keep_dscp_option() {
+        let lines = ["0:out:eth0:secret:keep-dscp=on", "1:out:eth0:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert!(configs[0].keep_dscp);
+        assert!(!configs[1].keep_dscp);
+        assert!(parse_config(&["0:out:eth0:secret:keep-dscp=ef".to_string()]).is_err());
+    }
+
+    /// Tests the flowlabel option and its default.
+    #[test]
+    fn test_parse_config_flowlabel_option() {
+        let lines =
+            ["0:out:eth0:secret:flowlabel=zero", "1:out:eth0:s:flowlabel=keep", "2:out:e:s"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert!(configs[0].zero_flow_label);
+        assert!(!configs[1].zero_flow_label);
+        assert!(!configs[2].zero_flow_label);
+        assert!(parse_config(&["0:out:eth0:secret:flowlabel=random".to_string()]).is_err());
+    }
+
+    /// Tests the min-size option, its default and its check against chaff sizes.
+    #[test]
+    fn test_parse_config_min_size_option() {
+        let lines = ["0:out:eth0:secret:min-size=100", "1:out:eth0:s:1400", "2:out:e:s:min-size=0"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].min_size, 100);
+        assert_eq!(configs[0].mtu, 1500);
+        assert_eq!(configs[1].min_size, 0);
+        assert_eq!(configs[2].min_size, 0);
+        assert!(parse_config(&["0:out:eth0:secret:min-size=-1".to_string()]).is_err());
+        assert!(parse_config(&["0:out:eth0:secret:min-size=big".to_string()]).is_err());
+        let chaff = |line: &str| parse_config(&[line.to_string()]);
+        assert!(chaff("0:out:eth0:secret:min-size=64:chaff=100,64,128").is_ok());
+        assert!(chaff("0:out:eth0:secret:chaff=100,32,128:min-size=64").is_err());
+    }
+
+    /// Tests the ratelimit and bytelimit options, their burst default and their bounds.
+    #[test]
+    fn test_parse_config_rate_limit_options() {
+        let lines = [
+            "0:out:eth0:secret:ratelimit=1000,50:bytelimit=1000000",
+            "1:in:eth0:secret:1400:ratelimit=200",
+            "2:in:eth0:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].packet_limit, Some(RateLimit { rate: 1000, burst: 50 }));
+        assert_eq!(configs[0].byte_limit, Some(RateLimit { rate: 1000000, burst: 1000000 }));
+        assert_eq!(configs[1].packet_limit, Some(RateLimit { rate: 200, burst: 200 }));
+        assert_eq!(configs[1].byte_limit, None);
+        assert_eq!(configs[2].packet_limit, None);
+        let parse = |line: &str| parse_config(&[line.to_string()]);
+        for bad in ["ratelimit=0", "ratelimit=10,0", "ratelimit=fast", "ratelimit=", "bytelimit=-1"]
+        {
+            assert!(parse(&format!("0:out:eth0:secret:{}", bad)).is_err(), "{}", bad);
+        }
+        // The byte burst must hold a packet of the MTU
+        assert!(parse("0:out:eth0:secret:1400:bytelimit=100000,1400").is_ok());
+        assert!(parse("0:out:eth0:secret:bytelimit=100000,1400").is_err());
+    }
+
+    /// Tests the encrypt-bytes option, its default and its range.
+    #[test]
+    fn test_parse_config_encrypt_bytes_option() {
+        let lines = ["0:out:eth0:secret:encrypt-bytes=64", "1:in:eth0:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].encrypt_bytes, 64);
+        assert_eq!(configs[1].encrypt_bytes, ENCRYPT_BYTES_MIN);
+        for bad in ["15", "129", "0", "all", ""] {
+            let line = format!("0:out:eth0:secret:encrypt-bytes={}", bad);
+            assert!(parse_config(&[line]).is_err(), "{}", bad);
+        }
+        let line = format!("0:out:eth0:secret:encrypt-bytes={}", ENCRYPT_BYTES_MAX);
+        assert!(parse_config(&[line]).is_ok());
+    }
+
+    /// Tests that MTUs up to jumbo frames are accepted and absurd ones rejected, rather than
+    /// ignored or sized into huge buffers.
+    #[test]
+    fn test_parse_config_mtu_range() {
+        for (mtu, expected) in [("74", 74), ("1280", 1280), ("9000", MTU_MAX)] {
+            let line = format!("0:out:eth0:secret:{}", mtu);
+            assert_eq!(parse_config(&[line]).unwrap()[0].mtu, expected);
+        }
+        for bad in ["0", "67", "9001", "65535", "70000", "99999999999999999999999"] {
+            let line = format!("0:out:eth0:secret:{}:auth=on", bad);
+            let err = parse_config(&[line]).err().expect(bad);
+            assert!(err.to_string().contains("Invalid MTU"), "{}: {}", bad, err);
+        }
+    }
+
+    /// Tests that an MTU must hold the smallest obfuscated packet under the cipher and `auth`
+    /// setting of its rule, and that the error names the queue.
+    #[test]
+    fn test_parse_config_mtu_overhead() {
+        let rule = |line: &str| parse_config(&[line.to_string()]);
+        assert_eq!(rule("0:out:eth0:secret:74").unwrap()[0].mtu, 74);
+        assert_eq!(min_mtu(&rule("0:out:eth0:secret:1500").unwrap()[0]), 74);
+        let err = rule("3:out:eth0:secret:73").err().unwrap();
+        assert!(err.to_string().contains("MTU 73 of queue 3"), "{err}");
+        assert!(err.to_string().contains("at least 74"), "{err}");
+
+        let strict = "0:out:eth0:secret:{}:cipher=xchacha20:auth=on";
+        assert!(rule(&strict.replace("{}", "101")).is_err());
+        assert_eq!(rule(&strict.replace("{}", "102")).unwrap()[0].mtu, 102);
+        let err =
+            parse_config(&["1:in:eth0:k".to_string(), "7:in:eth0:k:68".to_string()]).err().unwrap();
+        assert!(err.to_string().contains("of queue 7"), "{err}");
+    }
+
+    /// Tests that `auto` defers the MTU to the interface: loopback's is clamped to
+    /// [`MTU_MAX`], a missing interface falls back to 1500, and numeric MTUs are left alone.
+    #[test]
+    fn test_parse_config_mtu_auto() {
+        let lines = [
+            "0:out:lo:secret:auto:auth=on".to_string(),
+            "2:out:nf-wgobfs-none:secret:AUTO".to_string(),
+            "4:out:lo:secret:1400".to_string(),
+        ];
+        let mut configs = parse_config(&lines).unwrap();
+        assert!(configs[0].mtu_auto && configs[1].mtu_auto && !configs[2].mtu_auto);
+        assert_eq!(configs[0].mtu, DEFAULT_MTU);
+
+        if interface_mtu("lo").is_ok_and(|mtu| mtu > MTU_MAX) {
+            assert!(resolve_auto_mtu(&mut configs[0]));
+            assert_eq!(configs[0].mtu, MTU_MAX);
+        }
+        assert!(interface_mtu("nf-wgobfs-none").is_err());
+        assert!(!resolve_auto_mtu(&mut configs[1]));
+        assert_eq!(configs[1].mtu, DEFAULT_MTU);
+        assert!(!resolve_auto_mtu(&mut configs[2]));
+        assert_eq!(configs[2].mtu, 1400);
+        assert!(interface_mtu("an-overly-long-interface-name").is_err());
+    }
+
+    /// Tests that a tunnel line yields the same rules as the two lines it stands for, in both
+    /// the explicit and the short queue form, and that bad queues are rejected.
+    #[test]
+    fn test_parse_config_tunnel() {
+        let lines = [
+            "tunnel:wg0:newkey,oldkey:out=10,in=11:1420:auth=on:allow=[2001:db8::]/32",
+            "tunnel:wg1:secret:in=20,out=30",
+            "Tunnel:wg2:secret:40",
+            "5:in:eth0:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let queues: Vec<_> = configs.iter().map(|c| (c.queue_num, c.direction)).collect();
+        assert_eq!(
+            queues,
+            [
+                (10, Direction::Out),
+                (11, Direction::In),
+                (30, Direction::Out),
+                (20, Direction::In),
+                (40, Direction::Out),
+                (41, Direction::In),
+                (5, Direction::In)
+            ]
+        );
+        for config in &configs[..2] {
+            assert_eq!(config.iface, "wg0");
+            assert_eq!(config.key, ascii_to_key("newkey"));
+            assert_eq!(config.prev_key, Some(ascii_to_key("oldkey")));
+            assert_eq!(config.mtu, 1420);
+            assert!(config.auth);
+            assert_eq!(config.allow, vec![Cidr::parse("2001:db8::/32").unwrap()]);
+        }
+        assert_eq!(configs[4].iface, "wg2");
+        assert_eq!(configs[4].mtu, 1500);
+
+        for bad in [
+            "tunnel:wg0:secret",
+            "tunnel:wg0:secret:out=10",
+            "tunnel:wg0:secret:out=10,out=11",
+            "tunnel:wg0:secret:out=10,up=11",
+            "tunnel:wg0:secret:out=10,in=10",
+            "tunnel:wg0:secret:65535",
+            "tunnel:wg0:secret:ten",
+            "tunnel:wg0:secret:10:workers=2",
+        ] {
+            assert!(parse_config(&[bad.to_string()]).is_err(), "{}", bad);
+        }
+        // The queues of a tunnel may not clash with other rules
+        let lines = ["tunnel:wg0:secret:10".to_string(), "11:in:eth0:secret".to_string()];
+        assert!(parse_config(&lines).is_err());
+    }
+
+    /// Tests the allow and deny source lists.
+    #[test]
+    fn test_parse_config_source_lists() {
+        let lines = [
+            "0:out:eth0:secret:allow=10.0.0.0/8, [2001:db8::]/32:deny=10.9.0.0/16",
+            "1:in:eth0:secret:1400:deny=192.0.2.1",
+            "2:in:eth0:secret",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let cidr = |s: &str| Cidr::parse(s).unwrap();
+        assert_eq!(configs[0].allow, vec![cidr("10.0.0.0/8"), cidr("2001:db8::/32")]);
+        assert_eq!(configs[0].deny, vec![cidr("10.9.0.0/16")]);
+        assert!(configs[1].allow.is_empty());
+        assert_eq!(configs[1].deny, vec![cidr("192.0.2.1/32")]);
+        assert!(configs[2].allow.is_empty() && configs[2].deny.is_empty());
+        let bad = ["allow=10.0.0.0/33", "allow=", "deny=10.0.0.0/8,", "deny=2001:db8::/32"];
+        for bad in bad {
+            let line = format!("0:out:eth0:secret:{}", bad);
+            assert!(parse_config(&[line]).is_err(), "{}", bad);
+        }
+    }
+
+    /// Tests the auth option and its default.
+    #[test]
+    fn test_parse_config_auth_option() {
+        let lines = ["0:out:wg_out:secret:auth=on", "1:in:wg_in:secret:1400"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert!(configs[0].auth);
+        assert!(!configs[1].auth);
+    }
+
+    /// Tests that bucket sizes are sorted and deduplicated, and that bad sizes are rejected.
+    #[test]
+    fn test_parse_config_buckets_option() {
+        let lines = ["0:out:wg_out:secret:1400:buckets=1280, 512,1024,512", "1:in:wg_in:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].buckets, vec![512, 1024, 1280]);
+        assert_eq!(configs[0].mtu, 1400);
+        assert!(configs[1].buckets.is_empty());
+
+        for bad in ["buckets=", "buckets=0", "buckets=512,big", "buckets=70000"] {
+            let lines = vec![format!("0:out:wg_out:secret:{bad}")];
+            assert!(parse_config(&lines).is_err(), "{bad}");
+        }
+    }
+
+    /// Tests parsing the jitter option and rejecting out-of-range values.
+    #[test]
+    fn test_parse_config_jitter_option() {
+        let lines = ["0:out:wg_out:secret:jitter=25,10", "1:in:wg_in:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let jitter = Jitter { percent: 25, max_delay: Duration::from_millis(10) };
+        assert_eq!(configs[0].jitter, Some(jitter));
+        assert_eq!(configs[1].jitter, None);
+
+        for bad in ["jitter=25", "jitter=101,10", "jitter=25,0", "jitter=25,5000", "jitter=a,b"] {
+            let lines = vec![format!("0:out:wg_out:secret:{bad}")];
+            assert!(parse_config(&lines).is_err(), "{bad}");
+        }
+    }
+
+    /// Tests parsing the chaff option and rejecting unusable rates and sizes.
+    #[test]
+    fn test_parse_config_chaff_option() {
+        let lines = ["0:out:wg_out:secret:chaff=500,32,256", "1:in:wg_in:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        let chaff = Chaff { interval: Duration::from_millis(500), min_len: 32, max_len: 256 };
+        assert_eq!(configs[0].chaff, Some(chaff));
+        assert_eq!(configs[1].chaff, None);
+
+        for bad in ["chaff=500,32", "chaff=5,32,64", "chaff=500,16,64", "chaff=500,64,32"] {
+            let lines = vec![format!("0:out:wg_out:secret:{bad}")];
+            assert!(parse_config(&lines).is_err(), "{bad}");
+        }
+    }
+
+    /// Tests parsing the cipher option and its ChaCha20 default.
+    #[test]
+    fn test_parse_config_cipher_option() {
+        let lines = [
+            "0:out:wg_out:secret:cipher=chacha8",
+            "1:in:wg_in:secret:F:cipher=ChaCha12",
+            "2:in:wg_in:secret:1400:cipher=xchacha20",
+        ];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].cipher, CipherMode::ChaCha8);
+        assert_eq!(configs[1].cipher, CipherMode::ChaCha12);
+        assert_eq!(configs[2].cipher, CipherMode::XChaCha20);
+
+        let lines = vec!["0:out:wg_out:secret".to_string()];
+        assert_eq!(parse_config(&lines).unwrap()[0].cipher, CipherMode::ChaCha20);
+        let lines = vec!["0:out:wg_out:secret:cipher=aes".to_string()];
+        assert!(parse_config(&lines).is_err());
+    }
+
+    /// Tests that `salt=` switches both keys to the salted derivation.
+    #[test]
+    fn test_parse_config_salt_option() {
+        let lines = ["0:in:wg_in:newkey,oldkey:1400:salt=site-a", "1:out:wg_out:newkey"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].key, derive_key("newkey", "site-a"));
+        assert_eq!(configs[0].prev_key, Some(derive_key("oldkey", "site-a")));
+        assert_eq!(configs[1].key, ascii_to_key("newkey"));
+
+        let lines = vec!["0:in:wg_in:newkey:salt=".to_string()];
+        assert!(parse_config(&lines).is_err());
+    }
+
+    /// Tests the workers option and that worker queues may not overlap other rules.
+    #[test]
+    fn test_parse_config_workers_option() {
+        let lines = ["0:out:wg_out:secret:workers=4", "4:in:wg_in:secret"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].queues(), 0..4);
+        assert_eq!(configs[1].queues(), 4..5);
+
+        let lines = ["0:out:wg_out:secret:workers=auto".to_string()];
+        assert!(parse_config(&lines).unwrap()[0].workers >= 1);
+        for bad in [
+            ["0:out:wg_out:secret:workers=4", "3:in:wg_in:secret"],
+            ["3:in:wg_in:secret", "0:out:wg_out:secret:workers=4"],
+            ["0:out:wg_out:secret:workers=0", "9:in:wg_in:secret"],
+            ["65535:out:wg_out:secret:workers=2", "9:in:wg_in:secret"],
+        ] {
+            let lines: Vec<String> = bad.iter().map(|s| s.to_string()).collect();
+            assert!(parse_config(&lines).is_err(), "{bad:?}");
+        }
+    }
+
+    /// Tests the `newkey,oldkey` form used for key rotation.
+    #[test]
+    fn test_parse_config_previous_key() {
+        let lines = ["0:in:wg_in:newkey,oldkey:1400", "1:out:wg_out:newkey"];
+        let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+        let configs = parse_config(&lines).expect("Failed to parse config lines");
+        assert_eq!(configs[0].key, ascii_to_key("newkey"));
+        assert_eq!(configs[0].prev_key, Some(ascii_to_key("oldkey")));
+        assert_eq!(configs[0].mtu, 1400);
+        assert_eq!(configs[1].key, ascii_to_key("newkey"));
+        assert_eq!(configs[1].prev_key, None);
+    }
+
+    /// Tests that the interface name is kept and that empty names are rejected.
+    #[test]
+    fn test_parse_config_iface() {
+        let lines = vec!["0:in: eth0 :secret".to_string()];
+        assert_eq!(parse_config(&lines).unwrap()[0].iface, "eth0");
+
+        for bad in ["0:in::secret", "0:in:   :secret", "0:in:eth 0:secret"] {
+            let err = parse_config(&[bad.to_string()]).err().expect(bad);
+            assert!(err.to_string().contains("interface name"), "{bad}: {err}");
+        }
+    }
+
+    /// Tests that empty, placeholder, short and repetitive passphrases are reported as weak.
+    #[test]
+    fn test_passphrase_weakness() {
+        for weak in ["", "ChangeMe", "secretkey", "short-pass", "abababababababababab"] {
+            assert!(passphrase_weakness(weak).is_some(), "{weak:?}");
+        }
+        for strong in ["0123456789abcdef0123456789abcdef", "q8Zr-4vLx+Tn0wYb3KpE"] {
+            assert_eq!(passphrase_weakness(strong), None, "{strong:?}");
+        }
+        assert!(passphrase_bits("aaaa") == 0.0);
+        assert!((passphrase_bits("abcd") - 8.0).abs() < 1e-9);
+    }
+
+    /// Tests that `keycheck=strict` rejects weak keys (current or previous) naming the queue,
+    /// while the default only warns and `off` skips the check.
+    #[test]
+    fn test_parse_config_keycheck_option() {
+        let strong = "0123456789abcdef0123456789abcdef";
+        let lines =
+            vec!["3:in:eth0:changeme".to_string(), "4:in:eth0:changeme:keycheck=off".into()];
+        assert_eq!(parse_config(&lines).unwrap().len(), 2);
+
+        let err = parse_config(&["3:in:eth0:changeme:keycheck=strict".to_string()]).err().unwrap();
+        assert!(err.to_string().contains("Weak key for queue 3"), "{err}");
+        let line = format!("5:in:eth0:{strong},x:keycheck=strict");
+        let err = parse_config(&[line]).err().unwrap();
+        assert!(err.to_string().contains("Weak previous key for queue 5"), "{err}");
+        assert!(parse_config(&[format!("6:in:eth0:{strong}:keycheck=strict")]).is_ok());
+        assert!(parse_config(&["7:in:eth0:k:keycheck=maybe".to_string()]).is_err());
+    }
+
+    /// Tests that comments after a rule are ignored while a `#` within or escaped in a key is
+    /// kept, and that lines holding only a comment are skipped.
+    #[test]
+    fn test_parse_config_inline_comments() {
+        assert_eq!(strip_comment("0:out:wg0:key:1420 # main tunnel"), "0:out:wg0:key:1420");
+        assert_eq!(strip_comment("0:out:wg0:pa#ss\t# x"), "0:out:wg0:pa#ss");
+        assert_eq!(strip_comment("0:out:wg0:my \\#1 key"), "0:out:wg0:my \\#1 key");
+        assert_eq!(strip_comment("0:out:wg0:k\\\\ # x"), "0:out:wg0:k\\\\");
+        assert_eq!(strip_comment("  # only a comment"), "");
+
+        let lines = [
+            "0:out:wg0:pa#ss # main tunnel",
+            "  # disabled: 1:in:wg0:key",
+            "tunnel:wg1:my \\#2 key:2\t#both ways",
+        ]
+        .map(String::from);
+        let configs = parse_config(&lines).unwrap();
+        assert_eq!(configs.len(), 3);
+        assert_eq!(configs[0].mtu, DEFAULT_MTU);
+        assert_eq!(configs[0].iface, "wg0");
+        assert_eq!(configs[0].key, ascii_to_key("pa#ss"));
+        assert_eq!(configs[1].key, ascii_to_key("my #2 key"));
+        assert_eq!(configs[2].queue_num, 3);
+        assert_eq!(configs[2].key, ascii_to_key("my #2 key"));
+    }
+
+    /// Tests that escaped colons stay within their field, in keys, previous keys, interface
+    /// names, options and tunnel lines, and that escaped backslashes end a field.
+    #[test]
+    fn test_parse_config_escaped_colons() {
+        let fields: Vec<_> = split_fields("0:out:eth0\\:1:a\\:b\\\\:1420").map(unescape).collect();
+        assert_eq!(fields, ["0", "out", "eth0:1", "a:b\\", "1420"]);
+        assert_eq!(unescape("a\\b\\\\c\\#d"), "a\\b\\c#d");
+
+        let lines = [
+            "0:out:eth0\\:1:dGVz\\:dA==\\:\\:x,old\\:key:1420:salt=site\\:a",
+            "tunnel:wg0:k\\:1\\:2:out=4,in=5:auto",
+        ]
+        .map(String::from);
+        let configs = parse_config(&lines).unwrap();
+        assert_eq!(configs[0].iface, "eth0:1");
+        assert_eq!(configs[0].key, derive_key("dGVz:dA==::x", "site:a"));
+        assert_eq!(configs[0].prev_key.as_ref(), Some(&derive_key("old:key", "site:a")));
+        assert_eq!(configs[0].mtu, 1420);
+        assert_eq!((configs[1].queue_num, configs[2].queue_num), (4, 5));
+        assert_eq!(configs[1].key, ascii_to_key("k:1:2"));
+        assert_eq!(configs[2].key, ascii_to_key("k:1:2"));
+        assert!(configs[2].mtu_auto);
+
+        // Unescaped, the colon still splits the key and shifts the other fields
+        let configs = parse_config(&["0:out:eth0:a:b".to_string()]).unwrap();
+        assert_eq!(configs[0].key, ascii_to_key("a"));
+    }
+
+    /// Tests that streamed configuration skips blank lines and comments and trims rules.
+    #[test]
+    fn test_config_lines() {
+        let input = "# rules\n\n  0:in:eth0:k  \n\t# off\n1:out:eth0:k";
+        let lines = config_lines(std::io::Cursor::new(input));
+        assert_eq!(lines, ["0:in:eth0:k", "1:out:eth0:k"]);
+        assert_eq!(parse_config(&lines).unwrap().len(), 2);
+    }
+
+    /// Tests that `--config` replaces the base, that the default file wins over the
+    /// environment, that fragments are appended in name order, and that `-` reads stdin only.
+    #[test]
+    fn test_config_sources() {
+        let dir = std::env::temp_dir().join(format!("nf_wgobfs-src-{}", std::process::id()));
+        let conf_dir = dir.join("conf.d");
+        fs::create_dir_all(&conf_dir).unwrap();
+        let default = dir.join("config");
+        let explicit = dir.join("explicit");
+        let from_env = dir.join("env");
+        fs::write(conf_dir.join("20-b.conf"), "").unwrap();
+        fs::write(conf_dir.join("10-a.conf"), "").unwrap();
+        fs::write(conf_dir.join("30-c.conf.disabled"), "").unwrap();
+
+        let file = |path: &Path| ConfigSource::File(path.to_path_buf());
+        let fragments = [file(&conf_dir.join("10-a.conf")), file(&conf_dir.join("20-b.conf"))];
+        let sources = |explicit: Option<&Path>, env: Option<&Path>| {
+            config_sources(explicit, &default, env, &conf_dir).unwrap()
+        };
+        assert_eq!(sources(None, None), fragments);
+        assert_eq!(sources(None, Some(&from_env))[0], file(&from_env));
+        assert_eq!(sources(Some(&explicit), Some(&from_env))[0], file(&explicit));
+        assert_eq!(sources(None, Some(&from_env))[1..], fragments);
+
+        fs::write(&default, "").unwrap();
+        assert_eq!(sources(None, Some(&from_env))[0], file(&default));
+        assert_eq!(sources(Some(&explicit), None)[0], file(&explicit));
+        assert_eq!(sources(Some(Path::new("-")), None), [ConfigSource::Stdin]);
+        assert_eq!(sources(None, Some(Path::new("-")))[0], file(&default));
+        fs::remove_file(&default).unwrap();
+        assert_eq!(sources(None, Some(Path::new("-"))), [ConfigSource::Stdin]);
+
+        assert!(config_sources(None, &default, None, &dir.join("none")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that standard input, once read, is refused instead of read again, as SIGHUP
+    /// would.
+    #[test]
+    fn test_stdin_read_once() {
+        STDIN_READ.store(true, Ordering::Relaxed);
+        assert!(config_from_stdin());
+        let err = read_layers(vec![ConfigSource::Stdin]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    /// Tests the NF_WGOBFS_QUEUE base and that shifted queues, workers included, stay within
+    /// the queue numbers.
+    #[test]
+    fn test_queue_base() {
+        assert_eq!(queue_base(None).unwrap(), 0);
+        assert_eq!(queue_base(Some(OsStr::new("100"))).unwrap(), 100);
+        assert_eq!(queue_base(Some(OsStr::new(" 7 "))).unwrap(), 7);
+        for bad in ["", "-1", "65536", "ten"] {
+            assert!(queue_base(Some(OsStr::new(bad))).is_err(), "{:?}", bad);
+        }
+
+        let lines = ["3:out:eth0:k:workers=4", "65000:in:eth0:k"].map(String::from);
+        let mut rules = parse_config(&lines).unwrap();
+        shift_queues(&mut rules[0], 100).unwrap();
+        assert_eq!(rules[0].queues(), 103..107);
+        shift_queues(&mut rules[0], 65429).unwrap();
+        assert_eq!(rules[0].queue_num, 65532);
+        assert!(shift_queues(&mut rules[0], 1).is_err());
+        assert_eq!(rules[0].queue_num, 65532);
+        assert!(shift_queues(&mut rules[1], 536).is_err());
+    }
+
+    /// Tests that each source is parsed on its own and that a queue, workers included,
+    /// configured by two sources is rejected naming both.
+    #[test]
+    fn test_read_layers() {
+        let dir = std::env::temp_dir().join(format!("nf_wgobfs-layers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("config");
+        let extra = dir.join("10-extra.conf");
+        fs::write(&main, "# main\n0:in:eth0:k:workers=2\n").unwrap();
+        fs::write(&extra, "\n3:out:ppp0:k\n").unwrap();
+        let file = |path: &Path| ConfigSource::File(path.to_path_buf());
+
+        let layers = read_layers(vec![file(&main), file(&extra)]).unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].source, file(&main));
+        assert_eq!(layers[1].rules[0].queue_num, 3);
+
+        fs::write(&extra, "1:out:ppp0:k\n").unwrap();
+        let err = read_layers(vec![file(&main), file(&extra)]).err().unwrap();
+        let msg = err.to_string();
+        assert!(msg.contains("Queue 1 of") && msg.contains("already configured"), "{msg}");
+        assert!(msg.contains(&*extra.to_string_lossy()), "{msg}");
+        assert!(msg.contains(&*main.to_string_lossy()), "{msg}");
+
+        fs::write(&extra, "3:sideways\n").unwrap();
+        let err = read_layers(vec![file(&main), file(&extra)]).err().unwrap();
+        assert!(err.to_string().starts_with(&*extra.to_string_lossy()), "{err}");
+        assert!(read_layers(vec![file(&dir.join("none"))]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
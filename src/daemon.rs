@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Running as a classic Unix daemon, for init systems without systemd.
+//!
+//! With `--daemonize`, [`daemonize`] forks twice (the intermediate child starts a new
+//! session, so the daemon has no controlling terminal and cannot acquire one), changes to
+//! `/`, points the standard streams at `/dev/null` or the `--log-file`, and writes the PID
+//! file that OpenRC's `start-stop-daemon` and similar tools watch. It must be called before
+//! any thread is spawned: only the calling thread survives a fork.
+
+use nix::libc;
+use nix::unistd::{self, ForkResult};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Default path of the PID file.
+pub const DEFAULT_PID_FILE: &str = "/run/nf_wgobfs.pid";
+
+/// The PID file of the running daemon; removed when dropped, i.e. on a clean shutdown.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Best effort: after --user, /run may no longer be writable
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads the PID recorded in `path`, if the file holds one.
+fn recorded_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Fails if `path` names a process that is still running.
+fn check_not_running(path: &Path) -> io::Result<()> {
+    match recorded_pid(path) {
+        Some(pid) if Path::new(&format!("/proc/{}", pid)).exists() => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Already running as PID {} (see {})", pid, path.display()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Forks; the parent exits at once without running destructors or flushing buffers twice.
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: called before any thread is spawned, so the child is a full copy of the process
+    match unsafe { unistd::fork() }? {
+        ForkResult::Parent { .. } => unsafe { libc::_exit(0) },
+        ForkResult::Child => Ok(()),
+    }
+}
+
+/// Detaches the process from its terminal and records its PID in `pid_file`.
+///
+/// Everything that can fail is checked before forking, so errors still reach the terminal:
+/// a PID file naming a running process, an unwritable PID file and an unopenable log file.
+///
+/// # Arguments
+/// * `pid_file` - Where to write the PID of the daemon.
+/// * `log_file` - File to append stdout and stderr (and so the log) to; discarded if `None`.
+///
+/// # Returns
+/// * `Ok(PidFile)` - In the daemon; keep it alive for as long as the daemon runs.
+/// * `Err(e)` - If a check or a system call failed.
+pub fn daemonize(pid_file: &Path, log_file: Option<&Path>) -> io::Result<PidFile> {
+    let with_path = |path: &Path| {
+        let path = path.display().to_string();
+        move |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e))
+    };
+    check_not_running(pid_file)?;
+    let mut pid_out = File::create(pid_file).map_err(with_path(pid_file))?;
+    let output = match log_file {
+        Some(path) => {
+            OpenOptions::new().create(true).append(true).open(path).map_err(with_path(path))?
+        }
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+
+    fork_and_exit_parent()?;
+    unistd::setsid()?;
+    fork_and_exit_parent()?;
+    unistd::chdir("/")?;
+    unistd::dup2_stdin(&null)?;
+    unistd::dup2_stdout(&output)?;
+    unistd::dup2_stderr(&output)?;
+
+    writeln!(pid_out, "{}", std::process::id())?;
+    Ok(PidFile { path: pid_file.to_path_buf() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a PID file naming a live process blocks a second daemon, and that stale
+    /// or garbled ones do not.
+    #[test]
+    fn test_check_not_running() {
+        let path = std::env::temp_dir().join(format!("nf_wgobfs-test-{}.pid", std::process::id()));
+        assert!(check_not_running(&path).is_ok(), "no file");
+        fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        let err = check_not_running(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        fs::write(&path, "4294967295\n").unwrap();
+        assert!(check_not_running(&path).is_ok(), "stale");
+        fs::write(&path, "nf_wgobfs\n").unwrap();
+        assert!(check_not_running(&path).is_ok(), "garbled");
+
+        // Dropping the guard removes the file
+        drop(PidFile { path: path.clone() });
+        assert!(!path.exists());
+    }
+}
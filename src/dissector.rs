@@ -0,0 +1,192 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Wireshark dissector for the obfuscated framing.
+//!
+//! [`lua_script`] renders a Lua dissector that labels the fields of the packets of a rule:
+//! the WireGuard header encrypted in place, the WireGuard payload and ballast, the ballast
+//! length, version byte and MAC2 of the encrypted block, the nonce and the integrity tag.
+//! The field sizes come from the constants of [`crate::filter::obfuscator`] and the settings
+//! of the rule, so the script always matches the framing of the build that generated it.
+//! Without the key the dissector cannot read the ballast length, so the payload and the
+//! ballast are shown as one range.
+
+use crate::config::FilterConfig;
+use crate::filter::obfuscator::{LEN_VERSION_LEN, MAC2_LEN, MIN_WG_LEN, TAG_LEN};
+
+/// Default UDP port the dissector registers on: the usual WireGuard listen port.
+pub const DEFAULT_PORT: u16 = 51820;
+
+/// Template of the dissector; `@NAME@` placeholders are filled in by [`lua_script`].
+const LUA: &str = r#"-- Wireshark dissector for nf_wgobfs @VERSION@, generated for queue @QUEUE@
+-- (@CIPHER@, auth @AUTH@, encrypt-bytes=@HEADER_LEN@).
+--
+-- The fields of an obfuscated packet are encrypted, so they are labelled by position only.
+-- The ballast length is one of them, so the WireGuard payload and the ballast are shown as
+-- one range; with a wide ballast length, its high byte is the last byte of that range.
+-- Plain WireGuard messages (e.g. below min-size=) go to the built-in WireGuard dissector.
+--
+-- Install: copy to ~/.local/lib/wireshark/plugins/ or run wireshark -X lua_script:<file>,
+-- then capture on the WireGuard port or choose nf_wgobfs under "Decode As...".
+
+-- UDP port the dissector registers on: the WireGuard listen port.
+local WG_PORT = @PORT@
+
+-- Framing of the rule, from the constants of this nf_wgobfs build.
+local HEADER_LEN = @HEADER_LEN@ -- bytes of the message encrypted in place
+local MIN_WG_LEN = @MIN_WG_LEN@ -- smallest obfuscated WireGuard message
+local MAC2_LEN = @MAC2_LEN@ -- WireGuard trailer moved into the encrypted block
+local LEN_VERSION_LEN = @LEN_VERSION_LEN@ -- ballast length and version bytes
+local NONCE_LEN = @NONCE_LEN@
+local TAG_LEN = @TAG_LEN@ -- integrity tag with auth=on, none without
+
+local TRAILER_LEN = LEN_VERSION_LEN + MAC2_LEN + NONCE_LEN + TAG_LEN
+
+local proto = Proto("nf_wgobfs", "nf_wgobfs obfuscated WireGuard")
+local fields = {
+    header = ProtoField.bytes("nf_wgobfs.header", "WireGuard header (encrypted)"),
+    body = ProtoField.bytes("nf_wgobfs.body", "WireGuard payload and ballast"),
+    ballast_len = ProtoField.bytes("nf_wgobfs.ballast_len", "Ballast length (encrypted)"),
+    version = ProtoField.bytes("nf_wgobfs.version", "Version and flags (encrypted)"),
+    mac2 = ProtoField.bytes("nf_wgobfs.mac2", "MAC2 (encrypted)"),
+    nonce = ProtoField.bytes("nf_wgobfs.nonce", "Nonce"),
+    tag = ProtoField.bytes("nf_wgobfs.tag", "Integrity tag"),
+}
+proto.fields = {
+    fields.header, fields.body, fields.ballast_len, fields.version, fields.mac2,
+    fields.nonce, fields.tag,
+}
+
+-- The built-in WireGuard dissector, if this Wireshark has one
+local ok, wireguard = pcall(Dissector.get, "wg")
+if not ok then
+    wireguard = nil
+end
+
+-- Plain WireGuard messages start with a type from 1 to 4 and three zero bytes
+local function is_plain_wireguard(tvb)
+    if tvb:len() < 4 then
+        return false
+    end
+    local message_type = tvb(0, 1):uint()
+    return message_type >= 1 and message_type <= 4 and tvb(1, 3):uint() == 0
+end
+
+function proto.dissector(tvb, pinfo, tree)
+    local len = tvb:len()
+    if is_plain_wireguard(tvb) then
+        if wireguard then
+            return wireguard:call(tvb, pinfo, tree)
+        end
+        return 0
+    end
+    if len < MIN_WG_LEN - MAC2_LEN + TRAILER_LEN then
+        return 0
+    end
+
+    local body_len = len - TRAILER_LEN
+    local header_len = math.min(HEADER_LEN, body_len)
+    pinfo.cols.protocol = "nf_wgobfs"
+    pinfo.cols.info = string.format("Obfuscated WireGuard, %d bytes of payload and ballast",
+        body_len - header_len)
+
+    local subtree = tree:add(proto, tvb(), "nf_wgobfs obfuscated WireGuard")
+    subtree:add(fields.header, tvb(0, header_len))
+    if body_len > header_len then
+        subtree:add(fields.body, tvb(header_len, body_len - header_len))
+    end
+    local offset = body_len
+    subtree:add(fields.ballast_len, tvb(offset, 1))
+    subtree:add(fields.version, tvb(offset + 1, 1))
+    offset = offset + LEN_VERSION_LEN
+    subtree:add(fields.mac2, tvb(offset, MAC2_LEN))
+    offset = offset + MAC2_LEN
+    subtree:add(fields.nonce, tvb(offset, NONCE_LEN))
+    offset = offset + NONCE_LEN
+    if TAG_LEN > 0 then
+        subtree:add(fields.tag, tvb(offset, TAG_LEN))
+    end
+    return len
+end
+
+DissectorTable.get("udp.port"):add(WG_PORT, proto)
+"#;
+
+/// Renders a Lua Wireshark dissector for the packets of `filter`, registered on UDP `port`.
+///
+/// The cipher mode (nonce length), `auth=` (tag) and `encrypt-bytes=` of the rule shape the
+/// framing, so the script is only valid for peers with the same settings.
+pub fn lua_script(filter: &FilterConfig, port: u16) -> String {
+    let tag_len = if filter.auth { TAG_LEN } else { 0 };
+    LUA.replace("@VERSION@", env!("CARGO_PKG_VERSION"))
+        .replace("@QUEUE@", &filter.queue_num.to_string())
+        .replace("@CIPHER@", filter.cipher.name())
+        .replace("@AUTH@", if filter.auth { "on" } else { "off" })
+        .replace("@PORT@", &port.to_string())
+        .replace("@HEADER_LEN@", &filter.encrypt_bytes.to_string())
+        .replace("@MIN_WG_LEN@", &MIN_WG_LEN.to_string())
+        .replace("@MAC2_LEN@", &MAC2_LEN.to_string())
+        .replace("@LEN_VERSION_LEN@", &LEN_VERSION_LEN.to_string())
+        .replace("@NONCE_LEN@", &filter.cipher.nonce_len().to_string())
+        .replace("@TAG_LEN@", &tag_len.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    /// Tests that the framing constants and the settings of the rule end up in the script.
+    #[test]
+    fn test_lua_script_framing() {
+        let lines = [
+            "0:out:eth0:secret".to_string(),
+            "3:out:eth0:secret:cipher=xchacha20:auth=on:encrypt-bytes=48".to_string(),
+        ];
+        let configs = parse_config(&lines).unwrap();
+
+        let script = lua_script(&configs[0], DEFAULT_PORT);
+        assert!(!script.contains('@'), "unfilled placeholder");
+        assert!(script.contains("queue 0\n-- (chacha20, auth off, encrypt-bytes=16)"));
+        for line in [
+            "local WG_PORT = 51820\n",
+            "local HEADER_LEN = 16 ",
+            "local MIN_WG_LEN = 32 ",
+            "local MAC2_LEN = 16 ",
+            "local LEN_VERSION_LEN = 2 ",
+            "local NONCE_LEN = 12\n",
+            "local TAG_LEN = 0 ",
+        ] {
+            assert!(script.contains(line), "{:?}", line);
+        }
+
+        let script = lua_script(&configs[1], 443);
+        assert!(script.contains("(xchacha20, auth on, encrypt-bytes=48)"));
+        for line in [
+            "local WG_PORT = 443\n",
+            "local HEADER_LEN = 48 ",
+            "local NONCE_LEN = 24\n",
+            "local TAG_LEN = 16 ",
+        ] {
+            assert!(script.contains(line), "{:?}", line);
+        }
+    }
+
+    /// Tests that the script is syntactically valid Lua.
+    #[test]
+    fn test_lua_script_parses() {
+        let configs = parse_config(&["0:out:eth0:secret:auth=on".to_string()]).unwrap();
+        let path = std::env::temp_dir().join("nf_wgobfs_dissector_test.lua");
+        std::fs::write(&path, lua_script(&configs[0], DEFAULT_PORT)).unwrap();
+        match std::process::Command::new("luac").arg("-p").arg(&path).status() {
+            Ok(status) => assert!(status.success()),
+            Err(_) => eprintln!("luac not available, skipping"),
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
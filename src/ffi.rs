@@ -0,0 +1,458 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! C interface to the packet transform (feature `ffi`).
+//!
+//! The functions work on a caller-owned buffer holding a full IPv4 or IPv6 packet, like an
+//! NFQUEUE payload, and transform it in place as a rule with only a key and an MTU would:
+//! ChaCha20, random ballast, no integrity tag, no keepalive thinning. They never allocate
+//! per call and never unwind into C; a panic is reported as [`NF_WGOBFS_EPANIC`].
+//!
+//! The declarations are in `include/nf_wgobfs.h`, generated with
+//! `cbindgen --config cbindgen.toml --output include/nf_wgobfs.h src/ffi.rs`.
+
+use crate::config::{ascii_to_key, derive_key, FilterConfig, Key};
+use crate::filter::keepalive::KeepaliveDropper;
+use crate::filter::obfuscator::{deobfuscate_wg_packet, obfuscate_wg_packet};
+use crate::randomiser::Reseeder;
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use std::cell::RefCell;
+use std::os::raw::c_int;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::slice;
+use std::time::Instant;
+use zeroize::Zeroizing;
+
+/// The call succeeded; `*out_len` holds the packet length.
+pub const NF_WGOBFS_OK: c_int = 0;
+/// The packet failed to deobfuscate under the key (wrong key or cipher, or chaff) and must
+/// be dropped.
+pub const NF_WGOBFS_DROP: c_int = 1;
+/// A required pointer is NULL, `len` exceeds `cap`, or a passphrase is not UTF-8.
+pub const NF_WGOBFS_EINVAL: c_int = -1;
+/// The buffer is too small for the obfuscated packet.
+pub const NF_WGOBFS_ENOSPC: c_int = -2;
+/// The transform panicked; the buffer contents are unspecified.
+pub const NF_WGOBFS_EPANIC: c_int = -3;
+
+thread_local! {
+    /// Generator for nonces and ballast, seeded from the operating system on first use and
+    /// reseeded as it goes, and the dropper the obfuscator requires but does not consult
+    /// with keepalive thinning off.
+    static STATE: RefCell<(SmallRng, Reseeder, KeepaliveDropper)> = RefCell::new((
+        SmallRng::from_os_rng(),
+        Reseeder::new(Instant::now()),
+        KeepaliveDropper::with_rng(0, 0, SmallRng::seed_from_u64(0)),
+    ));
+}
+
+/// Builds the rule the C interface transforms packets under.
+fn rule(key: Key, mtu: usize) -> FilterConfig {
+    let rule = FilterConfig::builder().key(key).keepalive(false).build();
+    // The defaults are valid; the MTU is set afterwards as the C interface takes any
+    FilterConfig { mtu, ..rule.expect("the default rule is valid") }
+}
+
+/// Copies the 32-byte key behind `key`.
+///
+/// # Safety
+/// `key` must point to 32 readable bytes.
+unsafe fn read_key(key: *const u8) -> Key {
+    let mut copy = Zeroizing::new([0u8; 32]);
+    copy.copy_from_slice(slice::from_raw_parts(key, 32));
+    copy
+}
+
+/// Runs `f`, reporting a panic as [`NF_WGOBFS_EPANIC`] instead of unwinding into C.
+fn guarded(f: impl FnOnce() -> c_int) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(NF_WGOBFS_EPANIC)
+}
+
+/// Obfuscates the packet in `buf` in place.
+///
+/// Packets that are not WireGuard over UDP or TCP, are too short to obfuscate or are longer
+/// than `mtu` are left unchanged, with `*out_len` set to `len`. An obfuscated packet is at
+/// most `max(mtu, len + 14)` bytes long, so a capacity of `mtu + 14` always suffices.
+///
+/// Returns [`NF_WGOBFS_OK`], [`NF_WGOBFS_EINVAL`], [`NF_WGOBFS_ENOSPC`] or
+/// [`NF_WGOBFS_EPANIC`]; `*out_len` is only written on success.
+///
+/// # Safety
+/// `buf` must point to `cap` initialised, writable bytes, `key` to the 32-byte key (see
+/// [`nf_wgobfs_derive_key`]) and `out_len` to a writable `size_t`. None of them may be
+/// accessed by another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn nf_wgobfs_obfuscate(
+    buf: *mut u8,
+    len: usize,
+    cap: usize,
+    key: *const u8,
+    mtu: usize,
+    out_len: *mut usize,
+) -> c_int {
+    if buf.is_null() || key.is_null() || out_len.is_null() || len > cap {
+        return NF_WGOBFS_EINVAL;
+    }
+    guarded(|| {
+        let buf = slice::from_raw_parts_mut(buf, cap);
+        let config = rule(read_key(key), mtu);
+        let result = STATE.with_borrow_mut(|(rng, reseeder, dropper)| {
+            let result = obfuscate_wg_packet(buf, len, &config, dropper, rng);
+            reseeder.tick(rng);
+            result
+        });
+        match result {
+            Some(new_len) => {
+                *out_len = new_len;
+                NF_WGOBFS_OK
+            }
+            // Without keepalive thinning, the only reason to give up is a full buffer
+            None => NF_WGOBFS_ENOSPC,
+        }
+    })
+}
+
+/// Deobfuscates the packet in `buf` in place, reversing [`nf_wgobfs_obfuscate`].
+///
+/// Packets that are not WireGuard over UDP or TCP or are too short to have been obfuscated
+/// are left unchanged, with `*out_len` set to `len`. The packet only ever shrinks.
+///
+/// Returns [`NF_WGOBFS_OK`], [`NF_WGOBFS_DROP`], [`NF_WGOBFS_EINVAL`] or
+/// [`NF_WGOBFS_EPANIC`]; `*out_len` is only written on success.
+///
+/// # Safety
+/// `buf` must point to `len` initialised, writable bytes, `key` to the 32-byte key and
+/// `out_len` to a writable `size_t`. None of them may be accessed by another thread during
+/// the call.
+#[no_mangle]
+pub unsafe extern "C" fn nf_wgobfs_deobfuscate(
+    buf: *mut u8,
+    len: usize,
+    key: *const u8,
+    out_len: *mut usize,
+) -> c_int {
+    if buf.is_null() || key.is_null() || out_len.is_null() {
+        return NF_WGOBFS_EINVAL;
+    }
+    guarded(|| {
+        let buf = slice::from_raw_parts_mut(buf, len);
+        // The MTU only bounds obfuscation
+        let config = rule(read_key(key), usize::MAX);
+        match deobfuscate_wg_packet(buf, &config) {
+            Some(new_len) => {
+                *out_len = new_len;
+                NF_WGOBFS_OK
+            }
+            None => NF_WGOBFS_DROP,
+        }
+    })
+}
+
+/// A packet of a batch for [`nf_wgobfs_obfuscate_batch`] and [`nf_wgobfs_deobfuscate_batch`].
+#[repr(C)]
+pub struct NfWgobfsPacket {
+    /// The packet, at the start of `cap` writable bytes.
+    pub buf: *mut u8,
+    /// Length of the packet; its new length once transformed with [`NF_WGOBFS_OK`].
+    pub len: usize,
+    /// Capacity of `buf`; only obfuscation uses it.
+    pub cap: usize,
+    /// Outcome for this packet, the status the single-packet call would have returned.
+    pub status: c_int,
+}
+
+/// Obfuscates `count` packets in place, each as [`nf_wgobfs_obfuscate`] would, setting up
+/// the rule once for all of them, e.g. for the packets of one batched receive.
+///
+/// Each packet gets its own status, and its new length on success: a packet with a NULL
+/// `buf` or a `len` over `cap` gets [`NF_WGOBFS_EINVAL`] without stopping the others.
+///
+/// Returns [`NF_WGOBFS_OK`] once every packet has its status, [`NF_WGOBFS_EINVAL`] if
+/// `packets` or `key` is NULL, or [`NF_WGOBFS_EPANIC`], after which the statuses are
+/// unspecified.
+///
+/// # Safety
+/// `packets` must point to `count` writable [`NfWgobfsPacket`]s, each `buf` to `cap`
+/// initialised, writable bytes not shared with another packet, and `key` to the 32-byte
+/// key. None of them may be accessed by another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn nf_wgobfs_obfuscate_batch(
+    packets: *mut NfWgobfsPacket,
+    count: usize,
+    key: *const u8,
+    mtu: usize,
+) -> c_int {
+    if packets.is_null() || key.is_null() {
+        return NF_WGOBFS_EINVAL;
+    }
+    guarded(|| {
+        let packets = slice::from_raw_parts_mut(packets, count);
+        let config = rule(read_key(key), mtu);
+        STATE.with_borrow_mut(|(rng, reseeder, dropper)| {
+            for packet in packets {
+                if packet.buf.is_null() || packet.len > packet.cap {
+                    packet.status = NF_WGOBFS_EINVAL;
+                    continue;
+                }
+                let buf = slice::from_raw_parts_mut(packet.buf, packet.cap);
+                packet.status = match obfuscate_wg_packet(buf, packet.len, &config, dropper, rng) {
+                    Some(new_len) => {
+                        packet.len = new_len;
+                        NF_WGOBFS_OK
+                    }
+                    None => NF_WGOBFS_ENOSPC,
+                };
+                reseeder.tick(rng);
+            }
+        });
+        NF_WGOBFS_OK
+    })
+}
+
+/// Deobfuscates `count` packets in place, each as [`nf_wgobfs_deobfuscate`] would, like
+/// [`nf_wgobfs_obfuscate_batch`]. `cap` is not used; a packet with a NULL `buf` gets
+/// [`NF_WGOBFS_EINVAL`].
+///
+/// Returns [`NF_WGOBFS_OK`] once every packet has its status, [`NF_WGOBFS_EINVAL`] if
+/// `packets` or `key` is NULL, or [`NF_WGOBFS_EPANIC`].
+///
+/// # Safety
+/// `packets` must point to `count` writable [`NfWgobfsPacket`]s, each `buf` to `len`
+/// initialised, writable bytes not shared with another packet, and `key` to the 32-byte
+/// key. None of them may be accessed by another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn nf_wgobfs_deobfuscate_batch(
+    packets: *mut NfWgobfsPacket,
+    count: usize,
+    key: *const u8,
+) -> c_int {
+    if packets.is_null() || key.is_null() {
+        return NF_WGOBFS_EINVAL;
+    }
+    guarded(|| {
+        let packets = slice::from_raw_parts_mut(packets, count);
+        let config = rule(read_key(key), usize::MAX);
+        for packet in packets {
+            if packet.buf.is_null() {
+                packet.status = NF_WGOBFS_EINVAL;
+                continue;
+            }
+            let buf = slice::from_raw_parts_mut(packet.buf, packet.len);
+            packet.status = match deobfuscate_wg_packet(buf, &config) {
+                Some(new_len) => {
+                    packet.len = new_len;
+                    NF_WGOBFS_OK
+                }
+                None => NF_WGOBFS_DROP,
+            };
+        }
+        NF_WGOBFS_OK
+    })
+}
+
+/// Derives the 32-byte key from a passphrase as the configuration does for `SECRET_KEY`,
+/// under the `salt=` option if `salt` is not NULL, and writes it to `key`.
+///
+/// Returns [`NF_WGOBFS_OK`], [`NF_WGOBFS_EINVAL`] or [`NF_WGOBFS_EPANIC`].
+///
+/// # Safety
+/// `passphrase` must point to `passphrase_len` readable bytes, `salt` (unless NULL) to
+/// `salt_len` readable bytes, and `key` to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nf_wgobfs_derive_key(
+    passphrase: *const u8,
+    passphrase_len: usize,
+    salt: *const u8,
+    salt_len: usize,
+    key: *mut u8,
+) -> c_int {
+    if passphrase.is_null() || key.is_null() {
+        return NF_WGOBFS_EINVAL;
+    }
+    guarded(|| {
+        let Ok(passphrase) = std::str::from_utf8(slice::from_raw_parts(passphrase, passphrase_len))
+        else {
+            return NF_WGOBFS_EINVAL;
+        };
+        let derived = match salt.is_null() {
+            true => ascii_to_key(passphrase),
+            false => match std::str::from_utf8(slice::from_raw_parts(salt, salt_len)) {
+                Ok(salt) => derive_key(passphrase, salt),
+                Err(_) => return NF_WGOBFS_EINVAL,
+            },
+        };
+        slice::from_raw_parts_mut(key, 32).copy_from_slice(&derived[..]);
+        NF_WGOBFS_OK
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::obfuscator::obfuscate_chaff_packet;
+    use crate::netutils::ipv4;
+    use std::ptr;
+
+    /// Builds an IPv4/UDP WireGuard transport packet of `wg_len` bytes.
+    fn packet(wg_len: usize) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, 0xca, 0x6c, 0xca, 0x6c, 0x00, 0x00, 0x00, 0x00,
+        ];
+        packet.extend((0..wg_len).map(|b| b as u8));
+        packet[28..32].copy_from_slice(&[4, 0, 0, 0]);
+        ipv4::fix_udp_headers(&mut packet);
+        packet
+    }
+
+    fn key(passphrase: &str) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        let status = unsafe {
+            nf_wgobfs_derive_key(passphrase.as_ptr(), passphrase.len(), ptr::null(), 0, &mut key[0])
+        };
+        assert_eq!(status, NF_WGOBFS_OK);
+        key
+    }
+
+    /// Tests that packets round-trip through the C interface and interoperate with a rule
+    /// that has the same passphrase.
+    #[test]
+    fn test_ffi_round_trip() {
+        let original = packet(96);
+        let key = key("secretkey");
+        assert_eq!(key, *ascii_to_key("secretkey"));
+
+        let mut buf = original.clone();
+        buf.resize(1500 + 14, 0);
+        let mut len = 0;
+        let status = unsafe {
+            nf_wgobfs_obfuscate(
+                buf.as_mut_ptr(),
+                original.len(),
+                buf.len(),
+                &key[0],
+                1500,
+                &mut len,
+            )
+        };
+        assert_eq!(status, NF_WGOBFS_OK);
+        assert!(len > original.len());
+
+        let mut copy = buf[..len].to_vec();
+        let config = rule(ascii_to_key("secretkey"), 1500);
+        assert_eq!(deobfuscate_wg_packet(&mut copy, &config), Some(original.len()));
+
+        let mut out = 0;
+        let status = unsafe { nf_wgobfs_deobfuscate(buf.as_mut_ptr(), len, &key[0], &mut out) };
+        assert_eq!(status, NF_WGOBFS_OK);
+        assert_eq!(buf[..out], original[..]);
+    }
+
+    /// Tests the status codes for bad arguments, a full buffer and a dropped packet.
+    #[test]
+    fn test_ffi_status_codes() {
+        let original = packet(64);
+        let key = key("secretkey");
+        let mut buf = original.clone();
+        let mut len = 0;
+        let cap = buf.len();
+        unsafe {
+            let status = nf_wgobfs_obfuscate(ptr::null_mut(), 0, 0, &key[0], 1500, &mut len);
+            assert_eq!(status, NF_WGOBFS_EINVAL);
+            let status =
+                nf_wgobfs_obfuscate(buf.as_mut_ptr(), cap + 1, cap, &key[0], 1500, &mut len);
+            assert_eq!(status, NF_WGOBFS_EINVAL);
+            let status = nf_wgobfs_obfuscate(buf.as_mut_ptr(), cap, cap, &key[0], 1500, &mut len);
+            assert_eq!(status, NF_WGOBFS_ENOSPC);
+            let status =
+                nf_wgobfs_derive_key([0xff].as_ptr(), 1, ptr::null(), 0, &mut [0u8; 32][0]);
+            assert_eq!(status, NF_WGOBFS_EINVAL);
+        }
+
+        // Chaff validates under the key and is then dropped
+        buf.resize(cap + 100, 0);
+        let config = rule(ascii_to_key("secretkey"), 1500);
+        let len = obfuscate_chaff_packet(&mut buf, cap, &config, &mut SmallRng::seed_from_u64(1))
+            .expect("obfuscation failed");
+        let status = unsafe { nf_wgobfs_deobfuscate(buf.as_mut_ptr(), len, &key[0], &mut 0) };
+        assert_eq!(status, NF_WGOBFS_DROP);
+    }
+
+    /// Tests that a batch round-trips, with a status per packet: a full buffer, a NULL buffer
+    /// and a packet under another key fail on their own.
+    #[test]
+    fn test_ffi_batch() {
+        let originals: Vec<Vec<u8>> = (0..4).map(|i| packet(64 + 32 * i)).collect();
+        let mut bufs = originals.clone();
+        for buf in &mut bufs[..3] {
+            buf.resize(1500 + 14, 0);
+        }
+        let key = key("secretkey");
+        let mut packets: Vec<NfWgobfsPacket> = originals
+            .iter()
+            .zip(&mut bufs)
+            .map(|(original, buf)| NfWgobfsPacket {
+                buf: buf.as_mut_ptr(),
+                len: original.len(),
+                cap: buf.len(),
+                status: NF_WGOBFS_EPANIC,
+            })
+            .collect();
+        packets.push(NfWgobfsPacket { buf: ptr::null_mut(), len: 0, cap: 0, status: 0 });
+
+        let count = packets.len();
+        let status =
+            unsafe { nf_wgobfs_obfuscate_batch(packets.as_mut_ptr(), count, &key[0], 1500) };
+        assert_eq!(status, NF_WGOBFS_OK);
+        let statuses: Vec<c_int> = packets.iter().map(|p| p.status).collect();
+        assert_eq!(
+            statuses,
+            [NF_WGOBFS_OK, NF_WGOBFS_OK, NF_WGOBFS_OK, NF_WGOBFS_ENOSPC, NF_WGOBFS_EINVAL]
+        );
+        assert!(packets[..3].iter().zip(&originals).all(|(p, o)| p.len > o.len()));
+
+        let other = self::key("otherkey");
+        let status = unsafe { nf_wgobfs_deobfuscate_batch(&mut packets[2], 1, &other[0]) };
+        assert_eq!((status, packets[2].status), (NF_WGOBFS_OK, NF_WGOBFS_DROP));
+        let status = unsafe { nf_wgobfs_deobfuscate_batch(packets.as_mut_ptr(), 3, &key[0]) };
+        assert_eq!(status, NF_WGOBFS_OK);
+        for (i, original) in originals[..3].iter().enumerate() {
+            assert_eq!(packets[i].status, NF_WGOBFS_OK);
+            assert_eq!(bufs[i][..packets[i].len], original[..]);
+        }
+        unsafe {
+            assert_eq!(
+                nf_wgobfs_obfuscate_batch(ptr::null_mut(), 0, &key[0], 1500),
+                NF_WGOBFS_EINVAL
+            );
+            assert_eq!(
+                nf_wgobfs_deobfuscate_batch(packets.as_mut_ptr(), 0, ptr::null()),
+                NF_WGOBFS_EINVAL
+            );
+        }
+    }
+
+    /// Tests that the salted derivation matches the `salt=` option.
+    #[test]
+    fn test_ffi_derive_key_salt() {
+        let (passphrase, salt) = ("secretkey", "site-a");
+        let mut key = [0u8; 32];
+        let status = unsafe {
+            nf_wgobfs_derive_key(
+                passphrase.as_ptr(),
+                passphrase.len(),
+                salt.as_ptr(),
+                salt.len(),
+                &mut key[0],
+            )
+        };
+        assert_eq!(status, NF_WGOBFS_OK);
+        assert_eq!(key, *derive_key(passphrase, salt));
+    }
+}
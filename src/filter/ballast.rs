@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Ballast content.
+//!
+//! The ballast goes on the wire unencrypted, so its bytes are part of what an observer sees.
+//! Uniform random bytes look like the ciphertext around them and do not compress; a rule
+//! mimicking another protocol may rather want the byte distribution of that protocol's
+//! padding. A [`BallastGenerator`] fills the ballast of a packet: the profile of the rule
+//! ([`BallastProfile`], `ballast=`), or any closure passed to
+//! [`obfuscate_wg_packet_with`](crate::filter::obfuscator::obfuscate_wg_packet_with).
+//! The receiver discards the ballast unread, so the peers need not agree on it.
+
+use rand::{Rng, RngCore};
+
+/// Fills the ballast of obfuscated packets.
+///
+/// Implemented by [`BallastProfile`] and by every `Fn(&mut [u8], &mut dyn RngCore)` closure.
+pub trait BallastGenerator {
+    /// Fills `ballast` with the ballast of one packet, drawing randomness from `rng`.
+    fn fill(&self, ballast: &mut [u8], rng: &mut dyn RngCore);
+}
+
+impl<F: Fn(&mut [u8], &mut dyn RngCore)> BallastGenerator for F {
+    fn fill(&self, ballast: &mut [u8], rng: &mut dyn RngCore) {
+        self(ballast, rng)
+    }
+}
+
+/// Built-in ballast content (`ballast=random|zero|text`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BallastProfile {
+    /// Uniform random bytes, indistinguishable from the ciphertext around them.
+    #[default]
+    Random,
+    /// Zero bytes, like the PADDING frames of QUIC or the padding of TLS records.
+    Zero,
+    /// Printable ASCII, like the headers and bodies of plain-text protocols.
+    Text,
+}
+
+impl BallastProfile {
+    /// All profiles.
+    pub const ALL: [BallastProfile; 3] =
+        [BallastProfile::Random, BallastProfile::Zero, BallastProfile::Text];
+
+    /// Name used in the configuration file.
+    pub fn name(self) -> &'static str {
+        match self {
+            BallastProfile::Random => "random",
+            BallastProfile::Zero => "zero",
+            BallastProfile::Text => "text",
+        }
+    }
+
+    /// Parses a profile from its configuration name (case-insensitive).
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|profile| profile.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl BallastGenerator for BallastProfile {
+    fn fill(&self, ballast: &mut [u8], rng: &mut dyn RngCore) {
+        match self {
+            BallastProfile::Random => rng.fill_bytes(ballast),
+            BallastProfile::Zero => ballast.fill(0),
+            BallastProfile::Text => {
+                ballast.iter_mut().for_each(|b| *b = rng.random_range(b' '..=b'~'))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Tests that profile names round-trip and are matched case-insensitively.
+    #[test]
+    fn test_profile_names() {
+        for profile in BallastProfile::ALL {
+            assert_eq!(BallastProfile::parse(profile.name()), Some(profile));
+        }
+        assert_eq!(BallastProfile::parse("TEXT"), Some(BallastProfile::Text));
+        assert_eq!(BallastProfile::parse("quic"), None);
+        assert_eq!(BallastProfile::default(), BallastProfile::Random);
+    }
+
+    /// Tests the byte distribution of each profile, and that closures are generators too.
+    #[test]
+    fn test_profile_fill() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut ballast = [0xAAu8; 4096];
+
+        BallastProfile::Random.fill(&mut ballast, &mut rng);
+        let distinct = ballast.iter().collect::<std::collections::HashSet<_>>().len();
+        assert!(distinct > 250, "{} distinct bytes", distinct);
+
+        BallastProfile::Zero.fill(&mut ballast, &mut rng);
+        assert!(ballast.iter().all(|&b| b == 0));
+
+        BallastProfile::Text.fill(&mut ballast, &mut rng);
+        assert!(ballast.iter().all(|b| (b' '..=b'~').contains(b)));
+        assert!(ballast.contains(&b' ') && ballast.contains(&b'~'));
+
+        let fixed = |ballast: &mut [u8], _: &mut dyn RngCore| ballast.fill(0x5A);
+        fixed.fill(&mut ballast, &mut rng);
+        assert!(ballast.iter().all(|&b| b == 0x5A));
+    }
+}
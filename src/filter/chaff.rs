@@ -0,0 +1,296 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Chaff: dummy packets toward the peer of an outbound rule.
+//!
+//! NFQUEUE cannot create packets, so a sender thread writes plain WireGuard-looking
+//! packets to a raw socket, using the addresses and ports of the tunnel as last seen
+//! by the rule. They leave through the same firewall rule as the real traffic, and
+//! the queue recognizes them by a random per-rule token in place of the WireGuard
+//! header, then obfuscates them flagged as chaff. The receiver validates chaff like
+//! any obfuscated packet and discards it, so on the wire it is indistinguishable
+//! from real traffic.
+//!
+//! The raw sockets need `CAP_NET_RAW`: [`spawn`] must run before privileges are
+//! dropped.
+
+use crate::config::{Chaff, Direction, FilterConfig};
+use crate::filter::obfuscator::fixed_overhead;
+use crate::netutils::{ipv4, ipv6};
+use crate::randomiser::{self, fill_random};
+use log::{debug, info};
+use nix::sys::socket::{
+    sendto, socket, AddressFamily, MsgFlags, SockFlag, SockProtocol, SockType, SockaddrIn,
+    SockaddrIn6,
+};
+use rand::Rng;
+use std::io;
+use std::net::{IpAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Length of the token that marks a packet as chaff.
+const TOKEN_LEN: usize = 16;
+/// Smallest WireGuard transport packet: header and authentication tag.
+const MIN_WG_LEN: usize = 32;
+
+/// Addresses and ports of a tunnel, as seen on an outbound packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flow {
+    /// Local address.
+    pub src: IpAddr,
+    /// Peer address.
+    pub dst: IpAddr,
+    /// Local WireGuard port.
+    pub sport: u16,
+    /// Peer WireGuard port.
+    pub dport: u16,
+}
+
+impl Flow {
+    /// Reads the flow of an IPv4 or IPv6 UDP packet.
+    fn parse(packet: &[u8]) -> Option<Flow> {
+        let udp = udp_offset(packet)?;
+        let (src, dst) = match packet[0] >> 4 {
+            4 => (
+                IpAddr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?),
+                IpAddr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?),
+            ),
+            _ => (
+                IpAddr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?),
+                IpAddr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?),
+            ),
+        };
+        let port = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+        Some(Flow { src, dst, sport: port(udp), dport: port(udp + 2) })
+    }
+}
+
+/// Offset of the UDP header of an IPv4 or IPv6 packet.
+fn udp_offset(packet: &[u8]) -> Option<usize> {
+    match packet.first()? >> 4 {
+        4 => ipv4::header_len(packet),
+        6 => ipv6::udp_offset(packet),
+        _ => None,
+    }
+}
+
+/// Chaff state of one outbound rule, shared by its queue and its sender.
+pub struct ChaffState {
+    queue_num: u16,
+    token: [u8; TOKEN_LEN],
+    flow: Mutex<Option<Flow>>,
+}
+
+impl ChaffState {
+    /// Returns true if `packet` was built by the chaff sender of this rule.
+    #[inline(always)]
+    pub fn is_chaff(&self, packet: &[u8]) -> bool {
+        udp_offset(packet)
+            .and_then(|udp| packet.get(udp + 8..udp + 8 + TOKEN_LEN))
+            .is_some_and(|start| start == self.token)
+    }
+
+    /// Records the flow of a real outbound packet as the destination of chaff.
+    #[inline(always)]
+    pub fn learn(&self, packet: &[u8]) {
+        if let Some(flow) = Flow::parse(packet) {
+            *self.flow.lock().unwrap_or_else(|e| e.into_inner()) = Some(flow);
+        }
+    }
+
+    /// Returns the last flow seen, if any.
+    fn flow(&self) -> Option<Flow> {
+        *self.flow.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Chaff states of all outbound rules that send chaff.
+static REGISTRY: Mutex<Vec<Arc<ChaffState>>> = Mutex::new(Vec::new());
+
+/// Returns the chaff state of `queue_num`, registering a new one with a fresh token on
+/// first use.
+pub fn state(queue_num: u16) -> Arc<ChaffState> {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(state) = registry.iter().find(|s| s.queue_num == queue_num) {
+        return state.clone();
+    }
+    let state = Arc::new(ChaffState { queue_num, token: rand::random(), flow: Mutex::new(None) });
+    registry.push(state.clone());
+    state
+}
+
+/// Builds a plain chaff packet for `flow`: IP and UDP headers and a WireGuard-sized
+/// payload of `wg_len` bytes that starts with `token` and is random otherwise.
+fn build_packet(
+    flow: &Flow,
+    token: &[u8; TOKEN_LEN],
+    wg_len: usize,
+    rng: &mut impl Rng,
+) -> Vec<u8> {
+    let udp = match flow.dst {
+        IpAddr::V4(_) => 20,
+        IpAddr::V6(_) => ipv6::HEADER_LEN,
+    };
+    let mut packet = vec![0u8; udp + 8 + wg_len];
+    match (flow.src, flow.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet[0] = 0x45;
+            packet[6] = 0x40; // Don't Fragment
+            packet[8] = 64;
+            packet[9] = 17;
+            packet[12..16].copy_from_slice(&src.octets());
+            packet[16..20].copy_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            packet[0] = 0x60;
+            packet[6] = 17;
+            packet[7] = 64;
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V6(ip) => ip.octets(),
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            };
+            packet[8..24].copy_from_slice(&v6(src));
+            packet[24..40].copy_from_slice(&v6(dst));
+        }
+    }
+    packet[udp..udp + 2].copy_from_slice(&flow.sport.to_be_bytes());
+    packet[udp + 2..udp + 4].copy_from_slice(&flow.dport.to_be_bytes());
+    packet[udp + 8..udp + 8 + TOKEN_LEN].copy_from_slice(token);
+    fill_random(&mut packet[udp + 8 + TOKEN_LEN..], rng);
+    match flow.dst {
+        IpAddr::V4(_) => ipv4::fix_udp_headers(&mut packet),
+        IpAddr::V6(_) => ipv6::fix_udp_headers(&mut packet),
+    }
+    packet
+}
+
+/// Picks the WireGuard payload size of the next chaff packet: uniform between the
+/// configured bounds, capped so the obfuscated packet fits `max_len`, and rounded down
+/// to a transport packet size (32 bytes plus a multiple of 16).
+fn wg_len(chaff: &Chaff, max_len: usize, rng: &mut impl Rng) -> Option<usize> {
+    let max = chaff.max_len.min(max_len);
+    if max < MIN_WG_LEN {
+        return None;
+    }
+    let len = rng.random_range(chaff.min_len.min(max)..=max);
+    Some(MIN_WG_LEN + (len - MIN_WG_LEN) / 16 * 16)
+}
+
+/// Picks the pause before the next chaff packet: exponentially distributed around the
+/// configured interval (a Poisson process), at most ten times the interval.
+fn next_interval(chaff: &Chaff, rng: &mut impl Rng) -> Duration {
+    let u: f64 = rng.random_range(f64::EPSILON..1.0);
+    chaff.interval.mul_f64((-u.ln()).min(10.0))
+}
+
+/// Opens the raw sockets and starts the chaff sender of `filter`, if it sends chaff.
+///
+/// Requires `CAP_NET_RAW`; call it before dropping privileges. An IPv6 socket is only
+/// used if it can be opened.
+pub fn spawn(filter: &FilterConfig) -> io::Result<()> {
+    let Some(chaff) = filter.chaff.filter(|_| filter.direction == Direction::Out) else {
+        return Ok(());
+    };
+    let open = |family| socket(family, SockType::Raw, SockFlag::SOCK_CLOEXEC, SockProtocol::Raw);
+    let v4 = open(AddressFamily::Inet).map_err(|e| {
+        io::Error::new(
+            io::Error::from(e).kind(),
+            format!("Failed to open a raw socket for chaff on queue {}: {}", filter.queue_num, e),
+        )
+    })?;
+    let v6 = open(AddressFamily::Inet6).ok();
+
+    let state = state(filter.queue_num);
+    let ip_overhead = 40 + 8 + fixed_overhead(filter);
+    let max_len = filter.mtu.saturating_sub(ip_overhead);
+    info!(
+        "Sending chaff on NFQUEUE {} ({}) every {:?} on average",
+        filter.queue_num, filter.iface, chaff.interval
+    );
+    thread::spawn(move || run(&state, &chaff, max_len, &v4, v6.as_ref()));
+    Ok(())
+}
+
+/// Sender loop: sends a chaff packet toward the last flow seen after every pause.
+fn run(state: &ChaffState, chaff: &Chaff, max_len: usize, v4: &OwnedFd, v6: Option<&OwnedFd>) {
+    let mut rng = randomiser::create_secure_rng();
+    loop {
+        thread::sleep(next_interval(chaff, &mut rng));
+        let (Some(flow), Some(len)) = (state.flow(), wg_len(chaff, max_len, &mut rng)) else {
+            continue;
+        };
+        let packet = build_packet(&flow, &state.token, len, &mut rng);
+        let sent = match flow.dst {
+            IpAddr::V4(dst) => {
+                let addr = SockaddrIn::from(SocketAddrV4::new(dst, 0));
+                sendto(v4.as_raw_fd(), &packet, &addr, MsgFlags::empty())
+            }
+            IpAddr::V6(dst) => match v6 {
+                Some(v6) => {
+                    let addr = SockaddrIn6::from(SocketAddrV6::new(dst, 0, 0, 0));
+                    sendto(v6.as_raw_fd(), &packet, &addr, MsgFlags::empty())
+                }
+                None => continue,
+            },
+        };
+        if let Err(e) = sent {
+            debug!("NFQUEUE {}: failed to send chaff: {}", state.queue_num, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Tests that built chaff carries the flow and token, valid checksums and a
+    /// transport packet size, and is recognized by its own rule only.
+    #[test]
+    fn test_build_and_recognize() {
+        let mut rng = SmallRng::from_seed([1u8; 32]);
+        let state = state(60001);
+        let other = super::state(60002);
+        for (src, dst) in [
+            (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])),
+            ("2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap()),
+        ] {
+            let flow = Flow { src, dst, sport: 51820, dport: 51821 };
+            let chaff = Chaff { interval: Duration::from_millis(100), min_len: 40, max_len: 200 };
+            let len = wg_len(&chaff, 1400, &mut rng).unwrap();
+            assert!((32..=200).contains(&len) && (len - 32) % 16 == 0);
+
+            let packet = build_packet(&flow, &state.token, len, &mut rng);
+            assert_eq!(Flow::parse(&packet), Some(flow));
+            let mut fixed = packet.clone();
+            match dst {
+                IpAddr::V4(_) => ipv4::fix_udp_headers(&mut fixed),
+                IpAddr::V6(_) => ipv6::fix_udp_headers(&mut fixed),
+            }
+            assert_eq!(packet, fixed);
+            assert!(state.is_chaff(&packet));
+            assert!(!other.is_chaff(&packet));
+        }
+    }
+
+    /// Tests that the size cap wins over the configured bounds and that no chaff is
+    /// built when not even a minimal packet fits.
+    #[test]
+    fn test_wg_len_cap() {
+        let mut rng = SmallRng::from_seed([2u8; 32]);
+        let chaff = Chaff { interval: Duration::from_millis(100), min_len: 500, max_len: 900 };
+        for _ in 0..100 {
+            assert!(wg_len(&chaff, 300, &mut rng).unwrap() <= 300);
+        }
+        assert_eq!(wg_len(&chaff, 31, &mut rng), None);
+    }
+}
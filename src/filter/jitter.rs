@@ -0,0 +1,127 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Timing jitter for outbound packets.
+//!
+//! NFQUEUE verdicts may be issued in any order, so a packet is delayed by holding its
+//! message and issuing the verdict later; the receive loop keeps running meanwhile.
+//! [`JitterQueue`] is a FIFO of held messages with release deadlines. Deadlines never
+//! decrease along the queue, so packets leave in the order they arrived (no flow is ever
+//! reordered), and every packet leaves at most `max_delay` after it was queued.
+
+use crate::config::Jitter;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// FIFO of items held back until their release deadline.
+pub struct JitterQueue<T> {
+    jitter: Jitter,
+    pending: VecDeque<(Instant, T)>,
+}
+
+impl<T> JitterQueue<T> {
+    /// Creates an empty queue delaying packets as configured by `jitter`.
+    pub fn new(jitter: Jitter) -> Self {
+        Self { jitter, pending: VecDeque::new() }
+    }
+
+    /// Schedules `item`, arrived at `now`, for release.
+    ///
+    /// A share of items (`jitter.percent`) gets a random delay of up to `jitter.max_delay`.
+    /// Items also wait for every item queued before them.
+    ///
+    /// # Returns
+    /// * `Some(item)` - The item is not delayed and nothing is pending: release it now.
+    /// * `None` - The item was queued; take it from [`JitterQueue::pop_due`] later.
+    pub fn push(&mut self, item: T, now: Instant, rng: &mut impl Rng) -> Option<T> {
+        let delay = if rng.random_range(0..100u8) < self.jitter.percent {
+            let max = self.jitter.max_delay.as_micros() as u64;
+            Duration::from_micros(rng.random_range(0..=max))
+        } else {
+            Duration::ZERO
+        };
+        let deadline = match self.pending.back() {
+            Some((last, _)) => (*last).max(now + delay),
+            None if delay.is_zero() => return Some(item),
+            None => now + delay,
+        };
+        self.pending.push_back((deadline, item));
+        None
+    }
+
+    /// Takes the oldest item if its deadline has passed at `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        match self.pending.front() {
+            Some((deadline, _)) if *deadline <= now => {
+                self.pending.pop_front().map(|(_, item)| item)
+            }
+            _ => None,
+        }
+    }
+
+    /// Deadline of the oldest pending item, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.front().map(|(deadline, _)| *deadline)
+    }
+
+    /// Returns true if no item is pending.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Tests that items leave in arrival order and within the configured delay.
+    #[test]
+    fn test_order_and_bound() {
+        let max_delay = Duration::from_millis(10);
+        let mut queue = JitterQueue::new(Jitter { percent: 50, max_delay });
+        let mut rng = SmallRng::from_seed([1u8; 32]);
+        let start = Instant::now();
+
+        let mut released = Vec::new();
+        // One item every 100 µs, then keep ticking until the queue drains
+        for i in 0..1200u32 {
+            let now = start + Duration::from_micros(i as u64 * 100);
+            while let Some(item) = queue.pop_due(now) {
+                released.push((item, now));
+            }
+            if i < 1000 {
+                if let Some(item) = queue.push(i, now, &mut rng) {
+                    released.push((item, now));
+                }
+            }
+        }
+
+        assert!(queue.is_empty());
+        assert!(released.iter().map(|(item, _)| *item).eq(0..1000), "reordered");
+        for (item, at) in &released {
+            let arrived = start + Duration::from_micros(*item as u64 * 100);
+            assert!(*at - arrived <= max_delay + Duration::from_micros(100), "item {item}");
+        }
+    }
+
+    /// Tests that nothing is held back when no packet is selected for a delay.
+    #[test]
+    fn test_zero_percent_passes_through() {
+        let jitter = Jitter { percent: 0, max_delay: Duration::from_millis(10) };
+        let mut queue = JitterQueue::new(jitter);
+        let mut rng = SmallRng::from_seed([2u8; 32]);
+        let now = Instant::now();
+        for i in 0..100 {
+            assert_eq!(queue.push(i, now, &mut rng), Some(i));
+        }
+        assert_eq!(queue.next_deadline(), None);
+    }
+}
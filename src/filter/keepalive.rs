@@ -1,6 +1,10 @@
-use rand::{rng, Rng};
-use std::ops::Range;
-use std::time::{Duration, Instant};
+use core::net::{IpAddr, Ipv4Addr};
+use core::time::Duration;
+#[cfg(feature = "std")]
+use {
+    crate::randomiser, rand::rngs::SmallRng, rand::Rng, std::collections::HashMap, std::ops::Range,
+    std::time::Instant,
+};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum PacketDecision {
@@ -8,80 +12,359 @@ pub enum PacketDecision {
     Drop,
 }
 
-pub struct KeepaliveDropper {
+/// Source and destination addresses of a packet: the pair of tunnel endpoints whose
+/// keepalives are thinned together.
+pub type Peer = (IpAddr, IpAddr);
+
+/// Most peers tracked per dropper; the least recently seen one makes room for a new one.
+#[cfg(feature = "std")]
+const MAX_PEERS: usize = 4096;
+
+/// Peers silent for this long are forgotten (WireGuard's session lifetime, REJECT_AFTER_TIME).
+pub const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Time without data after which a peer counts as idle and its keepalives are thinned by
+/// default: WireGuard's KEEPALIVE_TIMEOUT, so the passive keepalive answering the last data
+/// still goes out.
+pub const DEFAULT_IDLE_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Reads the peer of an IPv4 or IPv6 packet from its header.
+///
+/// Packets of other versions, or too short to hold the addresses, map to a single
+/// unspecified peer.
+pub fn peer_of(packet: &[u8]) -> Peer {
+    let addrs = match packet.first().map(|b| b >> 4) {
+        Some(4) => packet.get(12..20).map(|a| {
+            let addr = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&a[at..at + 4]).unwrap());
+            (addr(0), addr(4))
+        }),
+        Some(6) => packet.get(8..40).map(|a| {
+            let addr = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&a[at..at + 16]).unwrap());
+            (addr(0), addr(16))
+        }),
+        _ => None,
+    };
+    addrs.unwrap_or((Ipv4Addr::UNSPECIFIED.into(), Ipv4Addr::UNSPECIFIED.into()))
+}
+
+/// Per-peer state the obfuscator keeps on the way out: keepalive thinning, the time of the
+/// latest data for the watchdog, and recent packet sizes for length hiding.
+///
+/// [`KeepaliveDropper`] keeps it on the clock and maps of `std`. Without `std`, firmware
+/// implements it over a clock of its own, or passes [`NoTracking`].
+pub trait PeerTracker {
+    /// Decides whether `packet`, a WireGuard message from `peer`, goes out.
+    fn filter_packet(&mut self, peer: Peer, packet: &[u8]) -> PacketDecision;
+
+    /// Notes `packet`, a WireGuard message that is not filtered.
+    fn record(&mut self, packet: &[u8]);
+
+    /// Notes an outbound packet of `size` bytes from `peer` and returns the largest size
+    /// `peer` sent within about the last `window`, `size` included.
+    fn recent_max_size(&mut self, peer: Peer, size: usize, window: Duration) -> usize;
+}
+
+/// A [`PeerTracker`] that keeps nothing: every keepalive goes out, and length hiding only
+/// sees the packet at hand.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTracking;
+
+impl PeerTracker for NoTracking {
+    fn filter_packet(&mut self, _: Peer, _: &[u8]) -> PacketDecision {
+        PacketDecision::Allow
+    }
+
+    fn record(&mut self, _: &[u8]) {}
+
+    fn recent_max_size(&mut self, _: Peer, size: usize, _: Duration) -> usize {
+        size
+    }
+}
+
+/// Largest packet sizes of a peer over the last two windows, for length hiding.
+///
+/// Sizes are kept per window rather than per packet: the maximum of the current and the
+/// previous window covers at least the last full window, in constant space.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct SizeWindow {
+    current: usize,
+    previous: usize,
+    start: Instant,
+}
+
+#[cfg(feature = "std")]
+impl SizeWindow {
+    fn new(now: Instant) -> Self {
+        Self { current: 0, previous: 0, start: now }
+    }
+
+    /// Notes a packet of `size` bytes at `now` and returns the largest size seen within
+    /// the last one to two `window`s.
+    fn observe(&mut self, size: usize, window: Duration, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= window {
+            self.previous = if elapsed < window * 2 { self.current } else { 0 };
+            self.current = 0;
+            self.start = now;
+        }
+        self.current = self.current.max(size);
+        self.current.max(self.previous)
+    }
+}
+
+/// Keepalive thinning state of one peer.
+#[cfg(feature = "std")]
+struct PeerState {
     drop_left: u8,
+    pending_until: Option<Instant>,
+    last_seen: Instant,
+    /// When the peer last sent a message other than a keepalive; `None` if it has not yet.
+    last_data: Option<Instant>,
+    /// Recent packet sizes, for length hiding.
+    sizes: SizeWindow,
+}
+
+/// Thins out WireGuard keepalives, independently for every peer.
+///
+/// A hub queue carries the keepalives of many peers; with a single state, one peer's data
+/// would reset the thinning of all the others and their keepalives would share one
+/// rhythm. The state of each [`Peer`] lives in a map bounded to [`MAX_PEERS`] entries:
+/// peers idle for [`PEER_IDLE_TIMEOUT`] expire and, when the map is full, the least
+/// recently seen one is evicted.
+///
+/// Only idle peers are thinned: keepalives pass until a peer has sent no data for the idle
+/// threshold ([`DEFAULT_IDLE_THRESHOLD`] unless set), and data ends a thinning burst at once.
+///
+/// A burst drops the first keepalive of an idle peer, then a random number from `min` to
+/// `max` of those that follow, and any others that come within 3 to 10 seconds of the
+/// first; the next one goes out and the following one starts a new burst.
+///
+/// The time of the latest data across all peers is kept as well, for the idle watchdog
+/// ([`Self::last_data`]), and so are the recent packet sizes of each peer, for length
+/// hiding ([`Self::recent_max_size`]).
+#[cfg(feature = "std")]
+pub struct KeepaliveDropper {
+    /// Fewest keepalives dropped after the first of a burst.
     min: u8,
+    /// Most keepalives dropped after the first of a burst.
     max: u8,
-    pending_until: Option<Instant>,
     delay_range: Range<u64>,
-    last_data_time: Instant,
+    idle_threshold: Duration,
+    peers: HashMap<Peer, PeerState>,
+    last_sweep: Instant,
+    last_data: Option<Instant>,
+    rng: SmallRng,
 }
 
+#[cfg(feature = "std")]
 impl KeepaliveDropper {
+    /// Creates a dropper whose bursts drop `min` to `max` keepalives after the first, as a
+    /// rule's `keepalive-drop=` sets them. A `min` of 0 counts as 1, and a `max` below it as
+    /// `min`.
     pub fn new(min: u8, max: u8) -> Self {
+        Self::with_rng(min, max, randomiser::create_secure_rng())
+    }
+
+    /// Creates a dropper that draws its delays and drop counts from `rng`.
+    pub fn with_rng(min: u8, max: u8, rng: SmallRng) -> Self {
         Self {
-            drop_left: 0,
             min: min.max(1),
             max: max.max(min.max(1)),
-            pending_until: None,
             delay_range: 3000..10000,
-            last_data_time: Instant::now(),
+            idle_threshold: DEFAULT_IDLE_THRESHOLD,
+            peers: HashMap::new(),
+            last_sweep: Instant::now(),
+            last_data: None,
+            rng,
         }
     }
 
-    pub fn filter_packet(&mut self, packet: &[u8]) -> PacketDecision {
-        let now = Instant::now();
+    /// Sets how long a peer must go without data before its keepalives are thinned.
+    pub fn set_idle_threshold(&mut self, threshold: Duration) {
+        self.idle_threshold = threshold;
+    }
+
+    /// Decides whether `packet`, a WireGuard message from `peer`, goes out.
+    pub fn filter_packet(&mut self, peer: Peer, packet: &[u8]) -> PacketDecision {
+        self.filter_packet_at(peer, packet, Instant::now())
+    }
+
+    fn filter_packet_at(&mut self, peer: Peer, packet: &[u8], now: Instant) -> PacketDecision {
+        let state = Self::peer(&mut self.peers, &mut self.last_sweep, peer, now);
 
         if !is_keepalive(packet) {
-            self.last_data_time = now;
-            self.pending_until = None;
-            self.reset();
+            state.pending_until = None;
+            state.drop_left = 0;
+            state.last_data = Some(now);
+            self.last_data = Some(now);
             return PacketDecision::Allow;
         }
 
-        if self.drop_left > 0 {
-            self.drop_left -= 1;
-            return PacketDecision::Drop;
+        // Keepalives of a tunnel carrying data go out as they are
+        if state.last_data.is_some_and(|at| now.saturating_duration_since(at) < self.idle_threshold)
+        {
+            return PacketDecision::Allow;
         }
 
-        if self.pending_until.is_none() {
-            let delay = rng().random_range(self.delay_range.clone());
-            self.pending_until = Some(now + Duration::from_millis(delay));
-            self.drop_left = rng().random_range(self.min..=self.max);
+        if state.drop_left > 0 {
+            state.drop_left -= 1;
             return PacketDecision::Drop;
         }
 
-        if let Some(when) = self.pending_until {
-            if now >= when {
-                self.pending_until = None;
-                return PacketDecision::Allow;
+        match state.pending_until {
+            None => {
+                let delay = self.rng.random_range(self.delay_range.clone());
+                state.pending_until = Some(now + Duration::from_millis(delay));
+                state.drop_left = self.rng.random_range(self.min..=self.max);
+                PacketDecision::Drop
+            }
+            Some(when) if now >= when => {
+                state.pending_until = None;
+                PacketDecision::Allow
+            }
+            Some(_) => PacketDecision::Drop,
+        }
+    }
+
+    /// Returns the state of `peer` in `peers`, marked as seen at `now`, creating it if
+    /// needed.
+    fn peer<'a>(
+        peers: &'a mut HashMap<Peer, PeerState>,
+        last_sweep: &mut Instant,
+        peer: Peer,
+        now: Instant,
+    ) -> &'a mut PeerState {
+        if !peers.contains_key(&peer) {
+            if now.saturating_duration_since(*last_sweep) >= PEER_IDLE_TIMEOUT
+                || peers.len() >= MAX_PEERS
+            {
+                peers.retain(|_, s| now.saturating_duration_since(s.last_seen) < PEER_IDLE_TIMEOUT);
+                *last_sweep = now;
+            }
+            if peers.len() >= MAX_PEERS {
+                let oldest = peers.iter().min_by_key(|(_, s)| s.last_seen).map(|(p, _)| *p);
+                if let Some(oldest) = oldest {
+                    peers.remove(&oldest);
+                }
             }
         }
+        let state = peers.entry(peer).or_insert(PeerState {
+            drop_left: 0,
+            pending_until: None,
+            last_seen: now,
+            last_data: None,
+            sizes: SizeWindow::new(now),
+        });
+        // An expired peer that was not swept yet starts over
+        if now.saturating_duration_since(state.last_seen) >= PEER_IDLE_TIMEOUT {
+            state.drop_left = 0;
+            state.pending_until = None;
+            state.last_data = None;
+            state.sizes = SizeWindow::new(now);
+        }
+        state.last_seen = now;
+        state
+    }
 
-        PacketDecision::Drop
+    /// Notes `packet`, a WireGuard message that is not filtered (inbound, or with thinning
+    /// off), for [`Self::last_data`] only.
+    pub fn record(&mut self, packet: &[u8]) {
+        self.record_at(packet, Instant::now());
     }
 
+    fn record_at(&mut self, packet: &[u8], now: Instant) {
+        if !is_keepalive(packet) {
+            self.last_data = Some(now);
+        }
+    }
+
+    /// Returns when a message other than a keepalive was last filtered or recorded, from
+    /// any peer; `None` if none was yet.
+    pub fn last_data(&self) -> Option<Instant> {
+        self.last_data
+    }
+
+    /// Notes an outbound packet of `size` bytes from `peer` and returns the largest size
+    /// `peer` sent within the last one to two `window`s, `size` included.
+    pub fn recent_max_size(&mut self, peer: Peer, size: usize, window: Duration) -> usize {
+        self.recent_max_size_at(peer, size, window, Instant::now())
+    }
+
+    fn recent_max_size_at(
+        &mut self,
+        peer: Peer,
+        size: usize,
+        window: Duration,
+        now: Instant,
+    ) -> usize {
+        let state = Self::peer(&mut self.peers, &mut self.last_sweep, peer, now);
+        state.sizes.observe(size, window, now)
+    }
+
+    /// Forgets the thinning state of every peer.
     pub fn reset(&mut self) {
-        self.drop_left = 0;
+        self.peers.clear();
     }
 }
 
+#[cfg(feature = "std")]
+impl PeerTracker for KeepaliveDropper {
+    fn filter_packet(&mut self, peer: Peer, packet: &[u8]) -> PacketDecision {
+        KeepaliveDropper::filter_packet(self, peer, packet)
+    }
+
+    fn record(&mut self, packet: &[u8]) {
+        KeepaliveDropper::record(self, packet)
+    }
+
+    fn recent_max_size(&mut self, peer: Peer, size: usize, window: Duration) -> usize {
+        KeepaliveDropper::recent_max_size(self, peer, size, window)
+    }
+}
+
+/// Length of a WireGuard keepalive: a type-4 data message with an empty inner payload
+/// (4-byte header, 4-byte receiver index, 8-byte counter and a 16-byte Poly1305 tag).
+const KEEPALIVE_LEN: usize = 32;
+
+/// Returns true if `packet` is a WireGuard keepalive: exactly 32 bytes, message type 4
+/// and the three reserved bytes set to zero.
 #[inline]
 pub fn is_keepalive(packet: &[u8]) -> bool {
-    !packet.is_empty() && packet.len() <= 32 && packet[0] == 0x04
+    packet.len() == KEEPALIVE_LEN && packet[0] == 0x04 && packet[1..4] == [0, 0, 0]
+}
+
+/// WireGuard handshake message types with their fixed lengths: initiation (MAC2 in its last
+/// 16 bytes), response (likewise) and cookie reply.
+const HANDSHAKE_MESSAGES: [(u8, usize); 3] = [(1, 148), (2, 92), (3, 64)];
+
+/// Returns true if `packet` is a WireGuard handshake message: an initiation (type 1, 148
+/// bytes), a response (type 2, 92 bytes) or a cookie reply (type 3, 64 bytes), with the
+/// three reserved bytes set to zero.
+#[inline]
+pub fn is_handshake(packet: &[u8]) -> bool {
+    packet.len() >= 4
+        && packet[1..4] == [0, 0, 0]
+        && HANDSHAKE_MESSAGES.contains(&(packet[0], packet.len()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "std")]
+    use rand::SeedableRng;
+
+    fn keepalive() -> [u8; 32] {
+        let mut pkt = [0xAA; 32];
+        pkt[..4].copy_from_slice(&[0x04, 0, 0, 0]);
+        pkt
+    }
 
     #[test]
     fn test_is_keepalive_true() {
-        let pkt = [0x04, 0, 0, 0];
-        assert!(is_keepalive(&pkt));
-        let pkt = [0x04];
-        assert!(is_keepalive(&pkt));
-        let pkt = [0x04; 32];
+        assert!(is_keepalive(&keepalive()));
+        let mut pkt = [0u8; 32];
+        pkt[0] = 0x04;
         assert!(is_keepalive(&pkt));
     }
 
@@ -89,6 +372,10 @@ mod tests {
     fn test_is_keepalive_false() {
         let pkt = [0x01, 0, 0, 0];
         assert!(!is_keepalive(&pkt));
+        let pkt = [0x04, 0, 0, 0];
+        assert!(!is_keepalive(&pkt));
+        let pkt = [0x04; 32];
+        assert!(!is_keepalive(&pkt));
         let pkt = [0x04; 33];
         assert!(!is_keepalive(&pkt));
         let pkt: [u8; 0] = [];
@@ -96,34 +383,279 @@ mod tests {
     }
 
     #[test]
+    fn test_is_keepalive_short_data_packet() {
+        let mut pkt = [0x5A; 16];
+        pkt[..4].copy_from_slice(&[0x04, 0, 0, 0]);
+        assert!(!is_keepalive(&pkt));
+        let mut pkt = keepalive().to_vec();
+        pkt.push(0);
+        assert!(!is_keepalive(&pkt));
+    }
+
+    #[test]
+    fn test_is_handshake() {
+        for (msg_type, len) in HANDSHAKE_MESSAGES {
+            let mut pkt = vec![0x77; len];
+            pkt[..4].copy_from_slice(&[msg_type, 0, 0, 0]);
+            assert!(is_handshake(&pkt));
+            assert!(!is_keepalive(&pkt));
+            pkt.push(0);
+            assert!(!is_handshake(&pkt));
+        }
+        let mut pkt = [0u8; 148];
+        pkt[..4].copy_from_slice(&[0x02, 0, 0, 0]);
+        assert!(!is_handshake(&pkt));
+        pkt[..4].copy_from_slice(&[0x01, 0, 1, 0]);
+        assert!(!is_handshake(&pkt));
+        assert!(!is_handshake(&keepalive()));
+        assert!(!is_handshake(&[]));
+    }
+
+    const PEER: Peer =
+        (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+    #[cfg(feature = "std")]
+    fn peer(n: u32) -> Peer {
+        (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::from(0x0a01_0000 + n)))
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
     fn test_dropper_allows_non_keepalive() {
         let mut dropper = KeepaliveDropper::new(1, 2);
         let pkt = [0x01, 0, 0, 0];
-        assert_eq!(dropper.filter_packet(&pkt), PacketDecision::Allow);
+        assert_eq!(dropper.filter_packet(PEER, &pkt), PacketDecision::Allow);
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn test_dropper_resets_on_non_keepalive() {
         let mut dropper = KeepaliveDropper::new(1, 2);
-        let keepalive = [0x04, 0, 0, 0];
+        let keepalive = keepalive();
 
-        dropper.drop_left = 2;
-        dropper.filter_packet(&keepalive);
+        dropper.filter_packet(PEER, &keepalive);
+        dropper.peers.get_mut(&PEER).unwrap().drop_left = 2;
+        dropper.filter_packet(PEER, &keepalive);
         let non_keepalive = [0x01, 0, 0, 0];
-        assert_eq!(dropper.filter_packet(&non_keepalive), PacketDecision::Allow);
-        assert_eq!(dropper.drop_left, 0);
+        assert_eq!(dropper.filter_packet(PEER, &non_keepalive), PacketDecision::Allow);
+        assert_eq!(dropper.peers[&PEER].drop_left, 0);
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn test_dropper_drop_and_allow() {
         let mut dropper = KeepaliveDropper::new(1, 1);
-        let keepalive = [0x04, 0, 0, 0];
+        let keepalive = keepalive();
 
-        let res1 = dropper.filter_packet(&keepalive);
+        let res1 = dropper.filter_packet(PEER, &keepalive);
         assert_eq!(res1, PacketDecision::Drop);
 
-        let res2 = dropper.filter_packet(&keepalive);
+        let res2 = dropper.filter_packet(PEER, &keepalive);
 
         assert!(matches!(res2, PacketDecision::Drop | PacketDecision::Allow));
     }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_reproducible_with_seeded_rng() {
+        let keepalive = keepalive();
+        let mut a = KeepaliveDropper::with_rng(1, 9, SmallRng::seed_from_u64(42));
+        let mut b = KeepaliveDropper::with_rng(1, 9, SmallRng::seed_from_u64(42));
+
+        assert_eq!(a.filter_packet(PEER, &keepalive), b.filter_packet(PEER, &keepalive));
+        assert_eq!(a.peers[&PEER].drop_left, b.peers[&PEER].drop_left);
+        assert_eq!(a.peers[&PEER].pending_until.is_some(), b.peers[&PEER].pending_until.is_some());
+    }
+
+    /// Tests that data from one peer does not reset the keepalive thinning of another.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_peers_are_independent() {
+        let mut dropper = KeepaliveDropper::with_rng(5, 5, SmallRng::seed_from_u64(1));
+        let now = Instant::now();
+        let (a, b) = (peer(1), peer(2));
+        assert_eq!(dropper.filter_packet_at(a, &keepalive(), now), PacketDecision::Drop);
+        assert_eq!(dropper.filter_packet_at(b, &[0x04; 100], now), PacketDecision::Allow);
+        assert_eq!(dropper.peers[&a].drop_left, 5);
+        assert!(dropper.peers[&a].pending_until.is_some());
+        assert_eq!(dropper.peers[&b].drop_left, 0);
+        assert!(dropper.peers[&b].pending_until.is_none());
+    }
+
+    /// Tests that idle peers expire and that the map stays bounded, evicting the least
+    /// recently seen peer.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_peer_expiry_and_bound() {
+        let mut dropper = KeepaliveDropper::with_rng(1, 1, SmallRng::seed_from_u64(1));
+        let start = Instant::now();
+        dropper.filter_packet_at(PEER, &keepalive(), start);
+        for n in 0..MAX_PEERS as u32 {
+            dropper.filter_packet_at(peer(n), &keepalive(), start + Duration::from_millis(1));
+        }
+        assert_eq!(dropper.peers.len(), MAX_PEERS);
+        assert!(!dropper.peers.contains_key(&PEER), "least recently seen peer evicted");
+
+        // Seen again after the idle timeout, a peer starts over
+        let later = start + PEER_IDLE_TIMEOUT + Duration::from_secs(1);
+        dropper.filter_packet_at(peer(7), &keepalive(), start + Duration::from_secs(1));
+        assert_eq!(dropper.filter_packet_at(peer(7), &keepalive(), later), PacketDecision::Drop);
+        assert!(dropper.peers[&peer(7)].pending_until.is_some_and(|t| t > later));
+
+        // A new peer after the idle timeout sweeps out the expired ones
+        dropper.filter_packet_at(PEER, &keepalive(), later);
+        assert_eq!(dropper.peers.len(), 2);
+    }
+
+    /// Tests that keepalives pass while data flows, are thinned once the peer has been idle
+    /// for the threshold, and pass again as soon as data arrives mid-burst.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_thins_only_when_idle() {
+        let mut dropper = KeepaliveDropper::with_rng(5, 5, SmallRng::seed_from_u64(1));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let data = [0x04; 100];
+
+        assert_eq!(dropper.filter_packet_at(PEER, &data, at(0)), PacketDecision::Allow);
+        for secs in [1, 5, 9] {
+            assert_eq!(
+                dropper.filter_packet_at(PEER, &keepalive(), at(secs)),
+                PacketDecision::Allow
+            );
+        }
+        assert!(dropper.peers[&PEER].pending_until.is_none());
+
+        // Idle for the threshold: a burst starts
+        let idle = DEFAULT_IDLE_THRESHOLD.as_secs();
+        assert_eq!(dropper.filter_packet_at(PEER, &keepalive(), at(idle)), PacketDecision::Drop);
+        assert_eq!(
+            dropper.filter_packet_at(PEER, &keepalive(), at(idle + 1)),
+            PacketDecision::Drop
+        );
+        assert_eq!(dropper.peers[&PEER].drop_left, 4);
+
+        // Data mid-burst: keepalives pass again right away
+        assert_eq!(dropper.filter_packet_at(PEER, &data, at(idle + 2)), PacketDecision::Allow);
+        for secs in idle + 2..idle * 2 + 2 {
+            assert_eq!(
+                dropper.filter_packet_at(PEER, &keepalive(), at(secs)),
+                PacketDecision::Allow
+            );
+        }
+        assert_eq!(dropper.peers[&PEER].drop_left, 0);
+        assert!(dropper.peers[&PEER].pending_until.is_none());
+        assert_eq!(
+            dropper.filter_packet_at(PEER, &keepalive(), at(idle * 2 + 2)),
+            PacketDecision::Drop
+        );
+    }
+
+    /// Tests the idle threshold setting; peers that never sent data count as idle.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_idle_threshold() {
+        let mut dropper = KeepaliveDropper::with_rng(1, 1, SmallRng::seed_from_u64(1));
+        dropper.set_idle_threshold(Duration::from_secs(60));
+        let start = Instant::now();
+        let (a, b) = (peer(1), peer(2));
+        assert_eq!(dropper.filter_packet_at(a, &keepalive(), start), PacketDecision::Drop);
+
+        dropper.filter_packet_at(b, &[0x04; 100], start);
+        let later = start + Duration::from_secs(59);
+        assert_eq!(dropper.filter_packet_at(b, &keepalive(), later), PacketDecision::Allow);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(dropper.filter_packet_at(b, &keepalive(), later), PacketDecision::Drop);
+
+        // Without a threshold every keepalive is subject to thinning
+        dropper.set_idle_threshold(Duration::ZERO);
+        dropper.filter_packet_at(a, &[0x04; 100], later);
+        assert_eq!(dropper.filter_packet_at(a, &keepalive(), later), PacketDecision::Drop);
+    }
+
+    /// Tests that with keepalives further apart than the burst delay, a burst drops the first
+    /// keepalive and then exactly `min..=max` more before letting one out.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_burst_length() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(25);
+        for (min, max) in [(1, 1), (3, 3), (2, 5)] {
+            let mut dropper = KeepaliveDropper::with_rng(min, max, SmallRng::seed_from_u64(7));
+            let mut dropped = 0;
+            let mut bursts = Vec::new();
+            for i in 0..200 {
+                let at = start + interval * i;
+                match dropper.filter_packet_at(PEER, &keepalive(), at) {
+                    PacketDecision::Drop => dropped += 1,
+                    PacketDecision::Allow => bursts.push(std::mem::take(&mut dropped)),
+                }
+            }
+            assert!(bursts.len() > 10);
+            assert!(bursts.iter().all(|n| (1 + min..=1 + max).contains(n)), "{:?}", bursts);
+        }
+    }
+
+    /// Tests that the recent maximum size is kept per peer and decays over two windows.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_recent_max_size() {
+        let mut dropper = KeepaliveDropper::with_rng(1, 1, SmallRng::seed_from_u64(1));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let window = Duration::from_secs(10);
+        let (a, b) = (peer(1), peer(2));
+
+        assert_eq!(dropper.recent_max_size_at(a, 100, window, at(0)), 100);
+        assert_eq!(dropper.recent_max_size_at(a, 1400, window, at(1)), 1400);
+        assert_eq!(dropper.recent_max_size_at(a, 80, window, at(2)), 1400);
+        assert_eq!(dropper.recent_max_size_at(b, 80, window, at(2)), 80);
+
+        // The previous window still counts, the one before it no longer does
+        assert_eq!(dropper.recent_max_size_at(a, 80, window, at(12)), 1400);
+        assert_eq!(dropper.recent_max_size_at(a, 90, window, at(22)), 90);
+
+        // A gap of two windows forgets everything at once
+        assert_eq!(dropper.recent_max_size_at(b, 60, window, at(25)), 60);
+    }
+
+    /// Tests that the latest data of any peer is kept, filtered or recorded, and that
+    /// keepalives do not count as data.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_dropper_last_data() {
+        let mut dropper = KeepaliveDropper::with_rng(1, 1, SmallRng::seed_from_u64(1));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert_eq!(dropper.last_data(), None);
+
+        dropper.filter_packet_at(peer(1), &keepalive(), at(1));
+        dropper.record_at(&keepalive(), at(2));
+        assert_eq!(dropper.last_data(), None);
+
+        dropper.filter_packet_at(peer(1), &[0x04; 100], at(3));
+        assert_eq!(dropper.last_data(), Some(at(3)));
+        dropper.filter_packet_at(peer(2), &[0x04; 100], at(4));
+        dropper.filter_packet_at(peer(1), &keepalive(), at(5));
+        assert_eq!(dropper.last_data(), Some(at(4)));
+        dropper.record_at(&[0x01; 148], at(6));
+        assert_eq!(dropper.last_data(), Some(at(6)));
+    }
+
+    #[test]
+    fn test_peer_of() {
+        let mut v4 = [0u8; 28];
+        v4[0] = 0x45;
+        v4[12..20].copy_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        assert_eq!(peer_of(&v4), PEER);
+        let mut v6 = [0u8; 48];
+        v6[0] = 0x60;
+        v6[23] = 1;
+        v6[39] = 2;
+        let (src, dst) = peer_of(&v6);
+        assert_eq!((src.to_string(), dst.to_string()), ("::1".into(), "::2".into()));
+        let unspecified = IpAddr::from(Ipv4Addr::UNSPECIFIED);
+        assert_eq!(peer_of(&v6[..30]), (unspecified, unspecified));
+        assert_eq!(peer_of(&[]), (unspecified, unspecified));
+    }
 }
@@ -1,3 +1,15 @@
-mod keepalive;
-mod obfuscator;
+pub mod ballast;
+#[cfg(feature = "std")]
+pub mod chaff;
+#[cfg(feature = "std")]
+pub mod jitter;
+pub mod keepalive;
+pub mod obfuscator;
+#[cfg(feature = "std")]
 pub mod queue;
+#[cfg(feature = "std")]
+pub mod ratelimit;
+#[cfg(feature = "std")]
+pub mod tap;
+#[cfg(feature = "std")]
+pub mod watchdog;
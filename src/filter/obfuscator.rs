@@ -33,123 +33,688 @@
  * - [`obfuscate_wg_packet`]: Obfuscates a WireGuard packet in-place by encrypting selected fields,
  *   adding random ballast, and appending a nonce.
  * - [`deobfuscate_wg_packet`]: Reverses the obfuscation process, restoring the original packet.
+ * - [`Obfuscator`]: Both of the above behind one value that owns the key, random generator
+ *   and keepalive state of a rule, e.g. one per connection of a proxy.
  *
  * ## Usage
  * Use these functions to protect WireGuard packets from fingerprinting and traffic analysis
  * by making their structure less predictable.
  */
 
-use crate::config::FilterConfig;
-use crate::filter::keepalive::{KeepaliveDropper, PacketDecision};
-use crate::netutils::{ipv4, ipv6};
-use crate::randomiser::fill_random;
-use fast_chacha::FastChaCha20;
-use rand::rngs::SmallRng;
-use rand::Rng;
+use crate::cipher::{CipherImpl, CipherMode};
+use crate::config::{Direction, FilterConfig};
+use crate::filter::ballast::BallastGenerator;
+use crate::filter::keepalive::{is_handshake, peer_of, PacketDecision, PeerTracker};
+use crate::netutils::common::{ones_add, ones_sum};
+use crate::netutils::{cidr, ipv4, ipv6, tcp};
+use alloc::vec;
+use alloc::vec::Vec;
+use hmac::{Hmac, Mac};
+use log::debug;
+use rand::{Rng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+#[cfg(feature = "std")]
+use {
+    crate::filter::keepalive::KeepaliveDropper,
+    crate::randomiser::{self, Reseeder},
+    core::fmt,
+    rand::rngs::SmallRng,
+    std::time::Instant,
+};
 
-const NONCE_LEN: usize = 12;
-const MAC2_LEN: usize = 16;
+/// Longest nonce, used by XChaCha20; the ChaCha modes use 12 bytes.
+const MAX_NONCE_LEN: usize = 24;
+/// Trailing bytes of a WireGuard message moved into the encrypted block. They are the MAC2 of
+/// a handshake initiation or response, the end of the encrypted cookie of a cookie reply and
+/// the Poly1305 tag of a data packet; all are carried as opaque bytes and restored verbatim,
+/// so no message type needs a layout of its own.
+pub const MAC2_LEN: usize = 16;
 const BALLAST_LEN_MAX: usize = 65;
+/// Most random bytes added on top of the recent maximum size under `hide-length=`, so that
+/// the padded packets of a flow do not all share one size.
+const HIDE_LENGTH_JITTER: usize = 32;
+/// Leading bytes of the WireGuard message that are always encrypted in place: the message
+/// type, the reserved bytes, the receiver or sender index and, in data packets, the counter.
+pub const WG_HEADER_LEN: usize = 16;
+/// Fewest leading bytes of a message encrypted in place (`encrypt-bytes=`, the default).
+pub const ENCRYPT_BYTES_MIN: usize = WG_HEADER_LEN;
+/// Most leading bytes of a message encrypted in place (`encrypt-bytes=`). Beyond the first
+/// 16 they continue the keystream of the block, after its fixed part.
+pub const ENCRYPT_BYTES_MAX: usize = 128;
+/// Smallest WireGuard message that is (de)obfuscated: the encrypted header and the MAC2
+/// field it carries at its end. A keepalive is exactly this long.
+pub const MIN_WG_LEN: usize = WG_HEADER_LEN + MAC2_LEN;
+/// The ballast length and version bytes, inserted between the ballast and MAC2.
+pub const LEN_VERSION_LEN: usize = 2;
+/// Bytes between the end of the ballast and the nonce: ballast length, version and MAC2.
+const TRAILER_LEN: usize = LEN_VERSION_LEN + MAC2_LEN;
+
+/// Layout of the encrypted block: the WireGuard header, the ballast length, the version byte,
+/// MAC2 and the high byte of a wide ballast length, followed by the message bytes after the
+/// header that `encrypt-bytes=` adds, if any.
+const BLOCK_BALLAST: usize = WG_HEADER_LEN;
+const BLOCK_VERSION: usize = BLOCK_BALLAST + 1;
+const BLOCK_MAC2: usize = BLOCK_VERSION + 1;
+const BLOCK_BALLAST_HIGH: usize = BLOCK_MAC2 + MAC2_LEN;
+const BLOCK_LEN: usize = BLOCK_BALLAST_HIGH + 1;
+const BLOCK_EXTRA_MAX: usize = ENCRYPT_BYTES_MAX - WG_HEADER_LEN;
+
+/// Framing version, stored in the high nibble of the encrypted version byte.
+///
+/// The layout is the same under every cipher mode; the version names the mode, so a peer
+/// configured with a different one drops the packet instead of misreading it. ChaCha20 keeps
+/// version 1, the original framing.
+fn format_version(mode: CipherMode) -> u8 {
+    match mode {
+        CipherMode::ChaCha20 => 1,
+        CipherMode::ChaCha12 => 2,
+        CipherMode::ChaCha8 => 3,
+        CipherMode::XChaCha20 => 4,
+    }
+}
+/// Version byte flag: the packet ends with an integrity tag.
+const FLAG_AUTH: u8 = 0x01;
+/// Version byte flag: the ballast length is 16 bits wide; the last ballast byte holds its
+/// encrypted high byte. Set only when bucket padding needs more than 255 bytes of ballast.
+const FLAG_WIDE_BALLAST: u8 = 0x02;
+/// Version byte flag: the packet is chaff; the receiver discards it after validation.
+const FLAG_CHAFF: u8 = 0x04;
+/// Version byte flag: more than the first 16 bytes of the message are encrypted
+/// (`encrypt-bytes=`). The count is not carried; both ends must configure the same one.
+const FLAG_WIDE_HEADER: u8 = 0x08;
+/// Flags understood by this version of the framing.
+const KNOWN_FLAGS: u8 = FLAG_AUTH | FLAG_WIDE_BALLAST | FLAG_CHAFF | FLAG_WIDE_HEADER;
+
+/// Length of the integrity tag appended in authenticated mode (truncated HMAC-SHA256).
+pub const TAG_LEN: usize = 16;
+/// Context string mixed into the integrity tag.
+const TAG_CONTEXT: &[u8] = b"nf_wgobfs auth v1";
+
+/// Most bytes obfuscation adds to a packet under any settings: the largest random ballast,
+/// the length and version bytes, the longest nonce and the tag. Bucket padding never goes
+/// beyond the MTU, so `len.max(mtu) + OBFUSCATION_OVERHEAD` bytes always hold the result;
+/// packet buffers are sized from this rather than from a literal.
+pub const OBFUSCATION_OVERHEAD: usize = BALLAST_LEN_MAX + LEN_VERSION_LEN + MAX_NONCE_LEN + TAG_LEN;
+
+/// Bytes added to every obfuscated packet on top of the ballast under `config`: the length
+/// byte, the version byte, the nonce and, in authenticated mode, the tag.
+pub fn fixed_overhead(config: &FilterConfig) -> usize {
+    LEN_VERSION_LEN + config.cipher.nonce_len() + if config.auth { TAG_LEN } else { 0 }
+}
+
+/// Bytes obfuscation adds to the data packets of a rule, see [`overhead`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overhead {
+    /// Least added: the fixed part, all a packet that fills the MTU gets.
+    pub min: usize,
+    /// Most added: the fixed part and the largest random ballast.
+    pub max: usize,
+    /// Added on average to packets with room for the full ballast.
+    pub typical: usize,
+}
+
+/// Bytes obfuscation adds to a data packet under `config`, for planning the MTU of the
+/// WireGuard interface: one of at most `mtu - max` bytes always gets its full random
+/// ballast, larger ones get less, and one of over `mtu - min` bytes goes out oversized.
+///
+/// Padding up to `buckets=` and the padding of handshake messages are not counted: they
+/// never take a packet beyond the MTU.
+pub fn overhead(config: &FilterConfig) -> Overhead {
+    let fixed = fixed_overhead(config);
+    Overhead {
+        min: fixed,
+        max: fixed + BALLAST_LEN_MAX,
+        typical: fixed + (3 + BALLAST_LEN_MAX) / 2,
+    }
+}
+
+/// Builds the version byte for packets obfuscated under `config` with `ballast_len` bytes
+/// of ballast, flagged as chaff if `chaff` is set.
+fn version_byte(config: &FilterConfig, ballast_len: usize, chaff: bool) -> u8 {
+    let mut flags = if config.auth { FLAG_AUTH } else { 0 };
+    if ballast_len > u8::MAX as usize {
+        flags |= FLAG_WIDE_BALLAST;
+    }
+    if chaff {
+        flags |= FLAG_CHAFF;
+    }
+    if config.encrypt_bytes > WG_HEADER_LEN {
+        flags |= FLAG_WIDE_HEADER;
+    }
+    format_version(config.cipher) << 4 | flags
+}
+
+/// Returns true if the `allow=` and `deny=` lists of `config` let the rule transform
+/// `packet`, judged by its source address.
+#[inline(always)]
+fn source_allowed(packet: &[u8], config: &FilterConfig) -> bool {
+    if config.allow.is_empty() && config.deny.is_empty() {
+        return true;
+    }
+    let Some(source) = cidr::source_addr(packet) else {
+        return false;
+    };
+    (config.allow.is_empty() || config.allow.iter().any(|net| net.contains(source)))
+        && !config.deny.iter().any(|net| net.contains(source))
+}
+
+/// Returns true if the `port=` list of `config` lets the rule transform `packet`, whose UDP
+/// or TCP header starts at `transport_start`: if either of its ports is listed. Peers roam
+/// and send from whatever port they have, so one end at a listed port is enough.
+#[inline(always)]
+fn port_allowed(packet: &[u8], transport_start: usize, config: &FilterConfig) -> bool {
+    if config.ports.is_empty() {
+        return true;
+    }
+    let port = |at: usize| packet.get(at..at + 2).map(|p| u16::from_be_bytes([p[0], p[1]]));
+    [port(transport_start), port(transport_start + 2)]
+        .into_iter()
+        .flatten()
+        .any(|port| config.ports.iter().any(|range| range.contains(port)))
+}
+
+/// Chooses the ballast length for a packet of `len` bytes, a handshake message if
+/// `handshake` is set.
+///
+/// Pads up to the smallest bucket of `config.buckets` that fits the packet and its overhead
+/// under the MTU. Without such a bucket, picks a random length of up to [`BALLAST_LEN_MAX`].
+///
+/// Handshake messages have fixed sizes that a few bytes of ballast would not hide among data
+/// packets of every size: they are padded up to a random one of the buckets that fit, or
+/// without one to a random size up to the MTU. The receiver reads the ballast length from
+/// the packet either way.
+fn ballast_len_for(
+    len: usize,
+    config: &FilterConfig,
+    handshake: bool,
+    rng: &mut dyn RngCore,
+) -> usize {
+    let overhead = fixed_overhead(config);
+    let min_len = len + overhead;
+    let fits = |size: &&usize| **size >= min_len && **size <= config.mtu;
+    if handshake {
+        let fitting = config.buckets.iter().filter(fits).count();
+        if fitting > 0 {
+            let pick = rng.random_range(0..fitting);
+            return config.buckets.iter().filter(fits).nth(pick).map_or(0, |size| size - min_len);
+        }
+        if config.mtu >= min_len + 3 {
+            return rng.random_range(3..=config.mtu - min_len);
+        }
+    }
+    if let Some(size) = config.buckets.iter().find(fits) {
+        return size - min_len;
+    }
+    let max_ballast = config.mtu.saturating_sub(min_len).min(BALLAST_LEN_MAX);
+    if max_ballast >= 3 {
+        rng.random_range(3..=max_ballast)
+    } else {
+        0
+    }
+}
+
+/// Chooses the on-wire size of a packet under `hide-length=`: the largest size `recent` its
+/// flow had lately, plus up to [`HIDE_LENGTH_JITTER`] random bytes, but not beyond the
+/// ceiling or the MTU and never below the `natural` size the packet has without hiding.
+///
+/// With buckets the jitter is left out: the recent maximum is then a bucket size already.
+fn hidden_len(
+    natural: usize,
+    recent: usize,
+    ceiling: usize,
+    config: &FilterConfig,
+    rng: &mut dyn RngCore,
+) -> usize {
+    let jitter =
+        if config.buckets.is_empty() { rng.random_range(0..=HIDE_LENGTH_JITTER) } else { 0 };
+    (recent + jitter).min(ceiling.min(config.mtu)).max(natural)
+}
+
+/// Starts the integrity tag over an obfuscated UDP payload (up to and including the nonce).
+fn tag_mac(key: &[u8; 32], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(TAG_CONTEXT);
+    mac.update(payload);
+    mac
+}
+
+/// Number of leading bytes of a `msg_len`-byte message encrypted in place under `config`:
+/// `encrypt-bytes=`, but never into the MAC2 field the block carries.
+#[inline(always)]
+fn encrypted_len(config: &FilterConfig, msg_len: usize) -> usize {
+    config.encrypt_bytes.min(msg_len - MAC2_LEN)
+}
+
+/// One's complement sum of the bytes the (de)obfuscator rewrites: the first `header_len`
+/// bytes of the WireGuard payload and the tail starting at the MAC2 field.
+#[inline(always)]
+fn rewritten_sum(
+    buf: &[u8],
+    wg_start: usize,
+    header_len: usize,
+    tail_start: usize,
+    tail_end: usize,
+) -> u16 {
+    ones_add(
+        ones_sum(&buf[wg_start..wg_start + header_len], wg_start),
+        ones_sum(&buf[tail_start..tail_end], tail_start),
+    )
+}
+
+/// IP protocol number of UDP.
+const PROTO_UDP: u8 = 17;
+
+/// Transport protocol carrying the WireGuard message.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    /// WireGuard tunnelled over TCP, one message per segment.
+    Tcp,
+}
+
+/// Reads the big-endian 16-bit field at `at`, if in bounds.
+#[inline(always)]
+fn be16(buf: &[u8], at: usize) -> Option<usize> {
+    Some(u16::from_be_bytes([*buf.get(at)?, *buf.get(at + 1)?]) as usize)
+}
+
+/// Locates the WireGuard message in an IPv4 or IPv6 packet.
+///
+/// The length fields must agree with the length of `buf`: the IPv4 header length must
+/// cover at least 20 bytes and lie within the packet, and the IPv4 total length, IPv6
+/// payload length and UDP length must match what `buf` holds. Otherwise the offsets would
+/// point into or past other headers, or take stale bytes beyond the packet for its payload.
+///
+/// # Returns
+/// * `Some((transport, transport_start, wg_start))` - Offsets of the UDP or TCP header and
+///   of its payload.
+/// * `None` - If the packet carries neither UDP nor TCP, its headers are truncated, or its
+///   length fields disagree with its length.
+#[inline(always)]
+fn locate_payload(buf: &[u8]) -> Option<(Transport, usize, usize)> {
+    let (proto, start) = match buf.first()? >> 4 {
+        4 => {
+            let ihl = ((buf[0] & 0x0F) as usize) * 4;
+            if ihl < 20 || ihl > buf.len() || be16(buf, 2)? != buf.len() {
+                return None;
+            }
+            (buf[9], ihl)
+        }
+        6 if be16(buf, 4)? + ipv6::HEADER_LEN == buf.len() => ipv6::upper_layer(buf)?,
+        _ => return None,
+    };
+    match proto {
+        PROTO_UDP if be16(buf, start + 4)? == buf.len() - start => {
+            Some((Transport::Udp, start, start + 8))
+        }
+        tcp::PROTO_TCP => {
+            Some((Transport::Tcp, start, start + tcp::header_len(buf.get(start..)?)?))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the WireGuard message of `packet`, the payload behind its UDP or TCP header, or
+/// `None` if its headers are truncated or inconsistent or it carries neither.
+pub fn wg_message(packet: &[u8]) -> Option<&[u8]> {
+    locate_payload(packet).and_then(|(_, _, wg_start)| packet.get(wg_start..))
+}
+
+/// Why a packet was passed on unchanged, for the packets that were malformed or out of the
+/// range the filter handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skip {
+    /// The IP version is neither 4 nor 6.
+    UnknownVersion,
+    /// The packet ends within its headers or is too short to hold a (obfuscated) message.
+    TooShort,
+    /// The length fields of the IP or UDP header disagree with the length of the packet.
+    Malformed,
+    /// The packet is longer than the MTU of the rule (outbound only).
+    TooLarge,
+}
+
+/// Tells why [`obfuscate_wg_packet`] or [`deobfuscate_wg_packet`], as chosen by the
+/// direction of `config`, passes `packet` on unchanged.
+///
+/// Meant for packets that were passed through, to count them: it repeats the length and
+/// header checks of the transforms, off their fast path.
+///
+/// # Returns
+/// * `Some(skip)` - The packet is malformed or out of range.
+/// * `None` - The packet is well-formed: not UDP or TCP, or left alone by the rule
+///   (`allow=`, `deny=`, `port=`, `min-size=`).
+pub fn skip_reason(packet: &[u8], config: &FilterConfig) -> Option<Skip> {
+    let Some(version) = packet.first().map(|b| b >> 4) else {
+        return Some(Skip::TooShort);
+    };
+    if version != 4 && version != 6 {
+        return Some(Skip::UnknownVersion);
+    }
+    let min_wg_len = match config.direction {
+        Direction::Out if packet.len() > config.mtu => return Some(Skip::TooLarge),
+        Direction::Out => MIN_WG_LEN,
+        Direction::In => MIN_WG_LEN + LEN_VERSION_LEN + config.cipher.nonce_len(),
+    };
+    match locate_payload(packet) {
+        Some((_, _, wg_start)) => (packet.len() < wg_start + min_wg_len).then_some(Skip::TooShort),
+        // The IP headers are truncated or inconsistent, or the UDP or TCP header is
+        None if lengths_disagree(packet) => Some(Skip::Malformed),
+        None => {
+            let proto = match version {
+                4 => packet.get(9).copied(),
+                _ => ipv6::upper_layer(packet).map(|(proto, _)| proto),
+            };
+            matches!(proto, None | Some(PROTO_UDP | tcp::PROTO_TCP)).then_some(Skip::TooShort)
+        }
+    }
+}
+
+/// Tells whether the length fields of an IPv4 or IPv6 packet contradict its length, as
+/// [`locate_payload`] checks them. Fields cut off by the end of the packet are not counted:
+/// such a packet is truncated.
+fn lengths_disagree(packet: &[u8]) -> bool {
+    let (ip_len, start) = match packet[0] >> 4 {
+        4 if packet.len() >= 20 => {
+            let ihl = ((packet[0] & 0x0F) as usize) * 4;
+            if ihl < 20 || ihl > packet.len() {
+                return true;
+            }
+            (be16(packet, 2).unwrap_or(0), Some(ihl).filter(|_| packet[9] == PROTO_UDP))
+        }
+        6 if packet.len() >= ipv6::HEADER_LEN => {
+            let udp = ipv6::upper_layer(packet).filter(|(proto, _)| *proto == PROTO_UDP);
+            (be16(packet, 4).unwrap_or(0) + ipv6::HEADER_LEN, udp.map(|(_, start)| start))
+        }
+        _ => return false,
+    };
+    let udp_len = start.and_then(|start| Some((be16(packet, start + 4)?, packet.len() - start)));
+    ip_len != packet.len() || udp_len.is_some_and(|(field, actual)| field != actual)
+}
+
+/// Fixes the IP and transport headers of a packet whose WireGuard message was rewritten.
+///
+/// UDP checksums are updated incrementally from the sums of the rewritten bytes; TCP
+/// checksums are recalculated over the whole segment.
+#[inline(always)]
+fn fix_headers(buf: &mut [u8], transport: Transport, transport_start: usize, sums: (u16, u16)) {
+    let (old_sum, new_sum) = sums;
+    match (transport, buf[0] >> 4) {
+        (Transport::Udp, 4) => ipv4::update_udp_headers(buf, old_sum, new_sum),
+        (Transport::Udp, _) => ipv6::update_udp_headers(buf, old_sum, new_sum),
+        (Transport::Tcp, 4) => tcp::fix_ipv4_headers(buf, transport_start),
+        (Transport::Tcp, _) => tcp::fix_ipv6_headers(buf, transport_start),
+    }
+}
 
 /// Obfuscates a WireGuard packet in-place.
 ///
 /// This function encrypts selected fields of the WireGuard packet, adds random
-/// ballast (padding), and appends a nonce. It also updates UDP and IP headers as needed.
+/// ballast (padding), and appends a nonce. It also updates the UDP (or TCP) and IP headers
+/// as needed.
 ///
 /// # Arguments
 /// * `buf` - Mutable buffer containing the packet data.
 /// * `len` - Length of the valid data in the buffer.
 /// * `config` - Filter configuration, including the obfuscation key and MTU.
-/// * `dropper` - KeepaliveDropper instance for filtering keepalive packets.
-/// * `rng` - Random number generator.
+/// * `dropper` - Per-peer state for thinning keepalives and hiding lengths, usually a
+///   [`KeepaliveDropper`](crate::filter::keepalive::KeepaliveDropper); not consulted when
+///   keepalive thinning is disabled in `config`.
+/// * `rng` - Source of the nonce and ballast bytes. Any [`RngCore`] will do, so tests can
+///   inject a seeded or scripted generator and firmware can bring its own.
 ///
 /// # Returns
 /// * `Some(new_len)` - The new length of the obfuscated packet.
 /// * `None` - If the packet should be dropped or an error occurred.
 ///
 /// # Details
-/// - Encrypts the first 16 bytes of the WireGuard payload and the MAC2 field using ChaCha20.
-/// - Inserts random ballast (padding) to make packet sizes less predictable.
+/// - Encrypts the first 16 bytes of the WireGuard payload, the ballast length, the framing
+///   version byte and the MAC2 field using the configured ChaCha variant. Any message of at
+///   least 32 bytes is obfuscated: data packets, and the handshake initiation (148 bytes),
+///   response (92) and cookie reply (64), whose type, sender index and MAC2 are hidden
+///   and whose fixed sizes are blurred by the ballast.
+/// - Inserts ballast (padding) to make packet sizes less predictable: up to the nearest of
+///   `config.buckets` that fits under the MTU, or a random amount otherwise. Handshake
+///   messages are padded further, to a random fitting bucket or a random size up to the MTU,
+///   so that their fixed sizes blend in with data packets. The ballast bytes follow the
+///   profile of the rule (`config.ballast`).
 /// - Appends a nonce for encryption.
-/// - Updates UDP and IP headers to reflect the new packet size.
+/// - In authenticated mode (`config.auth`), appends a tag over the obfuscated payload.
+/// - Updates the UDP or TCP and IP headers to reflect the new packet size.
+/// - Returns packets from sources outside `config.allow`, or inside `config.deny`, unchanged,
+///   as well as packets with neither port in `config.ports`.
 pub fn obfuscate_wg_packet(
     buf: &mut [u8],
     len: usize,
     config: &FilterConfig,
-    dropper: &mut KeepaliveDropper,
-    rng: &mut SmallRng,
+    dropper: &mut dyn PeerTracker,
+    rng: &mut dyn RngCore,
+) -> Option<usize> {
+    obfuscate(buf, len, config, Some(dropper), rng, &config.ballast, None)
+}
+
+/// Obfuscates a WireGuard packet in-place like [`obfuscate_wg_packet`], with its ballast
+/// filled by `ballast` instead of the profile of the rule: a
+/// [`BallastProfile`](crate::filter::ballast::BallastProfile) or a closure.
+pub fn obfuscate_wg_packet_with(
+    buf: &mut [u8],
+    len: usize,
+    config: &FilterConfig,
+    dropper: &mut dyn PeerTracker,
+    rng: &mut dyn RngCore,
+    ballast: &impl BallastGenerator,
+) -> Option<usize> {
+    obfuscate(buf, len, config, Some(dropper), rng, ballast, None)
+}
+
+/// Nonce and ballast length of a deterministic obfuscation, see
+/// [`obfuscate_wg_packet_fixed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixed<'a> {
+    /// Nonce, as long as the cipher of the rule needs ([`CipherMode::nonce_len`]).
+    pub nonce: &'a [u8],
+    /// Number of ballast bytes to insert.
+    pub ballast_len: usize,
+}
+
+/// Obfuscates a WireGuard packet in-place like [`obfuscate_wg_packet_with`], with the nonce
+/// and ballast length of `fixed` instead of random ones. With a ballast generator that does
+/// not draw from `rng`, the output depends on the inputs alone: this produces the test
+/// vectors other implementations check their framing against.
+///
+/// # Returns
+/// As [`obfuscate_wg_packet`]; also `None` if the nonce does not fit the cipher of the rule
+/// or the ballast is longer than obfuscation ever adds to a packet of `len` bytes.
+pub fn obfuscate_wg_packet_fixed(
+    buf: &mut [u8],
+    len: usize,
+    config: &FilterConfig,
+    dropper: &mut dyn PeerTracker,
+    rng: &mut dyn RngCore,
+    ballast: &impl BallastGenerator,
+    fixed: Fixed,
+) -> Option<usize> {
+    let max_len = len.max(config.mtu) + OBFUSCATION_OVERHEAD;
+    if fixed.nonce.len() != config.cipher.nonce_len()
+        || len + fixed.ballast_len + fixed_overhead(config) > max_len
+    {
+        return None;
+    }
+    obfuscate(buf, len, config, Some(dropper), rng, ballast, Some(fixed))
+}
+
+/// Obfuscates a chaff packet in-place, like [`obfuscate_wg_packet`], flagging it so that
+/// the receiver discards it. Chaff is never thinned out as a keepalive.
+pub fn obfuscate_chaff_packet(
+    buf: &mut [u8],
+    len: usize,
+    config: &FilterConfig,
+    rng: &mut dyn RngCore,
+) -> Option<usize> {
+    obfuscate(buf, len, config, None, rng, &config.ballast, None)
+}
+
+/// Obfuscates a packet; chaff if no peer tracker is given. The nonce and ballast
+/// length are drawn from `rng` unless `fixed` gives them.
+#[inline(always)]
+fn obfuscate(
+    buf: &mut [u8],
+    len: usize,
+    config: &FilterConfig,
+    mut dropper: Option<&mut dyn PeerTracker>,
+    rng: &mut dyn RngCore,
+    ballast: &impl BallastGenerator,
+    fixed: Option<Fixed>,
 ) -> Option<usize> {
     if len < 1 || len > config.mtu {
         return Some(len);
     }
 
-    // Determine IP version and calculate start of WireGuard payload
-    let ip_version = buf[0] >> 4;
-    let wg_start = match ip_version {
-        4 => ((buf[0] & 0x0F) as usize) * 4 + 8,
-        6 => 48,
-        _ => return Some(len),
+    // Locate the WireGuard payload behind the UDP or TCP header
+    let Some((transport, transport_start, wg_start)) = locate_payload(&buf[..len]) else {
+        return Some(len);
     };
 
-    if len < wg_start + 32 {
+    if len < wg_start + MIN_WG_LEN {
         return Some(len);
     }
 
-    let wg_payload = &buf[wg_start..len];
-    if matches!(dropper.filter_packet(wg_payload), PacketDecision::Drop) {
-        return None;
+    // Packets from sources outside the rule's allow/deny lists, or between ports outside
+    // its port list, go out as they are
+    let chaff = dropper.is_none();
+    let matched = source_allowed(&buf[..len], config) && port_allowed(buf, transport_start, config);
+    if !chaff && !matched {
+        return Some(len);
+    }
+
+    let peer = peer_of(&buf[..wg_start]);
+    match dropper.as_deref_mut() {
+        Some(dropper) if config.keepalive => {
+            if matches!(dropper.filter_packet(peer, &buf[wg_start..len]), PacketDecision::Drop) {
+                return None;
+            }
+        }
+        // The watchdog learns of data from the dropper even when it does not thin
+        Some(dropper) if config.watchdog.is_some() => dropper.record(&buf[wg_start..len]),
+        _ => {}
+    }
+
+    // Messages below the rule's minimum size go out as they are; chaff is always obfuscated
+    if len - wg_start < config.min_size && !chaff {
+        return Some(len);
     }
 
-    // Calculate how much random ballast can be inserted
-    let max_insert = config.mtu.saturating_sub(len);
-    let max_ballast = max_insert.saturating_sub(1 + NONCE_LEN).min(BALLAST_LEN_MAX);
-    let ballast_len = if max_ballast >= 3 { rng.random_range(3..=max_ballast) } else { 0 };
+    // Handshakes are rare and the most recognisable messages; note them for debugging
+    let handshake = is_handshake(&buf[wg_start..len]).then_some(buf[wg_start]);
 
-    let new_len = len + 1 + ballast_len + NONCE_LEN;
+    // Calculate how much ballast to insert
+    let ballast_len = match fixed {
+        Some(fixed) => fixed.ballast_len,
+        None => ballast_len_for(len, config, handshake.is_some(), rng),
+    };
+    // Length hiding pads up to the flow's recent maximum; the random sizes of handshakes
+    // would inflate it, so they are padded but not counted
+    let ballast_len = match (config.hide_length, dropper, fixed) {
+        (Some(hide), Some(dropper), None) => {
+            let natural = len + ballast_len + fixed_overhead(config);
+            let observed = if handshake.is_some() { 0 } else { natural };
+            let recent = dropper.recent_max_size(peer, observed, hide.window);
+            hidden_len(natural, recent, hide.ceiling, config, rng) - len - fixed_overhead(config)
+        }
+        _ => ballast_len,
+    };
+    let new_len = len + ballast_len + fixed_overhead(config);
+    debug_assert!(
+        new_len <= len.max(config.mtu) + OBFUSCATION_OVERHEAD,
+        "{} bytes obfuscated to {}, beyond OBFUSCATION_OVERHEAD",
+        len,
+        new_len
+    );
     if new_len > buf.len() {
         return None;
     }
 
+    // Sum of the bytes rewritten below, for the incremental checksum update
+    let header_len = encrypted_len(config, len - wg_start);
+    let old_sum = rewritten_sum(buf, wg_start, header_len, len - MAC2_LEN, len);
+
     // Generate random nonce
-    let mut nonce = [0u8; NONCE_LEN];
-    fill_random(&mut nonce, rng);
+    let mut nonce = [0u8; MAX_NONCE_LEN];
+    let nonce = &mut nonce[..config.cipher.nonce_len()];
+    match fixed {
+        Some(fixed) => nonce.copy_from_slice(fixed.nonce),
+        None => rng.fill_bytes(nonce),
+    }
 
-    // Prepare block for encryption: first 16 bytes of payload, ballast length, MAC2
-    let mut block = [0u8; 33];
-    block[..16].copy_from_slice(&buf[wg_start..wg_start + 16]);
-    block[16] = ballast_len as u8;
-    block[17..].copy_from_slice(&buf[len - MAC2_LEN..len]);
+    // Prepare block for encryption: first 16 bytes of payload, ballast length, version, MAC2,
+    // the high byte of the ballast length and any further payload bytes to encrypt. It holds
+    // plaintext header fields and is wiped when it goes out of scope.
+    let extra = header_len - WG_HEADER_LEN;
+    let extra_start = wg_start + WG_HEADER_LEN;
+    let mut block = Zeroizing::new([0u8; BLOCK_LEN + BLOCK_EXTRA_MAX]);
+    block[..BLOCK_BALLAST].copy_from_slice(&buf[wg_start..wg_start + WG_HEADER_LEN]);
+    block[BLOCK_BALLAST] = ballast_len as u8;
+    block[BLOCK_VERSION] = version_byte(config, ballast_len, chaff);
+    block[BLOCK_MAC2..BLOCK_BALLAST_HIGH].copy_from_slice(&buf[len - MAC2_LEN..len]);
+    block[BLOCK_BALLAST_HIGH] = (ballast_len >> 8) as u8;
+    block[BLOCK_LEN..BLOCK_LEN + extra].copy_from_slice(&buf[extra_start..extra_start + extra]);
 
-    // Encrypt block with ChaCha20
-    let mut cipher = FastChaCha20::new(&config.key, &nonce);
-    cipher.apply_keystream(&mut block);
+    // Encrypt block with the configured ChaCha variant, in a single pass: the fast and the
+    // portable implementations only agree on where a later call resumes at block boundaries
+    let mut cipher = CipherImpl::new(config.cipher, &config.key, nonce);
+    cipher.apply_keystream(&mut block[..BLOCK_LEN + extra]);
 
     // Write encrypted fields back to buffer
-    buf[wg_start..wg_start + 16].copy_from_slice(&block[..16]);
+    buf[wg_start..wg_start + WG_HEADER_LEN].copy_from_slice(&block[..BLOCK_BALLAST]);
+    buf[extra_start..extra_start + extra].copy_from_slice(&block[BLOCK_LEN..BLOCK_LEN + extra]);
 
-    // Insert random ballast instead of MAC2
+    // Insert ballast instead of MAC2
     let mut offset = len - MAC2_LEN;
-    fill_random(&mut buf[offset..offset + ballast_len], rng);
+    ballast.fill(&mut buf[offset..offset + ballast_len], rng);
     offset += ballast_len;
+    if ballast_len > u8::MAX as usize {
+        // Wide ballast length: the last ballast byte carries its encrypted high byte
+        buf[offset - 1] = block[BLOCK_BALLAST_HIGH];
+    }
 
-    // Insert encrypted ballast length and MAC2
-    buf[offset] = block[16];
-    offset += 1;
-    buf[offset..offset + MAC2_LEN].copy_from_slice(&block[17..]);
+    // Insert encrypted ballast length, version and MAC2
+    buf[offset..offset + LEN_VERSION_LEN].copy_from_slice(&block[BLOCK_BALLAST..BLOCK_MAC2]);
+    offset += LEN_VERSION_LEN;
+    buf[offset..offset + MAC2_LEN].copy_from_slice(&block[BLOCK_MAC2..BLOCK_BALLAST_HIGH]);
     offset += MAC2_LEN;
 
     // Append nonce
-    buf[offset..offset + NONCE_LEN].copy_from_slice(&nonce);
+    buf[offset..offset + nonce.len()].copy_from_slice(nonce);
+    offset += nonce.len();
+
+    // Append integrity tag over everything obfuscated so far
+    if config.auth {
+        let tag = tag_mac(&config.key, &buf[wg_start..offset]).finalize().into_bytes();
+        buf[offset..offset + TAG_LEN].copy_from_slice(&tag[..TAG_LEN]);
+    }
 
     // Fix headers to reflect new packet size
-    match ip_version {
-        4 => {
-            ipv4::clear_diffserv(&mut buf[..new_len]);
-            ipv4::fix_udp_headers(&mut buf[..new_len]);
+    let new_sum = rewritten_sum(buf, wg_start, header_len, len - MAC2_LEN, new_len);
+    // DSCP marks can tell flows apart; they are cleared unless the rule keeps them for QoS.
+    // Neither field is covered by a checksum.
+    match buf[0] >> 4 {
+        4 if !config.keep_dscp => ipv4::clear_diffserv(&mut buf[..new_len]),
+        6 => {
+            if !config.keep_dscp {
+                ipv6::clear_traffic_class(&mut buf[..new_len]);
+            }
+            if config.zero_flow_label {
+                ipv6::clear_flow_label(&mut buf[..new_len]);
+            }
         }
-        6 => ipv6::fix_udp_headers(&mut buf[..new_len]),
         _ => {}
     }
+    fix_headers(&mut buf[..new_len], transport, transport_start, (old_sum, new_sum));
+
+    if let Some(msg_type) = handshake {
+        debug!("Obfuscated handshake message type {} ({} -> {} bytes)", msg_type, len, new_len);
+    }
 
     Some(new_len)
 }
@@ -161,70 +726,313 @@ pub fn obfuscate_wg_packet(
 ///
 /// # Arguments
 /// * `buf` - Mutable buffer containing the obfuscated packet data.
-/// * `config` - Filter configuration, including the obfuscation key.
+/// * `config` - Filter configuration, including the obfuscation key and, during a key
+///   rotation, the previous key tried when the packet does not validate under the current one.
 ///
 /// # Returns
 /// * `Some(new_len)` - The new length of the deobfuscated packet.
-/// * `None` - If an error occurred.
+/// * `None` - If the packet must be dropped: its framing version is unknown, its integrity
+///   tag does not verify, its authenticated mode does not match `config`, its ballast
+///   length does not fit its length, or it is chaff.
 ///
 /// # Details
-/// - Extracts and decrypts the encrypted fields using the nonce and key.
+/// - In authenticated mode, verifies the trailing tag in constant time and strips it.
+/// - Extracts and decrypts the encrypted fields using the nonce and key, and checks the
+///   framing version before modifying the packet.
 /// - Removes the random ballast and nonce.
 /// - Restores the original MAC2 field and packet structure.
-/// - Fixes UDP and IP headers to match the restored packet.
+/// - Fixes the UDP or TCP and IP headers to match the restored packet.
 #[inline(always)]
 pub fn deobfuscate_wg_packet(buf: &mut [u8], config: &FilterConfig) -> Option<usize> {
-    let len = buf.len();
-    if len < 1 {
-        return Some(len);
+    deobfuscate_with_key(buf, config, &config.key).or_else(|| {
+        // During a key rotation the peer may still be sending with the previous key
+        let prev_key = config.prev_key.as_ref()?;
+        deobfuscate_with_key(buf, config, prev_key)
+    })
+}
+
+/// A packet of a batch, see [`obfuscate_batch`]: a buffer holding the packet at its start,
+/// with room behind it for the packet to grow, and the outcome of the transform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketBuf {
+    /// The packet, followed by spare room for obfuscation.
+    pub data: Vec<u8>,
+    /// Length of the packet in `data`; the transformed length once processed.
+    pub len: usize,
+    /// Whether processing decided to drop the packet instead of passing it on; every batch
+    /// sets or clears it.
+    pub drop: bool,
+}
+
+impl PacketBuf {
+    /// Copies `packet` into a buffer large enough for its obfuscation under any rule of the
+    /// given MTU.
+    pub fn new(packet: &[u8], mtu: usize) -> Self {
+        let mut data = vec![0; packet.len().max(mtu) + OBFUSCATION_OVERHEAD];
+        data[..packet.len()].copy_from_slice(packet);
+        Self { data, len: packet.len(), drop: false }
+    }
+
+    /// The packet as it stands.
+    pub fn packet(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Records the outcome of a transform: the new length, or a drop. Both are set either
+    /// way, so a buffer reused across batches never keeps the verdict of an earlier packet.
+    fn set_outcome(&mut self, result: Option<usize>) {
+        if let Some(new_len) = result {
+            self.len = new_len;
+        }
+        self.drop = result.is_none();
+    }
+}
+
+/// Obfuscates a batch of packets in place, as [`obfuscate_wg_packet`] does one, e.g. those
+/// of one batched receive. Each packet gets its new length in `len` and `drop` cleared, or
+/// `drop` set where the single-packet call would return `None`.
+///
+/// The packets are processed in order with one generator and one keepalive dropper, so the
+/// result is that of obfuscating them one by one. Taking them together leaves room for
+/// sharing work across packets later (a wider keystream, vectorised ciphers) without
+/// changing callers.
+pub fn obfuscate_batch(
+    packets: &mut [PacketBuf],
+    config: &FilterConfig,
+    dropper: &mut dyn PeerTracker,
+    rng: &mut dyn RngCore,
+) {
+    for packet in packets {
+        let result = obfuscate_wg_packet(&mut packet.data, packet.len, config, dropper, rng);
+        packet.set_outcome(result);
+    }
+}
+
+/// Deobfuscates a batch of packets in place, as [`deobfuscate_wg_packet`] does one,
+/// recording the outcome of each like [`obfuscate_batch`].
+pub fn deobfuscate_batch(packets: &mut [PacketBuf], config: &FilterConfig) {
+    for packet in packets {
+        let result = deobfuscate_wg_packet(&mut packet.data[..packet.len], config);
+        packet.set_outcome(result);
+    }
+}
+
+/// Why an [`Obfuscator`] gives no packet back.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The packet is not to be sent on: a keepalive thinned out, or an inbound packet that
+    /// failed to deobfuscate (wrong key or cipher, a tag that does not verify, or chaff).
+    Drop,
+    /// The buffer has no room for the obfuscated packet, see [`Obfuscator::obfuscate`].
+    NoSpace,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Drop => f.write_str("packet dropped"),
+            Error::NoSpace => f.write_str("no room in the buffer for the obfuscated packet"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Transforms the packets of one rule with all the state that takes: the keys of the rule,
+/// a generator for nonces and ballast, reseeded as it goes, and the keepalive thinning and
+/// length hiding state of its peers.
+///
+/// Nothing is kept in globals or shared between instances, so a proxy can hold one per
+/// connection or task. An `Obfuscator` is `Send` and its calls never block: an async task
+/// can own it across `.await` points and call it between socket reads and writes.
+#[cfg(feature = "std")]
+pub struct Obfuscator {
+    config: FilterConfig,
+    rng: SmallRng,
+    reseeder: Reseeder,
+    dropper: KeepaliveDropper,
+}
+
+#[cfg(feature = "std")]
+impl Obfuscator {
+    /// Creates an obfuscator for `config`, thinning keepalives as its `keepalive=`,
+    /// `keepalive-idle=` and `keepalive-drop=` set.
+    pub fn new(config: FilterConfig) -> Self {
+        Self::with_rng(config, randomiser::create_secure_rng())
+    }
+
+    /// Creates an obfuscator that draws its nonces and ballast from `rng`.
+    pub fn with_rng(config: FilterConfig, rng: SmallRng) -> Self {
+        let drop = config.keepalive_drop;
+        let mut dropper = KeepaliveDropper::new(drop.min, drop.max);
+        dropper.set_idle_threshold(config.keepalive_idle);
+        Self { config, rng, reseeder: Reseeder::new(Instant::now()), dropper }
+    }
+
+    /// The rule packets are transformed under.
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+
+    /// Obfuscates the packet in the first `len` bytes of `buf` in place, as
+    /// [`obfuscate_wg_packet`] does.
+    ///
+    /// `buf` must leave the packet room to grow: at least `len.max(mtu)` plus
+    /// [`OBFUSCATION_OVERHEAD`] bytes, as [`PacketBuf::new`] allocates.
+    ///
+    /// # Returns
+    /// * `Ok(new_len)` - The length of the packet to send; `len` for packets the rule leaves
+    ///   alone.
+    /// * `Err(Error::Drop)` - The packet is a keepalive thinned out.
+    /// * `Err(Error::NoSpace)` - `buf` is shorter than the above.
+    pub fn obfuscate(&mut self, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if buf.len() < len.max(self.config.mtu) + OBFUSCATION_OVERHEAD {
+            return Err(Error::NoSpace);
+        }
+        let result = obfuscate_wg_packet(buf, len, &self.config, &mut self.dropper, &mut self.rng);
+        self.reseeder.tick(&mut self.rng);
+        // With room for any packet, the only reason to give up is a thinned keepalive
+        result.ok_or(Error::Drop)
+    }
+
+    /// Deobfuscates the packet in `buf` in place, as [`deobfuscate_wg_packet`] does, under
+    /// the key of the rule or its previous one.
+    ///
+    /// # Returns
+    /// * `Ok(new_len)` - The length of the restored packet; that of `buf` for packets the
+    ///   rule leaves alone.
+    /// * `Err(Error::Drop)` - The packet failed to deobfuscate or is chaff.
+    pub fn deobfuscate(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        deobfuscate_wg_packet(buf, &self.config).ok_or(Error::Drop)
     }
+}
+
+/// Deobfuscates a packet with the given key; see [`deobfuscate_wg_packet`].
+///
+/// Returns `None` without modifying `buf` when the packet does not validate under `key`.
+#[inline(always)]
+fn deobfuscate_with_key(buf: &mut [u8], config: &FilterConfig, key: &[u8; 32]) -> Option<usize> {
+    let packet_len = buf.len();
+    if packet_len < 1 {
+        return Some(packet_len);
+    }
+    let mut len = packet_len;
 
-    // Determine IP version and calculate start of WireGuard payload
-    let ip_version = buf[0] >> 4;
-    let wg_start = match ip_version {
-        4 => ((buf[0] & 0x0F) as usize) * 4 + 8,
-        6 => 48,
-        _ => return Some(len),
+    // Locate the WireGuard payload behind the UDP or TCP header
+    let Some((transport, transport_start, wg_start)) = locate_payload(&buf[..len]) else {
+        return Some(len);
     };
-    // Ensure packet is large enough for deobfuscation
-    if len <= wg_start + 45 {
+    // Ensure packet is large enough for deobfuscation: the smallest message, obfuscated
+    // without ballast
+    let nonce_len = config.cipher.nonce_len();
+    let min_len = wg_start + MIN_WG_LEN + LEN_VERSION_LEN + nonce_len;
+    if len < min_len {
+        return Some(len);
+    }
+    // The peer sends messages below the rule's minimum size unobfuscated; obfuscated ones
+    // are always longer than that
+    if len - wg_start < config.min_size {
+        return Some(len);
+    }
+    // Packets from sources outside the rule's allow/deny lists, or between ports outside its
+    // port list, are not the rule's to restore
+    if !source_allowed(&buf[..len], config) || !port_allowed(buf, transport_start, config) {
         return Some(len);
     }
 
+    // Verify and strip the integrity tag before touching the packet
+    if config.auth {
+        if len < min_len + TAG_LEN {
+            return None;
+        }
+        len -= TAG_LEN;
+        let mac = tag_mac(key, &buf[wg_start..len]);
+        mac.verify_truncated_left(&buf[len..packet_len]).ok()?;
+    }
+
     // Extract nonce from the end of the packet
-    let nonce_offset = len - NONCE_LEN;
-    let mut nonce = [0u8; NONCE_LEN];
-    nonce.copy_from_slice(&buf[nonce_offset..len]);
-    let mut cipher = FastChaCha20::new(&config.key, &nonce);
-
-    // Extract encrypted block (fields + ballast length + MAC2)
-    let offset = len - 1 - NONCE_LEN - MAC2_LEN;
-    let mut block = [0u8; 33];
-    block[..16].copy_from_slice(&buf[wg_start..wg_start + 16]);
-    block[16..].copy_from_slice(&buf[offset..len - NONCE_LEN]);
-
-    // Decrypt block
-    cipher.apply_keystream(&mut block);
-
-    // Restore original fields
-    buf[wg_start..wg_start + 16].copy_from_slice(&block[..16]);
-    let ballast_len = block[16] as usize;
-
-    // Check minimum length after removing ballast and nonce
-    let min_len = ballast_len + 45;
-    if len < min_len {
-        return Some(len);
+    let nonce_offset = len - nonce_len;
+    let mut cipher = CipherImpl::new(config.cipher, key, &buf[nonce_offset..len]);
+
+    // Extract encrypted block (fields + ballast length + version + MAC2), the byte before
+    // the ballast length, which is its high byte if the version byte says so, and as many
+    // of the bytes after the header as `encrypt-bytes=` may have encrypted. The message
+    // length is not known yet, so this can take more than was encrypted; only the encrypted
+    // ones are restored below.
+    let offset = nonce_offset - TRAILER_LEN;
+    let extra_start = wg_start + WG_HEADER_LEN;
+    let max_extra = (config.encrypt_bytes - WG_HEADER_LEN).min(offset - extra_start);
+    let mut block = Zeroizing::new([0u8; BLOCK_LEN + BLOCK_EXTRA_MAX]);
+    block[..BLOCK_BALLAST].copy_from_slice(&buf[wg_start..extra_start]);
+    block[BLOCK_BALLAST..BLOCK_BALLAST_HIGH].copy_from_slice(&buf[offset..nonce_offset]);
+    block[BLOCK_BALLAST_HIGH] = buf[offset - 1];
+    block[BLOCK_LEN..BLOCK_LEN + max_extra]
+        .copy_from_slice(&buf[extra_start..extra_start + max_extra]);
+
+    // Decrypt block in a single pass, as it was encrypted; the packet is only modified once
+    // it has been validated
+    cipher.apply_keystream(&mut block[..BLOCK_LEN + max_extra]);
+
+    // Drop packets of another framing version (or cipher mode) or with unknown flags.
+    // Authenticated packets are only accepted with auth enabled, and vice versa.
+    let version = block[BLOCK_VERSION];
+    if version >> 4 != format_version(config.cipher) || version & !KNOWN_FLAGS & 0x0f != 0 {
+        return None;
+    }
+    if (version & FLAG_AUTH != 0) != config.auth {
+        return None;
+    }
+    // Likewise for a wider encrypted region; a mismatch would restore a garbled message
+    if (version & FLAG_WIDE_HEADER != 0) != (config.encrypt_bytes > WG_HEADER_LEN) {
+        return None;
+    }
+    // Chaff carries nothing; it is dropped once it has validated
+    if version & FLAG_CHAFF != 0 {
+        return None;
     }
 
-    // Calculate new length and restore MAC2
-    let new_len = len - 1 - ballast_len - NONCE_LEN;
-    buf[new_len - MAC2_LEN..new_len].copy_from_slice(&block[17..]);
+    // Check that the message left after removing ballast and nonce is at least the smallest
+    // one, so the restored MAC2 does not overlap the header or the transport headers. A
+    // ballast length that does not fit the packet, or a wide one that would fit a byte, was
+    // not written by an obfuscator: the block decrypted to garbage (wrong key, corrupted
+    // bytes) and the packet is dropped rather than cut at a random offset. The previous key,
+    // if any, gets its turn.
+    let ballast_len = match version & FLAG_WIDE_BALLAST {
+        0 => block[BLOCK_BALLAST] as usize,
+        _ => u16::from_le_bytes([block[BLOCK_BALLAST], block[BLOCK_BALLAST_HIGH]]) as usize,
+    };
+    if (version & FLAG_WIDE_BALLAST != 0) != (ballast_len > u8::MAX as usize) {
+        return None;
+    }
+    if nonce_offset < wg_start + MIN_WG_LEN + LEN_VERSION_LEN + ballast_len {
+        return None;
+    }
 
-    // Fix UDP and IP headers as needed
-    match ip_version {
-        4 => ipv4::fix_udp_headers(&mut buf[..new_len]),
-        6 => ipv6::fix_udp_headers(&mut buf[..new_len]),
-        _ => {}
+    // Restore original fields, as many as were encrypted for a message of the new length
+    let new_len = nonce_offset - LEN_VERSION_LEN - ballast_len;
+    let header_len = encrypted_len(config, new_len - wg_start);
+    let extra = header_len - WG_HEADER_LEN;
+    let header_sum = ones_sum(&buf[wg_start..wg_start + header_len], wg_start);
+    buf[wg_start..extra_start].copy_from_slice(&block[..BLOCK_BALLAST]);
+    buf[extra_start..extra_start + extra].copy_from_slice(&block[BLOCK_LEN..BLOCK_LEN + extra]);
+
+    // Restore MAC2
+    let tail_start = new_len - MAC2_LEN;
+    let old_sum = ones_add(header_sum, ones_sum(&buf[tail_start..packet_len], tail_start));
+    buf[tail_start..new_len].copy_from_slice(&block[BLOCK_MAC2..BLOCK_BALLAST_HIGH]);
+
+    // Fix transport and IP headers as needed
+    let new_sum = rewritten_sum(buf, wg_start, header_len, tail_start, new_len);
+    fix_headers(&mut buf[..new_len], transport, transport_start, (old_sum, new_sum));
+
+    if is_handshake(&buf[wg_start..new_len]) {
+        debug!(
+            "Deobfuscated handshake message type {} ({} -> {} bytes)",
+            buf[wg_start], packet_len, new_len
+        );
     }
 
     Some(new_len)
@@ -232,17 +1040,41 @@ pub fn deobfuscate_wg_packet(buf: &mut [u8], config: &FilterConfig) -> Option<us
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{ascii_to_key, Direction, FilterConfig};
+    use crate::config::{Direction, FilterConfig, FilterConfigBuilder};
+    use crate::filter::keepalive::NoTracking;
 
     use super::*;
     use rand::rngs::SmallRng;
     use rand::SeedableRng;
+    #[cfg(feature = "std")]
+    use {
+        crate::config::{ascii_to_key, parse_config, LengthHiding, MTU_MAX},
+        crate::filter::keepalive::KeepaliveDropper,
+        crate::netutils::common::MAX_STACK_UDP,
+        std::time::Duration,
+    };
+
+    /// A rule on eth0 with the key the tests share, otherwise at the defaults.
+    fn builder() -> FilterConfigBuilder {
+        FilterConfig::builder().iface("eth0").key_from_ascii("secretkey")
+    }
+
+    /// [`transport_packet`] obfuscated by [`test_golden_output`].
+    const GOLDEN: &str = concat!(
+        "45000071000000004011667a0a0000010a000002ca6cca6c005da3c5", // IPv4 and UDP headers
+        "61574f2efc9ca2ff0f65b2fda393e2b0",                         // WireGuard header
+        "0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f", // payload
+        "101112",                                                   // ballast
+        "e12c4c65f02365f4db4b794e22c451e716bf",                     // length, version and MAC2
+        "0405060708090a0b0c0d0e0f",                                 // nonce
+    );
 
     /// Tests obfuscation and deobfuscation round-trip for a sample packet.
     ///
     /// This test ensures that after obfuscating and then deobfuscating a packet,
     /// the result matches the original input.
     #[test]
+    #[cfg(feature = "std")]
     fn test_obfuscate_and_deobfuscate() {
         let before: [u8; 156] = [
             0x45, 0x00, 0x00, 0x9c, 0x5e, 0x1c, 0x00, 0x00, 0x40, 0x11, 0x51, 0xf0, 0xd5, 0xa5,
@@ -259,8 +1091,7 @@ mod tests {
             0xff, 0x35,
         ];
 
-        let mut config =
-            FilterConfig { mtu: 256, key: [0u8; 32], queue_num: 0, direction: Direction::Out };
+        let mut config = builder().mtu(256).key(Zeroizing::new([0u8; 32])).build().unwrap();
         let mut dropper = KeepaliveDropper::new(0, 9);
         let mut rng = SmallRng::from_seed([0u8; 32]);
 
@@ -271,6 +1102,7 @@ mod tests {
 
         let obf_len = obfuscate_wg_packet(&mut buf, before.len(), &config, &mut dropper, &mut rng)
             .expect("obfuscation failed");
+        assert_checksums_recomputed(&buf[..obf_len]);
 
         config.direction = Direction::In;
         let deobf_len =
@@ -278,4 +1110,1414 @@ mod tests {
 
         assert_eq!(&buf[..deobf_len], &before[..], "deobfuscated != original");
     }
+
+    /// Asserts that the incrementally updated headers equal a full recomputation.
+    fn assert_checksums_recomputed(packet: &[u8]) {
+        let mut full = packet.to_vec();
+        match packet[0] >> 4 {
+            4 => ipv4::fix_udp_headers(&mut full),
+            _ => ipv6::fix_udp_headers(&mut full),
+        }
+        assert_eq!(packet, &full[..], "incremental checksum != full recomputation");
+    }
+
+    /// Tests that the handshake initiation, response and cookie reply are obfuscated, with
+    /// their type, sender index and MAC2 hidden on the wire, and restored over IPv4 and IPv6.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_handshake_messages() {
+        let config = builder().build().unwrap();
+        let mut dropper = KeepaliveDropper::new(1, 1);
+        let mut rng = SmallRng::from_seed([3u8; 32]);
+
+        for (msg_type, wg_len) in [(1u8, 148usize), (2, 92), (3, 64)] {
+            for ipv6 in [false, true] {
+                let mut packet = match ipv6 {
+                    false => vec![
+                        0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10,
+                        0, 0, 1, 10, 0, 0, 2,
+                    ],
+                    true => {
+                        let mut header = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+                        header.extend_from_slice(&[
+                            0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+                        ]);
+                        header.extend_from_slice(&[
+                            0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+                        ]);
+                        header
+                    }
+                };
+                let wg_start = packet.len() + 8;
+                packet.extend_from_slice(&[0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0]);
+                packet.extend_from_slice(&[msg_type, 0, 0, 0, 0x11, 0x22, 0x33, 0x44]);
+                packet.extend((8..wg_len - MAC2_LEN).map(|i| i as u8));
+                packet.extend_from_slice(&[0xee; MAC2_LEN]);
+                match ipv6 {
+                    false => ipv4::fix_udp_headers(&mut packet),
+                    true => ipv6::fix_udp_headers(&mut packet),
+                }
+                assert!(is_handshake(&packet[wg_start..]));
+
+                let mut buf = vec![0u8; config.mtu + OBFUSCATION_OVERHEAD];
+                buf[..packet.len()].copy_from_slice(&packet);
+                let obf_len =
+                    obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                        .expect("handshake dropped");
+                let wire = &buf[wg_start..obf_len];
+                assert!(obf_len >= packet.len() + 3 + fixed_overhead(&config));
+                assert_ne!(wire[..8], packet[wg_start..wg_start + 8], "type/index visible");
+                assert!(!wire.windows(MAC2_LEN).any(|w| w == [0xee; MAC2_LEN]), "MAC2 visible");
+                assert_checksums_recomputed(&buf[..obf_len]);
+
+                let deobf_len = deobfuscate_wg_packet(&mut buf[..obf_len], &config)
+                    .expect("deobfuscation failed");
+                assert_eq!(&buf[..deobf_len], &packet[..], "type {msg_type}, ipv6 {ipv6}");
+            }
+        }
+    }
+
+    /// Tests that DSCP marks are cleared by default and kept with `keep-dscp`, with valid
+    /// checksums either way; ECN is always kept.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_keep_dscp() {
+        let mut packet = transport_packet();
+        packet[1] = 0xb9; // EF (46) with ECN CE
+        ipv4::fix_udp_headers(&mut packet);
+        for keep_dscp in [false, true] {
+            let config = builder().mtu(256).keepalive(false).keep_dscp(keep_dscp).build().unwrap();
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut rng = SmallRng::from_seed([0u8; 32]);
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            assert_eq!(buf[1], if keep_dscp { 0xb9 } else { 0x01 });
+            assert_checksums_recomputed(&buf[..obf_len]);
+        }
+    }
+
+    /// Tests that messages below min-size pass both ways unchanged, while larger ones and
+    /// chaff of any size are obfuscated and restored.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_min_size() {
+        let config = builder().mtu(256).keepalive(false).min_size(69).build().unwrap();
+        let small = transport_packet();
+        let mut large = small.clone();
+        large.push(0x40);
+        ipv4::fix_udp_headers(&mut large);
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([0u8; 32]);
+
+        let mut buf = [0u8; 256];
+        buf[..small.len()].copy_from_slice(&small);
+        let len = obfuscate_wg_packet(&mut buf, small.len(), &config, &mut dropper, &mut rng);
+        assert_eq!(len, Some(small.len()));
+        assert_eq!(&buf[..small.len()], &small[..]);
+        assert_eq!(deobfuscate_wg_packet(&mut buf[..small.len()], &config), Some(small.len()));
+        assert_eq!(&buf[..small.len()], &small[..]);
+
+        buf[..large.len()].copy_from_slice(&large);
+        let obf_len = obfuscate_wg_packet(&mut buf, large.len(), &config, &mut dropper, &mut rng)
+            .expect("obfuscation failed");
+        assert!(obf_len > large.len());
+        let deobf_len =
+            deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+        assert_eq!(&buf[..deobf_len], &large[..]);
+
+        buf[..small.len()].copy_from_slice(&small);
+        let chaff_len = obfuscate_chaff_packet(&mut buf, small.len(), &config, &mut rng)
+            .expect("obfuscation failed");
+        assert!(chaff_len > small.len());
+        assert_eq!(deobfuscate_wg_packet(&mut buf[..chaff_len], &config), None);
+    }
+
+    /// Tests that the IPv6 Traffic Class follows keep-dscp like IPv4 DiffServ, and that the
+    /// Flow Label is only zeroed with flowlabel=zero.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_ipv6_traffic_class_and_flow_label() {
+        let mut packet = vec![0x6b, 0x9a, 0xbc, 0xde, 0, 0, 17, 64];
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0, 0x04, 0, 0, 0]);
+        packet.extend((0..64).map(|b| b as u8));
+        ipv6::fix_udp_headers(&mut packet);
+        let cases = [
+            (false, false, [0x60, 0x1a, 0xbc, 0xde]),
+            (true, false, [0x6b, 0x9a, 0xbc, 0xde]),
+            (false, true, [0x60, 0x10, 0x00, 0x00]),
+            (true, true, [0x6b, 0x90, 0x00, 0x00]),
+        ];
+        for (keep_dscp, zero_flow_label, expected) in cases {
+            let config = builder()
+                .mtu(256)
+                .keepalive(false)
+                .keep_dscp(keep_dscp)
+                .zero_flow_label(zero_flow_label)
+                .build()
+                .unwrap();
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut rng = SmallRng::from_seed([0u8; 32]);
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            assert_eq!(buf[..4], expected, "keep_dscp={keep_dscp} zero={zero_flow_label}");
+            assert_checksums_recomputed(&buf[..obf_len]);
+        }
+    }
+
+    /// Tests that keepalives are always obfuscated when keepalive thinning is disabled.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_keepalive_off_passes_keepalives() {
+        let packet = keepalive_packet();
+
+        let config = builder().mtu(256).keepalive(false).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(1, 1);
+        let mut rng = SmallRng::from_seed([0u8; 32]);
+
+        for _ in 0..8 {
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("keepalive dropped with keepalive=off");
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[28..deobf_len], &packet[28..]);
+        }
+    }
+
+    /// Tests that every keepalive goes out with thinning on when the peer state is kept by
+    /// [`NoTracking`], as firmware without `std` may do, with the generator behind a trait
+    /// object.
+    #[test]
+    fn test_no_tracking() {
+        let packet = keepalive_packet();
+
+        let config = builder().mtu(256).build().unwrap();
+        let mut seeded = SmallRng::from_seed([0u8; 32]);
+        let rng: &mut dyn RngCore = &mut seeded;
+
+        for _ in 0..8 {
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut NoTracking, rng)
+                    .expect("keepalive dropped without tracking");
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[28..deobf_len], &packet[28..]);
+        }
+    }
+
+    /// Tests that an [`Obfuscator`] transforms packets as the free functions do, and that two
+    /// of them, one per end, round-trip a packet.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_obfuscator_round_trip() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Obfuscator>();
+
+        let packet = transport_packet();
+        let config = builder().mtu(256).build().unwrap();
+        let mut sender = Obfuscator::with_rng(config.clone(), SmallRng::from_seed([3u8; 32]));
+        let mut receiver = Obfuscator::new(config.clone());
+
+        let mut rng = SmallRng::from_seed([3u8; 32]);
+        let mut dropper = KeepaliveDropper::new(1, 9);
+        for _ in 0..4 {
+            let mut buf = PacketBuf::new(&packet, config.mtu).data;
+            let obf_len = sender.obfuscate(&mut buf, packet.len()).expect("obfuscation failed");
+
+            let mut expected = PacketBuf::new(&packet, config.mtu).data;
+            let expected_len =
+                obfuscate_wg_packet(&mut expected, packet.len(), &config, &mut dropper, &mut rng)
+                    .unwrap();
+            assert_eq!(&buf[..obf_len], &expected[..expected_len]);
+
+            let deobf_len =
+                receiver.deobfuscate(&mut buf[..obf_len]).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+        }
+    }
+
+    /// Tests the errors of an [`Obfuscator`]: a thinned keepalive, a buffer without room to
+    /// grow and a packet under another key.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_obfuscator_errors() {
+        let keepalive = keepalive_packet();
+
+        let mut obfuscator = Obfuscator::new(builder().mtu(256).build().unwrap());
+        // The first keepalive of a peer without data starts a thinning burst
+        let mut buf = PacketBuf::new(&keepalive, 256).data;
+        assert_eq!(obfuscator.obfuscate(&mut buf, keepalive.len()), Err(Error::Drop));
+
+        let packet = transport_packet();
+        let mut buf = PacketBuf::new(&packet, 256).data;
+        buf.truncate(256);
+        assert_eq!(obfuscator.obfuscate(&mut buf, packet.len()), Err(Error::NoSpace));
+
+        let mut buf = PacketBuf::new(&packet, 256).data;
+        let obf_len = obfuscator.obfuscate(&mut buf, packet.len()).unwrap();
+        let mut other = Obfuscator::new(builder().key_from_ascii("otherkey").build().unwrap());
+        assert_eq!(other.deobfuscate(&mut buf[..obf_len]), Err(Error::Drop));
+        assert_eq!(obfuscator.deobfuscate(&mut buf[..obf_len]), Ok(packet.len()));
+    }
+
+    /// Tests that an IPv6 packet with a Hop-by-Hop header round-trips and keeps the header.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_ipv6_extension_header_round_trip() {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 0, 64];
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[17, 0, 1, 4, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0]);
+        packet.push(0x04);
+        packet.extend((1..=63).map(|b| b as u8));
+        ipv6::fix_udp_headers(&mut packet);
+
+        let config = builder().mtu(256).keepalive(false).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([0u8; 32]);
+
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let obf_len = obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+            .expect("obfuscation failed");
+        assert_checksums_recomputed(&buf[..obf_len]);
+        assert_eq!(&buf[40..48], &packet[40..48], "extension header modified");
+        assert_ne!(&buf[56..72], &packet[56..72], "WireGuard header not obfuscated");
+
+        let deobf_len =
+            deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+        assert_eq!(&buf[..deobf_len], &packet[..]);
+    }
+
+    /// Tests that every WireGuard message type, at its standard sizes and at sizes and
+    /// contents no standard message has, round-trips byte for byte with its first and last
+    /// 16 bytes hidden on the wire.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_every_message_type_round_trips() {
+        let config = builder().keepalive(false).build().unwrap();
+        let mut rng = SmallRng::from_seed([9u8; 32]);
+        let messages = [
+            ([1, 0, 0, 0], 148), // initiation
+            ([2, 0, 0, 0], 92),  // response
+            ([3, 0, 0, 0], 64),  // cookie reply: nonce and encrypted cookie, no MACs
+            ([4, 0, 0, 0], 32),  // keepalive
+            ([4, 0, 0, 0], 1000),
+            // Non-standard sizes, reserved bytes and types
+            ([3, 0, 0, 0], 65),
+            ([1, 0, 0, 0], 100),
+            ([2, 0, 1, 0], 92),
+            ([9, 0, 0, 0], 48),
+        ];
+        for (header, wg_len) in messages {
+            let mut packet = vec![
+                0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0,
+                1, 10, 0, 0, 2, 0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0,
+            ];
+            let mut message = vec![0u8; wg_len];
+            rng.fill_bytes(&mut message);
+            message[..4].copy_from_slice(&header);
+            packet.extend_from_slice(&message);
+            ipv4::fix_udp_headers(&mut packet);
+
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut buf = [0u8; 1600];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("message dropped");
+            let wire = &buf[28..obf_len];
+            assert_ne!(wire[..WG_HEADER_LEN], message[..WG_HEADER_LEN], "{header:?} {wg_len}");
+            let tail = &message[wg_len - MAC2_LEN..];
+            assert!(!wire.windows(MAC2_LEN).any(|w| w == tail), "{header:?} {wg_len}: tail");
+            assert_checksums_recomputed(&buf[..obf_len]);
+
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..], "{header:?} {wg_len}");
+        }
+    }
+
+    /// Tests that IPv4 packets with options (IHL 6 and 7) round-trip: the WireGuard
+    /// payload is found behind the options, the options are kept and the header checksum
+    /// covers them.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_ipv4_options_round_trip() {
+        let config = builder().mtu(256).keepalive(false).build().unwrap();
+        // Router Alert, then NOPs and End of Option List
+        let options: [&[u8]; 2] =
+            [&[0x94, 0x04, 0, 0], &[0x94, 0x04, 0, 0, 0x01, 0x01, 0x01, 0x00]];
+        for options in options {
+            let ihl = 20 + options.len();
+            let mut packet = vec![0x40 | (ihl / 4) as u8, 0, 0, 0, 0, 0, 0x40, 0, 64, 17, 0, 0];
+            packet.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2]);
+            packet.extend_from_slice(options);
+            packet.extend_from_slice(&[0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0]);
+            packet.extend_from_slice(&[0x04, 0, 0, 0]);
+            packet.extend((4..=63).map(|b| b as u8));
+            ipv4::fix_udp_headers(&mut packet);
+            let wg_start = ihl + 8;
+
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut rng = SmallRng::from_seed([0u8; 32]);
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            assert!(obf_len > packet.len());
+            assert_checksums_recomputed(&buf[..obf_len]);
+            assert_eq!(ones_sum(&buf[..ihl], 0), 0xffff, "header checksum does not cover options");
+            assert_eq!(&buf[20..ihl], options, "options modified");
+            assert_eq!(&buf[ihl..ihl + 4], &packet[ihl..ihl + 4], "UDP ports modified");
+            assert_ne!(&buf[wg_start..wg_start + 16], &packet[wg_start..wg_start + 16]);
+
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+        }
+    }
+
+    /// Generator that yields the bytes 0, 1, 2, ... so that the nonce and ballast are known.
+    struct CountingRng(u8);
+
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            let mut bytes = [0u8; 4];
+            self.fill_bytes(&mut bytes);
+            u32::from_le_bytes(bytes)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            let mut bytes = [0u8; 8];
+            self.fill_bytes(&mut bytes);
+            u64::from_le_bytes(bytes)
+        }
+
+        fn fill_bytes(&mut self, dst: &mut [u8]) {
+            for byte in dst {
+                *byte = self.0;
+                self.0 = self.0.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Tests the exact obfuscated bytes for a fixed key and a scripted generator, so that
+    /// any change to the wire format shows up here.
+    #[test]
+    fn test_golden_output() {
+        let packet = transport_packet();
+        let config = builder().mtu(256).keepalive(false).build().unwrap();
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let obf_len = obfuscate_wg_packet(
+            &mut buf,
+            packet.len(),
+            &config,
+            &mut NoTracking,
+            &mut CountingRng(0),
+        )
+        .expect("obfuscation failed");
+        assert_eq!(hex::encode(&buf[..obf_len]), GOLDEN);
+
+        let deobf_len =
+            deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+        assert_eq!(&buf[..deobf_len], &packet[..]);
+    }
+
+    /// Tests the whole pipeline at the largest MTU: a 9000-byte transport packet over IPv4
+    /// and IPv6 fits its packet buffer once obfuscated, gets checksums summed on the stack
+    /// path and round-trips, with and without a tag and a wide encrypted header.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_jumbo_frames() {
+        let lines = [
+            format!("0:out:eth0:secretkey:{}", MTU_MAX),
+            format!("1:out:eth0:secretkey:{}:auth=on:encrypt-bytes=128", MTU_MAX),
+        ];
+        let mut rng = SmallRng::seed_from_u64(9000);
+        for mut config in parse_config(&lines).unwrap() {
+            for ipv6 in [false, true] {
+                let header = if ipv6 { 48 } else { 28 };
+                let mut packet = if ipv6 {
+                    let mut packet = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+                    packet.extend([0x20, 0x01, 0x0d, 0xb8].iter().chain(&[0; 11]).chain(&[1]));
+                    packet.extend([0x20, 0x01, 0x0d, 0xb8].iter().chain(&[0; 11]).chain(&[2]));
+                    packet
+                } else {
+                    vec![
+                        0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10,
+                        0, 0, 1, 10, 0, 0, 2,
+                    ]
+                };
+                packet.extend([0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0, 0x04, 0, 0, 0]);
+                packet.extend((packet.len()..MTU_MAX).map(|i| (i * 7) as u8));
+                if ipv6 {
+                    ipv6::fix_udp_headers(&mut packet);
+                } else {
+                    ipv4::fix_udp_headers(&mut packet);
+                }
+
+                let mut buf = vec![0u8; config.mtu + OBFUSCATION_OVERHEAD];
+                buf[..packet.len()].copy_from_slice(&packet);
+                config.direction = Direction::Out;
+                let mut dropper = KeepaliveDropper::new(0, 9);
+                let obf_len =
+                    obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                        .expect("obfuscation failed");
+                assert!(obf_len > MTU_MAX && obf_len - header + 8 <= MAX_STACK_UDP);
+                assert_checksums_recomputed(&buf[..obf_len]);
+                assert_ne!(&buf[header..header + 32], &packet[header..header + 32]);
+
+                config.direction = Direction::In;
+                let len = deobfuscate_wg_packet(&mut buf[..obf_len], &config)
+                    .expect("deobfuscation failed");
+                assert_eq!(&buf[..len], &packet[..], "ipv6={}", ipv6);
+            }
+        }
+    }
+
+    /// Tests that the ballast comes from the profile of the rule or from the generator
+    /// passed in, on the wire where MAC2 was, and that the packets still round-trip.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_ballast_generator() {
+        let packet = transport_packet();
+        let ballast_at = packet.len() - MAC2_LEN;
+        let lines = ["0:out:eth0:secretkey:1500:ballast=zero".to_string()];
+        let mut config = parse_config(&lines).unwrap().remove(0);
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::seed_from_u64(7);
+
+        let filled = std::cell::Cell::new(0);
+        let marker = |ballast: &mut [u8], _: &mut dyn RngCore| {
+            filled.set(ballast.len());
+            ballast.fill(0x5A);
+        };
+        for custom in [false, true] {
+            let mut buf = vec![0u8; 1500 + OBFUSCATION_OVERHEAD];
+            buf[..packet.len()].copy_from_slice(&packet);
+            config.direction = Direction::Out;
+            let obf_len = if custom {
+                obfuscate_wg_packet_with(
+                    &mut buf,
+                    packet.len(),
+                    &config,
+                    &mut dropper,
+                    &mut rng,
+                    &marker,
+                )
+            } else {
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+            }
+            .unwrap();
+            let ballast_len = obf_len - packet.len() - fixed_overhead(&config);
+            assert!(ballast_len >= 3);
+            let expected = if custom { 0x5A } else { 0 };
+            assert!(buf[ballast_at..ballast_at + ballast_len].iter().all(|&b| b == expected));
+            assert_eq!(filled.get(), if custom { ballast_len } else { 0 });
+
+            config.direction = Direction::In;
+            let len = deobfuscate_wg_packet(&mut buf[..obf_len], &config).unwrap();
+            assert_eq!(&buf[..len], &packet[..]);
+        }
+    }
+
+    /// Tests that the port list selects packets by either port, in both directions, and
+    /// that packets between other ports pass unchanged.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_port_filter() {
+        let mut packet = transport_packet();
+        packet[20..24].copy_from_slice(&[0x9c, 0x40, 0xca, 0x71]); // 40000 -> 51825
+        ipv4::fix_udp_headers(&mut packet);
+        let mut rng = SmallRng::seed_from_u64(1854);
+        for (ports, expected) in [
+            ("51820", false),
+            ("51821-51830", true),
+            ("51820,51825", true),
+            ("40000", true),
+            ("1-39999,40001-51824", false),
+        ] {
+            let line = format!("0:out:eth0:secretkey:1500:port={}", ports);
+            let mut config = parse_config(&[line]).unwrap().remove(0);
+            let mut buf = vec![0u8; 1500 + OBFUSCATION_OVERHEAD];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let len = obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                .unwrap();
+            assert_eq!(len != packet.len(), expected, "{}", ports);
+
+            // Inbound, packets between other ports are passed on as they are
+            let mut obfuscated = buf.clone();
+            let unfiltered = FilterConfig { ports: Vec::new(), ..config.clone() };
+            let obf_len = obfuscate_wg_packet(
+                &mut obfuscated,
+                packet.len(),
+                &unfiltered,
+                &mut dropper,
+                &mut rng,
+            )
+            .unwrap();
+            config.direction = Direction::In;
+            let restored = deobfuscate_wg_packet(&mut obfuscated[..obf_len], &config);
+            let expected_len = if expected { packet.len() } else { obf_len };
+            assert_eq!(restored, Some(expected_len), "{}", ports);
+        }
+    }
+
+    /// Builds an IPv4 WireGuard keepalive: a transport packet with an empty payload.
+    fn keepalive_packet() -> [u8; 28 + 32] {
+        let mut packet = [0u8; 28 + 32];
+        packet[..20].copy_from_slice(&[
+            0x45, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2,
+        ]);
+        packet[20..28].copy_from_slice(&[0xca, 0x6c, 0xca, 0x6c, 0x00, 0x28, 0x00, 0x00]);
+        packet[28] = 0x04;
+        packet
+    }
+
+    /// Builds an IPv4 WireGuard transport packet with a zero UDP checksum.
+    fn transport_packet() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, 0xca, 0x6c, 0xca, 0x6c, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+        ];
+        packet.extend((0..64).map(|b| b as u8));
+        ipv4::fix_udp_headers(&mut packet);
+        packet
+    }
+
+    /// Tests that authenticated packets round-trip and that tampering is detected.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_auth_round_trip_and_tamper() {
+        let packet = transport_packet();
+        let config = builder().mtu(256).keepalive(false).auth(true).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([0u8; 32]);
+
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let obf_len = obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+            .expect("obfuscation failed");
+        assert_checksums_recomputed(&buf[..obf_len]);
+
+        for i in [28, 60, obf_len - TAG_LEN - 1, obf_len - 1] {
+            let mut tampered = buf;
+            tampered[i] ^= 0x01;
+            assert_eq!(deobfuscate_wg_packet(&mut tampered[..obf_len], &config), None);
+        }
+
+        // Without auth the trailing tag is taken for the nonce: never deobfuscated
+        let plain = FilterConfig { auth: false, ..config.clone() };
+        let mut copy = buf;
+        let result = deobfuscate_wg_packet(&mut copy[..obf_len], &plain);
+        assert!(result.is_none() || result == Some(obf_len));
+        assert_eq!(copy, buf);
+
+        let deobf_len =
+            deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+        assert_eq!(&buf[..deobf_len], &packet[..]);
+    }
+
+    /// Tests that a receiver in authenticated mode drops untagged packets.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_auth_receiver_drops_plain_packets() {
+        let packet = transport_packet();
+        let plain = builder().mtu(256).keepalive(false).build().unwrap();
+        let auth = FilterConfig { auth: true, ..plain.clone() };
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([1u8; 32]);
+
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let obf_len = obfuscate_wg_packet(&mut buf, packet.len(), &plain, &mut dropper, &mut rng)
+            .expect("obfuscation failed");
+        assert_eq!(deobfuscate_wg_packet(&mut buf[..obf_len], &auth), None);
+    }
+
+    /// Tests that packets with an unknown framing version are dropped untouched.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_unknown_version_dropped() {
+        let packet = transport_packet();
+        let config = builder().mtu(256).keepalive(false).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([2u8; 32]);
+
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let obf_len = obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+            .expect("obfuscation failed");
+
+        // The version byte is stream-encrypted: flipping a ciphertext bit flips the version
+        let version_at = obf_len - config.cipher.nonce_len() - MAC2_LEN - 1;
+        for flip in [0x10u8, 0x20, 0x08] {
+            let mut other = buf;
+            other[version_at] ^= flip;
+            let tampered = other;
+            assert_eq!(deobfuscate_wg_packet(&mut other[..obf_len], &config), None);
+            assert_eq!(other, tampered, "dropped packet was modified");
+        }
+    }
+
+    /// Tests that packets sent with the previous key are accepted during a rotation.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_previous_key_fallback() {
+        let packet = transport_packet();
+        let old = builder()
+            .mtu(256)
+            .key_from_ascii("oldkey")
+            .keepalive(false)
+            .auth(true)
+            .build()
+            .unwrap();
+        let rotated = FilterConfig {
+            key: ascii_to_key("newkey"),
+            prev_key: Some(ascii_to_key("oldkey")),
+            ..old.clone()
+        };
+        let new_only = FilterConfig { prev_key: None, ..rotated.clone() };
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([3u8; 32]);
+
+        for sender in [&old, &rotated] {
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), sender, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            let sent = buf;
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &rotated).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+
+            let mut buf = sent;
+            let result = deobfuscate_wg_packet(&mut buf[..obf_len], &new_only);
+            assert_eq!(result.is_some(), std::ptr::eq(sender, &rotated));
+        }
+    }
+
+    /// Tests that packets are padded to the nearest bucket, including buckets that need a
+    /// wide ballast length, and fall back to random ballast when no bucket fits.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_bucket_padding() {
+        let packet = transport_packet();
+        let config = builder()
+            .mtu(1400)
+            .keepalive(false)
+            .buckets(vec![64, 128, 1024, 1500])
+            .build()
+            .unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([5u8; 32]);
+
+        for (buckets, expected) in [(vec![64, 128, 1024], Some(128)), (vec![1024], Some(1024))]
+            .into_iter()
+            .chain([(vec![64, 1500], None), (vec![], None)])
+        {
+            let config = FilterConfig { buckets, ..config.clone() };
+            let mut buf = [0u8; 1500];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            match expected {
+                Some(size) => assert_eq!(obf_len, size),
+                None => {
+                    assert!(obf_len <= packet.len() + fixed_overhead(&config) + BALLAST_LEN_MAX)
+                }
+            }
+            assert_checksums_recomputed(&buf[..obf_len]);
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+        }
+    }
+
+    /// Tests that length hiding pads the packets of a flow to its recent maximum plus a
+    /// little jitter, never beyond the ceiling, and that they still round-trip.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_hide_length() {
+        let hide = LengthHiding { ceiling: 1000, window: Duration::from_secs(30) };
+        let config = builder().mtu(1400).keepalive(false).hide_length(hide).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([7u8; 32]);
+        let obfuscate = |payload: usize, dropper: &mut KeepaliveDropper, rng: &mut SmallRng| {
+            let mut packet = transport_packet();
+            packet.resize(32 + payload, 0x5a);
+            ipv4::fix_udp_headers(&mut packet);
+            let mut buf = [0u8; 1400 + OBFUSCATION_OVERHEAD];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let len = obfuscate_wg_packet(&mut buf, packet.len(), &config, dropper, rng)
+                .expect("obfuscation failed");
+            assert_checksums_recomputed(&buf[..len]);
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+            len
+        };
+
+        let first = obfuscate(600, &mut dropper, &mut rng);
+        assert!(first > 600 + 32);
+        for payload in [40, 64, 300, 40] {
+            let len = obfuscate(payload, &mut dropper, &mut rng);
+            assert!((first..=first + HIDE_LENGTH_JITTER).contains(&len), "{} -> {}", payload, len);
+        }
+
+        // Larger packets raise the maximum up to the ceiling; beyond it they keep their size
+        for payload in [900, 100] {
+            assert!(obfuscate(payload, &mut dropper, &mut rng) <= hide.ceiling);
+        }
+        assert!(obfuscate(1000, &mut dropper, &mut rng) > hide.ceiling);
+        assert!(obfuscate(40, &mut dropper, &mut rng) >= hide.ceiling - HIDE_LENGTH_JITTER);
+    }
+
+    /// Tests that handshake initiations are padded to sizes spread up to the MTU, or to the
+    /// fitting buckets, while data packets keep their small random ballast.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_handshake_ballast_policy() {
+        let mut initiation = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, 0xca, 0x6c, 0xca, 0x6c, 0, 0, 0, 0,
+        ];
+        initiation.extend_from_slice(&[1, 0, 0, 0]);
+        initiation.extend((4..148).map(|i| i as u8));
+        ipv4::fix_udp_headers(&mut initiation);
+        let data = transport_packet();
+        let lines = [
+            "0:out:eth0:secretkey:1400".to_string(),
+            "1:out:eth0:secretkey:1400:buckets=128,512,1024,1500".to_string(),
+        ];
+        let configs = parse_config(&lines).unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::seed_from_u64(1853);
+
+        let mut sizes = |config: &FilterConfig, packet: &[u8]| {
+            let mut seen = std::collections::BTreeSet::new();
+            for _ in 0..200 {
+                let mut buf = vec![0u8; config.mtu + OBFUSCATION_OVERHEAD];
+                buf[..packet.len()].copy_from_slice(packet);
+                let obf_len =
+                    obfuscate_wg_packet(&mut buf, packet.len(), config, &mut dropper, &mut rng)
+                        .unwrap();
+                let mut inbound = config.clone();
+                inbound.direction = Direction::In;
+                let len = deobfuscate_wg_packet(&mut buf[..obf_len], &inbound).unwrap();
+                assert_eq!(&buf[..len], packet);
+                seen.insert(obf_len);
+            }
+            seen
+        };
+
+        let min_len = initiation.len() + fixed_overhead(&configs[0]);
+        let handshakes = sizes(&configs[0], &initiation);
+        assert!(handshakes.iter().all(|&size| size >= min_len + 3 && size <= 1400));
+        assert!(handshakes.first() < Some(&400) && handshakes.last() > Some(&1200));
+        assert!(handshakes.iter().any(|&size| size - min_len > u8::MAX as usize));
+        let data_sizes = sizes(&configs[0], &data);
+        let data_max = data.len() + fixed_overhead(&configs[0]) + BALLAST_LEN_MAX;
+        assert!(data_sizes.iter().all(|&size| size <= data_max));
+
+        // With buckets: any bucket that fits, not always the smallest one
+        assert_eq!(sizes(&configs[1], &initiation).into_iter().collect::<Vec<_>>(), [512, 1024]);
+        assert_eq!(sizes(&configs[1], &data).into_iter().collect::<Vec<_>>(), [128]);
+    }
+
+    /// Tests that chaff validates like any obfuscated packet and is then discarded.
+    #[test]
+    fn test_chaff_discarded() {
+        let packet = transport_packet();
+        let config = builder().mtu(256).keepalive(false).auth(true).build().unwrap();
+        let mut rng = SmallRng::from_seed([6u8; 32]);
+
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let obf_len = obfuscate_chaff_packet(&mut buf, packet.len(), &config, &mut rng)
+            .expect("obfuscation failed");
+        assert!(obf_len > packet.len());
+        assert_checksums_recomputed(&buf[..obf_len]);
+        let sent = buf;
+        assert_eq!(deobfuscate_wg_packet(&mut buf[..obf_len], &config), None);
+        assert_eq!(buf, sent, "dropped chaff was modified");
+    }
+
+    /// Tests a round trip under each cipher mode, and that a peer on another mode drops the
+    /// packet untouched.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_cipher_modes() {
+        let packet = transport_packet();
+        let mut rng = SmallRng::from_seed([7u8; 32]);
+        for mode in CipherMode::ALL {
+            let config = builder().mtu(256).keepalive(false).cipher(mode).build().unwrap();
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+
+            for other in CipherMode::ALL.into_iter().filter(|other| *other != mode) {
+                let other = FilterConfig { cipher: other, ..config.clone() };
+                let mut copy = buf;
+                assert_eq!(deobfuscate_wg_packet(&mut copy[..obf_len], &other), None);
+                assert_eq!(copy, buf, "{mode:?} packet modified by {:?}", other.cipher);
+            }
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..], "{mode:?}");
+        }
+    }
+
+    /// Tests that `encrypt-bytes=` round-trips with every cipher, clipped short of MAC2 on
+    /// small messages, hides the bytes after the header when above 16, and that both ends
+    /// must agree on whether it is above 16.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_encrypt_bytes() {
+        let mut rng = SmallRng::from_seed([8u8; 32]);
+        for cipher in CipherMode::ALL {
+            for encrypt_bytes in [ENCRYPT_BYTES_MIN, 17, 48, ENCRYPT_BYTES_MAX] {
+                let config = builder()
+                    .mtu(256)
+                    .keepalive(false)
+                    .cipher(cipher)
+                    .encrypt_bytes(encrypt_bytes)
+                    .build()
+                    .unwrap();
+                let mut dropper = KeepaliveDropper::new(0, 9);
+                for (packet, wg_start) in
+                    [(transport_packet(), 28), (min_packet(), 28), (tcp_packet(false), 52)]
+                {
+                    let mut buf = [0u8; 256];
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    let obf_len = obfuscate_wg_packet(
+                        &mut buf,
+                        packet.len(),
+                        &config,
+                        &mut dropper,
+                        &mut rng,
+                    )
+                    .expect("obfuscation failed");
+                    if wg_start == 28 {
+                        assert_checksums_recomputed(&buf[..obf_len]);
+                    }
+
+                    // Bytes after the header are hidden up to the configured count, short
+                    // of MAC2, and left as they were beyond it
+                    let header_len = encrypt_bytes.min(packet.len() - wg_start - MAC2_LEN);
+                    let extra = wg_start + WG_HEADER_LEN..wg_start + header_len;
+                    if !extra.is_empty() {
+                        assert_ne!(buf[extra.clone()], packet[extra.clone()], "{encrypt_bytes}");
+                    }
+                    let plain = extra.end..packet.len() - MAC2_LEN;
+                    assert_eq!(buf[plain.clone()], packet[plain], "{encrypt_bytes}");
+
+                    // A peer on the other side of 16 drops the packet untouched
+                    let other = match encrypt_bytes {
+                        ENCRYPT_BYTES_MIN => 48,
+                        _ => ENCRYPT_BYTES_MIN,
+                    };
+                    let other = FilterConfig { encrypt_bytes: other, ..config.clone() };
+                    let mut copy = buf;
+                    assert_eq!(deobfuscate_wg_packet(&mut copy[..obf_len], &other), None);
+                    assert_eq!(copy, buf);
+
+                    let deobf_len = deobfuscate_wg_packet(&mut buf[..obf_len], &config)
+                        .expect("deobfuscation failed");
+                    assert_eq!(&buf[..deobf_len], &packet[..], "{cipher:?} {encrypt_bytes}");
+                }
+            }
+        }
+    }
+
+    /// Builds a WireGuard transport message in a TCP segment with a timestamp option.
+    fn tcp_packet(ipv6: bool) -> Vec<u8> {
+        let mut packet = match ipv6 {
+            false => vec![
+                0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 0, 0,
+                1, 10, 0, 0, 2,
+            ],
+            true => {
+                let mut packet = vec![0x60, 0, 0, 0, 0, 0, 6, 64];
+                packet.extend_from_slice(&[
+                    0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+                ]);
+                packet.extend_from_slice(&[
+                    0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+                ]);
+                packet
+            }
+        };
+        let tcp_start = packet.len();
+        packet.extend_from_slice(&[
+            0xca, 0x6c, 0x01, 0xbb, 0, 0, 0x10, 0, 0, 0, 0x20, 0, 0x80, 0x18, 0x01, 0xf5, 0, 0, 0,
+            0, 0x01, 0x01, 0x08, 0x0a, 0, 0, 0, 1, 0, 0, 0, 2,
+        ]);
+        packet.extend_from_slice(&[0x04, 0, 0, 0]);
+        packet.extend((0..80).map(|b| b as u8));
+        match ipv6 {
+            false => tcp::fix_ipv4_headers(&mut packet, tcp_start),
+            true => tcp::fix_ipv6_headers(&mut packet, tcp_start),
+        }
+        packet
+    }
+
+    /// Tests that WireGuard-over-TCP segments round-trip over IPv4 and IPv6, with the TCP
+    /// header kept and the TCP checksum valid on the wire.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_tcp_round_trip() {
+        let config = builder().mtu(256).auth(true).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([0u8; 32]);
+
+        for (ipv6, tcp_start) in [(false, 20), (true, 40)] {
+            let packet = tcp_packet(ipv6);
+            let wg_start = tcp_start + 32;
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            assert!(obf_len > packet.len());
+            // Everything but the checksum of the TCP header is kept
+            assert_eq!(&buf[tcp_start..tcp_start + 16], &packet[tcp_start..tcp_start + 16]);
+            assert_eq!(&buf[tcp_start + 18..wg_start], &packet[tcp_start + 18..wg_start]);
+            assert_ne!(&buf[wg_start..wg_start + 16], &packet[wg_start..wg_start + 16]);
+
+            let mut full = buf[..obf_len].to_vec();
+            match ipv6 {
+                false => tcp::fix_ipv4_headers(&mut full, tcp_start),
+                true => tcp::fix_ipv6_headers(&mut full, tcp_start),
+            }
+            assert_eq!(&buf[..obf_len], &full[..], "headers not fixed");
+
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+        }
+    }
+
+    /// Tests that TCP segments without a WireGuard message, such as pure ACKs, and other
+    /// protocols pass through unchanged in both directions.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_tcp_pure_ack_passes() {
+        let config = builder().mtu(256).build().unwrap();
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let mut rng = SmallRng::from_seed([0u8; 32]);
+
+        let mut icmp = tcp_packet(false);
+        icmp[9] = 1;
+        for packet in [tcp_packet(false)[..52].to_vec(), tcp_packet(true)[..72].to_vec(), icmp] {
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let len = obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng);
+            assert_eq!(len, Some(packet.len()));
+            assert_eq!(
+                deobfuscate_wg_packet(&mut buf[..packet.len()], &config),
+                Some(packet.len())
+            );
+            assert_eq!(&buf[..packet.len()], &packet[..]);
+        }
+    }
+
+    /// Builds an IPv4/UDP packet carrying the smallest WireGuard message handled.
+    fn min_packet() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, 0xca, 0x6c, 0xca, 0x6c, 0x00, 0x00, 0x00, 0x00,
+        ];
+        packet.extend((0..MIN_WG_LEN).map(|b| b as u8 ^ 0x5a));
+        packet[28] = 0x04;
+        ipv4::fix_udp_headers(&mut packet);
+        packet
+    }
+
+    /// Tests that the packets the transforms pass through are classified as the queue
+    /// counts them, and that those classified as malformed are indeed passed unchanged.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_skip_reason() {
+        let mut config = builder().mtu(100).keepalive(false).build().unwrap();
+        let packet = transport_packet(); // 96 bytes, a 68-byte message
+        let mut unknown = packet.clone();
+        unknown[0] = 0x55;
+        let mut icmp = packet.clone();
+        icmp[9] = 1;
+        let cut = |len: usize| {
+            let mut packet = packet[..len.min(packet.len())].to_vec();
+            packet.resize(len, 0);
+            ipv4::fix_udp_headers(&mut packet);
+            packet
+        };
+        let long = cut(packet.len() + 8);
+        let short = cut(20 + 8 + MIN_WG_LEN - 1);
+        let mut trailing = packet.clone();
+        trailing[3] -= 8;
+        let cases: [(&[u8], Option<Skip>, Option<Skip>); 10] = [
+            (&packet, None, None),
+            (&icmp, None, None),
+            (&[], Some(Skip::TooShort), Some(Skip::TooShort)),
+            (&unknown, Some(Skip::UnknownVersion), Some(Skip::UnknownVersion)),
+            (&long, Some(Skip::TooLarge), None),
+            (&packet[..16], Some(Skip::TooShort), Some(Skip::TooShort)),
+            (&short, Some(Skip::TooShort), Some(Skip::TooShort)),
+            // Long enough to obfuscate, too short to be obfuscated
+            (&cut(20 + 8 + MIN_WG_LEN), None, Some(Skip::TooShort)),
+            (&trailing, Some(Skip::Malformed), Some(Skip::Malformed)),
+            (&packet[..60], Some(Skip::Malformed), Some(Skip::Malformed)),
+        ];
+        let mut rng = SmallRng::from_seed([1u8; 32]);
+        for (i, (packet, out, inbound)) in cases.into_iter().enumerate() {
+            config.direction = Direction::Out;
+            assert_eq!(skip_reason(packet, &config), out, "case {i} out");
+            config.direction = Direction::In;
+            assert_eq!(skip_reason(packet, &config), inbound, "case {i} in");
+
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(packet);
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            if out.is_some() {
+                let len =
+                    obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng);
+                assert_eq!(len, Some(packet.len()), "case {i} out");
+            }
+            if inbound.is_some() {
+                let len = deobfuscate_wg_packet(&mut buf[..packet.len()], &config);
+                assert_eq!(len, Some(packet.len()), "case {i} in");
+            }
+            assert_eq!(&buf[..packet.len()], packet, "case {i}");
+        }
+    }
+
+    /// Tests that the allow and deny lists restrict both transforms to the listed sources,
+    /// for IPv4 and IPv6, and never hold back chaff.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_source_lists() {
+        let mut config = builder().keepalive(false).build().unwrap();
+        let cidrs = |list: &[&str]| list.iter().map(|c| cidr::Cidr::parse(c).unwrap()).collect();
+        let mut rng = SmallRng::from_seed([1u8; 32]);
+        let packet = transport_packet(); // from 10.0.0.1
+        let transform = |config: &FilterConfig, rng: &mut SmallRng| {
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let len = obfuscate_wg_packet(&mut buf, packet.len(), config, &mut dropper, rng);
+            let obfuscated = len != Some(packet.len());
+            buf[..packet.len()].copy_from_slice(&packet);
+            let chaff_len = obfuscate_chaff_packet(&mut buf, packet.len(), config, rng);
+            (obfuscated, chaff_len > Some(packet.len()))
+        };
+        let cases: [(&[&str], &[&str], bool); 6] = [
+            (&[], &[], true),
+            (&["10.0.0.0/8"], &[], true),
+            (&["192.168.0.0/16", "10.0.0.1"], &[], true),
+            (&["192.168.0.0/16", "2001:db8::/32"], &[], false),
+            (&[], &["10.0.0.0/30"], false),
+            (&["10.0.0.0/8"], &["10.0.0.1/32"], false),
+        ];
+        for (allow, deny, expected) in cases {
+            config.allow = cidrs(allow);
+            config.deny = cidrs(deny);
+            assert_eq!(transform(&config, &mut rng), (expected, true), "{allow:?} {deny:?}");
+        }
+
+        // Inbound, a denied source is passed unchanged instead of being dropped as garbage
+        config.allow = Vec::new();
+        config.deny = Vec::new();
+        let mut buf = [0u8; 256];
+        buf[..packet.len()].copy_from_slice(&packet);
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        let len =
+            obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng).unwrap();
+        let obfuscated = buf[..len].to_vec();
+        config.deny = cidrs(&["10.0.0.1"]);
+        assert_eq!(deobfuscate_wg_packet(&mut buf[..len], &config), Some(len));
+        assert_eq!(buf[..len], obfuscated[..]);
+        config.deny = cidrs(&["fd00::/8"]);
+        assert_eq!(deobfuscate_wg_packet(&mut buf[..len], &config), Some(packet.len()));
+
+        // IPv6 sources
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+        v6.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        v6.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        v6.extend_from_slice(&packet[20..]);
+        ipv6::fix_udp_headers(&mut v6);
+        config.deny = Vec::new();
+        for (allow, expected) in [("fd00::/8", true), ("fe80::/10", false), ("10.0.0.0/8", false)] {
+            config.allow = cidrs(&[allow]);
+            let mut buf = [0u8; 256];
+            buf[..v6.len()].copy_from_slice(&v6);
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let len = obfuscate_wg_packet(&mut buf, v6.len(), &config, &mut dropper, &mut rng);
+            assert_eq!(len != Some(v6.len()), expected, "{allow}");
+        }
+    }
+
+    /// Tests that a buffer of the MTU plus [`OBFUSCATION_OVERHEAD`] holds every obfuscated
+    /// packet up to the MTU, under every cipher, with and without the tag.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_obfuscation_overhead_bound() {
+        let mut rng = SmallRng::from_seed([5u8; 32]);
+        for cipher in CipherMode::ALL {
+            for auth in [false, true] {
+                let config =
+                    builder().mtu(300).keepalive(false).auth(auth).cipher(cipher).build().unwrap();
+                let mut dropper = KeepaliveDropper::new(0, 9);
+                for len in min_packet().len()..=config.mtu {
+                    let mut packet = min_packet();
+                    packet.resize(len, 0x33);
+                    ipv4::fix_udp_headers(&mut packet);
+                    let mut buf = vec![0u8; config.mtu + OBFUSCATION_OVERHEAD];
+                    buf[..len].copy_from_slice(&packet);
+                    let obf_len =
+                        obfuscate_wg_packet(&mut buf, len, &config, &mut dropper, &mut rng)
+                            .expect("buffer too small");
+                    assert!(obf_len > len, "{} auth {auth}: {len} not obfuscated", cipher.name());
+                }
+            }
+        }
+    }
+
+    /// Tests that the overhead reported for MTU planning bounds what obfuscation adds: packets
+    /// of up to `mtu - max` bytes get between `min + 3` and `max`, about `typical` on
+    /// average, and a packet of `mtu - min` bytes gets exactly `min`.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_overhead() {
+        let mut rng = SmallRng::from_seed([6u8; 32]);
+        let lines = ["0:out:eth0:key:400".to_string(), "1:out:eth0:key:400:auth=on".to_string()];
+        for config in parse_config(&lines).unwrap() {
+            let overhead = overhead(&config);
+            assert_eq!(overhead.min, fixed_overhead(&config));
+            assert!(overhead.min < overhead.typical && overhead.typical < overhead.max);
+            assert!(overhead.max <= OBFUSCATION_OVERHEAD);
+
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut obfuscate = |len: usize| {
+                let mut packet = transport_packet();
+                packet.resize(len, 0x33);
+                ipv4::fix_udp_headers(&mut packet);
+                let mut buf = vec![0u8; config.mtu + OBFUSCATION_OVERHEAD];
+                buf[..len].copy_from_slice(&packet);
+                obfuscate_wg_packet(&mut buf, len, &config, &mut dropper, &mut rng).unwrap() - len
+            };
+            let added: Vec<usize> =
+                (0..2000).map(|_| obfuscate(config.mtu - overhead.max)).collect();
+            assert!(added.iter().all(|n| (overhead.min + 3..=overhead.max).contains(n)));
+            assert_eq!(added.iter().max(), Some(&overhead.max));
+            let mean = added.iter().sum::<usize>() as f64 / added.len() as f64;
+            assert!((mean - overhead.typical as f64).abs() < 2.0, "mean {mean}");
+            assert_eq!(obfuscate(config.mtu - overhead.min), overhead.min);
+        }
+    }
+
+    /// Tests that a batch comes out as the same packets transformed one by one, that a packet
+    /// without room to grow or under another key is marked dropped, and that the batch
+    /// round-trips.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_batch() {
+        let config = &parse_config(&["0:out:eth0:key:400".to_string()]).unwrap()[0];
+        let packets: Vec<Vec<u8>> = (0..6)
+            .map(|i| {
+                let mut packet = transport_packet();
+                packet.resize(packet.len() + 40 * i, i as u8);
+                ipv4::fix_udp_headers(&mut packet);
+                packet
+            })
+            .collect();
+
+        let mut batch: Vec<PacketBuf> =
+            packets.iter().map(|packet| PacketBuf::new(packet, config.mtu)).collect();
+        batch[4].data.truncate(packets[4].len());
+        let mut rng = SmallRng::from_seed([8u8; 32]);
+        obfuscate_batch(&mut batch, config, &mut KeepaliveDropper::new(0, 9), &mut rng);
+
+        let mut rng = SmallRng::from_seed([8u8; 32]);
+        let mut dropper = KeepaliveDropper::new(0, 9);
+        for (i, packet) in packets.iter().enumerate() {
+            let mut buf = PacketBuf::new(packet, config.mtu).data;
+            if i == 4 {
+                buf.truncate(packet.len());
+            }
+            match obfuscate_wg_packet(&mut buf, packet.len(), config, &mut dropper, &mut rng) {
+                Some(len) => assert_eq!(batch[i].packet(), &buf[..len], "packet {i}"),
+                None => assert!(batch[i].drop && i == 4, "packet {i}"),
+            }
+        }
+        assert_eq!(batch.iter().filter(|packet| packet.drop).count(), 1);
+
+        let other = &parse_config(&["0:in:eth0:other:400".to_string()]).unwrap()[0];
+        let mut foreign = [batch[5].clone()];
+        deobfuscate_batch(&mut foreign, other);
+        assert!(foreign[0].drop);
+        // A reused buffer does not keep the drop of its last packet
+        deobfuscate_batch(&mut foreign, config);
+        assert!(!foreign[0].drop);
+        assert_eq!(foreign[0].packet(), &packets[5][..]);
+
+        batch.remove(4);
+        deobfuscate_batch(&mut batch, config);
+        let restored: Vec<&[u8]> = batch.iter().map(PacketBuf::packet).collect();
+        let expected: Vec<&[u8]> =
+            packets.iter().enumerate().filter(|(i, _)| *i != 4).map(|(_, p)| &p[..]).collect();
+        assert_eq!(restored, expected);
+        assert!(batch.iter().all(|packet| !packet.drop));
+    }
+
+    /// Tests that packets whose IHL, IP total length, IPv6 payload length or UDP length
+    /// disagree with their length pass both transforms unchanged, without a panic, and that
+    /// bytes beyond the claimed length never make it into an obfuscated packet.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_inconsistent_lengths() {
+        let config = &parse_config(&["0:out:eth0:key:400".to_string()]).unwrap()[0];
+        let packet = transport_packet();
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+        v6.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        v6.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        v6.extend_from_slice(&packet[20..]);
+        ipv6::fix_udp_headers(&mut v6);
+
+        let forge = |base: &[u8], at: usize, value: u8| {
+            let mut forged = base.to_vec();
+            forged[at] = value;
+            forged
+        };
+        let cases = [
+            forge(&packet, 0, 0x44),            // IHL of 16 bytes
+            forge(&packet, 0, 0x40),            // IHL of 0
+            forge(&packet, 0, 0x4f),            // IHL of 60, UDP length now off
+            forge(&packet[..40], 0, 0x4f),      // IHL of 60 beyond a 40-byte packet
+            forge(&packet, 3, packet[3] - 8),   // total length short of the packet
+            forge(&packet, 3, packet[3] + 8),   // total length beyond the packet
+            forge(&packet, 25, packet[25] - 1), // UDP length short of the segment
+            forge(&v6, 5, v6[5] - 8),           // IPv6 payload length short
+            forge(&v6, 5, v6[5] + 1),           // IPv6 payload length beyond
+            forge(&v6, 45, v6[45] + 4),         // UDP length beyond the segment
+            packet[..20].to_vec(),              // IPv4 header claiming 96 bytes
+        ];
+
+        let mut rng = SmallRng::from_seed([4u8; 32]);
+        for (i, case) in cases.iter().enumerate() {
+            let mut in_config = config.clone();
+            in_config.direction = Direction::In;
+            assert_eq!(skip_reason(case, config), Some(Skip::Malformed), "case {i}");
+            assert_eq!(skip_reason(case, &in_config), Some(Skip::Malformed), "case {i}");
+
+            // Stale bytes follow the packet in its buffer
+            let mut buf = [0xeeu8; 512];
+            buf[..case.len()].copy_from_slice(case);
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let len = obfuscate_wg_packet(&mut buf, case.len(), config, &mut dropper, &mut rng);
+            assert_eq!(len, Some(case.len()), "case {i}");
+            assert_eq!(&buf[..case.len()], &case[..], "case {i}");
+            assert!(buf[case.len()..].iter().all(|b| *b == 0xee), "case {i}");
+            assert_eq!(deobfuscate_wg_packet(&mut buf[..case.len()], config), Some(case.len()));
+            assert_eq!(&buf[..case.len()], &case[..], "case {i}");
+        }
+    }
+
+    /// Tests packets at the exact length boundaries: the smallest message obfuscated without
+    /// ballast round-trips, one byte less passes untouched (or is dropped in authenticated
+    /// mode), and a ballast length beyond the packet is rejected without modifying it.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_boundary_lengths() {
+        let packet = min_packet();
+        for auth in [false, true] {
+            let mut config =
+                FilterConfig { mtu: 0, ..builder().keepalive(false).auth(auth).build().unwrap() };
+            // An MTU that leaves no room for ballast
+            config.mtu = packet.len() + fixed_overhead(&config);
+            let mut dropper = KeepaliveDropper::new(0, 9);
+            let mut rng = SmallRng::from_seed([0u8; 32]);
+            let mut buf = [0u8; 256];
+            buf[..packet.len()].copy_from_slice(&packet);
+            let obf_len =
+                obfuscate_wg_packet(&mut buf, packet.len(), &config, &mut dropper, &mut rng)
+                    .expect("obfuscation failed");
+            let min_len = 28 + MIN_WG_LEN + LEN_VERSION_LEN + config.cipher.nonce_len();
+            let tag_len = if auth { TAG_LEN } else { 0 };
+            assert_eq!(obf_len, min_len + tag_len);
+            let obfuscated = buf[..obf_len].to_vec();
+
+            // One byte short of the smallest obfuscated packet
+            let mut short = obfuscated[..obf_len - 1].to_vec();
+            ipv4::fix_udp_headers(&mut short);
+            let unchanged = short.clone();
+            let expected = if auth { None } else { Some(short.len()) };
+            assert_eq!(deobfuscate_wg_packet(&mut short, &config), expected);
+            assert_eq!(short, unchanged);
+
+            // A ballast length of 1 or 40 bytes claimed in a packet without ballast, or a
+            // wide one, is dropped and the packet left as it is
+            if !auth {
+                let ballast_at = obf_len - config.cipher.nonce_len() - TRAILER_LEN;
+                for (at, flip) in [(0, 1), (0, 40), (1, FLAG_WIDE_BALLAST)] {
+                    let mut forged = obfuscated.clone();
+                    forged[ballast_at + at] ^= flip;
+                    let before = forged.clone();
+                    assert_eq!(deobfuscate_wg_packet(&mut forged, &config), None);
+                    assert_eq!(forged, before, "packet modified");
+                }
+            }
+
+            let deobf_len =
+                deobfuscate_wg_packet(&mut buf[..obf_len], &config).expect("deobfuscation failed");
+            assert_eq!(&buf[..deobf_len], &packet[..]);
+        }
+    }
+
+    /// Tests that random bytes behind UDP and TCP headers of every length up to past the
+    /// boundaries never panic and never grow the packet.
+    #[test]
+    fn test_deobfuscate_garbage() {
+        let mut rng = SmallRng::from_seed([7u8; 32]);
+        for auth in [false, true] {
+            let config = builder().direction(Direction::In).auth(auth).build().unwrap();
+            for (template, headers) in
+                [(min_packet(), 28), (tcp_packet(false), 52), (tcp_packet(true), 72)]
+            {
+                for len in 0..headers + 120 {
+                    for _ in 0..8 {
+                        let mut buf = template.clone();
+                        buf.resize(len.max(headers), 0);
+                        rng.fill(&mut buf[headers..]);
+                        buf.truncate(len);
+                        if let Some(new_len) = deobfuscate_wg_packet(&mut buf, &config) {
+                            assert!(new_len <= len);
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
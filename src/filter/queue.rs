@@ -18,6 +18,15 @@
 //! - Receives packets from the kernel, applies obfuscation or deobfuscation, and sets verdicts.
 //! - Handles panics and errors gracefully, automatically restarting the handler as needed.
 //! - Supports configurable MTU and direction for flexible deployment.
+//! - Optionally delays outbound verdicts for timing jitter without stalling the loop.
+//! - Spreads a rule over several worker threads, one queue each, that the kernel fans
+//!   packets out to (`workers=`). Workers keep their own RNG and keepalive state.
+//! - Drops packets beyond the packet or byte rate of the rule (`ratelimit=`, `bytelimit=`).
+//! - Accepts every packet unchanged while the rule is switched off (`enabled=off`),
+//!   which SIGHUP reads again without unbinding the queue.
+//! - Enlarges the socket receive buffer (`--rcvbuf`) and survives buffer overruns (ENOBUFS).
+//! - Unbinds queues explicitly when they are dropped, and waits out a binding still being
+//!   released when binding again on restart.
 //!
 //! ## Usage
 //! Use [`run_nfqueue_filter`] to start the event loop with a given [`FilterConfig`].
@@ -25,24 +34,382 @@
 //! ## Safety
 //! Panics are caught and logged; the handler is automatically restarted to ensure robustness.
 
-use crate::config::{Direction, FilterConfig};
+use crate::cipher;
+use crate::config::{self, Direction, FilterConfig, MTU_MAX};
+use crate::filter::chaff;
+use crate::filter::jitter::JitterQueue;
 use crate::filter::keepalive::KeepaliveDropper;
-use crate::filter::obfuscator::{deobfuscate_wg_packet, obfuscate_wg_packet};
+use crate::filter::obfuscator::{
+    deobfuscate_wg_packet, fixed_overhead, obfuscate_chaff_packet, obfuscate_wg_packet,
+    skip_reason, wg_message, Skip, OBFUSCATION_OVERHEAD,
+};
+use crate::filter::ratelimit::RateLimiter;
+use crate::filter::tap::PacketTap;
 use crate::randomiser;
+use crate::stats::{self, QueueStats, SizeHistogram};
+use log::{debug, error, info, trace, warn};
 use nfq::{Queue, Verdict};
-use std::panic;
+use nix::libc;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Longest wait for new packets while delayed packets are pending (jitter mode).
+const JITTER_POLL: Duration = Duration::from_millis(1);
+
+/// Bytes of packet data copied from the kernel beyond the MTU of the rule. Packets in the
+/// queue normally fit the MTU; the margin leaves room for a peer with a slightly larger one.
+const COPY_MARGIN: usize = 80;
+
+/// Default receive buffer of NFQUEUE sockets, enough to absorb bursts on fast links.
+pub const DEFAULT_RCVBUF: usize = 4 << 20;
+
+/// Attempts at binding a queue the kernel reports as taken before giving up.
+const BIND_ATTEMPTS: u32 = 10;
+
+/// Wait between attempts at binding a taken queue, for a previous binding to be released.
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Receive buffer requested for NFQUEUE sockets (`--rcvbuf`), 0 for the kernel default.
+static RCVBUF: AtomicUsize = AtomicUsize::new(DEFAULT_RCVBUF);
+
+/// Bumped by [`refresh_mtu`]; filter threads of `auto` MTU rules compare it to the value
+/// they last read the MTU at.
+static MTU_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of packet bytes the kernel copies to the filter of `filter`: the MTU plus
+/// [`COPY_MARGIN`], at most 65535. Longer packets arrive truncated.
+pub fn copy_range(filter: &FilterConfig) -> u16 {
+    (filter.mtu + COPY_MARGIN).min(u16::MAX as usize) as u16
+}
+
+/// Size of the packet buffer of `filter`: the copy range, or an MTU-sized packet with the
+/// most obfuscation can add to it, whichever is larger. An `auto` MTU may grow up to
+/// [`MTU_MAX`] while the filter runs, so its buffer is sized for that.
+fn buffer_len(filter: &FilterConfig) -> usize {
+    let mtu = if filter.mtu_auto { MTU_MAX } else { filter.mtu };
+    (mtu + COPY_MARGIN).min(u16::MAX as usize).max(mtu + OBFUSCATION_OVERHEAD)
+}
+
+/// Copies the payload of a packet into `buf`, as much of it as fits, and returns the length
+/// copied and whether that is all of it.
+///
+/// The buffer holds the copy range, so a payload never should exceed it; one that does all
+/// the same is cut rather than panicking the handler into a restart on every such packet.
+fn copy_payload(buf: &mut [u8], payload: &[u8]) -> (usize, bool) {
+    let len = payload.len().min(buf.len());
+    buf[..len].copy_from_slice(&payload[..len]);
+    (len, len == payload.len())
+}
+
+/// Why a received packet is not there in full, to pass it on unmodified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cut {
+    /// The kernel copied only the copy range of it.
+    CopyRange,
+    /// Its payload did not fit the packet buffer, see [`copy_payload`].
+    Buffer,
+}
+
+/// Tells whether a packet of `original_len` bytes, of which the kernel copied a payload of
+/// `payload_len` bytes, `complete` or not into the packet buffer, was cut, and where.
+fn cut_of(original_len: usize, payload_len: usize, complete: bool) -> Option<Cut> {
+    if !complete {
+        Some(Cut::Buffer)
+    } else if original_len > payload_len {
+        Some(Cut::CopyRange)
+    } else {
+        None
+    }
+}
+
+/// Makes the filter threads of rules with an `auto` MTU read the MTU of their interface
+/// again, before their next packet. Called on SIGHUP.
+pub fn refresh_mtu() {
+    MTU_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Reads the MTU of an `auto` rule again if [`refresh_mtu`] was called since `generation`,
+/// and sets the copy range of `queue` to match.
+fn follow_mtu(
+    filter: &mut FilterConfig,
+    queue: &mut Queue,
+    queue_num: u16,
+    generation: &mut usize,
+) -> io::Result<()> {
+    let current = MTU_GENERATION.load(Ordering::Relaxed);
+    if !filter.mtu_auto || current == *generation {
+        return Ok(());
+    }
+    *generation = current;
+    if config::resolve_auto_mtu(filter) {
+        queue.set_copy_range(queue_num, copy_range(filter))?;
+        info!(
+            queue = queue_num, event = "mtu", mtu = filter.mtu;
+            "NFQUEUE {} ({}): MTU now {}", queue_num, filter.iface, filter.mtu
+        );
+    }
+    Ok(())
+}
+
+/// Sets the receive buffer size, in bytes, of the NFQUEUE sockets opened from now on.
+pub fn set_rcvbuf(bytes: usize) {
+    RCVBUF.store(bytes, Ordering::Relaxed);
+}
+
+/// Reads an integer socket option of `fd`.
+fn get_int_sockopt(fd: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for writes of the sizes passed.
+    let ret =
+        unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, name, (&raw mut value).cast(), &mut len) };
+    (ret == 0).then_some(value)
+}
+
+/// Sets an integer socket option of `fd`.
+fn set_int_sockopt(fd: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` is valid for reads of the size passed.
+    let ret =
+        unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, name, (&raw const value).cast(), len) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sets the receive buffer of every netfilter netlink socket of this process to `bytes`.
+///
+/// `nfq` does not expose its socket, so the sockets are found through /proc/self/fd; the
+/// process opens no netfilter netlink sockets other than its queues. `SO_RCVBUFFORCE`
+/// (`CAP_NET_ADMIN`) may exceed `net.core.rmem_max`; plain `SO_RCVBUF`, capped by it, is
+/// the fallback.
+///
+/// # Returns
+/// * `Ok(size)` - The buffer size the kernel reports for the last socket, or 0 if none
+///   was found.
+fn set_receive_buffers(bytes: usize) -> io::Result<usize> {
+    let bytes = bytes.min(libc::c_int::MAX as usize / 2) as libc::c_int;
+    let mut size = 0;
+    for entry in std::fs::read_dir("/proc/self/fd")? {
+        let Some(fd) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        if get_int_sockopt(fd, libc::SO_DOMAIN) != Some(libc::AF_NETLINK)
+            || get_int_sockopt(fd, libc::SO_PROTOCOL) != Some(libc::NETLINK_NETFILTER)
+        {
+            continue;
+        }
+        set_int_sockopt(fd, libc::SO_RCVBUFFORCE, bytes)
+            .or_else(|_| set_int_sockopt(fd, libc::SO_RCVBUF, bytes))?;
+        // The kernel doubles the value for bookkeeping overhead and reports the doubled size
+        size = get_int_sockopt(fd, libc::SO_RCVBUF).unwrap_or_default() as usize / 2;
+    }
+    Ok(size)
+}
+
+/// An NFQUEUE socket bound to one queue, unbound explicitly when dropped.
+///
+/// Closing the socket releases the queue too, but leaves it to the kernel's socket teardown;
+/// unbinding first means a handler restarting on the same queue does not find it still
+/// held by its own previous binding.
+pub struct BoundQueue {
+    queue: Queue,
+    queue_num: u16,
+}
+
+impl Deref for BoundQueue {
+    type Target = Queue;
+
+    fn deref(&self) -> &Queue {
+        &self.queue
+    }
+}
+
+impl DerefMut for BoundQueue {
+    fn deref_mut(&mut self) -> &mut Queue {
+        &mut self.queue
+    }
+}
+
+impl Drop for BoundQueue {
+    fn drop(&mut self) {
+        // Wait for the kernel's acknowledgement even if the jitter loop left it non-blocking
+        self.queue.set_nonblocking(false);
+        match self.queue.unbind(self.queue_num) {
+            Ok(()) => debug!(
+                queue = self.queue_num, event = "unbind";
+                "NFQUEUE {}: unbound", self.queue_num
+            ),
+            Err(e) => debug!(
+                queue = self.queue_num, event = "unbind", error:% = e;
+                "NFQUEUE {}: failed to unbind: {}", self.queue_num, e
+            ),
+        }
+    }
+}
+
+/// Opens an NFQUEUE socket and binds it to `queue_num`, one of the queues of `filter`.
+///
+/// A queue the kernel reports as taken is tried again for up to half a second, in case
+/// a previous binding of this process is still being released.
+///
+/// Requires `CAP_NET_ADMIN`. Call this before dropping privileges and pass the
+/// returned queue to [`run_nfqueue_filter`].
+pub fn bind_queue(filter: &FilterConfig, queue_num: u16) -> io::Result<BoundQueue> {
+    // Open the NFQUEUE socket for packet interception
+    let mut q = Queue::open()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to open NFQUEUE: {e}")))?;
+
+    // Enlarge the receive buffer, and report overruns (ENOBUFS) instead of hiding them
+    let rcvbuf = RCVBUF.load(Ordering::Relaxed);
+    if rcvbuf > 0 {
+        match set_receive_buffers(rcvbuf) {
+            Ok(size) => debug!(
+                queue = queue_num, event = "rcvbuf";
+                "NFQUEUE {}: receive buffer {} bytes", queue_num, size
+            ),
+            Err(e) => warn!(
+                queue = queue_num, event = "rcvbuf", error:% = e;
+                "NFQUEUE {}: failed to set receive buffer: {}", queue_num, e
+            ),
+        }
+    }
+    q.set_recv_enobufs(true)?;
+
+    // Bind to the specified queue number
+    let mut attempt = 1;
+    let bound = loop {
+        match q.bind(queue_num) {
+            // Another socket holding the queue gets EPERM, the same socket EBUSY
+            Err(e)
+                if matches!(e.raw_os_error(), Some(libc::EPERM | libc::EBUSY))
+                    && attempt < BIND_ATTEMPTS =>
+            {
+                debug!(
+                    queue = queue_num, event = "bind_retry";
+                    "NFQUEUE {}: queue taken, retrying (attempt {})", queue_num, attempt
+                );
+                attempt += 1;
+                thread::sleep(BIND_RETRY_DELAY);
+            }
+            result => break result,
+        }
+    };
+    bound.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to bind NFQUEUE {}: {}. \
+                Probably, the queue is already occupied by another process. \
+                Try moving the queues of this instance up with the NF_WGOBFS_QUEUE environment \
+                variable, e.g. NF_WGOBFS_QUEUE=100.",
+                queue_num, e
+            ),
+        )
+    })?;
+
+    // Copy no more of each packet than the filter can process (the default is 64 KiB)
+    q.set_copy_range(queue_num, copy_range(filter)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to set the copy range of NFQUEUE {}: {}", queue_num, e),
+        )
+    })?;
+    Ok(BoundQueue { queue: q, queue_num })
+}
+
+/// Switches the queue of `stats` to passing packets unchanged, or back, logging a change.
+fn set_passthrough(stats: &QueueStats, filter: &FilterConfig, passthrough: bool) {
+    if stats.passthrough.swap(passthrough, Ordering::Relaxed) == passthrough {
+        return;
+    }
+    if passthrough {
+        info!(
+            queue = stats.queue_num, event = "disabled";
+            "NFQUEUE {} ({}): rule disabled, packets pass unchanged", stats.queue_num, filter.iface
+        );
+    } else {
+        info!(
+            queue = stats.queue_num, event = "enabled";
+            "NFQUEUE {} ({}): rule enabled", stats.queue_num, filter.iface
+        );
+    }
+}
+
+/// Applies the `enabled=` switch of `configs` to the running queues, e.g. after the
+/// configuration was read again on SIGHUP. Queues whose rule is gone keep their state.
+pub fn apply_enabled(configs: &[FilterConfig]) {
+    for stats in stats::snapshot() {
+        if let Some(filter) = configs.iter().find(|f| f.queues().contains(&stats.queue_num)) {
+            set_passthrough(&stats, filter, !filter.enabled);
+        }
+    }
+}
+
+/// Counts why a packet passed through unchanged, if it was malformed or out of range.
+fn count_skip(stats: &QueueStats, packet: &[u8], filter: &FilterConfig) {
+    let counter = match skip_reason(packet, filter) {
+        Some(Skip::UnknownVersion) => &stats.unknown_version,
+        Some(Skip::TooShort) => &stats.too_short,
+        Some(Skip::Malformed) => &stats.malformed,
+        Some(Skip::TooLarge) => &stats.too_large,
+        None => return,
+    };
+    QueueStats::add(counter, 1);
+}
+
+/// Counts a packet that obfuscation pushed from `len` to `new_len` bytes, beyond the MTU
+/// of `filter`, and warns with exponential back-off (the 1st, 2nd, 4th, 8th... time).
+///
+/// The packet is sent all the same: the kernel fragments it after the POSTROUTING hook where
+/// it may (IPv4 without DF, IPv6 sent by this host), and the original packet would be of no
+/// use to a peer that deobfuscates. The count tells whether "the tunnel works until packets
+/// get big" comes from a WireGuard MTU that leaves no room for the obfuscation overhead.
+fn report_oversized(
+    filter: &FilterConfig,
+    queue_num: u16,
+    stats: &QueueStats,
+    len: usize,
+    new_len: usize,
+) {
+    let count = stats.oversized.fetch_add(1, Ordering::Relaxed) + 1;
+    if count.is_power_of_two() {
+        warn!(
+            queue = queue_num, event = "oversized", len = new_len;
+            "NFQUEUE {} ({}): {}-byte packet obfuscated to {} bytes, over MTU {}; it is \
+            fragmented, or dropped where it cannot be. Lower the WireGuard MTU by at least {} \
+            bytes ({} such packets so far)",
+            queue_num,
+            filter.iface,
+            len,
+            new_len,
+            filter.mtu,
+            new_len - filter.mtu,
+            count
+        );
+    }
+}
 
 /// Runs the NFQUEUE filter event loop.
 ///
 /// This function binds to the specified NFQUEUE and enters a loop where it receives packets,
 /// applies obfuscation or deobfuscation depending on the direction, and sets the verdict
 /// (accept or drop) for each packet. If an error or panic occurs, the handler is restarted
-/// after a short delay to ensure continuous operation.
+/// after a short delay to ensure continuous operation. The packet buffer, the random
+/// generator, the keepalive state and the rate limits are kept across restarts.
 ///
 /// # Arguments
 /// * `filter` - The filter configuration, including queue number, direction, MTU, etc.
+/// * `queue_num` - The queue this worker serves, one of [`FilterConfig::queues`].
+/// * `queue` - A queue already bound with [`bind_queue`], or `None` to bind one here.
+///   A pre-bound queue is kept across restarts, so the handler keeps working after
+///   privileges have been dropped. A queue bound here is unbound before each restart.
 ///
 /// # Returns
 /// * `std::io::Result<()>` - Returns `Ok(())` on success, or an error if the handler fails to start.
@@ -52,160 +419,493 @@ use std::time::Duration;
 ///
 /// # Example
 /// ```no_run
-/// use crate::config::FilterConfig;
-/// run_nfqueue_filter(FilterConfig::default()).unwrap();
+/// # use nf_wgobfs::filter::queue::run_nfqueue_filter;
+/// let filter = nf_wgobfs::config::read_config().unwrap().remove(0);
+/// run_nfqueue_filter(filter, 0, None).unwrap();
 /// ```
-pub fn run_nfqueue_filter(filter: FilterConfig) -> std::io::Result<()> {
+pub fn run_nfqueue_filter(
+    mut filter: FilterConfig,
+    queue_num: u16,
+    queue: Option<BoundQueue>,
+) -> io::Result<()> {
+    let stats = stats::register(queue_num);
+    let key_fp = filter.key_fingerprints();
+    let _ = stats.key_fp.set(key_fp.clone());
+    let cipher = cipher::active_impl_name(filter.cipher);
+    let _ = stats.cipher.set(cipher);
+    set_passthrough(&stats, &filter, !filter.enabled);
+    // Packet sizes before and after the transform, with the edges of the rule
+    let sizes_in = stats.sizes_in.get_or_init(|| SizeHistogram::new(&filter.size_edges));
+    let sizes_out = stats.sizes_out.get_or_init(|| SizeHistogram::new(&filter.size_edges));
+    let keep_queue = queue.is_some();
+    let mut queue = queue;
+
+    // Packet state lives across restarts: only the queue is bound again. The buffer is
+    // allocated once (zeroed pages from the allocator, no per-restart fill), and keepalive
+    // thinning and rate limits carry on where they were. Jittered packets belong to the
+    // queue they came from and are not kept.
+    let mut buf = vec![0u8; buffer_len(&filter)];
+    let mut rng = randomiser::create_secure_rng();
+    let mut reseeder = randomiser::Reseeder::new(Instant::now());
+    let drop = filter.keepalive_drop;
+    let mut keepalive_dropper = KeepaliveDropper::new(drop.min, drop.max);
+    keepalive_dropper.set_idle_threshold(filter.keepalive_idle);
+    // Debugging capture (`tap=`, SIGUSR2), idle unless enabled
+    let mut tap = PacketTap::new(queue_num, filter.tap, Instant::now());
+    // Packet and byte limits of this queue, if the rule sets any
+    let mut limiter = RateLimiter::new(&filter, Instant::now());
+    // An `auto` MTU was read with the configuration; SIGHUP reads it again
+    let mut mtu_generation = MTU_GENERATION.load(Ordering::Relaxed);
     loop {
         // Catch panics to allow automatic restart of the handler
-        let result: Result<std::io::Result<()>, Box<dyn std::any::Any + Send>> =
-            panic::catch_unwind(|| {
-                // Open the NFQUEUE socket for packet interception
-                let mut q =
-                    Queue::open().map_err(|e| panic!("Failed to open NFQUEUE: {e}")).unwrap();
-
-                // Bind to the specified queue number
-                q.bind(filter.queue_num)
-                    .map_err(|e| {
-                        panic!(
-                            "Failed to bind NFQUEUE {}: {}. \
-                    Probably, the queue is already occupied by another process. \
-                    Try selecting another queue through the NF_WGOBFS_QUEUE environment variable.",
-                            filter.queue_num, e
-                        );
-                    })
-                    .unwrap();
-
-                #[cfg(debug_assertions)]
-                {
-                    println!(
-                        "User-space filter started (NFQUEUE{}), direction {:?}, mtu {}",
-                        filter.queue_num, filter.direction, filter.mtu
-                    );
+        let result: Result<io::Result<()>, Box<dyn std::any::Any + Send>> =
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                if queue.is_none() {
+                    queue = Some(bind_queue(&filter, queue_num)?);
                 }
+                let q = queue.as_mut().expect("queue is bound");
 
-                // Allocate buffer for packet processing
-                let buf_size = filter.mtu + 80;
-                let mut buf = vec![0u8; buf_size];
-                let mut rng = randomiser::create_secure_rng();
-                let mut keepalive_dropper = KeepaliveDropper::new(0, 9);
+                info!(
+                    queue = queue_num, event = "start", key_fp = key_fp.as_str(), cipher = cipher;
+                    "User-space filter started (NFQUEUE{}, {}), direction {:?}, mtu {}, key {}, \
+                    cipher {}",
+                    queue_num, filter.iface, filter.direction, filter.mtu, key_fp, cipher
+                );
+
+                // Outbound packets held back for timing jitter, verdicts issued when due
+                let mut jitter = filter
+                    .jitter
+                    .filter(|_| filter.direction == Direction::Out)
+                    .map(JitterQueue::new);
+                // Chaff of this rule: recognized by its token, sent toward the last flow seen
+                let chaff = filter
+                    .chaff
+                    .filter(|_| filter.direction == Direction::Out)
+                    .map(|_| chaff::state(filter.queue_num));
 
                 // Main packet processing loop
                 loop {
+                    // Release due packets; while some are pending, poll instead of blocking
+                    if let Some(jitter) = jitter.as_mut() {
+                        while let Some(msg) = jitter.pop_due(Instant::now()) {
+                            q.verdict(msg)?;
+                        }
+                        q.set_nonblocking(!jitter.is_empty());
+                    }
+
                     // Receive a packet from the queue
-                    let mut msg = q.recv().expect("Failed to receive from NFQUEUE");
-                    let pkt = msg.get_payload();
-                    let len = pkt.len();
-                    buf[..len].copy_from_slice(pkt);
-
-                    #[cfg(debug_assertions)]
-                    println!(
-                        "New packet in NFQUEUE {}: len={}, verdict={:?}",
-                        filter.queue_num,
-                        len,
-                        msg.get_verdict()
-                    );
+                    let mut msg = match q.recv() {
+                        Ok(msg) => msg,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            let next = jitter.as_ref().and_then(JitterQueue::next_deadline);
+                            let wait = next.map(|d| d.saturating_duration_since(Instant::now()));
+                            thread::sleep(wait.unwrap_or_default().min(JITTER_POLL));
+                            continue;
+                        }
+                        Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                            // The kernel dropped packets it could not queue; carry on
+                            warn!(
+                                queue = queue_num, event = "overrun";
+                                "NFQUEUE {} ({}): receive buffer overrun, packets were lost \
+                                (consider a larger --rcvbuf)",
+                                queue_num, filter.iface
+                            );
+                            continue;
+                        }
+                        Err(e) => panic!("Failed to receive from NFQUEUE: {e:?}"),
+                    };
+                    follow_mtu(&mut filter, q, queue_num, &mut mtu_generation)?;
+                    tap.poll(Instant::now());
+                    let payload_len = msg.get_payload().len();
+                    let (len, complete) = copy_payload(&mut buf, msg.get_payload());
+                    tap.before(&buf[..len]);
+                    // Packets cut by the kernel or by the buffer are passed on as is
+                    let cut = cut_of(msg.get_original_len(), payload_len, complete);
+                    QueueStats::add(&stats.packets, 1);
+                    QueueStats::add(&stats.bytes_in, payload_len as u64);
+                    sizes_in.record(payload_len);
+                    let passthrough = stats.passthrough.load(Ordering::Relaxed);
+                    let limited = !passthrough
+                        && limiter.as_mut().is_some_and(|l| !l.allow(len, Instant::now()));
 
-                    #[cfg(debug_assertions)]
-                    println!(
-                        "NFQUEUE {}: direction {:?}, payload_len={}",
-                        filter.queue_num, filter.direction, len
+                    trace!(
+                        queue = queue_num, event = "packet", len = len;
+                        "NFQUEUE {} ({}): new packet, direction {:?}, len={}",
+                        queue_num,
+                        filter.iface,
+                        filter.direction,
+                        len
                     );
 
                     // Process packet based on direction
                     match filter.direction {
+                        // Chaff is meant to reach the peer obfuscated only
+                        _ if passthrough
+                            && chaff.as_ref().is_some_and(|c| c.is_chaff(&buf[..len])) =>
+                        {
+                            QueueStats::add(&stats.dropped, 1);
+                            msg.set_verdict(Verdict::Drop);
+                        }
+                        _ if passthrough => {
+                            // Plain WireGuard either way, still data to the watchdog
+                            let message =
+                                wg_message(&buf[..len]).filter(|_| filter.watchdog.is_some());
+                            if let Some(message) = message {
+                                keepalive_dropper.record(message);
+                            }
+                            QueueStats::add(&stats.passed, 1);
+                            QueueStats::add(&stats.bytes_out, len as u64);
+                            tap.after(&buf[..len]);
+                            msg.set_verdict(Verdict::Accept);
+                        }
+                        _ if limited => {
+                            trace!(
+                                queue = queue_num, event = "rate_limited", len = len;
+                                "NFQUEUE {}: packet over the rate limit dropped", queue_num
+                            );
+                            QueueStats::add(&stats.dropped, 1);
+                            QueueStats::add(&stats.rate_limited, 1);
+                            msg.set_verdict(Verdict::Drop);
+                        }
+                        _ if cut == Some(Cut::Buffer) => {
+                            warn!(
+                                queue = queue_num, event = "truncated", len = payload_len;
+                                "NFQUEUE {} ({}): {}-byte packet exceeds the packet buffer ({} \
+                                bytes), passed unmodified",
+                                queue_num,
+                                filter.iface,
+                                payload_len,
+                                len
+                            );
+                            QueueStats::add(&stats.passed, 1);
+                            QueueStats::add(&stats.too_large, 1);
+                            tap.after(&buf[..len]);
+                            msg.set_verdict(Verdict::Accept);
+                        }
+                        _ if cut == Some(Cut::CopyRange) => {
+                            warn!(
+                                queue = queue_num, event = "truncated",
+                                len = msg.get_original_len();
+                                "NFQUEUE {} ({}): {}-byte packet exceeds the copy range ({} \
+                                bytes), passed unmodified",
+                                queue_num,
+                                filter.iface,
+                                msg.get_original_len(),
+                                payload_len
+                            );
+                            QueueStats::add(&stats.passed, 1);
+                            QueueStats::add(&stats.too_large, 1);
+                            tap.after(&buf[..len]);
+                            msg.set_verdict(Verdict::Accept);
+                        }
                         Direction::Out => {
-                            #[cfg(debug_assertions)]
-                            println!("Before obfuscation ({}): {:02x?}", len, &buf[..len]);
+                            trace!("Before obfuscation ({}): {:02x?}", len, &buf[..len]);
 
                             // Attempt to obfuscate the packet
-                            if let Some(new_len) = obfuscate_wg_packet(
-                                &mut buf,
-                                len,
-                                &filter,
-                                &mut keepalive_dropper,
-                                &mut rng,
-                            ) {
-                                #[cfg(debug_assertions)]
-                                {
-                                    println!(
-                                        "After obfuscation ({}): {:02x?}",
-                                        new_len,
-                                        &buf[..new_len]
-                                    );
+                            let is_chaff = chaff.as_ref().is_some_and(|c| c.is_chaff(&buf[..len]));
+                            let result = if is_chaff {
+                                obfuscate_chaff_packet(&mut buf, len, &filter, &mut rng)
+                            } else {
+                                if let Some(chaff) = chaff.as_ref() {
+                                    chaff.learn(&buf[..len]);
+                                }
+                                obfuscate_wg_packet(
+                                    &mut buf,
+                                    len,
+                                    &filter,
+                                    &mut keepalive_dropper,
+                                    &mut rng,
+                                )
+                            };
+                            if let Some(new_len) = result {
+                                trace!("After obfuscation ({}): {:02x?}", new_len, &buf[..new_len]);
+                                if new_len > len {
+                                    let counter =
+                                        if is_chaff { &stats.chaff } else { &stats.obfuscated };
+                                    QueueStats::add(counter, 1);
+                                    let ballast = new_len - len - fixed_overhead(&filter);
+                                    QueueStats::add(&stats.ballast_bytes, ballast as u64);
+                                    if new_len > filter.mtu {
+                                        report_oversized(&filter, queue_num, &stats, len, new_len);
+                                    }
+                                } else {
+                                    QueueStats::add(&stats.passed, 1);
+                                    count_skip(&stats, &buf[..len], &filter);
                                 }
+                                QueueStats::add(&stats.bytes_out, new_len as u64);
+                                tap.after(&buf[..new_len]);
                                 msg.set_payload(&buf[..new_len]);
                                 msg.set_verdict(Verdict::Accept);
                             } else {
-                                #[cfg(debug_assertions)]
-                                {
-                                    println!("Obfuscation skipped");
-                                }
+                                trace!(
+                                    queue = queue_num, event = "dropped", len = len;
+                                    "NFQUEUE {}: packet dropped", queue_num
+                                );
+                                QueueStats::add(&stats.dropped, 1);
+                                QueueStats::add(&stats.dropped_keepalive, 1);
                                 msg.set_verdict(Verdict::Drop);
                             }
                         }
                         Direction::In => {
-                            #[cfg(debug_assertions)]
-                            {
-                                println!("Deobfuscating packet ({}): {:02x?}", len, &buf[..len]);
-                            }
+                            trace!("Deobfuscating packet ({}): {:02x?}", len, &buf[..len]);
 
                             // Attempt to deobfuscate the packet
                             if let Some(new_len) = deobfuscate_wg_packet(&mut buf[..len], &filter) {
-                                #[cfg(debug_assertions)]
-                                {
-                                    println!(
-                                        "Deobfuscated packet ({}): {:02x?}",
-                                        new_len,
-                                        &buf[..new_len]
-                                    );
+                                trace!(
+                                    "Deobfuscated packet ({}): {:02x?}",
+                                    new_len,
+                                    &buf[..new_len]
+                                );
+                                if new_len < len {
+                                    QueueStats::add(&stats.deobfuscated, 1);
+                                    // Inbound data is only noted, for the watchdog
+                                    let message = wg_message(&buf[..new_len])
+                                        .filter(|_| filter.watchdog.is_some());
+                                    if let Some(message) = message {
+                                        keepalive_dropper.record(message);
+                                    }
+                                } else {
+                                    QueueStats::add(&stats.passed, 1);
+                                    count_skip(&stats, &buf[..len], &filter);
                                 }
+                                QueueStats::add(&stats.bytes_out, new_len as u64);
+                                tap.after(&buf[..new_len]);
                                 msg.set_payload(&buf[..new_len]);
                                 msg.set_verdict(Verdict::Accept);
                             } else {
-                                #[cfg(debug_assertions)]
-                                {
-                                    println!("Deobfuscation skipped");
-                                }
+                                trace!(
+                                    queue = queue_num, event = "dropped", len = len;
+                                    "NFQUEUE {}: packet dropped", queue_num
+                                );
+                                QueueStats::add(&stats.dropped, 1);
                                 msg.set_verdict(Verdict::Drop);
                             }
                         }
                     }
 
-                    #[cfg(debug_assertions)]
-                    {
-                        println!(
-                            "NFQUEUE {}: verdict={:?}, payload_len={}",
-                            filter.queue_num,
-                            msg.get_verdict(),
-                            msg.get_payload().len()
+                    if msg.get_verdict() == Verdict::Accept {
+                        sizes_out.record(msg.get_payload().len());
+                    }
+
+                    // The watchdog reads the time of the last data from the counters
+                    if filter.watchdog.is_some() {
+                        if let Some(at) = keepalive_dropper.last_data() {
+                            stats.set_last_data(at);
+                        }
+                    }
+
+                    trace!(
+                        queue = queue_num, event = "verdict", len = msg.get_payload().len(),
+                        verdict:? = msg.get_verdict();
+                        "NFQUEUE {}: verdict={:?}, payload_len={}",
+                        queue_num,
+                        msg.get_verdict(),
+                        msg.get_payload().len()
+                    );
+                    // Send verdict back to the queue, after the jitter delay if any
+                    let msg = match jitter.as_mut() {
+                        Some(jitter) if msg.get_verdict() == Verdict::Accept => {
+                            jitter.push(msg, Instant::now(), &mut rng)
+                        }
+                        _ => Some(msg),
+                    };
+                    if let Some(msg) = msg {
+                        q.verdict(msg)?;
+                    }
+                    // Fresh OS entropy now and then, with the packet already on its way
+                    if reseeder.tick(&mut rng) {
+                        debug!(
+                            queue = queue_num, event = "reseed";
+                            "NFQUEUE {}: generator reseeded", queue_num
                         );
                     }
-                    // Send verdict back to the queue
-                    q.verdict(msg)?;
                 }
-            });
+            }));
+        // Unbind now, before the pause, so the queue is free again by the time it is rebound
+        if !keep_queue {
+            queue = None;
+        }
 
         // Handle errors and panics, restart the handler if needed
         match result {
             Ok(Ok(())) => break,
             Ok(Err(e)) => {
-                eprintln!("NFQUEUE error: {e:?}");
+                QueueStats::add(&stats.restarts, 1);
+                stats.set_last_error(e.to_string());
+                error!(
+                    queue = queue_num, event = "error", error:% = e;
+                    "NFQUEUE {} ({}) error: {e:?}", queue_num, filter.iface
+                );
                 thread::sleep(Duration::from_secs(1));
-                eprintln!("Restarting NFQUEUE handler...");
+                error!(
+                    queue = queue_num, event = "restart";
+                    "Restarting NFQUEUE {} ({}) handler...", queue_num, filter.iface
+                );
             }
             Err(e) => {
-                if let Some(msg) = e.downcast_ref::<&str>() {
-                    eprintln!("NFQUEUE panic: {msg}");
+                QueueStats::add(&stats.restarts, 1);
+                let msg = if let Some(msg) = e.downcast_ref::<&str>() {
+                    msg.to_string()
                 } else if let Some(msg) = e.downcast_ref::<String>() {
-                    eprintln!("NFQUEUE panic: {msg}");
+                    msg.clone()
                 } else {
-                    eprintln!("NFQUEUE panic: unknown error");
-                }
+                    "unknown error".to_string()
+                };
+                error!(
+                    queue = queue_num, event = "panic", error = msg.as_str();
+                    "NFQUEUE {} ({}) panic: {msg}", queue_num, filter.iface
+                );
+                stats.set_last_error(msg);
                 thread::sleep(Duration::from_secs(1));
-                eprintln!("Restarting NFQUEUE handler after panic...");
+                error!(
+                    queue = queue_num, event = "restart";
+                    "Restarting NFQUEUE {} ({}) handler after panic...",
+                    queue_num, filter.iface
+                );
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType};
+    use std::os::fd::AsRawFd;
+
+    /// Tests that the `enabled=` switch reaches the running queues of a rule, workers
+    /// included, and leaves queues without a rule alone.
+    #[test]
+    fn test_apply_enabled() {
+        let rule = |options: &str| {
+            let line = format!("4370:in:eth0:key:workers=2{}", options);
+            crate::config::parse_config(&[line]).unwrap()
+        };
+        let (first, second, other) =
+            (stats::register(4370), stats::register(4371), stats::register(4372));
+        other.passthrough.store(true, Ordering::Relaxed);
+
+        apply_enabled(&rule(":enabled=off"));
+        assert!(first.passthrough.load(Ordering::Relaxed));
+        assert!(second.passthrough.load(Ordering::Relaxed));
+        apply_enabled(&rule(""));
+        assert!(!first.passthrough.load(Ordering::Relaxed));
+        assert!(!second.passthrough.load(Ordering::Relaxed));
+        assert!(other.passthrough.load(Ordering::Relaxed));
+    }
+
+    /// Tests that every packet over the MTU is counted.
+    #[test]
+    fn test_report_oversized() {
+        let filter =
+            crate::config::parse_config(&["0:out:eth0:key:1400".to_string()]).unwrap().remove(0);
+        let stats = stats::register(4344);
+        for _ in 0..5 {
+            report_oversized(&filter, 4344, &stats, 1390, 1404);
+        }
+        assert_eq!(stats.oversized.load(Ordering::Relaxed), 5);
+    }
+
+    /// Tests that the copy range covers the MTU and its margin within the 16-bit limit.
+    #[test]
+    fn test_copy_range() {
+        let mut filter =
+            crate::config::parse_config(&["0:out:eth0:key:1400".to_string()]).unwrap().remove(0);
+        assert_eq!(copy_range(&filter), 1480);
+        filter.mtu = 65535;
+        assert_eq!(copy_range(&filter), u16::MAX);
+    }
+
+    /// Tests that a payload larger than the packet buffer is cut to it and reported as
+    /// incomplete, and that one that fits is copied whole.
+    #[test]
+    fn test_copy_payload() {
+        let mut buf = [0u8; 8];
+        let payload: Vec<u8> = (1..=12).collect();
+        assert_eq!(copy_payload(&mut buf, &payload), (8, false));
+        assert_eq!(buf[..], payload[..8]);
+        assert_eq!(copy_payload(&mut buf, &payload[..5]), (5, true));
+        assert_eq!(buf[..5], payload[..5]);
+        assert_eq!(copy_payload(&mut buf, &[]), (0, true));
+    }
+
+    /// Tests that a payload over the packet buffer is reported as such, also when the
+    /// kernel cut it to the copy range, and that only a complete copy of a packet the kernel
+    /// cut reports the copy range.
+    #[test]
+    fn test_cut_of() {
+        let mut buf = [0u8; 8];
+        let payload = [0u8; 12];
+        let (len, complete) = copy_payload(&mut buf, &payload);
+        assert_eq!(len, 8);
+        assert_eq!(cut_of(12, payload.len(), complete), Some(Cut::Buffer));
+        assert_eq!(cut_of(1500, payload.len(), complete), Some(Cut::Buffer));
+
+        let (_, complete) = copy_payload(&mut buf, &payload[..6]);
+        assert_eq!(cut_of(1500, 6, complete), Some(Cut::CopyRange));
+        assert_eq!(cut_of(6, 6, complete), None);
+    }
+
+    /// Tests that the packet buffer of a jumbo-frame rule holds the copy range and an
+    /// obfuscated packet of the largest MTU.
+    #[test]
+    fn test_buffer_len_jumbo() {
+        let line = format!("0:out:eth0:key:{}", crate::config::MTU_MAX);
+        let filter = crate::config::parse_config(&[line]).unwrap().remove(0);
+        assert_eq!(copy_range(&filter) as usize, crate::config::MTU_MAX + COPY_MARGIN);
+        assert_eq!(buffer_len(&filter), crate::config::MTU_MAX + OBFUSCATION_OVERHEAD);
+    }
+
+    /// Tests that the buffer of an `auto` MTU rule holds a jumbo frame whatever the
+    /// interface reports, while the copy range follows the MTU read.
+    #[test]
+    fn test_buffer_len_auto() {
+        let line = "0:out:eth0:key:auto".to_string();
+        let mut filter = crate::config::parse_config(&[line]).unwrap().remove(0);
+        filter.mtu = 1400;
+        assert_eq!(copy_range(&filter), 1480);
+        assert_eq!(buffer_len(&filter), crate::config::MTU_MAX + OBFUSCATION_OVERHEAD);
+    }
+
+    /// Tests that a dropped queue is unbound: the queue can be bound again at once, while a
+    /// live binding still keeps it from another socket.
+    #[test]
+    fn test_bound_queue_unbinds_on_drop() {
+        let filter =
+            crate::config::parse_config(&["4346:out:eth0:key".to_string()]).unwrap().remove(0);
+        let first = match bind_queue(&filter, 4346) {
+            Ok(queue) => queue,
+            Err(e) => {
+                eprintln!("NFQUEUE not available ({}), skipping", e);
+                return;
+            }
+        };
+        let err = bind_queue(&filter, 4346).err().expect("queue bound twice");
+        assert!(err.to_string().contains("already occupied"), "{}", err);
+
+        drop(first);
+        let mut raw = Queue::open().unwrap();
+        raw.bind(4346).expect("queue still bound after drop");
+        raw.unbind(4346).unwrap();
+    }
+
+    /// Tests that netfilter netlink sockets get the requested buffer and other sockets do not.
+    #[test]
+    fn test_set_receive_buffers() {
+        let open = |protocol| {
+            socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC, protocol)
+        };
+        let (Ok(netfilter), Ok(route)) =
+            (open(SockProtocol::NetlinkNetFilter), open(SockProtocol::NetlinkRoute))
+        else {
+            eprintln!("netlink not available, skipping");
+            return;
+        };
+        let route_before = get_int_sockopt(route.as_raw_fd(), libc::SO_RCVBUF);
+
+        assert_eq!(set_receive_buffers(64 << 10).unwrap(), 64 << 10);
+        assert_eq!(get_int_sockopt(netfilter.as_raw_fd(), libc::SO_RCVBUF), Some(128 << 10));
+        assert_eq!(get_int_sockopt(route.as_raw_fd(), libc::SO_RCVBUF), route_before);
+    }
+}
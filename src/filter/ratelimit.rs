@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Token-bucket rate limiting of a queue.
+//!
+//! Every obfuscated packet is larger than the one that came in, so a flood through the
+//! filter would leave it amplified. A rule may cap its packets per second
+//! (`ratelimit=`), its bytes per second (`bytelimit=`) or both; [`RateLimiter`] checks
+//! every packet of a queue, in either direction, against them. A bucket holds up to
+//! `burst` tokens, refills at `rate` tokens per second, and a packet passes only if it can
+//! take its tokens (one, or its length in bytes) from every bucket.
+
+use crate::config::{FilterConfig, RateLimit};
+use std::time::Instant;
+
+/// A single token bucket.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a bucket for `limit`, full at `now`.
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        let burst = limit.burst as f64;
+        Self { rate: limit.rate as f64, burst, tokens: burst, last: now }
+    }
+
+    /// Adds the tokens accrued since the last refill, up to the burst size.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+/// The packet and byte limits of one queue.
+pub struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Creates the limiter of `filter`, or `None` if the rule sets no limit.
+    pub fn new(filter: &FilterConfig, now: Instant) -> Option<Self> {
+        if filter.packet_limit.is_none() && filter.byte_limit.is_none() {
+            return None;
+        }
+        Some(Self {
+            packets: filter.packet_limit.map(|limit| TokenBucket::new(limit, now)),
+            bytes: filter.byte_limit.map(|limit| TokenBucket::new(limit, now)),
+        })
+    }
+
+    /// Checks a packet of `len` bytes arriving at `now`.
+    ///
+    /// # Returns
+    /// * `true` - The packet is within the limits; its tokens are taken.
+    /// * `false` - The packet exceeds a limit and must be dropped; no tokens are taken, so
+    ///   dropped packets do not delay the ones after them.
+    pub fn allow(&mut self, len: usize, now: Instant) -> bool {
+        let mut buckets = [(self.packets.as_mut(), 1.0), (self.bytes.as_mut(), len as f64)];
+        let mut fits = true;
+        for (bucket, cost) in buckets.iter_mut() {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                fits &= bucket.tokens >= *cost;
+            }
+        }
+        if fits {
+            for (bucket, cost) in buckets.iter_mut() {
+                if let Some(bucket) = bucket {
+                    bucket.tokens -= *cost;
+                }
+            }
+        }
+        fits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use std::time::Duration;
+
+    fn limiter(options: &str, now: Instant) -> Option<RateLimiter> {
+        let line = format!("0:out:eth0:secretkey:1500:{}", options);
+        RateLimiter::new(&parse_config(&[line]).unwrap()[0], now)
+    }
+
+    /// Tests that a packet limit passes the burst at once and then the rate.
+    #[test]
+    fn test_packet_limit() {
+        let start = Instant::now();
+        let mut limiter = limiter("ratelimit=100,10", start).expect("limit configured");
+        let passed = (0..50).filter(|_| limiter.allow(60, start)).count();
+        assert_eq!(passed, 10);
+
+        // 100 packets per second: one every 10 ms
+        let passed =
+            (1..=100).filter(|i| limiter.allow(60, start + Duration::from_millis(i * 5))).count();
+        assert_eq!(passed, 50);
+
+        // The bucket refills up to the burst, not beyond
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..50).filter(|_| limiter.allow(60, later)).count(), 10);
+    }
+
+    /// Tests that a byte limit counts packet lengths, and that a packet must pass every
+    /// limit without a rejected one using up tokens.
+    #[test]
+    fn test_byte_and_packet_limits() {
+        let start = Instant::now();
+        let mut limiter =
+            limiter("bytelimit=10000,3000:ratelimit=1000,3", start).expect("limit configured");
+        assert!(limiter.allow(1500, start));
+        assert!(limiter.allow(1400, start));
+        // 100 bytes left: a large packet fails the byte limit, a small one passes
+        assert!(!limiter.allow(1500, start));
+        assert!(limiter.allow(100, start));
+        // The packet limit is exhausted too
+        assert!(!limiter.allow(1, start));
+        let later = start + Duration::from_millis(150);
+        assert!(limiter.allow(1500, later));
+    }
+
+    /// Tests that rules without limits get no limiter.
+    #[test]
+    fn test_no_limit() {
+        assert!(limiter("keepalive=on", Instant::now()).is_none());
+    }
+}
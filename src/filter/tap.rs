@@ -0,0 +1,429 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Debugging capture of the packets of a queue.
+//!
+//! A [`PacketTap`] writes the packets of one queue to a pcap file of raw IP packets, each
+//! as it arrived followed by what the filter made of it. It runs in the thread of its queue
+//! and writes through a buffer, so other queues are not slowed down. A capture starts with
+//! the queue if the rule sets `tap=`, and SIGUSR2 ([`toggle`]) starts one on every queue of
+//! the process, or stops it; with one process per queue, as in the generated units, that
+//! is one queue. A capture stops by itself after the packets and time of its [`Tap`], or
+//! at [`TAP_BYTES_MAX`] bytes, so it cannot fill the disk; nor can repeated captures, as a
+//! new one only gets what the captures already in the directory leave of [`TAP_TOTAL_MAX`].
+//!
+//! Captures go to the temporary directory (`$TMPDIR`, `/tmp` by default) as
+//! `nf_wgobfs-tap-<queue>-<unix time>.pcap`, the time to the nanosecond, readable by their
+//! owner only: they hold the tunnelled traffic as the kernel handed it over.
+
+use crate::config::Tap;
+use crate::pcap::{PcapWriter, RECORD_HEADER_LEN};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest capture file, whatever the limits of the tap.
+pub const TAP_BYTES_MAX: u64 = 64 << 20;
+
+/// Most bytes the capture files in the directory may take together; once they do, captures
+/// are refused until old ones are deleted.
+pub const TAP_TOTAL_MAX: u64 = 256 << 20;
+
+/// Size of the pcap file header.
+const FILE_HEADER_LEN: u64 = 24;
+
+/// Interval at which a running capture is flushed to its file. The process exits on SIGTERM
+/// without stopping the capture, so at most this much of it is lost then.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bumped by [`toggle`]; taps compare it to the value they last saw.
+static TAP_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts a capture on every tap that is not capturing and stops every running one, before
+/// their next packet. Called on SIGUSR2.
+pub fn toggle() {
+    TAP_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A capture file being written.
+struct Capture {
+    writer: PcapWriter<BufWriter<File>>,
+    path: PathBuf,
+    /// Packets recorded as they arrived.
+    packets: usize,
+    /// Bytes written, file header included.
+    bytes: u64,
+    /// Most bytes the file may take: [`TAP_BYTES_MAX`], or less if other captures take most
+    /// of [`TAP_TOTAL_MAX`].
+    max_bytes: u64,
+    deadline: Instant,
+    /// When the file was last flushed.
+    flushed: Instant,
+}
+
+/// Captures the packets of one queue while enabled; idle otherwise.
+pub struct PacketTap {
+    queue_num: u16,
+    limits: Tap,
+    dir: PathBuf,
+    generation: usize,
+    capture: Option<Capture>,
+}
+
+impl PacketTap {
+    /// Creates the tap of `queue_num`, capturing from `now` on if the rule sets `tap`.
+    /// Captures started by [`toggle`] use the limits of `tap`, or the defaults.
+    pub fn new(queue_num: u16, tap: Option<Tap>, now: Instant) -> Self {
+        Self::in_dir(queue_num, tap, std::env::temp_dir(), now)
+    }
+
+    /// Creates a tap writing its captures to `dir`.
+    fn in_dir(queue_num: u16, tap: Option<Tap>, dir: PathBuf, now: Instant) -> Self {
+        let mut this = PacketTap {
+            queue_num,
+            limits: tap.unwrap_or_default(),
+            dir,
+            generation: TAP_GENERATION.load(Ordering::Relaxed),
+            capture: None,
+        };
+        if tap.is_some() {
+            this.start(now);
+        }
+        this
+    }
+
+    /// Returns true while a capture is running.
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Starts or stops the capture if [`toggle`] was called since the last poll, and stops
+    /// it once its time is up. Call before each packet.
+    pub fn poll(&mut self, now: Instant) {
+        let generation = TAP_GENERATION.load(Ordering::Relaxed);
+        if generation != self.generation {
+            self.generation = generation;
+            match self.capture {
+                Some(_) => self.stop("stopped by signal"),
+                None => self.start(now),
+            }
+        }
+        let Some(capture) = self.capture.as_mut() else {
+            return;
+        };
+        if now >= capture.deadline {
+            self.stop("time limit reached");
+        } else if now >= capture.flushed + FLUSH_INTERVAL {
+            capture.flushed = now;
+            if let Err(e) = capture.writer.flush() {
+                self.fail(e);
+            }
+        }
+    }
+
+    /// Records `packet` as it arrived, unless the capture holds its packets already.
+    #[inline]
+    pub fn before(&mut self, packet: &[u8]) {
+        let limit = self.limits.packets;
+        if self.capture.as_ref().is_some_and(|capture| capture.packets >= limit) {
+            self.stop("packet limit reached");
+        } else if self.write(packet) {
+            self.capture.as_mut().expect("capture is running").packets += 1;
+        }
+    }
+
+    /// Records `packet` as the filter passes it on, after the packet given to [`before`].
+    ///
+    /// [`before`]: PacketTap::before
+    #[inline]
+    pub fn after(&mut self, packet: &[u8]) {
+        if self.capture.is_some() {
+            self.write(packet);
+        }
+    }
+
+    /// Writes one record; stops the capture instead at the size limit or on an error.
+    fn write(&mut self, packet: &[u8]) -> bool {
+        let Some(capture) = self.capture.as_mut() else {
+            return false;
+        };
+        let len = (RECORD_HEADER_LEN + packet.len()) as u64;
+        if capture.bytes + len > capture.max_bytes {
+            self.stop("size limit reached");
+            return false;
+        }
+        if let Err(e) = capture.writer.write_packet(SystemTime::now(), packet) {
+            self.fail(e);
+            return false;
+        }
+        capture.bytes += len;
+        true
+    }
+
+    /// Opens a new capture file, unless earlier captures leave no room for it.
+    fn start(&mut self, now: Instant) {
+        let used = captures_size(&self.dir);
+        if used + FILE_HEADER_LEN >= TAP_TOTAL_MAX {
+            warn!(
+                queue = self.queue_num, event = "tap_failed";
+                "NFQUEUE {}: not capturing: the captures in {} take {} bytes already (at most \
+                {}), delete old ones",
+                self.queue_num,
+                self.dir.display(),
+                used,
+                TAP_TOTAL_MAX
+            );
+            return;
+        }
+        match self.open(SystemTime::now()) {
+            Ok((writer, path)) => {
+                info!(
+                    queue = self.queue_num, event = "tap_start";
+                    "NFQUEUE {}: capturing up to {} packets for {}s to {}",
+                    self.queue_num,
+                    self.limits.packets,
+                    self.limits.duration.as_secs(),
+                    path.display()
+                );
+                self.capture = Some(Capture {
+                    writer,
+                    path,
+                    packets: 0,
+                    bytes: FILE_HEADER_LEN,
+                    max_bytes: TAP_BYTES_MAX.min(TAP_TOTAL_MAX - used),
+                    deadline: now + self.limits.duration,
+                    flushed: now,
+                });
+            }
+            Err(e) => warn!(
+                queue = self.queue_num, event = "tap_failed", error:% = e;
+                "NFQUEUE {}: cannot start a capture in {}: {}",
+                self.queue_num,
+                self.dir.display(),
+                e
+            ),
+        }
+    }
+
+    /// Creates the file of a capture started at `time`, failing rather than overwriting one.
+    fn open(&self, time: SystemTime) -> io::Result<(PcapWriter<BufWriter<File>>, PathBuf)> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = tap_path(&self.dir, self.queue_num, since_epoch);
+        let file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+        Ok((PcapWriter::new(BufWriter::new(file))?, path))
+    }
+
+    /// Ends the capture, flushing its file.
+    fn stop(&mut self, reason: &str) {
+        let Some(mut capture) = self.capture.take() else {
+            return;
+        };
+        match capture.writer.flush() {
+            Ok(()) => info!(
+                queue = self.queue_num, event = "tap_stop";
+                "NFQUEUE {}: capture of {} packets written to {} ({})",
+                self.queue_num,
+                capture.packets,
+                capture.path.display(),
+                reason
+            ),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Abandons the capture after a write error.
+    fn fail(&mut self, error: io::Error) {
+        if let Some(capture) = self.capture.take() {
+            warn!(
+                queue = self.queue_num, event = "tap_failed", error:% = error;
+                "NFQUEUE {}: capture to {} abandoned: {}",
+                self.queue_num,
+                capture.path.display(),
+                error
+            );
+        }
+    }
+}
+
+impl Drop for PacketTap {
+    /// Flushes a running capture, e.g. when the handler restarts.
+    fn drop(&mut self) {
+        self.stop("queue stopped");
+    }
+}
+
+/// Path of the capture of `queue_num` started at `since_epoch`. The time goes down to the
+/// nanosecond, so that captures toggled in quick succession get files of their own.
+fn tap_path(dir: &Path, queue_num: u16, since_epoch: Duration) -> PathBuf {
+    dir.join(format!(
+        "nf_wgobfs-tap-{}-{}.{:09}.pcap",
+        queue_num,
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    ))
+}
+
+/// Total size of the capture files in `dir`, of every queue; 0 if it cannot be listed.
+fn captures_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("nf_wgobfs-tap-") && name.ends_with(".pcap")
+        })
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for the captures of one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nf_wgobfs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns the only capture file in `dir`.
+    fn capture_in(dir: &Path) -> Vec<u8> {
+        let files: Vec<_> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(files.len(), 1, "{:?}", files);
+        std::fs::read(&files[0]).unwrap()
+    }
+
+    /// Tests that a tap records packets before and after the transform and stops at its
+    /// packet limit, and that an idle tap writes nothing.
+    #[test]
+    fn test_tap_packet_limit() {
+        let dir = test_dir("tap-limit");
+        let now = Instant::now();
+        let limits = Tap { packets: 2, duration: Duration::from_secs(60) };
+
+        let mut idle = PacketTap::in_dir(1, None, dir.clone(), now);
+        idle.before(&[0x45; 40]);
+        idle.after(&[0x45; 80]);
+        assert!(!idle.is_capturing());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let mut tap = PacketTap::in_dir(1, Some(limits), dir.clone(), now);
+        for _ in 0..5 {
+            tap.poll(now);
+            tap.before(&[0x45; 40]);
+            tap.after(&[0x45; 80]);
+        }
+        assert!(!tap.is_capturing());
+        let capture = capture_in(&dir);
+        assert_eq!(capture.len(), 24 + 2 * (2 * RECORD_HEADER_LEN + 40 + 80));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a running capture reaches its file once a flush interval has passed.
+    #[test]
+    fn test_tap_flushes_while_running() {
+        let dir = test_dir("tap-flush");
+        let now = Instant::now();
+        let mut tap = PacketTap::in_dir(5, Some(Tap::default()), dir.clone(), now);
+        tap.before(&[0x45; 40]);
+        tap.poll(now + FLUSH_INTERVAL);
+        assert!(tap.is_capturing());
+        assert_eq!(capture_in(&dir).len(), 24 + RECORD_HEADER_LEN + 40);
+        drop(tap);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a capture stops at its deadline and at the size limit.
+    #[test]
+    fn test_tap_time_and_size_limits() {
+        let dir = test_dir("tap-bounds");
+        let now = Instant::now();
+        let limits = Tap { packets: 100_000, duration: Duration::from_secs(1) };
+
+        let mut tap = PacketTap::in_dir(2, Some(limits), dir.clone(), now);
+        tap.before(&[0x45; 40]);
+        tap.poll(now + Duration::from_millis(999));
+        assert!(tap.is_capturing());
+        tap.poll(now + Duration::from_secs(1));
+        assert!(!tap.is_capturing());
+        assert_eq!(capture_in(&dir).len(), 24 + RECORD_HEADER_LEN + 40);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let dir = test_dir("tap-size");
+        let mut tap = PacketTap::in_dir(3, Some(limits), dir.clone(), now);
+        let packet = vec![0x60u8; 9000];
+        for _ in 0..10_000 {
+            tap.before(&packet);
+        }
+        assert!(!tap.is_capturing());
+        assert!(capture_in(&dir).len() as u64 <= TAP_BYTES_MAX);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a capture does not overwrite an existing file, and that captures toggled
+    /// within one second get files of their own.
+    #[test]
+    fn test_tap_never_overwrites() {
+        let dir = test_dir("tap-exists");
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let tap = PacketTap::in_dir(4, None, dir.clone(), Instant::now());
+        std::fs::write(tap_path(&dir, 4, time.duration_since(UNIX_EPOCH).unwrap()), b"keep")
+            .unwrap();
+        let err = tap.open(time).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(std::fs::read_dir(&dir)
+            .unwrap()
+            .all(|e| std::fs::read(e.unwrap().path()).unwrap() == b"keep"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let dir = test_dir("tap-toggle");
+        let now = Instant::now();
+        let mut tap = PacketTap::in_dir(4, None, dir.clone(), now);
+        for _ in 0..4 {
+            toggle();
+            tap.poll(now);
+            assert!(tap.is_capturing());
+            toggle();
+            tap.poll(now);
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that captures are refused once the files in the directory take
+    /// [`TAP_TOTAL_MAX`], and that a new one only gets what is left of it.
+    #[test]
+    fn test_tap_total_limit() {
+        let dir = test_dir("tap-total");
+        let old = File::create(dir.join("nf_wgobfs-tap-9-1.000000000.pcap")).unwrap();
+        old.set_len(TAP_TOTAL_MAX).unwrap();
+        let tap = PacketTap::in_dir(6, Some(Tap::default()), dir.clone(), Instant::now());
+        assert!(!tap.is_capturing());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        old.set_len(TAP_TOTAL_MAX - 1000).unwrap();
+        let mut tap = PacketTap::in_dir(6, Some(Tap::default()), dir.clone(), Instant::now());
+        for _ in 0..10 {
+            tap.before(&[0x45; 100]);
+        }
+        assert!(!tap.is_capturing());
+        assert!(captures_size(&dir) <= TAP_TOTAL_MAX);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
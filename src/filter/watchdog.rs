@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Idle watchdog: reports tunnels that stopped carrying data.
+//!
+//! A tunnel whose peer went away keeps sending keepalives for a while, so the packet
+//! counters alone do not tell it from a healthy one. The keepalive dropper of every queue
+//! already notes when a message other than a keepalive last went through; with
+//! `watchdog=` set, the queue publishes that time in its [`QueueStats`]. A single thread
+//! looks at these every [`CHECK_INTERVAL`] and logs a warning once a rule has carried no
+//! data on any of its worker queues for its timeout, and a notice when data resumes.
+
+use crate::config::FilterConfig;
+use crate::stats::{self, QueueStats};
+use log::{info, warn};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the watchdog looks at the queues.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watch state of one rule with a `watchdog=` timeout.
+struct Watch {
+    queue_num: u16,
+    iface: String,
+    timeout: Duration,
+    /// Counters of the queues of the rule, one per worker: a flow sticks to one of them.
+    queues: Vec<Arc<QueueStats>>,
+    /// When the rule last carried data, or when watching started if it has not yet.
+    since: Instant,
+    /// Set while the rule is reported idle.
+    idle: bool,
+}
+
+/// Idle state of the rules that set `watchdog=`.
+pub struct Watchdog {
+    watches: Vec<Watch>,
+}
+
+impl Watchdog {
+    /// Watches the rules of `configs` that set `watchdog=`, counting from `now`.
+    pub fn new(configs: &[FilterConfig], now: Instant) -> Self {
+        let watches = configs
+            .iter()
+            .filter_map(|filter| {
+                Some(Watch {
+                    queue_num: filter.queue_num,
+                    iface: filter.iface.clone(),
+                    timeout: filter.watchdog?,
+                    queues: filter.queues().map(stats::register).collect(),
+                    since: now,
+                    idle: false,
+                })
+            })
+            .collect();
+        Self { watches }
+    }
+
+    /// Logs the rules that went idle or carried data again since the last check.
+    pub fn check(&mut self, now: Instant) {
+        for watch in &mut self.watches {
+            let last = watch.queues.iter().filter_map(|queue| queue.last_data()).max();
+            if let Some(last) = last.filter(|last| *last > watch.since) {
+                if watch.idle {
+                    info!(
+                        queue = watch.queue_num, event = "resumed";
+                        "NFQUEUE {} ({}): data flowing again after {}s",
+                        watch.queue_num,
+                        watch.iface,
+                        last.saturating_duration_since(watch.since).as_secs()
+                    );
+                    watch.idle = false;
+                }
+                watch.since = last;
+            }
+            let quiet = now.saturating_duration_since(watch.since);
+            if !watch.idle && quiet >= watch.timeout {
+                warn!(
+                    queue = watch.queue_num, event = "idle";
+                    "NFQUEUE {} ({}): no data for {}s, the tunnel may be down",
+                    watch.queue_num,
+                    watch.iface,
+                    quiet.as_secs()
+                );
+                watch.idle = true;
+            }
+        }
+    }
+
+    /// Returns the queue numbers of the rules currently reported idle.
+    pub fn idle(&self) -> Vec<u16> {
+        self.watches.iter().filter(|watch| watch.idle).map(|watch| watch.queue_num).collect()
+    }
+}
+
+/// Starts the watchdog thread for the rules of `configs` that set `watchdog=`, if any.
+pub fn spawn(configs: &[FilterConfig]) {
+    let mut watchdog = Watchdog::new(configs, Instant::now());
+    if watchdog.watches.is_empty() {
+        return;
+    }
+    info!("Watching {} rule(s) for idle tunnels", watchdog.watches.len());
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        watchdog.check(Instant::now());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    /// Tests that a rule is reported idle once none of its queues carried data for the
+    /// timeout, and no longer once any of them does, while rules without a timeout are not
+    /// watched.
+    #[test]
+    fn test_watchdog_idle_and_resume() {
+        let lines = vec![
+            "4360:out:wg0:secret:workers=2:watchdog=10".to_string(),
+            "4362:in:wg0:secret".to_string(),
+        ];
+        let configs = parse_config(&lines).unwrap();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut watchdog = Watchdog::new(&configs, start);
+        assert_eq!(watchdog.watches.len(), 1);
+
+        watchdog.check(at(9));
+        assert!(watchdog.idle().is_empty());
+        watchdog.check(at(10));
+        assert_eq!(watchdog.idle(), vec![4360]);
+
+        // Data on the second worker queue counts for the rule
+        stats::register(4361).set_last_data(at(12));
+        watchdog.check(at(13));
+        assert!(watchdog.idle().is_empty());
+        watchdog.check(at(21));
+        assert!(watchdog.idle().is_empty());
+        watchdog.check(at(23));
+        assert_eq!(watchdog.idle(), vec![4360]);
+        watchdog.check(at(60));
+        assert_eq!(watchdog.idle(), vec![4360]);
+    }
+}
@@ -0,0 +1,55 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Library behind the nf_wgobfs daemon.
+//!
+//! The `nf_wgobfs` binary is a thin front end over these modules. The packet transform
+//! itself lives in [`filter::obfuscator`]; with the `ffi` feature, [`ffi`] exposes it to C.
+//!
+//! Without the default `std` feature the crate is `no_std` and needs only `alloc`: it keeps
+//! the transform ([`filter::obfuscator`] with [`filter::ballast`] and the keepalive
+//! helpers), the ciphers, the checksum helpers of [`netutils`] and the rule type of
+//! [`config`] with its builder, for firmware that moves the packets itself. The daemon,
+//! config files, statistics and everything else that needs an OS stay behind `std`.
+
+// Unit tests run on the host with std's harness; those of the core also run without the
+// `std` feature (`cargo test --lib --no-default-features`)
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod cipher;
+#[cfg(feature = "std")]
+pub mod cli;
+#[cfg(feature = "std")]
+pub mod completions;
+pub mod config;
+#[cfg(feature = "std")]
+pub mod daemon;
+#[cfg(feature = "std")]
+pub mod dissector;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod logger;
+#[cfg(feature = "std")]
+pub mod metrics;
+pub mod netutils;
+#[cfg(feature = "std")]
+pub mod pcap;
+#[cfg(feature = "std")]
+pub mod privileges;
+#[cfg(feature = "std")]
+pub mod randomiser;
+#[cfg(feature = "std")]
+pub mod selftest;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod vectors;
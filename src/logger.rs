@@ -0,0 +1,212 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Minimal stderr backend for the [`log`] facade.
+//!
+//! The verbosity is taken from the `NF_WGOBFS_LOG` environment variable
+//! (`off`, `error`, `warn`, `info`, `debug` or `trace`) and defaults to `info`.
+//! Per-packet diagnostics are emitted at `trace`, so they can be enabled on a
+//! release binary without recompiling.
+//!
+//! Records are written as text lines or, with `--log-format json`, as one JSON object per
+//! line for log pipelines: `ts` (RFC 3339, UTC), `level`, `target` and `msg`, followed by
+//! the structured fields of the record. The filter attaches `queue`, `event` and, where
+//! they apply, `len`, `verdict` and `error` to its records.
+
+use crate::stats::write_json_string;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the log level.
+const LOG_ENV: &str = "NF_WGOBFS_LOG";
+
+/// Layout of the log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[LEVEL] message`, for people.
+    #[default]
+    Text,
+    /// One JSON object per record, for log pipelines.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected `text` or `json`".to_string()),
+        }
+    }
+}
+
+/// Logger writing one line per record to stderr.
+struct StderrLogger {
+    format: LogFormat,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            match self.format {
+                LogFormat::Text => eprintln!("[{}] {}", record.level(), record.args()),
+                LogFormat::Json => eprintln!("{}", json_line(record, SystemTime::now())),
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static TEXT_LOGGER: StderrLogger = StderrLogger { format: LogFormat::Text };
+static JSON_LOGGER: StderrLogger = StderrLogger { format: LogFormat::Json };
+
+/// Installs the stderr logger with the level configured through `NF_WGOBFS_LOG`.
+///
+/// Unknown values fall back to `info`. Calling this more than once has no effect.
+pub fn init(format: LogFormat) {
+    let level = env::var(LOG_ENV)
+        .ok()
+        .and_then(|s| s.trim().parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::Info);
+    let logger = match format {
+        LogFormat::Text => &TEXT_LOGGER,
+        LogFormat::Json => &JSON_LOGGER,
+    };
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Writes the structured fields of a record as JSON members; numbers and booleans stay
+/// unquoted, everything else is written as a string.
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        write_json_string(self.0, key.as_str());
+        self.0.push(':');
+        if let Some(n) = value.to_u64() {
+            let _ = write!(self.0, "{}", n);
+        } else if let Some(n) = value.to_i64() {
+            let _ = write!(self.0, "{}", n);
+        } else if let Some(b) = value.to_bool() {
+            let _ = write!(self.0, "{}", b);
+        } else {
+            write_json_string(self.0, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Formats `record`, logged at `now`, as a JSON object on one line.
+fn json_line(record: &Record, now: SystemTime) -> String {
+    let mut out = String::with_capacity(128);
+    out.push_str("{\"ts\":");
+    write_json_string(&mut out, &rfc3339(now));
+    out.push_str(",\"level\":");
+    write_json_string(&mut out, &record.level().as_str().to_lowercase());
+    out.push_str(",\"target\":");
+    write_json_string(&mut out, record.target());
+    out.push_str(",\"msg\":");
+    write_json_string(&mut out, &record.args().to_string());
+    let _ = record.key_values().visit(&mut JsonFields(&mut out));
+    out.push('}');
+    out
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with microseconds.
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::time::Duration;
+
+    /// Tests timestamps around epoch, leap-day and year boundaries.
+    #[test]
+    fn test_rfc3339() {
+        let at = |secs: u64, micros: u64| {
+            rfc3339(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros))
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(at(951_825_600, 5), "2000-02-29T12:00:00.000005Z");
+        assert_eq!(at(1_767_225_599, 999_999), "2025-12-31T23:59:59.999999Z");
+        assert_eq!(at(1_772_323_200, 0), "2026-03-01T00:00:00.000000Z");
+    }
+
+    /// Tests the JSON layout of a record and its structured fields.
+    #[test]
+    fn test_json_line() {
+        let fields: &[(&str, Value)] = &[
+            ("queue", Value::from(3u16)),
+            ("event", Value::from("verdict")),
+            ("len", Value::from(148usize)),
+            ("verdict", Value::from_display(&"Accept")),
+        ];
+        let line = json_line(
+            &Record::builder()
+                .level(Level::Trace)
+                .target("nf_wgobfs::filter::queue")
+                .args(format_args!("NFQUEUE 3: \"done\""))
+                .key_values(&fields)
+                .build(),
+            UNIX_EPOCH,
+        );
+        assert_eq!(
+            line,
+            "{\"ts\":\"1970-01-01T00:00:00.000000Z\",\"level\":\"trace\",\
+            \"target\":\"nf_wgobfs::filter::queue\",\"msg\":\"NFQUEUE 3: \\\"done\\\"\",\
+            \"queue\":3,\"event\":\"verdict\",\"len\":148,\"verdict\":\"Accept\"}"
+        );
+    }
+
+    /// Tests parsing of the format names.
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!(" Text ".parse(), Ok(LogFormat::Text));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -11,19 +11,220 @@
 //! This module handles command-line argument parsing, configuration loading,
 //! and dispatches execution to the appropriate submodules based on user input.
 
-mod cli;
-mod config;
-mod filter;
-mod netutils;
-mod randomiser;
+use nf_wgobfs::cipher::{self, CipherMode};
+use nf_wgobfs::config::{self, FilterConfig};
+use nf_wgobfs::{
+    cli, completions, daemon, dissector, filter, logger, metrics, pcap, privileges, selftest,
+    stats, vectors,
+};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use std::thread;
+/// Waits for the filter threads to finish, or for SIGTERM or SIGINT.
+///
+/// Signals only raise flags (async-signal-safe); the statistics are printed to stderr
+/// from this loop on SIGUSR1, one line per queue. On SIGHUP, rules with an `auto` MTU read
+/// the MTU of their interface again, and the `enabled=` switch of every rule is read from
+/// the configuration again, on a thread of its own so that slow `keycmd=` commands do not
+/// hold up the signals; rules read from standard input cannot be read again and stay as
+/// they are. SIGUSR2 starts or stops a debugging capture on every queue (see
+/// `filter::tap`). On SIGTERM or SIGINT it returns, and the process exits with the filter
+/// threads (the kernel unbinds their queues).
+fn supervise(handles: Vec<JoinHandle<std::io::Result<()>>>) -> std::io::Result<()> {
+    let dump_stats = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGUSR1, dump_stats.clone())?;
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone())?;
+    let tap = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGUSR2, tap.clone())?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, shutdown.clone())?;
+    signal_hook::flag::register(SIGINT, shutdown.clone())?;
+    let mut reading: Option<JoinHandle<()>> = None;
+
+    while !handles.iter().all(|h| h.is_finished()) {
+        if dump_stats.swap(false, Ordering::Relaxed) {
+            eprint!("{}", stats::dump_text());
+        }
+        if reload.swap(false, Ordering::Relaxed) {
+            log::info!("Reading the configuration and interface MTUs again");
+            filter::queue::refresh_mtu();
+            if config::config_from_stdin() {
+                log::warn!(
+                    "Configuration not read again, rules unchanged: it was read from standard \
+                    input"
+                );
+            } else if reading.as_ref().is_some_and(|h| !h.is_finished()) {
+                log::warn!("Configuration still being read after the last SIGHUP, ignored");
+            } else {
+                reading = Some(thread::spawn(|| match config::read_config() {
+                    Ok(configs) => filter::queue::apply_enabled(&configs),
+                    Err(e) => log::warn!("Configuration not read again, rules unchanged: {}", e),
+                }));
+            }
+        }
+        if tap.swap(false, Ordering::Relaxed) {
+            filter::tap::toggle();
+        }
+        if shutdown.load(Ordering::Relaxed) {
+            log::info!("Shutting down");
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    for handle in handles {
+        handle.join().map_err(|_| std::io::Error::other("Filter thread panicked"))??;
+    }
+    Ok(())
+}
+
+/// Starts one filter thread per queue of each rule (see `workers=`) and supervises them.
+///
+/// With `--user`, every queue is bound first and privileges are dropped before any
+/// filter thread is spawned, so the threads inherit only `CAP_NET_ADMIN`.
+fn run_filters(configs: Vec<FilterConfig>, options: &cli::Options) -> std::io::Result<()> {
+    let mut ciphers: Vec<_> =
+        configs.iter().map(|filter| cipher::active_impl_name(filter.cipher)).collect();
+    ciphers.sort_unstable();
+    ciphers.dedup();
+    log::info!("Cipher implementation: {}", ciphers.join(", "));
+    if cipher::fast_available() && configs.iter().any(|filter| filter.cipher.has_fast_path()) {
+        log::info!(
+            "fast_chacha matched the portable core at startup: peers on either implementation \
+            interoperate, but must use the same cipher= setting"
+        );
+    }
+    // Chaff senders open their raw sockets while still privileged
+    for filter in &configs {
+        filter::chaff::spawn(filter)?;
+    }
+    let workers = configs
+        .iter()
+        .flat_map(|filter| filter.queues().map(move |queue_num| (filter, queue_num)))
+        .collect::<Vec<_>>();
+    let queues = match &options.user {
+        Some(user) => {
+            let queues = workers
+                .iter()
+                .map(|(f, queue_num)| filter::queue::bind_queue(f, *queue_num).map(Some))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            privileges::drop_privileges(user, options.group.as_deref())?;
+            queues
+        }
+        None => workers.iter().map(|_| None).collect(),
+    };
+    let handles = workers
+        .into_iter()
+        .zip(queues)
+        .map(|((filter, queue_num), queue)| {
+            let filter = filter.clone();
+            thread::spawn(move || filter::queue::run_nfqueue_filter(filter, queue_num, queue))
+        })
+        .collect();
+    filter::watchdog::spawn(&configs);
+    supervise(handles)
+}
 
 /// Application entry point.
 ///
 /// Loads configuration, parses command-line arguments, and executes the selected command.
 /// Returns a `std::io::Result<()>` indicating success or failure.
 fn main() -> std::io::Result<()> {
+    // Parse command-line arguments.
+    let (command, options) = cli::parse_args();
+    logger::init(options.log_format);
+    if let Some(path) = &options.config {
+        config::set_config_path(path.into());
+    }
+
+    // Commands that need neither the configuration nor root privileges.
+    if let cli::Command::Version = command {
+        println!("nf_wgobfs version {}", env!("CARGO_PKG_VERSION"));
+        println!("cipher {}", cipher::active_impl_name(CipherMode::default()));
+        return Ok(());
+    }
+    if let cli::Command::Status { path } = &command {
+        let path = path.as_deref().or(options.stats_socket.as_deref());
+        return cli::print_status(path.unwrap_or(stats::DEFAULT_SOCKET));
+    }
+    if let cli::Command::Completions { shell } = &command {
+        // Offer the configured queue numbers when the configuration is readable.
+        let queues: Vec<u16> = config::read_config()
+            .map(|configs| configs.iter().map(|f| f.queue_num).collect())
+            .unwrap_or_default();
+        let script = completions::script(shell, &queues).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported shell: {} (expected bash, zsh or fish)", shell),
+            )
+        })?;
+        print!("{}", script);
+        return Ok(());
+    }
+
+    if let cli::Command::SelfTest = command {
+        // Round-trip sample packets through every rule; needs neither NFQUEUE nor privileges.
+        return selftest::run_self_test(&config::read_config()?);
+    }
+    if let cli::Command::GenerateDissector { queue, port } = &command {
+        // Print a dissector for the framing of a rule; no privileges needed either.
+        let configs = config::read_config()?;
+        let rule = match queue {
+            Some(queue_num) => configs.iter().find(|f| f.queue_num == *queue_num),
+            None => configs.first(),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Queue not found in config")
+        })?;
+        print!("{}", dissector::lua_script(rule, *port));
+        return Ok(());
+    }
+    if let cli::Command::ObfuscateHex { hex, queue } = &command {
+        // Preview one packet with the settings of a rule; no privileges needed either.
+        let configs = config::read_config()?;
+        let rule = match queue {
+            Some(queue_num) => configs.iter().find(|f| f.queue_num == *queue_num),
+            None => configs.first(),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Queue not found in config")
+        })?;
+        return selftest::run_hex_preview(rule, hex);
+    }
+    if let cli::Command::Pcap { input, output, direction, queue } = &command {
+        // Transform a capture file with the key and MTU of a rule; no NFQUEUE, no privileges.
+        let configs = config::read_config()?;
+        let mut rule = match queue {
+            Some(queue_num) => configs.iter().find(|f| f.queue_num == *queue_num),
+            None => configs.first(),
+        }
+        .cloned()
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Queue not found in config")
+        })?;
+        rule.direction = *direction;
+        return pcap::run_pcap(&rule, input, output);
+    }
+    if let cli::Command::TestVectors = command {
+        // Fixed key and packets: needs no configuration at all.
+        return vectors::run_test_vectors();
+    }
+    if let cli::Command::Overhead { queue } = command {
+        // Report the overhead for MTU planning; no privileges needed either.
+        return cli::print_overhead(&config::read_config()?, queue);
+    }
+
+    if let cli::Command::DumpConfig = command {
+        // Show which sources were loaded and what each contributed; no privileges needed.
+        print!("{}", cli::config_dump(&config::read_config_layers()?));
+        return Ok(());
+    }
+
     // Load configuration from file.
     let configs = match config::load_config() {
         Ok(configs) => {
@@ -36,45 +237,93 @@ fn main() -> std::io::Result<()> {
             }
             configs
         }
-        Err(_) => {
-            // Configuration file not found or invalid.
+        Err(e) => {
+            // Configuration file not found or invalid, e.g. a weak key under keycheck=strict.
             return Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
-                "Configuration file not found or invalid",
+                format!("Configuration file not found or invalid: {}", e),
             ));
         }
     };
 
-    // Parse command-line arguments and execute the corresponding command.
-    match cli::parse_args() {
-        cli::Command::GenerateUnits => {
+    filter::queue::set_rcvbuf(options.rcvbuf);
+
+    // Serve per-queue statistics for the running filters if requested. The listeners
+    // are bound here, before privileges are dropped.
+    let runs_filters = matches!(command, cli::Command::Start { .. } | cli::Command::RunAll);
+    let stats_listener = match options.stats_socket.as_ref().filter(|_| runs_filters) {
+        Some(path) => Some(stats::bind(path).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Statistics socket {}: {}", path, e))
+        })?),
+        None => None,
+    };
+    let metrics_listener = match options.metrics.as_ref().filter(|_| runs_filters) {
+        Some(addr) => Some(TcpListener::bind(addr).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Metrics endpoint {}: {}", addr, e))
+        })?),
+        None => None,
+    };
+
+    // Detach once the configuration and listeners are known to be good, before any thread
+    // is spawned. The PID file is removed when the guard drops at a clean shutdown.
+    let _pid_file = if options.daemonize && runs_filters {
+        let pid_file = options.pid_file.as_deref().unwrap_or(daemon::DEFAULT_PID_FILE);
+        Some(daemon::daemonize(Path::new(pid_file), options.log_file.as_deref().map(Path::new))?)
+    } else {
+        None
+    };
+
+    if let Some(listener) = stats_listener {
+        thread::spawn(move || stats::serve(listener));
+    }
+    if let Some(listener) = metrics_listener {
+        thread::spawn(move || metrics::serve(listener));
+    }
+
+    // Execute the selected command.
+    match command {
+        cli::Command::GenerateUnits { out } => {
             // Generate systemd unit files for all configurations.
-            if cli::generate_systemd_units(&configs).is_err() {
-                return Err(std::io::Error::other("Failed to generate systemd units"));
+            cli::generate_systemd_units(&configs, out.as_deref())?;
+        }
+        cli::Command::GenerateOpenrc { out } => {
+            // Generate OpenRC service scripts for all configurations.
+            cli::generate_openrc_scripts(&configs, out.as_deref())?;
+        }
+        cli::Command::GenerateNftables => {
+            // Generate an nftables ruleset for all configurations.
+            if cli::generate_nftables(&configs).is_err() {
+                return Err(std::io::Error::other("Failed to generate nftables rules"));
             }
         }
-        cli::Command::Start(queue_num) => {
-            // Start the filter for the specified queue number.
-            let q = configs.iter().find(|f| f.queue_num == queue_num).unwrap();
-            filter::queue::run_nfqueue_filter(q.clone())?;
+        cli::Command::GenerateIptables => {
+            // Print iptables/ip6tables rules for all configurations.
+            cli::generate_iptables(&configs);
         }
-        cli::Command::Version => {
-            // Print application version.
-            println!("nf_wgobfs version {}", env!("CARGO_PKG_VERSION"));
-            return Ok(());
+        cli::Command::Start { queue_num } => {
+            // Start the filter for the specified queue number.
+            let q =
+                configs.iter().find(|f| f.queue_num == queue_num).cloned().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Queue {} not found in config", queue_num),
+                    )
+                })?;
+            run_filters(vec![q], &options)?;
         }
+        cli::Command::Version
+        | cli::Command::Status { .. }
+        | cli::Command::Completions { .. }
+        | cli::Command::SelfTest
+        | cli::Command::GenerateDissector { .. }
+        | cli::Command::ObfuscateHex { .. }
+        | cli::Command::TestVectors
+        | cli::Command::Overhead { .. }
+        | cli::Command::Pcap { .. }
+        | cli::Command::DumpConfig => {}
         cli::Command::RunAll => {
             // Start filters for all configurations in separate threads.
-            let mut handles = Vec::new();
-            for filter in configs {
-                handles.push(thread::spawn(move || {
-                    filter::queue::run_nfqueue_filter(filter).unwrap();
-                }));
-            }
-            // Wait for all threads to finish.
-            for handle in handles {
-                handle.join().unwrap();
-            }
+            run_filters(configs, &options)?;
         }
     }
     Ok(())
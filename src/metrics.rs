@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Prometheus metrics endpoint.
+//!
+//! A minimal HTTP/1.1 listener that answers every `GET` request with the per-queue
+//! counters from [`crate::stats`] in the Prometheus text exposition format (v0.0.4), and
+//! the packet size histograms as `nf_wgobfs_packet_size_bytes`, labelled `stage="in"`
+//! before the transform and `stage="out"` after it.
+//! It is only started when `--metrics <addr>` is given.
+
+use crate::stats::{self, SizeHistogram};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Most bytes read of a request, request line and headers together; the rest is ignored.
+const REQUEST_MAX: u64 = 8192;
+
+/// Timeout of each read and write on a connection.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exported metrics: name, help text and the counter they are read from.
+const METRICS: [(&str, &str, &str); 15] = [
+    ("packets_total", "Packets received from NFQUEUE.", "packets"),
+    ("dropped_total", "Packets given a drop verdict.", "dropped"),
+    ("dropped_keepalive_total", "Keepalive packets dropped.", "dropped_keepalive"),
+    ("passed_total", "Packets accepted unchanged.", "passed"),
+    ("unknown_version_total", "Packets passed with an unknown IP version.", "unknown_version"),
+    ("too_short_total", "Packets passed as too short or truncated.", "too_short"),
+    ("malformed_total", "Packets passed with inconsistent length fields.", "malformed"),
+    ("too_large_total", "Packets passed as larger than the MTU.", "too_large"),
+    ("ballast_bytes_total", "Random ballast bytes inserted by the obfuscator.", "ballast_bytes"),
+    ("chaff_total", "Chaff packets obfuscated and sent toward the peer.", "chaff"),
+    ("oversized_total", "Packets that exceeded the MTU once obfuscated.", "oversized"),
+    ("rate_limited_total", "Packets dropped over the rate limit of the rule.", "rate_limited"),
+    ("restarts_total", "Queue handler restarts after an error or panic.", "restarts"),
+    ("received_bytes_total", "Bytes received from NFQUEUE.", "bytes_in"),
+    ("sent_bytes_total", "Bytes handed back to the kernel.", "bytes_out"),
+];
+
+/// Renders all registered queues in the Prometheus text format.
+pub fn render() -> String {
+    let snapshot = stats::snapshot();
+    let mut out = String::new();
+    for (name, help, counter) in METRICS {
+        let _ = writeln!(out, "# HELP nf_wgobfs_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE nf_wgobfs_{} counter", name);
+        for queue in &snapshot {
+            let value = queue.counters().iter().find(|(n, _)| *n == counter).map_or(0, |c| c.1);
+            let _ = writeln!(out, "nf_wgobfs_{}{{queue=\"{}\"}} {}", name, queue.queue_num, value);
+        }
+    }
+    let _ = writeln!(
+        out,
+        "# HELP nf_wgobfs_packet_size_bytes Sizes of the packets received (stage in) and \
+        accepted (stage out)."
+    );
+    let _ = writeln!(out, "# TYPE nf_wgobfs_packet_size_bytes histogram");
+    for queue in &snapshot {
+        for (stage, sizes) in [("in", &queue.sizes_in), ("out", &queue.sizes_out)] {
+            if let Some(sizes) = sizes.get() {
+                render_histogram(&mut out, queue.queue_num, stage, sizes);
+            }
+        }
+    }
+    out
+}
+
+/// Renders one packet size histogram: cumulative buckets, then its sum and count.
+fn render_histogram(out: &mut String, queue_num: u16, stage: &str, sizes: &SizeHistogram) {
+    let labels = format!("queue=\"{}\",stage=\"{}\"", queue_num, stage);
+    let edges = sizes.edges().iter().map(usize::to_string).chain(["+Inf".to_string()]);
+    let mut total = 0;
+    for (edge, count) in edges.zip(sizes.counts()) {
+        total += count;
+        let _ = writeln!(
+            out,
+            "nf_wgobfs_packet_size_bytes_bucket{{{},le=\"{}\"}} {}",
+            labels, edge, total
+        );
+    }
+    let _ = writeln!(out, "nf_wgobfs_packet_size_bytes_sum{{{}}} {}", labels, sizes.sum());
+    let _ = writeln!(out, "nf_wgobfs_packet_size_bytes_count{{{}}} {}", labels, total);
+}
+
+/// Answers a single HTTP request on `stream`, reading at most [`REQUEST_MAX`] bytes of it.
+fn handle(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(REQUEST_MAX));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the request headers.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = if request_line.starts_with("GET ") {
+        ("200 OK", render())
+    } else {
+        ("405 Method Not Allowed", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serves metrics on `listener` (bound to e.g. `127.0.0.1:9184`).
+///
+/// This function blocks and is meant to run on its own thread.
+pub fn serve(listener: TcpListener) {
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(handle) {
+            log::warn!("Metrics endpoint: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::QueueStats;
+
+    /// Tests that registered queues are rendered with a queue label.
+    #[test]
+    fn test_render_exposition_format() {
+        let queue = stats::register(4343);
+        QueueStats::add(&queue.packets, 7);
+        QueueStats::add(&queue.restarts, 1);
+        QueueStats::add(&queue.too_short, 2);
+
+        let text = render();
+        assert!(text.contains("# TYPE nf_wgobfs_packets_total counter\n"));
+        assert!(text.contains("nf_wgobfs_packets_total{queue=\"4343\"} 7\n"));
+        assert!(text.contains("nf_wgobfs_restarts_total{queue=\"4343\"} 1\n"));
+        assert!(text.contains("nf_wgobfs_too_short_total{queue=\"4343\"} 2\n"));
+    }
+
+    /// Tests that size histograms are rendered cumulatively, with their sum and count.
+    #[test]
+    fn test_render_histogram() {
+        let queue = stats::register(4345);
+        let sizes = queue.sizes_out.get_or_init(|| SizeHistogram::new(&[100, 1500]));
+        for size in [60, 120, 1400, 1600] {
+            sizes.record(size);
+        }
+
+        let text = render();
+        assert!(text.contains("# TYPE nf_wgobfs_packet_size_bytes histogram\n"));
+        let series = "nf_wgobfs_packet_size_bytes_bucket{queue=\"4345\",stage=\"out\"";
+        assert!(text.contains(&format!("{},le=\"100\"}} 1\n", series)), "{text}");
+        assert!(text.contains(&format!("{},le=\"1500\"}} 3\n", series)), "{text}");
+        assert!(text.contains(&format!("{},le=\"+Inf\"}} 4\n", series)), "{text}");
+        assert!(text.contains("_sum{queue=\"4345\",stage=\"out\"} 3180\n"));
+        assert!(text.contains("_count{queue=\"4345\",stage=\"out\"} 4\n"));
+        assert!(!text.contains("queue=\"4345\",stage=\"in\""));
+    }
+
+    /// Tests that a request is answered once [`REQUEST_MAX`] bytes are read, even if its
+    /// headers never end.
+    #[test]
+    fn test_request_size_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = std::thread::spawn(move || handle(listener.accept().unwrap().0));
+
+        let mut request = b"GET /metrics HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(REQUEST_MAX as usize, b'a');
+        client.write_all(&request).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    }
+}
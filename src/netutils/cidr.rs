@@ -0,0 +1,119 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Address prefixes (CIDR blocks) and packet source addresses.
+//!
+//! Rules may restrict the packets they transform by source address (`allow=`, `deny=`).
+//! A [`Cidr`] keeps its network and mask as 128-bit integers, so matching an address is a
+//! mask and a compare, for IPv4 and IPv6 alike.
+
+use core::net::IpAddr;
+
+/// An IPv4 or IPv6 prefix, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    v4: bool,
+    network: u128,
+    mask: u128,
+}
+
+/// Splits an address into its family and its bits, IPv4 in the low 32.
+fn bits(addr: IpAddr) -> (bool, u128) {
+    match addr {
+        IpAddr::V4(addr) => (true, u32::from(addr) as u128),
+        IpAddr::V6(addr) => (false, u128::from(addr)),
+    }
+}
+
+impl Cidr {
+    /// Parses `addr/prefix`; a bare address is a single host. Host bits below the prefix
+    /// are ignored. The address may be in square brackets (`[2001:db8::]/32`).
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+            None => (s.trim(), None),
+        };
+        let addr = addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(addr);
+        let (v4, network) = bits(addr.parse().ok()?);
+        let width = if v4 { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(width);
+        if prefix > width {
+            return None;
+        }
+        let all = if v4 { u32::MAX as u128 } else { u128::MAX };
+        let mask = all.checked_shl((width - prefix) as u32).unwrap_or(0) & all;
+        Some(Cidr { v4, network: network & mask, mask })
+    }
+
+    /// Returns true if `addr` lies in the prefix. Addresses of the other family never do.
+    #[inline(always)]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (v4, bits) = bits(addr);
+        v4 == self.v4 && bits & self.mask == self.network
+    }
+}
+
+/// Reads the source address of an IPv4 or IPv6 packet.
+#[inline(always)]
+pub fn source_addr(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(packet.get(12..16)?).ok()?)),
+        6 => Some(IpAddr::from(<[u8; 16]>::try_from(packet.get(8..24)?).ok()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// Tests prefix parsing and matching for both families.
+    #[test]
+    fn test_cidr_contains() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::ffff:10.1.2.3")));
+        assert_eq!(Cidr::parse("10.1.2.3/16"), Some(net), "host bits ignored");
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+        let host = Cidr::parse("192.0.2.7").unwrap();
+        assert!(host.contains(ip("192.0.2.7")) && !host.contains(ip("192.0.2.8")));
+
+        let net6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(net6.contains(ip("2001:db8:ffff::1")));
+        assert!(!net6.contains(ip("2001:db9::1")));
+        assert!(!net6.contains(ip("10.1.2.3")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("fe80::1")));
+        assert_eq!(Cidr::parse("[2001:db8::]/32"), Some(net6));
+        assert_eq!(Cidr::parse("[::1]"), Cidr::parse("::1/128"));
+
+        for bad in ["10.0.0.0/33", "2001:db8::/129", "10.0.0/8", "10.0.0.0/", "host", "", "[::1"] {
+            assert_eq!(Cidr::parse(bad), None, "{bad}");
+        }
+    }
+
+    /// Tests reading the source address of IPv4 and IPv6 packets.
+    #[test]
+    fn test_source_addr() {
+        let mut v4 = [0u8; 20];
+        v4[0] = 0x45;
+        v4[12..16].copy_from_slice(&[192, 0, 2, 1]);
+        assert_eq!(source_addr(&v4), Some(ip("192.0.2.1")));
+        let mut v6 = [0u8; 40];
+        v6[0] = 0x60;
+        v6[8..10].copy_from_slice(&[0x20, 0x01]);
+        assert_eq!(source_addr(&v6), Some(ip("2001::")));
+        assert_eq!(source_addr(&v6[..20]), None);
+        assert_eq!(source_addr(&[0x50; 40]), None);
+        assert_eq!(source_addr(&[]), None);
+    }
+}
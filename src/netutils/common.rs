@@ -26,6 +26,20 @@
 //! This module provides utility functions for network programming,
 //! including a function to compute the 16-bit one's complement checksum,
 //! commonly used in network protocols such as IP, TCP, and UDP.
+//! Large buffers are summed with SIMD instructions when the CPU supports them.
+
+use crate::netutils::simd;
+
+/// Largest UDP segment the UDP checksum functions of [`ipv4`](crate::netutils::ipv4) and
+/// [`ipv6`](crate::netutils::ipv6) sum from a stack buffer: 9 KiB, room for a 9000-byte
+/// jumbo frame and what a tunnel adds to it, so such links never allocate. Larger segments
+/// are summed in place with [`checksum16_split`].
+pub const MAX_STACK_UDP: usize = 9216;
+
+/// Returned by the UDP checksum functions of [`ipv4`](crate::netutils::ipv4) and
+/// [`ipv6`](crate::netutils::ipv6) for inconsistent input instead of panicking. No computed
+/// checksum is 0, as [`checksum16`] sends a zero result as 0xffff.
+pub const INVALID_CHECKSUM: u16 = 0;
 
 /// Computes the 16-bit one's complement checksum for the given data slice.
 ///
@@ -47,11 +61,37 @@
 /// # Examples
 ///
 /// ```
+/// # use nf_wgobfs::netutils::common::checksum16;
 /// let data = [0x01u8, 0x02, 0x03, 0x04];
 /// let checksum = checksum16(&data);
 /// ```
 #[inline(always)]
 pub fn checksum16(data: &[u8]) -> u16 {
+    if data.len() >= SIMD_MIN_LEN {
+        if let Some((prefix, len)) = simd::sum_prefix(data) {
+            return finish(ones_add(prefix, ones_sum(&data[len..], 0)));
+        }
+    }
+    checksum16_scalar(data)
+}
+
+/// Buffers shorter than this are summed by the scalar loop.
+const SIMD_MIN_LEN: usize = 64;
+
+/// Complements a folded sum, mapping a zero result to 0xffff.
+#[inline(always)]
+fn finish(sum: u16) -> u16 {
+    let result = !sum;
+    if result == 0 {
+        0xffff
+    } else {
+        result
+    }
+}
+
+/// Scalar implementation of [`checksum16`], two bytes at a time.
+#[inline(always)]
+pub fn checksum16_scalar(data: &[u8]) -> u16 {
     let mut sum: u32 = 0;
     let len = data.len();
     let mut i = 0;
@@ -79,6 +119,73 @@ pub fn checksum16(data: &[u8]) -> u16 {
     }
 }
 
+/// Computes the [`checksum16`] of `header` followed by `data` without copying them into one
+/// buffer, e.g. a pseudo-header and the segment it covers.
+#[inline(always)]
+pub fn checksum16_split(header: &[u8], data: &[u8]) -> u16 {
+    finish(ones_add(ones_sum(header, 0), ones_sum(data, header.len())))
+}
+
+/// Adds two 16-bit values in one's complement arithmetic (end-around carry).
+#[inline(always)]
+pub fn ones_add(a: u16, b: u16) -> u16 {
+    let (sum, carry) = a.overflowing_add(b);
+    sum + carry as u16
+}
+
+/// Computes the folded one's complement sum (not complemented) of `data`.
+///
+/// `offset` is the position of `data` within the checksummed region: a slice
+/// starting at an odd offset contributes its bytes to the low halves of the
+/// 16-bit words, so its sum is byte-swapped. A trailing odd byte is padded with
+/// zero, as in [`checksum16`].
+#[inline(always)]
+pub fn ones_sum(data: &[u8], offset: usize) -> u16 {
+    let mut sum: u32 = 0;
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    if offset.is_multiple_of(2) {
+        sum as u16
+    } else {
+        (sum as u16).swap_bytes()
+    }
+}
+
+/// Updates a checksum incrementally (RFC 1624, eqn. 3).
+///
+/// `old` and `new` are the [`ones_sum`]s of the replaced and replacing bytes.
+/// The result is identical to a full recomputation with [`checksum16`],
+/// including the mapping of a zero result to 0xffff.
+///
+/// # Examples
+///
+/// ```
+/// # use nf_wgobfs::netutils::common::{checksum16, checksum_update, ones_sum};
+/// let mut data = [0x01u8, 0x02, 0x03, 0x04];
+/// let old = ones_sum(&data[2..], 2);
+/// data[2..].copy_from_slice(&[0xaa, 0xbb]);
+/// let checksum = checksum16(&[0x01, 0x02, 0x03, 0x04]);
+/// let updated = checksum_update(checksum, old, ones_sum(&data[2..], 2));
+/// assert_eq!(updated, checksum16(&data));
+/// ```
+#[inline(always)]
+pub fn checksum_update(checksum: u16, old: u16, new: u16) -> u16 {
+    let result = !ones_add(ones_add(!checksum, !old), new);
+    if result == 0 {
+        0xffff
+    } else {
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -117,4 +224,52 @@ mod tests {
         let data = [0x12u8, 0x34, 0x56, 0x78];
         assert_eq!(checksum16(&data), checksum16(&data));
     }
+
+    /// Test that incremental updates match a full recomputation, at even and odd offsets.
+    #[test]
+    fn test_checksum_update_matches_full() {
+        let mut rng = fastrand::Rng::with_seed(0x1624);
+        for _ in 0..2000 {
+            let len = rng.usize(1..96);
+            let mut data: Vec<u8> = (0..len).map(|_| rng.u8(..)).collect();
+            let checksum = checksum16(&data);
+
+            let start = rng.usize(..len);
+            let end = rng.usize(start..=len);
+            let old = ones_sum(&data[start..end], start);
+            for b in &mut data[start..end] {
+                *b = rng.u8(..);
+            }
+            let new = ones_sum(&data[start..end], start);
+            assert_eq!(checksum_update(checksum, old, new), checksum16(&data));
+        }
+    }
+
+    /// Test that appending bytes is handled as replacing an empty range.
+    #[test]
+    fn test_checksum_update_append() {
+        let data = [0x12u8, 0x34, 0x56];
+        let extended = [0x12u8, 0x34, 0x56, 0x78, 0x9a];
+        let updated = checksum_update(
+            checksum16(&data),
+            ones_sum(&data[2..], 2),
+            ones_sum(&extended[2..], 2),
+        );
+        assert_eq!(updated, checksum16(&extended));
+    }
+
+    /// Test that the SIMD dispatch matches the scalar loop on random buffers.
+    #[test]
+    fn test_checksum16_simd_matches_scalar() {
+        let mut rng = fastrand::Rng::with_seed(0x5136);
+        for _ in 0..2000 {
+            let len = rng.usize(..3000);
+            let data: Vec<u8> = (0..len).map(|_| rng.u8(..)).collect();
+            assert_eq!(checksum16(&data), checksum16_scalar(&data), "len {len}");
+        }
+        for fill in [0x00u8, 0xff] {
+            let data = vec![fill; 70000];
+            assert_eq!(checksum16(&data), checksum16_scalar(&data));
+        }
+    }
 }
@@ -26,7 +26,9 @@
 //! This module provides functions for manipulating IPv4 and UDP packet headers,
 //! including clearing the DiffServ field, fixing header fields, and calculating UDP checksums.
 
-use crate::netutils::common::checksum16;
+use crate::netutils::common::{
+    checksum16, checksum16_split, checksum_update, ones_add, INVALID_CHECKSUM, MAX_STACK_UDP,
+};
 
 /// Clears the DiffServ (DSCP) bits in the IPv4 header, preserving only the ECN bits.
 ///
@@ -57,24 +59,10 @@ pub fn clear_diffserv(packet: &mut [u8]) {
 /// - The function does nothing if the packet is too short or malformed.
 #[inline(always)]
 pub fn fix_udp_headers(packet: &mut [u8]) {
-    if packet.len() < 20 {
-        return;
-    }
-    let ihl = ((packet[0] & 0x0f) as usize) * 4;
-    if ihl < 20 || ihl + 8 > packet.len() {
+    let Some(ihl) = header_len(packet) else {
         return;
-    }
-    // Set IPv4 total length field
-    let total_len = packet.len() as u16;
-    packet[2] = (total_len >> 8) as u8;
-    packet[3] = (total_len & 0xff) as u8;
-
-    // Zero IPv4 header checksum before recalculation
-    packet[10] = 0;
-    packet[11] = 0;
-    let csum = checksum16(&packet[..ihl]);
-    packet[10] = (csum >> 8) as u8;
-    packet[11] = (csum & 0xff) as u8;
+    };
+    fix_ip_header(packet, ihl);
 
     // Set UDP length field
     let udp_len = (packet.len() - ihl) as u16;
@@ -92,6 +80,72 @@ pub fn fix_udp_headers(packet: &mut [u8]) {
     packet[ihl + 7] = (sum & 0xff) as u8;
 }
 
+/// Updates the IPv4 and UDP header fields after part of the UDP segment was rewritten.
+///
+/// Like [`fix_udp_headers`], but the UDP checksum is adjusted incrementally
+/// (RFC 1624) instead of being recomputed over the whole packet. The caller passes
+/// the [`ones_sum`]s of the bytes that were replaced (`old_sum`) and of the bytes
+/// that replaced them (`new_sum`); the length fields are accounted for here.
+///
+/// [`ones_sum`]: crate::netutils::common::ones_sum
+///
+/// # Arguments
+/// * `packet` - Mutable reference to the full IPv4+UDP packet bytes, at its new length.
+/// * `old_sum` - One's complement sum of the rewritten bytes before the change.
+/// * `new_sum` - One's complement sum of the rewritten bytes after the change.
+///
+/// # Details
+/// - A zero UDP checksum (checksum disabled) falls back to [`fix_udp_headers`].
+/// - The function does nothing if the packet is too short or malformed.
+#[inline(always)]
+pub fn update_udp_headers(packet: &mut [u8], old_sum: u16, new_sum: u16) {
+    let Some(ihl) = header_len(packet) else {
+        return;
+    };
+    let checksum = u16::from_be_bytes([packet[ihl + 6], packet[ihl + 7]]);
+    if checksum == 0 {
+        fix_udp_headers(packet);
+        return;
+    }
+    fix_ip_header(packet, ihl);
+
+    // The UDP length is counted twice: in the pseudo-header and in the UDP header
+    let old_len = u16::from_be_bytes([packet[ihl + 4], packet[ihl + 5]]);
+    let udp_len = (packet.len() - ihl) as u16;
+    packet[ihl + 4..ihl + 6].copy_from_slice(&udp_len.to_be_bytes());
+    let old = ones_add(old_sum, ones_add(old_len, old_len));
+    let new = ones_add(new_sum, ones_add(udp_len, udp_len));
+    let sum = checksum_update(checksum, old, new);
+    packet[ihl + 6..ihl + 8].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Returns the IPv4 header length if the packet holds a full IPv4 and UDP header.
+pub fn header_len(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 20 {
+        return None;
+    }
+    let ihl = ((packet[0] & 0x0f) as usize) * 4;
+    if ihl < 20 || ihl + 8 > packet.len() {
+        return None;
+    }
+    Some(ihl)
+}
+
+/// Sets the IPv4 total length to the packet length and recalculates the header checksum.
+pub fn fix_ip_header(packet: &mut [u8], ihl: usize) {
+    // Set IPv4 total length field
+    let total_len = packet.len() as u16;
+    packet[2] = (total_len >> 8) as u8;
+    packet[3] = (total_len & 0xff) as u8;
+
+    // Zero IPv4 header checksum before recalculation
+    packet[10] = 0;
+    packet[11] = 0;
+    let csum = checksum16(&packet[..ihl]);
+    packet[10] = (csum >> 8) as u8;
+    packet[11] = (csum & 0xff) as u8;
+}
+
 /// Calculates the UDP checksum for a given UDP segment and IPv4 addresses.
 ///
 /// # Arguments
@@ -100,18 +154,23 @@ pub fn fix_udp_headers(packet: &mut [u8]) {
 /// * `dst_ip` - Destination IPv4 address (4 bytes)
 ///
 /// # Returns
-/// * `u16` - The computed UDP checksum value.
+/// * `u16` - The computed UDP checksum value, or [`INVALID_CHECKSUM`] if the addresses are
+///   not 4 bytes long or `udp` is shorter than a UDP header or longer than its length field
+///   can express.
 ///
 /// # Details
 /// The function constructs a pseudo-header as required by the UDP checksum algorithm.
-/// If the UDP segment is small, a stack buffer is used for efficiency; otherwise, a heap buffer is allocated.
+/// Segments of up to [`MAX_STACK_UDP`] bytes, every packet up to the largest supported MTU,
+/// are built in a stack buffer; larger ones are summed in place after the pseudo-header.
 pub fn udp_checksum(udp: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> u16 {
-    const MAX_UDP: usize = 2048;
     let udp_len = udp.len();
+    if src_ip.len() != 4 || dst_ip.len() != 4 || !(8..=u16::MAX as usize).contains(&udp_len) {
+        return INVALID_CHECKSUM;
+    }
     let pseudo_len = 12 + udp_len + (udp_len % 2);
-    if udp_len <= MAX_UDP {
+    if udp_len <= MAX_STACK_UDP {
         // Use stack buffer for small UDP segments
-        let mut pseudo = [0u8; 12 + MAX_UDP + 1];
+        let mut pseudo = [0u8; 12 + MAX_STACK_UDP + 1];
         pseudo[..4].copy_from_slice(src_ip);
         pseudo[4..8].copy_from_slice(dst_ip);
         pseudo[8] = 0;
@@ -119,24 +178,18 @@ pub fn udp_checksum(udp: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> u16 {
         pseudo[10] = (udp_len >> 8) as u8;
         pseudo[11] = (udp_len & 0xff) as u8;
         pseudo[12..12 + udp_len].copy_from_slice(udp);
-        if udp_len % 2 != 0 {
+        if !udp_len.is_multiple_of(2) {
             pseudo[12 + udp_len] = 0; // Pad to even length
         }
         checksum16(&pseudo[..pseudo_len])
     } else {
-        // Use heap buffer for large UDP segments
-        let mut pseudo = Vec::with_capacity(pseudo_len);
-        pseudo.extend_from_slice(src_ip);
-        pseudo.extend_from_slice(dst_ip);
-        pseudo.push(0);
-        pseudo.push(17);
-        pseudo.push((udp_len >> 8) as u8);
-        pseudo.push((udp_len & 0xff) as u8);
-        pseudo.extend_from_slice(udp);
-        if pseudo.len() % 2 != 0 {
-            pseudo.push(0);
-        }
-        checksum16(&pseudo)
+        // Sum large UDP segments where they are, after the pseudo-header
+        let mut pseudo = [0u8; 12];
+        pseudo[..4].copy_from_slice(src_ip);
+        pseudo[4..8].copy_from_slice(dst_ip);
+        pseudo[9] = 17;
+        pseudo[10..].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        checksum16_split(&pseudo, udp)
     }
 }
 
@@ -197,4 +250,59 @@ mod tests {
         assert_ne!(sum_even, 0);
         assert_ne!(sum_odd, 0);
     }
+
+    /// Test that inconsistent input yields the sentinel instead of panicking: addresses of
+    /// the wrong length, and segments shorter than a UDP header or longer than 64 KiB.
+    #[test]
+    fn test_udp_checksum_inconsistent_input() {
+        let udp = [0x00, 0x35, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00];
+        let addr = [10, 0, 0, 1];
+        for bad in [&[][..], &addr[..3], &[10, 0, 0, 1, 0][..], &[0u8; 16][..]] {
+            assert_eq!(udp_checksum(&udp, bad, &addr), INVALID_CHECKSUM);
+            assert_eq!(udp_checksum(&udp, &addr, bad), INVALID_CHECKSUM);
+        }
+        for len in 0..8 {
+            assert_eq!(udp_checksum(&udp[..len], &addr, &addr), INVALID_CHECKSUM);
+        }
+        assert_eq!(udp_checksum(&vec![0u8; 65536], &addr, &addr), INVALID_CHECKSUM);
+        assert_ne!(udp_checksum(&vec![0u8; 65535], &addr, &addr), INVALID_CHECKSUM);
+    }
+
+    /// Test that the stack buffer and the in-place sum agree around their boundary, at even and odd
+    /// lengths.
+    #[test]
+    fn test_udp_checksum_stack_boundary() {
+        let (src, dst) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+        for len in MAX_STACK_UDP - 1..=MAX_STACK_UDP + 2 {
+            let udp: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let mut pseudo = [&src[..], &dst[..], &[0, 17], &(len as u16).to_be_bytes()].concat();
+            pseudo.extend_from_slice(&udp);
+            assert_eq!(udp_checksum(&udp, &src, &dst), checksum16(&pseudo), "{}", len);
+        }
+    }
+
+    /// Test that truncated packets and bogus header lengths never panic the header fixers,
+    /// and that packets without a full IPv4 and UDP header are left untouched.
+    #[test]
+    fn test_fix_headers_truncated() {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&[0xca, 0x6c, 0xca, 0x6c, 0x00, 0x00, 0x12, 0x34, 1, 2, 3]);
+        for ihl in 0..16u8 {
+            for len in 0..=packet.len() {
+                let mut truncated = packet[..len].to_vec();
+                if let Some(first) = truncated.first_mut() {
+                    *first = 0x40 | ihl;
+                }
+                let before = truncated.clone();
+                fix_udp_headers(&mut truncated);
+                update_udp_headers(&mut truncated, 0x1111, 0x2222);
+                if header_len(&before).is_none() {
+                    assert_eq!(truncated, before, "ihl {} len {}", ihl, len);
+                }
+            }
+        }
+    }
 }
@@ -26,13 +26,95 @@
 //! This module provides functions to fix and validate UDP headers in IPv6 packets,
 //! including length and checksum calculation according to RFC 2460.
 
-use crate::netutils::common::checksum16;
+use crate::netutils::common::{
+    checksum16, checksum16_split, checksum_update, ones_add, INVALID_CHECKSUM, MAX_STACK_UDP,
+};
+
+/// Length of the fixed IPv6 header.
+pub const HEADER_LEN: usize = 40;
+
+const NEXT_HOP_BY_HOP: u8 = 0;
+const NEXT_UDP: u8 = 17;
+const NEXT_ROUTING: u8 = 43;
+const NEXT_AUTH: u8 = 51;
+const NEXT_DEST_OPTS: u8 = 60;
+
+/// Clears the DSCP bits of the IPv6 Traffic Class, preserving only the ECN bits, like
+/// [`crate::netutils::ipv4::clear_diffserv`] does for IPv4.
+///
+/// The Traffic Class spans the low nibble of byte 0 and the high nibble of byte 1; its two
+/// low (ECN) bits are bits 5-4 of byte 1. The Flow Label is left alone.
+#[inline(always)]
+pub fn clear_traffic_class(packet: &mut [u8]) {
+    if packet.len() >= HEADER_LEN {
+        packet[0] &= 0xf0;
+        packet[1] &= 0x3f;
+    }
+}
+
+/// Zeroes the 20-bit IPv6 Flow Label (low nibble of byte 1 and bytes 2-3).
+///
+/// A label of zero marks the packet as unlabelled; routers then balance it by its
+/// addresses and ports, which stay the same for a tunnel.
+#[inline(always)]
+pub fn clear_flow_label(packet: &mut [u8]) {
+    if packet.len() >= HEADER_LEN {
+        packet[1] &= 0xf0;
+        packet[2..4].fill(0);
+    }
+}
+
+/// Finds the upper-layer header of an IPv6 packet by following the Next Header chain.
+///
+/// Hop-by-Hop, Routing, Destination Options and Authentication headers are
+/// skipped. Fragments are not reassembled, so a Fragment header is returned as
+/// the upper layer like any other next header that is not an extension header.
+///
+/// # Returns
+///
+/// * `Some((next_header, offset))` - Protocol number and offset of the upper-layer header.
+/// * `None` - If the packet is truncated within its extension headers.
+pub fn upper_layer(packet: &[u8]) -> Option<(u8, usize)> {
+    if packet.len() < HEADER_LEN {
+        return None;
+    }
+    let mut next = packet[6];
+    let mut offset = HEADER_LEN;
+    loop {
+        let ext_len = match next {
+            NEXT_HOP_BY_HOP | NEXT_ROUTING | NEXT_DEST_OPTS => {
+                (*packet.get(offset + 1)? as usize + 1) * 8
+            }
+            NEXT_AUTH => (*packet.get(offset + 1)? as usize + 2) * 4,
+            _ => return (offset <= packet.len()).then_some((next, offset)),
+        };
+        next = *packet.get(offset)?;
+        offset += ext_len;
+    }
+}
+
+/// Finds the UDP header of an IPv6 packet by following the Next Header chain.
+///
+/// Extension headers are skipped as by [`upper_layer`]; a Fragment header (like
+/// any other final next header than UDP) yields `None`.
+///
+/// # Returns
+///
+/// * `Some(offset)` - Offset of the UDP header, with the full 8-byte header in bounds.
+/// * `None` - If the packet is truncated or does not carry UDP.
+pub fn udp_offset(packet: &[u8]) -> Option<usize> {
+    match upper_layer(packet)? {
+        (NEXT_UDP, offset) if offset + 8 <= packet.len() => Some(offset),
+        _ => None,
+    }
+}
 
 /// Fixes the UDP header in an IPv6 packet buffer.
 ///
 /// This function updates the IPv6 payload length and the UDP length fields,
 /// and recalculates the UDP checksum. The packet is expected to be a full
-/// IPv6 packet with the UDP header starting at byte 40.
+/// IPv6 packet; the UDP header is located with [`udp_offset`], so extension
+/// headers are preserved.
 ///
 /// # Arguments
 ///
@@ -40,24 +122,26 @@ use crate::netutils::common::checksum16;
 ///
 /// # Behavior
 ///
-/// - If the packet is smaller than 48 bytes, the function returns immediately.
-/// - Updates the IPv6 payload length (bytes 4-5) and UDP length (bytes 44-45).
+/// - If the packet does not carry a complete UDP header, the function returns immediately.
+/// - Updates the IPv6 payload length (bytes 4-5) and the UDP length.
 /// - Sets the UDP checksum field to zero, then recalculates and writes the correct checksum.
+///   The pseudo-header uses the destination of the fixed header, which is not the final
+///   destination when a Routing header is present.
 pub fn fix_udp_headers(packet: &mut [u8]) {
-    if packet.len() < 48 {
+    let Some(udp_start) = udp_offset(packet) else {
         // Not enough data for IPv6 + UDP headers
         return;
-    }
+    };
 
-    let udp_start = 40;
-    let payload_len = (packet.len() - 40) as u16;
+    let payload_len = (packet.len() - HEADER_LEN) as u16;
     // Set IPv6 payload length
     packet[4] = (payload_len >> 8) as u8;
     packet[5] = (payload_len & 0xff) as u8;
 
     // Set UDP length
-    packet[udp_start + 4] = (payload_len >> 8) as u8;
-    packet[udp_start + 5] = (payload_len & 0xff) as u8;
+    let udp_len = (packet.len() - udp_start) as u16;
+    packet[udp_start + 4] = (udp_len >> 8) as u8;
+    packet[udp_start + 5] = (udp_len & 0xff) as u8;
 
     // Zero UDP checksum before calculation
     packet[udp_start + 6] = 0;
@@ -72,6 +156,48 @@ pub fn fix_udp_headers(packet: &mut [u8]) {
     packet[udp_start + 7] = (sum & 0xff) as u8;
 }
 
+/// Updates the IPv6 and UDP header fields after part of the UDP segment was rewritten.
+///
+/// Like [`fix_udp_headers`], but the UDP checksum is adjusted incrementally
+/// (RFC 1624) instead of being recomputed over the whole packet. The caller passes
+/// the [`ones_sum`]s of the bytes that were replaced (`old_sum`) and of the bytes
+/// that replaced them (`new_sum`); the length fields are accounted for here.
+///
+/// [`ones_sum`]: crate::netutils::common::ones_sum
+///
+/// # Arguments
+///
+/// * `packet` - Mutable byte slice containing the IPv6 packet, at its new length.
+/// * `old_sum` - One's complement sum of the rewritten bytes before the change.
+/// * `new_sum` - One's complement sum of the rewritten bytes after the change.
+///
+/// # Behavior
+///
+/// - If the packet does not carry a complete UDP header, the function returns immediately.
+/// - A zero UDP checksum (invalid for IPv6) falls back to [`fix_udp_headers`].
+pub fn update_udp_headers(packet: &mut [u8], old_sum: u16, new_sum: u16) {
+    let Some(udp_start) = udp_offset(packet) else {
+        return;
+    };
+    let checksum = u16::from_be_bytes([packet[udp_start + 6], packet[udp_start + 7]]);
+    if checksum == 0 {
+        fix_udp_headers(packet);
+        return;
+    }
+
+    let payload_len = (packet.len() - HEADER_LEN) as u16;
+    packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+
+    // The UDP length is counted twice: in the pseudo-header and in the UDP header
+    let old_len = u16::from_be_bytes([packet[udp_start + 4], packet[udp_start + 5]]);
+    let udp_len = (packet.len() - udp_start) as u16;
+    packet[udp_start + 4..udp_start + 6].copy_from_slice(&udp_len.to_be_bytes());
+    let old = ones_add(old_sum, ones_add(old_len, old_len));
+    let new = ones_add(new_sum, ones_add(udp_len, udp_len));
+    let sum = checksum_update(checksum, old, new);
+    packet[udp_start + 6..udp_start + 8].copy_from_slice(&sum.to_be_bytes());
+}
+
 /// Calculates the UDP checksum for an IPv6 packet.
 ///
 /// This function constructs the IPv6 pseudo-header and UDP segment,
@@ -85,19 +211,24 @@ pub fn fix_udp_headers(packet: &mut [u8]) {
 ///
 /// # Returns
 ///
-/// * `u16` - The computed UDP checksum.
+/// * `u16` - The computed UDP checksum, or [`INVALID_CHECKSUM`] if the addresses are not
+///   16 bytes long or `udp` is shorter than a UDP header or longer than its length field
+///   can express.
 ///
 /// # Notes
 ///
 /// - Handles both even and odd UDP payload lengths.
-/// - Uses a stack buffer for small packets, heap allocation for large ones.
+/// - Uses a stack buffer up to [`MAX_STACK_UDP`] bytes (jumbo frames included) and sums
+///   larger segments in place after the pseudo-header.
 pub fn udp_checksum(udp: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> u16 {
-    const MAX_UDP: usize = 2048;
     let udp_len = udp.len();
+    if src_ip.len() != 16 || dst_ip.len() != 16 || !(8..=u16::MAX as usize).contains(&udp_len) {
+        return INVALID_CHECKSUM;
+    }
     let pseudo_len = 40 + udp_len + (udp_len % 2);
-    if udp_len <= MAX_UDP {
+    if udp_len <= MAX_STACK_UDP {
         // Use stack buffer for efficiency
-        let mut pseudo = [0u8; 40 + MAX_UDP + 1];
+        let mut pseudo = [0u8; 40 + MAX_STACK_UDP + 1];
         // Source address (16 bytes)
         pseudo[..16].copy_from_slice(src_ip);
         // Destination address (16 bytes)
@@ -115,25 +246,18 @@ pub fn udp_checksum(udp: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> u16 {
         // UDP header and payload
         pseudo[40..40 + udp_len].copy_from_slice(udp);
         // Pad with zero if odd length
-        if udp_len % 2 != 0 {
+        if !udp_len.is_multiple_of(2) {
             pseudo[40 + udp_len] = 0;
         }
         checksum16(&pseudo[..pseudo_len])
     } else {
-        // Use heap allocation for large packets
-        let mut pseudo = Vec::with_capacity(pseudo_len);
-        pseudo.extend_from_slice(src_ip);
-        pseudo.extend_from_slice(dst_ip);
-
-        let len = udp_len as u32;
-        pseudo.extend_from_slice(&len.to_be_bytes());
-        pseudo.extend_from_slice(&[0, 0, 0, 17]);
-
-        pseudo.extend_from_slice(udp);
-        if pseudo.len() % 2 != 0 {
-            pseudo.push(0);
-        }
-        checksum16(&pseudo)
+        // Sum large packets where they are, after the pseudo-header
+        let mut pseudo = [0u8; 40];
+        pseudo[..16].copy_from_slice(src_ip);
+        pseudo[16..32].copy_from_slice(dst_ip);
+        pseudo[32..36].copy_from_slice(&(udp_len as u32).to_be_bytes());
+        pseudo[39] = 17;
+        checksum16_split(&pseudo, udp)
     }
 }
 
@@ -147,9 +271,8 @@ mod tests {
         // Construct a minimal IPv6 + UDP packet with 4 bytes of payload
         let mut packet = [
             // IPv6 header (first 8 bytes)
-            0x60, 0, 0, 0, 0, 0, 0, 0,
-            // Traffic class, flow label, payload length, next header, hop limit
-            0, 17, 64, 0, // Source address (16 bytes)
+            // Version/traffic class/flow label, payload length, next header (UDP), hop limit
+            0x60, 0, 0, 0, 0, 0, 17, 64, // Source address (16 bytes)
             0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
             // Destination address (16 bytes)
             0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
@@ -180,6 +303,81 @@ mod tests {
         assert_eq!(sum, packet_sum);
     }
 
+    /// Builds an IPv6 packet with the given extension headers in front of a UDP datagram.
+    fn packet_with_ext(first: u8, ext: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, first, 64];
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        packet.extend_from_slice(ext);
+        packet.extend_from_slice(&[0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Test that the header walker skips Hop-by-Hop and Destination Options headers.
+    #[test]
+    fn test_udp_offset_skips_extension_headers() {
+        assert_eq!(udp_offset(&packet_with_ext(17, &[], &[1, 2])), Some(40));
+
+        // Hop-by-Hop (8 bytes) -> Destination Options (16 bytes) -> UDP
+        let mut ext = vec![60, 0, 1, 4, 0, 0, 0, 0];
+        ext.extend_from_slice(&[17, 1, 1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let packet = packet_with_ext(0, &ext, &[1, 2]);
+        assert_eq!(udp_offset(&packet), Some(64));
+    }
+
+    /// Test that fragments, other protocols and truncated chains are not treated as UDP.
+    #[test]
+    fn test_udp_offset_rejects_non_udp() {
+        assert_eq!(udp_offset(&packet_with_ext(6, &[], &[])), None);
+        assert_eq!(udp_offset(&packet_with_ext(44, &[17, 0, 0, 0, 0, 0, 0, 0], &[])), None);
+        let packet = packet_with_ext(0, &[17, 0, 1, 4, 0, 0, 0, 0], &[]);
+        assert_eq!(udp_offset(&packet[..44]), None);
+        assert_eq!(udp_offset(&packet[..50]), None);
+    }
+
+    /// Test that DSCP and the Flow Label are cleared separately, keeping version and ECN.
+    #[test]
+    fn test_clear_traffic_class_and_flow_label() {
+        // Traffic Class 0xb9 (EF with ECN CE), Flow Label 0xabcde
+        let mut packet = packet_with_ext(17, &[], &[1, 2]);
+        packet[..4].copy_from_slice(&[0x6b, 0x9a, 0xbc, 0xde]);
+        clear_traffic_class(&mut packet);
+        assert_eq!(packet[..4], [0x60, 0x1a, 0xbc, 0xde]);
+        clear_flow_label(&mut packet);
+        assert_eq!(packet[..4], [0x60, 0x10, 0x00, 0x00]);
+
+        let mut short = [0x6b, 0x9a, 0xbc, 0xde];
+        clear_traffic_class(&mut short);
+        clear_flow_label(&mut short);
+        assert_eq!(short, [0x6b, 0x9a, 0xbc, 0xde]);
+    }
+
+    /// Test that the header walker reports other upper layers behind extension headers.
+    #[test]
+    fn test_upper_layer() {
+        assert_eq!(upper_layer(&packet_with_ext(6, &[], &[])), Some((6, 40)));
+        let packet = packet_with_ext(60, &[6, 0, 1, 4, 0, 0, 0, 0], &[]);
+        assert_eq!(upper_layer(&packet), Some((6, 48)));
+        assert_eq!(upper_layer(&packet[..44]), None);
+    }
+
+    /// Test that fix_udp_headers keeps extension headers and fixes the UDP header behind them.
+    #[test]
+    fn test_fix_udp_headers_with_extension_header() {
+        let mut packet = packet_with_ext(0, &[17, 0, 1, 4, 0, 0, 0, 0], &[1, 2, 3, 4]);
+        fix_udp_headers(&mut packet);
+
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 8 + 12);
+        assert_eq!(&packet[40..48], &[17, 0, 1, 4, 0, 0, 0, 0]);
+        assert_eq!(u16::from_be_bytes([packet[52], packet[53]]), 12);
+        let mut udp = packet[48..].to_vec();
+        udp[6] = 0;
+        udp[7] = 0;
+        let sum = udp_checksum(&udp, &packet[8..24], &packet[24..40]);
+        assert_eq!(u16::from_be_bytes([packet[54], packet[55]]), sum);
+    }
+
     /// Test UDP checksum calculation for even and odd UDP payload lengths.
     #[test]
     fn test_udp_checksum_even_and_odd_length() {
@@ -224,4 +422,56 @@ mod tests {
         // Acceptable values: 0, 0xffff, or 65510 (implementation-dependent)
         assert!(sum == 0 || sum == 0xffff || sum == 65510);
     }
+
+    /// Test that inconsistent input yields the sentinel instead of panicking: addresses of
+    /// the wrong length, and segments shorter than a UDP header or longer than 64 KiB.
+    #[test]
+    fn test_udp_checksum_inconsistent_input() {
+        let udp = [0x00, 0x35, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00];
+        let addr = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        for bad in [&[][..], &addr[..4], &addr[..15], &[0u8; 17][..]] {
+            assert_eq!(udp_checksum(&udp, bad, &addr), INVALID_CHECKSUM);
+            assert_eq!(udp_checksum(&udp, &addr, bad), INVALID_CHECKSUM);
+        }
+        for len in 0..8 {
+            assert_eq!(udp_checksum(&udp[..len], &addr, &addr), INVALID_CHECKSUM);
+        }
+        assert_eq!(udp_checksum(&vec![0u8; 65536], &addr, &addr), INVALID_CHECKSUM);
+        assert_ne!(udp_checksum(&vec![0u8; 65535], &addr, &addr), INVALID_CHECKSUM);
+    }
+
+    /// Test that the stack buffer and the in-place sum agree around their boundary, at even and odd
+    /// lengths.
+    #[test]
+    fn test_udp_checksum_stack_boundary() {
+        let packet = packet_with_ext(17, &[], &[]);
+        let (src, dst) = (&packet[8..24], &packet[24..40]);
+        for len in MAX_STACK_UDP - 1..=MAX_STACK_UDP + 2 {
+            let udp: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let mut pseudo = [src, dst, &(len as u32).to_be_bytes(), &[0, 0, 0, 17]].concat();
+            pseudo.extend_from_slice(&udp);
+            assert_eq!(udp_checksum(&udp, src, dst), checksum16(&pseudo), "{}", len);
+        }
+    }
+
+    /// Test that truncated packets, with and without extension headers, never panic the
+    /// header fixers and are left untouched without a full UDP header.
+    #[test]
+    fn test_fix_headers_truncated() {
+        for packet in [
+            packet_with_ext(17, &[], &[1, 2, 3]),
+            packet_with_ext(0, &[17, 0, 1, 4, 0, 0, 0, 0], &[1, 2, 3]),
+            packet_with_ext(0, &[17, 7, 1, 4, 0, 0, 0, 0], &[1, 2, 3]),
+        ] {
+            for len in 0..=packet.len() {
+                let mut truncated = packet[..len].to_vec();
+                let before = truncated.clone();
+                fix_udp_headers(&mut truncated);
+                update_udp_headers(&mut truncated, 0x1111, 0x2222);
+                if udp_offset(&before).is_none() {
+                    assert_eq!(truncated, before, "len {}", len);
+                }
+            }
+        }
+    }
 }
@@ -1,3 +1,6 @@
+pub mod cidr;
 pub mod common;
 pub mod ipv4;
 pub mod ipv6;
+pub mod simd;
+pub mod tcp;
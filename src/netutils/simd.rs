@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! SIMD one's complement sums.
+//!
+//! The one's complement sum is independent of byte order, so the vector code adds
+//! little-endian 16-bit lanes into 32-bit accumulators and byte-swaps the folded
+//! result. AVX2 is used on x86_64 and NEON on little-endian aarch64, both detected
+//! at runtime; [`sum_prefix`] returns `None` when neither is available. Without `std`
+//! there is no runtime detection, and only what the target enables at compile time is used.
+
+/// Folds a 64-bit sum of 16-bit words into 16 bits with end-around carry.
+#[cfg(any(target_arch = "x86_64", all(target_arch = "aarch64", target_endian = "little")))]
+fn fold(mut sum: u64) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Returns true if a SIMD implementation can be used on this CPU.
+pub fn is_available() -> bool {
+    #[cfg(all(target_arch = "x86_64", feature = "std"))]
+    {
+        std::arch::is_x86_feature_detected!("avx2")
+    }
+    #[cfg(all(target_arch = "x86_64", not(feature = "std")))]
+    {
+        cfg!(target_feature = "avx2")
+    }
+    #[cfg(all(target_arch = "aarch64", target_endian = "little", feature = "std"))]
+    {
+        std::arch::is_aarch64_feature_detected!("neon")
+    }
+    #[cfg(all(target_arch = "aarch64", target_endian = "little", not(feature = "std")))]
+    {
+        cfg!(target_feature = "neon")
+    }
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", target_endian = "little")
+    )))]
+    {
+        false
+    }
+}
+
+/// Sums the longest prefix of `data` that fills whole vectors.
+///
+/// # Returns
+/// * `Some((sum, len))` - The folded big-endian one's complement sum of `data[..len]`;
+///   `len` is even, so the rest can be summed by the scalar code from offset 0.
+/// * `None` - If no SIMD implementation is available.
+pub fn sum_prefix(data: &[u8]) -> Option<(u16, usize)> {
+    if !is_available() {
+        return None;
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        // SAFETY: AVX2 support was checked above.
+        Some(unsafe { avx2::sum_prefix(data) })
+    }
+    #[cfg(all(target_arch = "aarch64", target_endian = "little"))]
+    {
+        // SAFETY: NEON support was checked above.
+        Some(unsafe { neon::sum_prefix(data) })
+    }
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        all(target_arch = "aarch64", target_endian = "little")
+    )))]
+    {
+        let _ = data;
+        None
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::fold;
+    use core::arch::x86_64::*;
+
+    const LANES: usize = 32;
+    /// Vectors summed before the 32-bit lanes are spilled (each gains at most 2 * 0xffff).
+    const BATCH: usize = 16384;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn sum_prefix(data: &[u8]) -> (u16, usize) {
+        let len = data.len() / LANES * LANES;
+        let zero = _mm256_setzero_si256();
+        let mut total: u64 = 0;
+        let mut i = 0;
+        while i < len {
+            let end = len.min(i + LANES * BATCH);
+            let mut acc = zero;
+            while i < end {
+                // SAFETY: i + LANES <= len <= data.len()
+                let v = unsafe { _mm256_loadu_si256(data.as_ptr().add(i).cast()) };
+                let words = _mm256_add_epi32(
+                    _mm256_unpacklo_epi16(v, zero),
+                    _mm256_unpackhi_epi16(v, zero),
+                );
+                acc = _mm256_add_epi32(acc, words);
+                i += LANES;
+            }
+            let mut lanes = [0u32; 8];
+            // SAFETY: `lanes` holds exactly one 256-bit vector
+            unsafe { _mm256_storeu_si256(lanes.as_mut_ptr().cast(), acc) };
+            total += lanes.iter().map(|&l| l as u64).sum::<u64>();
+        }
+        (fold(total).swap_bytes(), len)
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", target_endian = "little"))]
+mod neon {
+    use super::fold;
+    use core::arch::aarch64::*;
+
+    const LANES: usize = 16;
+    /// Vectors summed before the 32-bit lanes are spilled (each gains at most 2 * 0xffff).
+    const BATCH: usize = 16384;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn sum_prefix(data: &[u8]) -> (u16, usize) {
+        let len = data.len() / LANES * LANES;
+        let mut total: u64 = 0;
+        let mut i = 0;
+        while i < len {
+            let end = len.min(i + LANES * BATCH);
+            let mut acc = vdupq_n_u32(0);
+            while i < end {
+                // SAFETY: i + LANES <= len <= data.len()
+                let v = unsafe { vreinterpretq_u16_u8(vld1q_u8(data.as_ptr().add(i))) };
+                acc = vpadalq_u16(acc, v);
+                i += LANES;
+            }
+            total += vaddlvq_u32(acc);
+        }
+        (fold(total).swap_bytes(), len)
+    }
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! TCP segment utilities.
+//!
+//! WireGuard tunnelled over TCP carries its messages in the segment payload. These helpers
+//! find the payload and, once it has been rewritten, restore the IP length fields and the
+//! TCP checksum over the RFC 793 (IPv4) or RFC 8200 (IPv6) pseudo-header.
+
+use crate::netutils::common::{ones_add, ones_sum};
+use crate::netutils::{ipv4, ipv6};
+
+/// IP protocol number of TCP.
+pub const PROTO_TCP: u8 = 6;
+
+/// Returns the length of the TCP header at the start of `segment` (its data offset).
+///
+/// # Returns
+/// * `Some(len)` - Header length, with the whole header (options included) in bounds.
+/// * `None` - If the segment is truncated or its data offset is below 5 words.
+pub fn header_len(segment: &[u8]) -> Option<usize> {
+    let len = (*segment.get(12)? >> 4) as usize * 4;
+    (len >= 20 && len <= segment.len()).then_some(len)
+}
+
+/// Calculates the TCP checksum of `segment` (header with a zero checksum field, and
+/// payload) between the addresses `src_ip` and `dst_ip` (4 or 16 bytes each).
+pub fn checksum(segment: &[u8], src_ip: &[u8], dst_ip: &[u8]) -> u16 {
+    // Pseudo-header: addresses, protocol and segment length; the length is 32 bits wide
+    // for IPv6, which adds nothing to the sum for segments below 64 KiB
+    let len = segment.len() as u16;
+    let pseudo = ones_add(ones_sum(src_ip, 0), ones_sum(dst_ip, 0));
+    let pseudo = ones_add(pseudo, ones_add(PROTO_TCP as u16, len));
+    !ones_add(pseudo, ones_sum(segment, 0))
+}
+
+/// Updates the IPv4 total length and header checksum and recalculates the TCP checksum.
+///
+/// # Arguments
+/// * `packet` - The full IPv4+TCP packet bytes, at their new length.
+/// * `ihl` - IPv4 header length, the offset of the TCP header.
+pub fn fix_ipv4_headers(packet: &mut [u8], ihl: usize) {
+    if header_len(&packet[ihl..]).is_none() {
+        return;
+    }
+    ipv4::fix_ip_header(packet, ihl);
+    fix_checksum(packet, ihl, 12..16, 16..20);
+}
+
+/// Updates the IPv6 payload length and recalculates the TCP checksum.
+///
+/// # Arguments
+/// * `packet` - The full IPv6+TCP packet bytes, at their new length.
+/// * `tcp_start` - Offset of the TCP header, after any extension headers.
+pub fn fix_ipv6_headers(packet: &mut [u8], tcp_start: usize) {
+    if header_len(&packet[tcp_start..]).is_none() {
+        return;
+    }
+    let payload_len = (packet.len() - ipv6::HEADER_LEN) as u16;
+    packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    fix_checksum(packet, tcp_start, 8..24, 24..40);
+}
+
+/// Recomputes the checksum of the TCP segment at `tcp_start` with the given address fields.
+fn fix_checksum(
+    packet: &mut [u8],
+    tcp_start: usize,
+    src: core::ops::Range<usize>,
+    dst: core::ops::Range<usize>,
+) {
+    packet[tcp_start + 16..tcp_start + 18].fill(0);
+    let sum = checksum(&packet[tcp_start..], &packet[src], &packet[dst]);
+    packet[tcp_start + 16..tcp_start + 18].copy_from_slice(&sum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netutils::common::checksum16;
+
+    /// IPv4 SYN-ACK-like packet with an 8-byte options field and a 4-byte payload.
+    fn ipv4_segment() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, // TCP header
+            0x30, 0x39, 0x01, 0xbb, 0, 0, 0, 1, 0, 0, 0, 0, 0x70, 0x18, 0xff, 0xff, 0, 0, 0, 0,
+            0x01, 0x01, 0x08, 0x0a, 0x01, 0x01, 0x01, 0x01, // payload
+            1, 2, 3, 4,
+        ];
+        let len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        packet
+    }
+
+    /// Tests the data offset, including options and truncated headers.
+    #[test]
+    fn test_header_len() {
+        let packet = ipv4_segment();
+        assert_eq!(header_len(&packet[20..]), Some(28));
+        assert_eq!(header_len(&packet[20..40]), None);
+        let mut short = packet.clone();
+        short[32] = 0x40;
+        assert_eq!(header_len(&short[20..]), None);
+    }
+
+    /// Tests that a fixed IPv4 packet checksums to zero, as a receiver verifies it.
+    #[test]
+    fn test_fix_ipv4_headers() {
+        let mut packet = ipv4_segment();
+        packet.extend_from_slice(&[5, 6, 7]);
+        fix_ipv4_headers(&mut packet, 20);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]) as usize, packet.len());
+        assert_eq!(checksum16(&packet[..20]), 0xffff);
+
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&packet[12..20]);
+        pseudo.extend_from_slice(&[0, PROTO_TCP]);
+        pseudo.extend_from_slice(&((packet.len() - 20) as u16).to_be_bytes());
+        pseudo.extend_from_slice(&packet[20..]);
+        assert_eq!(!checksum16(&pseudo), 0);
+    }
+}
@@ -0,0 +1,346 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Offline pcap mode.
+//!
+//! Reads a classic libpcap capture, runs every IPv4/IPv6 UDP packet through
+//! [`obfuscate_wg_packet`] or [`deobfuscate_wg_packet`] according to the rule's
+//! direction and writes the result to a new capture. Frames that are not IP/UDP
+//! (or were truncated by the capture) are copied through unchanged; packets the
+//! obfuscator drops (thinned keepalives) are left out of the output.
+//!
+//! Supported link types: Ethernet, Linux cooked capture (SLL) and raw IP. [`PcapWriter`]
+//! writes raw IP captures, such as those of the debugging tap ([`crate::filter::tap`]).
+
+use crate::config::{Direction, FilterConfig};
+use crate::filter::keepalive::KeepaliveDropper;
+use crate::filter::obfuscator::{deobfuscate_wg_packet, obfuscate_wg_packet, OBFUSCATION_OVERHEAD};
+use crate::netutils::ipv6;
+use crate::randomiser;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+/// Counts of what happened to the packets of a capture.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PcapSummary {
+    /// Records read from the input.
+    pub packets: usize,
+    /// Packets changed by the transform.
+    pub transformed: usize,
+    /// Records copied through unchanged.
+    pub copied: usize,
+    /// Packets dropped by the obfuscator and omitted from the output.
+    pub dropped: usize,
+}
+
+/// Largest record a capture may hold, libpcap's MAXIMUM_SNAPLEN. Records are checked
+/// against it, or the snapshot length of the file if lower, before they are read.
+const MAX_SNAPLEN: usize = 262_144;
+
+/// Size of a pcap record header.
+pub const RECORD_HEADER_LEN: usize = 16;
+
+/// Writes a capture of raw IP packets (link type 101) with microsecond timestamps.
+pub struct PcapWriter<W: Write> {
+    output: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header to `output`.
+    pub fn new(mut output: W) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        header[0..4].copy_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        header[16..20].copy_from_slice(&(u16::MAX as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        output.write_all(&header)?;
+        Ok(PcapWriter { output })
+    }
+
+    /// Appends `packet` as a record stamped with `time`.
+    pub fn write_packet(&mut self, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = [0u8; RECORD_HEADER_LEN];
+        record[0..4].copy_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        record[4..8].copy_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        record[8..12].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+        record[12..16].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.output.write_all(&record)?;
+        self.output.write_all(packet)
+    }
+
+    /// Flushes the output.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Returns the offset of the IP header for the given link type, if the frame carries IP.
+fn ip_offset(linktype: u32, frame: &[u8]) -> Option<usize> {
+    let (offset, ethertype) = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => return Some(0),
+        LINKTYPE_ETHERNET if frame.len() >= 14 => (14, u16::from_be_bytes([frame[12], frame[13]])),
+        LINKTYPE_LINUX_SLL if frame.len() >= 16 => (16, u16::from_be_bytes([frame[14], frame[15]])),
+        _ => return None,
+    };
+    matches!(ethertype, 0x0800 | 0x86dd).then_some(offset)
+}
+
+/// Returns true if `packet` is an IPv4 or IPv6 packet carrying UDP.
+fn is_udp(packet: &[u8]) -> bool {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => packet.len() >= 20 && packet[9] == 17,
+        Some(6) => ipv6::udp_offset(packet).is_some(),
+        _ => false,
+    }
+}
+
+/// Transforms every packet read from `input` and writes the resulting capture to `output`.
+///
+/// # Arguments
+/// * `filter` - Rule supplying the key, MTU and direction.
+/// * `input` - Reader positioned at the start of a pcap file.
+/// * `output` - Writer receiving the transformed pcap file.
+///
+/// # Returns
+/// * `io::Result<PcapSummary>` - Packet counts, or an error for malformed captures.
+pub fn process<R: Read, W: Write>(
+    filter: &FilterConfig,
+    mut input: R,
+    mut output: W,
+) -> io::Result<PcapSummary> {
+    let mut header = [0u8; 24];
+    input.read_exact(&mut header)?;
+    let magic = [header[0], header[1], header[2], header[3]];
+    let big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+        (MAGIC_MICROS | MAGIC_NANOS, _) => false,
+        (_, MAGIC_MICROS | MAGIC_NANOS) => true,
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a pcap file"));
+        }
+    };
+    let read_u32 = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    let write_u32 = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+    let snaplen = match read_u32(&header[16..20]) as usize {
+        0 => MAX_SNAPLEN,
+        snaplen => snaplen.min(MAX_SNAPLEN),
+    };
+    let linktype = read_u32(&header[20..24]);
+    output.write_all(&header)?;
+
+    let drop = filter.keepalive_drop;
+    let mut dropper = KeepaliveDropper::new(drop.min, drop.max);
+    dropper.set_idle_threshold(filter.keepalive_idle);
+    let mut rng = randomiser::create_secure_rng();
+    let mut summary = PcapSummary::default();
+    let mut record = [0u8; 16];
+    let mut frame = Vec::new();
+
+    loop {
+        match input.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let incl_len = read_u32(&record[8..12]) as usize;
+        let orig_len = read_u32(&record[12..16]) as usize;
+        if incl_len > snaplen {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Record {} holds {} bytes, over the snapshot length of {}",
+                    summary.packets + 1,
+                    incl_len,
+                    snaplen
+                ),
+            ));
+        }
+        frame.clear();
+        frame.resize(incl_len, 0);
+        input.read_exact(&mut frame)?;
+        summary.packets += 1;
+
+        let offset = ip_offset(linktype, &frame).filter(|_| incl_len == orig_len);
+        let new_len = match offset {
+            Some(offset) if is_udp(&frame[offset..]) => {
+                let len = incl_len - offset;
+                frame.resize(offset + len.max(filter.mtu) + OBFUSCATION_OVERHEAD, 0);
+                let packet = &mut frame[offset..];
+                let result = match filter.direction {
+                    Direction::Out => {
+                        obfuscate_wg_packet(packet, len, filter, &mut dropper, &mut rng)
+                    }
+                    Direction::In => deobfuscate_wg_packet(&mut packet[..len], filter),
+                };
+                match result {
+                    Some(new_len) => Some(offset + new_len),
+                    None => {
+                        summary.dropped += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let new_len = match new_len {
+            Some(new_len) if new_len != incl_len => {
+                summary.transformed += 1;
+                new_len
+            }
+            _ => {
+                summary.copied += 1;
+                incl_len
+            }
+        };
+        record[8..12].copy_from_slice(&write_u32(new_len as u32));
+        record[12..16].copy_from_slice(&write_u32(new_len as u32));
+        output.write_all(&record)?;
+        output.write_all(&frame[..new_len])?;
+    }
+    output.flush()?;
+    Ok(summary)
+}
+
+/// Runs [`process`] from the file `input` to the file `output` and prints a summary.
+pub fn run_pcap(filter: &FilterConfig, input: &str, output: &str) -> io::Result<()> {
+    let reader = BufReader::new(File::open(input)?);
+    let writer = BufWriter::new(File::create(output)?);
+    let summary = process(filter, reader, writer)?;
+    println!(
+        "{}: {} packets, {} transformed, {} copied, {} dropped -> {}",
+        input, summary.packets, summary.transformed, summary.copied, summary.dropped, output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an Ethernet frame carrying an IPv4/UDP WireGuard data packet.
+    fn wg_frame() -> Vec<u8> {
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        let mut ip = vec![
+            0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1,
+            10, 0, 0, 2, 0xca, 0x6c, 0xca, 0x6c, 0x00, 0x00, 0x00, 0x00,
+        ];
+        ip.extend((0..96u8).map(|i| i.wrapping_mul(7)));
+        ip[28..32].copy_from_slice(&[0x04, 0, 0, 0]);
+        crate::netutils::ipv4::fix_udp_headers(&mut ip);
+        frame.extend(ip);
+        frame
+    }
+
+    /// Builds a little-endian Ethernet pcap file from the given frames.
+    fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(MAGIC_MICROS.to_le_bytes());
+        out.extend([2, 0, 4, 0]);
+        out.extend([0u8; 8]);
+        out.extend(65535u32.to_le_bytes());
+        out.extend(LINKTYPE_ETHERNET.to_le_bytes());
+        for (i, frame) in frames.iter().enumerate() {
+            out.extend((i as u32).to_le_bytes());
+            out.extend(0u32.to_le_bytes());
+            out.extend((frame.len() as u32).to_le_bytes());
+            out.extend((frame.len() as u32).to_le_bytes());
+            out.extend(frame);
+        }
+        out
+    }
+
+    fn config(direction: Direction) -> FilterConfig {
+        FilterConfig::builder()
+            .direction(direction)
+            .iface("eth0")
+            .key_from_ascii("secretkey")
+            .build()
+            .unwrap()
+    }
+
+    /// Tests that a capture survives an obfuscate/deobfuscate round-trip and that
+    /// non-IP frames are copied through unchanged.
+    #[test]
+    fn test_pcap_round_trip() {
+        let arp = {
+            let mut frame = vec![0xffu8; 42];
+            frame[12..14].copy_from_slice(&[0x08, 0x06]);
+            frame
+        };
+        let input = pcap(&[wg_frame(), arp.clone()]);
+
+        let mut obfuscated = Vec::new();
+        let summary = process(&config(Direction::Out), &input[..], &mut obfuscated).unwrap();
+        assert_eq!(summary, PcapSummary { packets: 2, transformed: 1, copied: 1, dropped: 0 });
+        assert_ne!(obfuscated, input);
+
+        let mut restored = Vec::new();
+        let summary = process(&config(Direction::In), &obfuscated[..], &mut restored).unwrap();
+        assert_eq!(summary, PcapSummary { packets: 2, transformed: 1, copied: 1, dropped: 0 });
+        assert_eq!(restored, input);
+    }
+
+    /// Tests that captures of [`PcapWriter`] read back as raw IP, record for record.
+    #[test]
+    fn test_pcap_writer() {
+        let packet = wg_frame()[14..].to_vec();
+        let mut capture = Vec::new();
+        let mut writer = PcapWriter::new(&mut capture).unwrap();
+        writer.write_packet(UNIX_EPOCH, &packet).unwrap();
+        writer.write_packet(SystemTime::now(), &packet[..20]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(capture.len(), 24 + 2 * RECORD_HEADER_LEN + packet.len() + 20);
+        assert_eq!(capture[20..24], LINKTYPE_RAW.to_le_bytes());
+
+        let mut obfuscated = Vec::new();
+        let summary = process(&config(Direction::Out), &capture[..], &mut obfuscated).unwrap();
+        assert_eq!(summary, PcapSummary { packets: 2, transformed: 1, copied: 1, dropped: 0 });
+    }
+
+    /// Tests that records longer than the snapshot length are rejected before they are read.
+    #[test]
+    fn test_pcap_rejects_oversized_record() {
+        let mut input = pcap(&[wg_frame()]);
+        input[24 + 8..24 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = process(&config(Direction::Out), &input[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Record 1 "), "{err}");
+
+        let mut input = pcap(&[wg_frame()]);
+        input[16..20].copy_from_slice(&64u32.to_le_bytes());
+        let err = process(&config(Direction::Out), &input[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Tests that files without a pcap magic number are rejected.
+    #[test]
+    fn test_pcap_rejects_bad_magic() {
+        let mut out = Vec::new();
+        assert!(process(&config(Direction::Out), &[0u8; 24][..], &mut out).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Privilege dropping.
+//!
+//! Opening and binding an NFQUEUE needs root, but processing packets on an already
+//! bound queue only needs `CAP_NET_ADMIN` (nfnetlink checks it on every verdict).
+//! [`drop_privileges`] switches to an unprivileged user and keeps that single
+//! capability. It must be called from the main thread before the filter threads are
+//! spawned: capabilities are per-thread and are inherited by new threads only.
+
+use log::info;
+use nix::unistd::{self, Gid, Group, Uid, User};
+use std::io;
+
+use caps::{CapSet, Capability, CapsHashSet};
+
+fn caps_error(e: caps::errors::CapsError) -> io::Error {
+    io::Error::other(format!("Failed to adjust capabilities: {e}"))
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("Unknown {kind}: {name}"))
+}
+
+/// Resolves a user name or numeric uid into the uid and the user's primary gid.
+///
+/// A numeric uid without a passwd entry uses the gid with the same number.
+fn resolve_user(user: &str) -> io::Result<(Uid, Gid)> {
+    if let Ok(uid) = user.parse::<u32>() {
+        let uid = Uid::from_raw(uid);
+        let gid = User::from_uid(uid)?.map_or(Gid::from_raw(uid.as_raw()), |u| u.gid);
+        return Ok((uid, gid));
+    }
+    let entry = User::from_name(user)?.ok_or_else(|| not_found("user", user))?;
+    Ok((entry.uid, entry.gid))
+}
+
+/// Resolves a group name or numeric gid.
+fn resolve_group(group: &str) -> io::Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    let entry = Group::from_name(group)?.ok_or_else(|| not_found("group", group))?;
+    Ok(entry.gid)
+}
+
+/// Switches the process to `user` (and `group`, or the user's primary group),
+/// keeping only `CAP_NET_ADMIN`.
+///
+/// # Errors
+/// Fails if the user or group is unknown, or if the process may not change its
+/// credentials (it must start as root).
+pub fn drop_privileges(user: &str, group: Option<&str>) -> io::Result<()> {
+    let (uid, user_gid) = resolve_user(user)?;
+    let gid = match group {
+        Some(group) => resolve_group(group)?,
+        None => user_gid,
+    };
+
+    // Keep the permitted set across setuid(); it is trimmed to CAP_NET_ADMIN below.
+    caps::securebits::set_keepcaps(true).map_err(caps_error)?;
+    unistd::setgroups(&[gid])?;
+    unistd::setgid(gid)?;
+    unistd::setuid(uid)?;
+
+    let keep: CapsHashSet = [Capability::CAP_NET_ADMIN].into_iter().collect();
+    caps::set(None, CapSet::Effective, &keep).map_err(caps_error)?;
+    caps::set(None, CapSet::Permitted, &keep).map_err(caps_error)?;
+    caps::clear(None, CapSet::Inheritable).map_err(caps_error)?;
+    caps::securebits::set_keepcaps(false).map_err(caps_error)?;
+
+    info!("Dropped privileges to uid {} gid {}, keeping CAP_NET_ADMIN", uid, gid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_numeric_ids() {
+        let (uid, _) = resolve_user("12345").unwrap();
+        assert_eq!(uid.as_raw(), 12345);
+        assert_eq!(resolve_group("54321").unwrap().as_raw(), 54321);
+    }
+
+    #[test]
+    fn test_resolve_root_by_name() {
+        let (uid, gid) = resolve_user("root").unwrap();
+        assert!(uid.is_root());
+        assert_eq!(gid.as_raw(), 0);
+    }
+
+    #[test]
+    fn test_resolve_unknown_user() {
+        let err = resolve_user("no-such-user-nf-wgobfs").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
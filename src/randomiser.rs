@@ -26,10 +26,23 @@
 //! This module provides utility functions for creating a seeded random number generator
 //! and filling byte buffers with random data. The seeding process combines system time,
 //! process ID, and additional entropy to improve unpredictability.
+//!
+//! `SmallRng` is fast but not cryptographic, and a queue that runs for months draws a lot
+//! from it. A [`Reseeder`] next to each generator mixes fresh operating-system entropy
+//! into it every [`RESEED_PACKETS`] packets or [`RESEED_INTERVAL`], whichever comes first.
+
+use rand::rngs::{OsRng, SmallRng};
+use rand::{RngCore, SeedableRng, TryRngCore};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Packets after which a generator is reseeded.
+pub const RESEED_PACKETS: u32 = 1 << 20;
 
-use rand::rngs::SmallRng;
-use rand::{RngCore, SeedableRng};
-use std::time::{SystemTime, UNIX_EPOCH};
+/// Time after which a generator is reseeded, checked every [`RESEED_CHECK`] packets.
+pub const RESEED_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Packets between two looks at the clock; a power of two.
+const RESEED_CHECK: u32 = 256;
 
 /// Creates a new instance of `SmallRng` seeded with a combination of system time,
 /// process ID, and additional random noise.
@@ -39,6 +52,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 ///
 /// # Example
 /// ```
+/// # use nf_wgobfs::randomiser::create_secure_rng;
 /// let mut rng = create_secure_rng();
 /// ```
 pub fn create_secure_rng() -> SmallRng {
@@ -64,6 +78,7 @@ pub fn create_secure_rng() -> SmallRng {
 ///
 /// # Example
 /// ```
+/// # use nf_wgobfs::randomiser::{create_secure_rng, fill_random};
 /// let mut buf = [0u8; 16];
 /// let mut rng = create_secure_rng();
 /// fill_random(&mut buf, &mut rng);
@@ -72,3 +87,95 @@ pub fn create_secure_rng() -> SmallRng {
 pub fn fill_random(buf: &mut [u8], rng: &mut impl RngCore) {
     rng.fill_bytes(buf);
 }
+
+/// Mixes fresh operating-system entropy into `rng`.
+///
+/// The new seed is the generator's own output XORed with the OS bytes, so it is never worse
+/// than the current state; if the OS source fails, the generator is left as it is.
+///
+/// # Returns
+/// `true` if OS entropy was mixed in.
+pub fn reseed(rng: &mut SmallRng) -> bool {
+    let mut fresh = <SmallRng as SeedableRng>::Seed::default();
+    if OsRng.try_fill_bytes(&mut fresh).is_err() {
+        return false;
+    }
+    let mut seed = <SmallRng as SeedableRng>::Seed::default();
+    rng.fill_bytes(&mut seed);
+    seed.iter_mut().zip(fresh).for_each(|(byte, fresh)| *byte ^= fresh);
+    *rng = SmallRng::from_seed(seed);
+    true
+}
+
+/// Decides when a long-lived generator is reseeded.
+///
+/// [`Reseeder::tick`] is called once per packet, after its verdict is issued. It is a
+/// counter increment; the clock is read every [`RESEED_CHECK`] packets only.
+pub struct Reseeder {
+    packets: u32,
+    last: Instant,
+}
+
+impl Reseeder {
+    /// Creates a reseeder whose interval starts at `now`.
+    pub fn new(now: Instant) -> Self {
+        Self { packets: 0, last: now }
+    }
+
+    /// Counts a packet and reseeds `rng` when due.
+    ///
+    /// # Returns
+    /// `true` if `rng` was reseeded.
+    #[inline(always)]
+    pub fn tick(&mut self, rng: &mut SmallRng) -> bool {
+        self.packets += 1;
+        if self.packets & (RESEED_CHECK - 1) != 0 {
+            return false;
+        }
+        self.tick_at(rng, Instant::now())
+    }
+
+    /// Reseeds `rng` if [`RESEED_PACKETS`] packets or [`RESEED_INTERVAL`] have passed by
+    /// `now`.
+    #[cold]
+    fn tick_at(&mut self, rng: &mut SmallRng, now: Instant) -> bool {
+        if self.packets < RESEED_PACKETS && now.duration_since(self.last) < RESEED_INTERVAL {
+            return false;
+        }
+        self.packets = 0;
+        self.last = now;
+        reseed(rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that reseeding changes the output of a generator, and that two generators in
+    /// the same state diverge once reseeded.
+    #[test]
+    fn test_reseed() {
+        let mut a = SmallRng::seed_from_u64(7);
+        let mut b = SmallRng::seed_from_u64(7);
+        assert!(reseed(&mut a));
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+
+    /// Tests that a reseeder fires on the packet count and on the interval, and not before.
+    #[test]
+    fn test_reseeder() {
+        let start = Instant::now();
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut reseeder = Reseeder::new(start);
+        let fired = (0..RESEED_PACKETS).filter(|_| reseeder.tick(&mut rng)).count();
+        assert_eq!(fired, 1, "once after RESEED_PACKETS packets");
+        assert_eq!(reseeder.packets, 0);
+
+        let mut reseeder = Reseeder::new(start);
+        reseeder.packets = RESEED_CHECK;
+        assert!(!reseeder.tick_at(&mut rng, start + RESEED_INTERVAL / 2));
+        assert!(reseeder.tick_at(&mut rng, start + RESEED_INTERVAL));
+        assert!(!reseeder.tick_at(&mut rng, start + RESEED_INTERVAL * 3 / 2));
+    }
+}
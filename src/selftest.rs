@@ -0,0 +1,270 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Offline self-test of the configured rules.
+//!
+//! For every rule, [`check`] synthesizes representative WireGuard packets (a handshake
+//! initiation, a short data packet and a data packet as large as the MTU allows), runs them
+//! through [`obfuscate_wg_packet`] and [`deobfuscate_wg_packet`] with the rule's settings
+//! and verifies that they come back unchanged. No NFQUEUE and no privileges are needed.
+//!
+//! [`run_hex_preview`] does the same for a single packet given in hex and prints every
+//! stage, to look at the wire format and overhead of a rule without capturing traffic.
+
+use crate::config::FilterConfig;
+use crate::filter::keepalive::KeepaliveDropper;
+use crate::filter::obfuscator::{
+    deobfuscate_wg_packet, fixed_overhead, obfuscate_wg_packet, OBFUSCATION_OVERHEAD,
+};
+use crate::netutils::ipv4;
+use crate::randomiser;
+use std::io;
+
+/// IPv4 and UDP header length of the synthesized packets.
+const HEADERS_LEN: usize = 28;
+/// Size of a WireGuard handshake initiation.
+const HANDSHAKE_INIT_LEN: usize = 148;
+/// Size of a short WireGuard data packet, a 16-byte payload above a keepalive.
+const SHORT_DATA_LEN: usize = 64;
+
+/// Builds an IPv4/UDP packet carrying a WireGuard message of `msg_type` and `wg_len` bytes.
+fn wg_packet(msg_type: u8, wg_len: usize) -> Vec<u8> {
+    let mut packet = vec![
+        0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 192, 0, 2, 1, 192,
+        0, 2, 2, 0xca, 0x6c, 0xca, 0x6c, 0x00, 0x00, 0x00, 0x00,
+    ];
+    packet.extend((0..wg_len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(7)));
+    packet[HEADERS_LEN..HEADERS_LEN + 4].copy_from_slice(&[msg_type, 0, 0, 0]);
+    ipv4::fix_udp_headers(&mut packet);
+    packet
+}
+
+/// Obfuscates `packet` under `sender`, checks that it fits the MTU, and deobfuscates it
+/// under `receiver`.
+fn round_trip(
+    name: &str,
+    packet: &[u8],
+    sender: &FilterConfig,
+    receiver: &FilterConfig,
+) -> Result<(), String> {
+    let drop = sender.keepalive_drop;
+    let mut dropper = KeepaliveDropper::new(drop.min, drop.max);
+    let mut rng = randomiser::create_secure_rng();
+    let mut buf = vec![0u8; packet.len().max(sender.mtu) + OBFUSCATION_OVERHEAD];
+    buf[..packet.len()].copy_from_slice(packet);
+
+    let len = obfuscate_wg_packet(&mut buf, packet.len(), sender, &mut dropper, &mut rng)
+        .ok_or_else(|| format!("{}: dropped by the obfuscator", name))?;
+    // Messages below min-size go out unobfuscated, and must come back as they are
+    let below_min_size = packet.len() - HEADERS_LEN < sender.min_size;
+    let obfuscated = len != packet.len() || buf[..len] != *packet;
+    if obfuscated == below_min_size {
+        let reason = if below_min_size { "obfuscated below min-size" } else { "not obfuscated" };
+        return Err(format!("{}: {}", name, reason));
+    }
+    if len > sender.mtu {
+        return Err(format!("{}: {} bytes after obfuscation exceed MTU {}", name, len, sender.mtu));
+    }
+    let restored = deobfuscate_wg_packet(&mut buf[..len], receiver)
+        .ok_or_else(|| format!("{}: rejected by the deobfuscator", name))?;
+    if buf[..restored] != *packet {
+        return Err(format!("{}: deobfuscated packet differs from the original", name));
+    }
+    Ok(())
+}
+
+/// Round-trips representative packets through the obfuscator with the settings of `filter`.
+///
+/// During a key rotation, packets obfuscated under the previous key are checked as well.
+///
+/// # Returns
+/// * `Ok(())` - If every packet came back unchanged within the MTU.
+/// * `Err(reason)` - The first failure, naming the packet.
+pub fn check(filter: &FilterConfig) -> Result<(), String> {
+    let largest = filter.mtu.saturating_sub(HEADERS_LEN + fixed_overhead(filter));
+    if largest < HANDSHAKE_INIT_LEN {
+        return Err(format!("MTU {} leaves no room for a handshake initiation", filter.mtu));
+    }
+    let packets = [
+        ("handshake initiation", wg_packet(1, HANDSHAKE_INIT_LEN)),
+        ("short data packet", wg_packet(4, SHORT_DATA_LEN)),
+        ("full-size data packet", wg_packet(4, largest)),
+    ];
+    let previous = filter.prev_key.clone().map(|key| FilterConfig { key, ..filter.clone() });
+    for (name, packet) in &packets {
+        round_trip(name, packet, filter, filter)?;
+        if let Some(previous) = &previous {
+            round_trip(&format!("{} under the previous key", name), packet, previous, filter)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs [`check`] for every rule, printing one line per queue.
+///
+/// # Returns
+/// * `io::Result<()>` - Error if any rule failed, or if there are no rules.
+pub fn run_self_test(configs: &[FilterConfig]) -> io::Result<()> {
+    if configs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No rules to test in the config"));
+    }
+    let mut failed = 0;
+    for filter in configs {
+        let rule = format!(
+            "queue {} ({:?}, {}, key {})",
+            filter.queue_num,
+            filter.direction,
+            filter.iface,
+            filter.key_fingerprints()
+        );
+        match check(filter) {
+            Ok(()) => println!("{}: ok", rule),
+            Err(reason) => {
+                println!("{}: FAILED: {}", rule, reason);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => {
+            Err(io::Error::other(format!("Self-test failed for {} of {} rules", n, configs.len())))
+        }
+    }
+}
+
+/// A packet obfuscated and deobfuscated with the settings of a rule.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Preview {
+    /// The packet on the wire, as the obfuscator left it.
+    pub obfuscated: Vec<u8>,
+    /// The packet restored by the deobfuscator.
+    pub restored: Vec<u8>,
+}
+
+/// Obfuscates `packet`, a full IP packet, with the settings of `filter` and deobfuscates it
+/// again. Keepalives are not thinned out, so that they can be previewed too.
+///
+/// # Returns
+/// * `Ok(preview)` - Both stages; the obfuscated packet equals the input if the rule passes
+///   it unmodified (not WireGuard, below `min-size=` or larger than the MTU).
+/// * `Err(reason)` - If the deobfuscator rejects the packet.
+pub fn preview(packet: &[u8], filter: &FilterConfig) -> Result<Preview, String> {
+    let filter = FilterConfig { keepalive: false, ..filter.clone() };
+    let mut dropper = KeepaliveDropper::new(0, 9);
+    let mut rng = randomiser::create_secure_rng();
+    let mut buf = vec![0u8; packet.len().max(filter.mtu) + OBFUSCATION_OVERHEAD];
+    buf[..packet.len()].copy_from_slice(packet);
+
+    let len = obfuscate_wg_packet(&mut buf, packet.len(), &filter, &mut dropper, &mut rng)
+        .ok_or("dropped by the obfuscator")?;
+    let obfuscated = buf[..len].to_vec();
+    let restored =
+        deobfuscate_wg_packet(&mut buf[..len], &filter).ok_or("rejected by the deobfuscator")?;
+    Ok(Preview { obfuscated, restored: buf[..restored].to_vec() })
+}
+
+/// Parses `hex` (whitespace and colons allowed between bytes), previews it with
+/// [`preview`] and prints the input, obfuscated and restored packets in hex.
+///
+/// # Returns
+/// * `io::Result<()>` - Error if the hex is invalid or the packet does not round-trip.
+pub fn run_hex_preview(filter: &FilterConfig, hex: &str) -> io::Result<()> {
+    let digits: String = hex.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    let packet = hex::decode(&digits).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid hex packet: {}", e))
+    })?;
+    let preview = preview(&packet, filter).map_err(io::Error::other)?;
+    println!(
+        "queue {} ({:?}, {}), cipher {}, mtu {}",
+        filter.queue_num,
+        filter.direction,
+        filter.iface,
+        filter.cipher.name(),
+        filter.mtu
+    );
+    println!("input        {:>5} bytes: {}", packet.len(), hex::encode(&packet));
+    let grown = preview.obfuscated.len() as isize - packet.len() as isize;
+    println!(
+        "obfuscated   {:>5} bytes: {} ({:+} bytes)",
+        preview.obfuscated.len(),
+        hex::encode(&preview.obfuscated),
+        grown
+    );
+    println!(
+        "deobfuscated {:>5} bytes: {}",
+        preview.restored.len(),
+        hex::encode(&preview.restored)
+    );
+    if preview.obfuscated == packet {
+        println!("passed unmodified (not a WireGuard packet the rule obfuscates)");
+    }
+    if preview.restored != packet {
+        // The transform rewrites the length and checksum fields, so stale ones show up here
+        return Err(io::Error::other(
+            "Deobfuscated packet differs from the input (check its length and checksum fields)",
+        ));
+    }
+    println!("round trip: ok");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn configs(lines: &[&str]) -> Vec<FilterConfig> {
+        parse_config(&lines.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    /// Tests that the example rules and option combinations pass.
+    #[test]
+    fn test_self_test_passes() {
+        let configs = configs(&[
+            "0:in:eth0:secretkey:1500",
+            "1:out:eth0:secretkey",
+            "2:out:eth0:new,old:1280:auth=on:buckets=512,1024",
+            "3:in:eth0:secretkey:9000:cipher=xchacha20",
+            "4:out:eth0:secretkey:min-size=100",
+        ]);
+        for filter in &configs {
+            assert_eq!(check(filter), Ok(()), "queue {}", filter.queue_num);
+        }
+        assert!(run_self_test(&configs).is_ok());
+    }
+
+    /// Tests that a previewed packet grows by the rule's overhead and is restored, that a
+    /// keepalive is shown rather than dropped, and that bad hex is rejected.
+    #[test]
+    fn test_preview() {
+        let filter = &configs(&["0:out:eth0:secretkey:1500"])[0];
+        for packet in [wg_packet(4, SHORT_DATA_LEN), wg_packet(4, 32)] {
+            let preview = preview(&packet, filter).unwrap();
+            assert!(preview.obfuscated.len() >= packet.len() + fixed_overhead(filter));
+            assert_ne!(
+                preview.obfuscated[HEADERS_LEN..HEADERS_LEN + 4],
+                packet[HEADERS_LEN..][..4]
+            );
+            assert_eq!(preview.restored, packet);
+        }
+        let text = hex::encode(wg_packet(1, HANDSHAKE_INIT_LEN));
+        assert!(run_hex_preview(filter, &text).is_ok());
+        assert!(run_hex_preview(filter, &format!("45:00 {}", &text[4..])).is_ok());
+        let error = run_hex_preview(filter, "4500zz").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// Tests that an MTU too small for a handshake fails and fails the whole run.
+    #[test]
+    fn test_self_test_small_mtu_fails() {
+        let configs = configs(&["0:in:eth0:secretkey:1500", "1:out:eth0:secretkey:150"]);
+        assert!(check(&configs[1]).unwrap_err().contains("MTU 150"));
+        assert!(run_self_test(&configs).is_err());
+    }
+}
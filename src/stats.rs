@@ -0,0 +1,522 @@
+/*
+ * Copyright (c) 2025 sh0rch <sh0rch@iwl.dev>
+ *
+ * This file is part of nf_wgobfs.
+ *
+ * Licensed under the MIT License. See LICENSE file in the project root for full license information.
+ */
+
+//! Per-queue packet statistics.
+//!
+//! Every running queue registers a [`QueueStats`] block of atomic counters that the
+//! packet loop updates without locking. The registry can be snapshotted as JSON and
+//! served over a Unix domain socket, which is what the `status` command reads.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Default path of the statistics socket.
+pub const DEFAULT_SOCKET: &str = "/run/nf_wgobfs.sock";
+
+/// Packet counts by size: one bucket per upper edge, inclusive, and one for larger packets.
+#[derive(Debug)]
+pub struct SizeHistogram {
+    edges: Vec<usize>,
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl SizeHistogram {
+    /// Creates an empty histogram with the ascending upper bucket edges `edges`.
+    pub fn new(edges: &[usize]) -> Self {
+        Self {
+            edges: edges.to_vec(),
+            counts: (0..=edges.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a packet of `size` bytes.
+    #[inline(always)]
+    pub fn record(&self, size: usize) {
+        let bucket = self.edges.partition_point(|edge| *edge < size);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    /// Upper edges of the buckets; the last bucket, for larger packets, has none.
+    pub fn edges(&self) -> &[usize] {
+        &self.edges
+    }
+
+    /// Packets per bucket, not cumulative, the larger packets last.
+    pub fn counts(&self) -> Vec<u64> {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Total size of the packets counted, in bytes.
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    /// Serializes the histogram as a JSON object of its edges, counts and sum.
+    fn write_json(&self, out: &mut String) {
+        let list = |values: Vec<String>| values.join(",");
+        let _ = write!(
+            out,
+            "{{\"le\":[{}],\"counts\":[{}],\"sum\":{}}}",
+            list(self.edges.iter().map(usize::to_string).collect()),
+            list(self.counts().iter().map(u64::to_string).collect()),
+            self.sum()
+        );
+    }
+}
+
+/// Atomic packet counters of a single queue.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    /// Netfilter queue number these counters belong to.
+    pub queue_num: u16,
+    /// Packets received from the kernel.
+    pub packets: AtomicU64,
+    /// Packets obfuscated (outbound).
+    pub obfuscated: AtomicU64,
+    /// Packets deobfuscated (inbound).
+    pub deobfuscated: AtomicU64,
+    /// Packets given a drop verdict.
+    pub dropped: AtomicU64,
+    /// Keepalives dropped by the keepalive dropper.
+    pub dropped_keepalive: AtomicU64,
+    /// Packets accepted unchanged.
+    pub passed: AtomicU64,
+    /// Bytes received from the kernel.
+    pub bytes_in: AtomicU64,
+    /// Bytes handed back to the kernel with an accept verdict.
+    pub bytes_out: AtomicU64,
+    /// Random ballast bytes inserted by the obfuscator.
+    pub ballast_bytes: AtomicU64,
+    /// Chaff packets obfuscated and sent toward the peer (outbound).
+    pub chaff: AtomicU64,
+    /// Packets that grew beyond the MTU of the rule when obfuscated (outbound).
+    pub oversized: AtomicU64,
+    /// Packets dropped for exceeding the `ratelimit=`/`bytelimit=` of the rule.
+    pub rate_limited: AtomicU64,
+    /// Packets passed unchanged whose IP version is neither 4 nor 6.
+    pub unknown_version: AtomicU64,
+    /// Packets passed unchanged that end within their headers or are too short to hold a
+    /// (obfuscated) WireGuard message.
+    pub too_short: AtomicU64,
+    /// Packets passed unchanged whose IP or UDP length fields disagree with their length.
+    pub malformed: AtomicU64,
+    /// Packets passed unchanged for exceeding the MTU of the rule, the copy range or the
+    /// packet buffer.
+    pub too_large: AtomicU64,
+    /// Handler restarts after an error or panic.
+    pub restarts: AtomicU64,
+    /// Set while the rule is switched off (`enabled=off`): packets are accepted unchanged.
+    pub passthrough: AtomicBool,
+    /// Message of the last error or panic that restarted the handler.
+    pub last_error: Mutex<Option<String>>,
+    /// Fingerprint of the key(s) of the rule (see [`crate::config::key_fingerprint`]), set
+    /// when the queue starts.
+    pub key_fp: OnceLock<String>,
+    /// Cipher implementation of the rule (see [`crate::cipher::active_impl_name`]), set when
+    /// the queue starts.
+    pub cipher: OnceLock<&'static str>,
+    /// Sizes of the packets received from the kernel, set up with the edges of the rule
+    /// (`histogram=`) when the queue starts.
+    pub sizes_in: OnceLock<SizeHistogram>,
+    /// Sizes of the packets handed back with an accept verdict, like `sizes_in`.
+    pub sizes_out: OnceLock<SizeHistogram>,
+    /// When the queue last carried WireGuard data other than keepalives, in milliseconds
+    /// after [`EPOCH`] plus one; 0 if it has not, or no `watchdog=` is set to track it.
+    pub last_data: AtomicU64,
+}
+
+/// Start of the clock of [`QueueStats::last_data`], fixed on first use.
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+impl QueueStats {
+    /// Adds `n` to `counter`.
+    #[inline(always)]
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the counters as `(name, value)` pairs in a stable order.
+    pub fn counters(&self) -> [(&'static str, u64); 17] {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        [
+            ("packets", load(&self.packets)),
+            ("obfuscated", load(&self.obfuscated)),
+            ("deobfuscated", load(&self.deobfuscated)),
+            ("dropped", load(&self.dropped)),
+            ("dropped_keepalive", load(&self.dropped_keepalive)),
+            ("passed", load(&self.passed)),
+            ("bytes_in", load(&self.bytes_in)),
+            ("bytes_out", load(&self.bytes_out)),
+            ("ballast_bytes", load(&self.ballast_bytes)),
+            ("chaff", load(&self.chaff)),
+            ("oversized", load(&self.oversized)),
+            ("rate_limited", load(&self.rate_limited)),
+            ("unknown_version", load(&self.unknown_version)),
+            ("too_short", load(&self.too_short)),
+            ("malformed", load(&self.malformed)),
+            ("too_large", load(&self.too_large)),
+            ("restarts", load(&self.restarts)),
+        ]
+    }
+
+    /// Records that the queue carried data at `at`; earlier times than the last recorded
+    /// one are ignored.
+    pub fn set_last_data(&self, at: Instant) {
+        let epoch = *EPOCH.get_or_init(Instant::now);
+        let ms = at.saturating_duration_since(epoch).as_millis() as u64 + 1;
+        self.last_data.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    /// Returns when the queue last carried data, to the millisecond; `None` if never.
+    pub fn last_data(&self) -> Option<Instant> {
+        match self.last_data.load(Ordering::Relaxed) {
+            0 => None,
+            ms => EPOCH.get().map(|epoch| *epoch + Duration::from_millis(ms - 1)),
+        }
+    }
+
+    /// Records the error that caused the handler to restart.
+    pub fn set_last_error(&self, error: String) {
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error);
+    }
+
+    /// Returns the error that last caused the handler to restart, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Serializes the current counter values as a JSON object.
+    fn write_json(&self, out: &mut String) {
+        let _ = write!(out, "{{\"queue\":{}", self.queue_num);
+        let _ = write!(out, ",\"passthrough\":{}", self.passthrough.load(Ordering::Relaxed));
+        for (name, value) in self.counters() {
+            let _ = write!(out, ",\"{}\":{}", name, value);
+        }
+        match self.last_error() {
+            Some(error) => {
+                out.push_str(",\"last_error\":");
+                write_json_string(out, &error);
+            }
+            None => out.push_str(",\"last_error\":null"),
+        }
+        for (name, sizes) in [("sizes_in", &self.sizes_in), ("sizes_out", &self.sizes_out)] {
+            let _ = write!(out, ",\"{}\":", name);
+            match sizes.get() {
+                Some(sizes) => sizes.write_json(out),
+                None => out.push_str("null"),
+            }
+        }
+        match self.key_fp.get() {
+            Some(key_fp) => {
+                out.push_str(",\"key_fp\":");
+                write_json_string(out, key_fp);
+            }
+            None => out.push_str(",\"key_fp\":null"),
+        }
+        match self.cipher.get() {
+            Some(cipher) => {
+                out.push_str(",\"cipher\":");
+                write_json_string(out, cipher);
+            }
+            None => out.push_str(",\"cipher\":null"),
+        }
+        out.push('}');
+    }
+
+    /// Formats the counters as a single human-readable line.
+    pub fn summary_line(&self) -> String {
+        let mut line = format!("queue {}:", self.queue_num);
+        if self.passthrough.load(Ordering::Relaxed) {
+            line.push_str(" passthrough");
+        }
+        for (name, value) in self.counters() {
+            let _ = write!(line, " {}={}", name, value);
+        }
+        let _ = write!(line, " last_error={}", self.last_error().as_deref().unwrap_or("none"));
+        if let Some(key_fp) = self.key_fp.get() {
+            let _ = write!(line, " key_fp={}", key_fp);
+        }
+        if let Some(cipher) = self.cipher.get() {
+            let _ = write!(line, " cipher={}", cipher);
+        }
+        line
+    }
+}
+
+/// Appends `value` to `out` as a quoted, escaped JSON string.
+pub fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// All counter blocks registered by running queues.
+static REGISTRY: Mutex<Vec<Arc<QueueStats>>> = Mutex::new(Vec::new());
+
+/// Returns the counters for `queue_num`, registering a new block on first use.
+pub fn register(queue_num: u16) -> Arc<QueueStats> {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(stats) = registry.iter().find(|s| s.queue_num == queue_num) {
+        return stats.clone();
+    }
+    let stats = Arc::new(QueueStats { queue_num, ..Default::default() });
+    registry.push(stats.clone());
+    stats
+}
+
+/// Returns the counter blocks of all registered queues, ordered by queue number.
+pub fn snapshot() -> Vec<Arc<QueueStats>> {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    registry.sort_by_key(|s| s.queue_num);
+    registry
+}
+
+/// Returns a JSON snapshot of all registered queues, ordered by queue number.
+pub fn snapshot_json() -> String {
+    let mut out = String::from("{\"queues\":[");
+    for (i, stats) in snapshot().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        stats.write_json(&mut out);
+    }
+    out.push_str("]}");
+    out
+}
+
+/// Returns one summary line per registered queue, as printed on SIGUSR1.
+pub fn dump_text() -> String {
+    snapshot().iter().map(|s| s.summary_line() + "\n").collect()
+}
+
+/// Binds the statistics socket at `path`, removing a stale socket file left by a previous run.
+/// Any other file at `path` is left alone and fails the bind with `AlreadyExists`.
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the path exists and is not a socket",
+            ))
+        }
+        Err(_) => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Serves JSON snapshots on `listener`, one per connection.
+///
+/// This function blocks and is meant to run on its own thread.
+pub fn serve(listener: UnixListener) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let _ = stream.write_all(snapshot_json().as_bytes());
+            }
+            Err(e) => log::warn!("Statistics socket: {}", e),
+        }
+    }
+}
+
+/// Connects to the statistics socket at `path` and returns the raw JSON snapshot.
+pub fn fetch(path: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut json = String::new();
+    stream.read_to_string(&mut json)?;
+    Ok(json)
+}
+
+/// Re-indents a compact JSON document for display.
+pub fn pretty_json(json: &str) -> String {
+    let mut out = String::with_capacity(json.len() * 2);
+    let mut indent = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let newline = |out: &mut String, indent: usize| {
+        out.push('\n');
+        out.push_str(&"  ".repeat(indent));
+    };
+    for c in json.chars() {
+        if in_string {
+            out.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '{' | '[' => {
+                indent += 1;
+                out.push(c);
+                newline(&mut out, indent);
+            }
+            '}' | ']' => {
+                indent = indent.saturating_sub(1);
+                newline(&mut out, indent);
+                out.push(c);
+            }
+            ',' => {
+                out.push(c);
+                newline(&mut out, indent);
+            }
+            ':' => out.push_str(": "),
+            c if c.is_whitespace() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that counters show up in the snapshot and registration is idempotent.
+    #[test]
+    fn test_register_and_snapshot() {
+        let stats = register(4242);
+        QueueStats::add(&stats.obfuscated, 3);
+        QueueStats::add(&register(4242).bytes_in, 100);
+
+        let json = snapshot_json();
+        assert!(
+            json.contains("\"queue\":4242,\"passthrough\":false,\"packets\":0,\"obfuscated\":3")
+        );
+        assert!(json.contains("\"bytes_in\":100"));
+    }
+
+    /// Tests that the last error is escaped in JSON and shown in the summary line.
+    #[test]
+    fn test_last_error_reporting() {
+        let stats = register(4244);
+        assert!(snapshot_json().contains("\"restarts\":0,\"last_error\":null"));
+        stats.set_last_error("bind \"failed\"\n".to_string());
+        assert!(snapshot_json().contains("\"last_error\":\"bind \\\"failed\\\"\\n\""));
+        assert!(dump_text().contains("queue 4244: packets=0 "));
+        stats.passthrough.store(true, Ordering::Relaxed);
+        assert!(dump_text().contains("queue 4244: passthrough packets=0 "));
+        assert!(stats.summary_line().ends_with("last_error=bind \"failed\"\n"));
+    }
+
+    /// Tests that the key fingerprint is reported once set.
+    #[test]
+    fn test_key_fp_reporting() {
+        let stats = register(4245);
+        let json = || {
+            let mut out = String::new();
+            stats.write_json(&mut out);
+            out
+        };
+        assert!(json().ends_with(",\"key_fp\":null,\"cipher\":null}"));
+        assert!(json().starts_with("{\"queue\":4245,\"passthrough\":false,\"packets\":0,"));
+        stats.key_fp.set("1a2b3c4d".to_string()).unwrap();
+        assert!(json().ends_with(",\"key_fp\":\"1a2b3c4d\",\"cipher\":null}"));
+        assert!(stats.summary_line().ends_with(" key_fp=1a2b3c4d"));
+    }
+
+    /// Tests that the cipher implementation is reported once set.
+    #[test]
+    fn test_cipher_reporting() {
+        let stats = register(4246);
+        assert!(!snapshot_json().contains("\"cipher\":\"chacha20-fast\""));
+        stats.cipher.set("chacha20-fast").unwrap();
+        assert!(snapshot_json().contains("\"queue\":4246,"));
+        assert!(snapshot_json().contains(",\"cipher\":\"chacha20-fast\"}"));
+        assert!(stats.summary_line().ends_with(" cipher=chacha20-fast"));
+    }
+
+    /// Tests that the time of the last data is kept to the millisecond and only moves
+    /// forward.
+    #[test]
+    fn test_last_data() {
+        let stats = register(4247);
+        assert_eq!(stats.last_data(), None);
+        let now = Instant::now();
+        stats.set_last_data(now + Duration::from_secs(2));
+        stats.set_last_data(now + Duration::from_secs(1));
+        let last = stats.last_data().unwrap();
+        let expected = now + Duration::from_secs(2);
+        assert!(last <= expected && expected - last < Duration::from_millis(1));
+    }
+
+    /// Tests that sizes land in the bucket of the smallest edge that holds them, and that
+    /// the histograms show up in the snapshot once set up.
+    #[test]
+    fn test_size_histogram() {
+        let sizes = SizeHistogram::new(&[64, 128, 1500]);
+        for size in [1, 64, 65, 128, 1400, 1500, 1501, 9000] {
+            sizes.record(size);
+        }
+        assert_eq!(sizes.edges(), [64, 128, 1500]);
+        assert_eq!(sizes.counts(), [2, 2, 2, 2]);
+        assert_eq!(sizes.sum(), 13659);
+
+        let stats = register(4248);
+        assert!(snapshot_json().contains("\"sizes_in\":null,\"sizes_out\":null,"));
+        stats.sizes_in.set(sizes).unwrap();
+        assert!(snapshot_json()
+            .contains("\"sizes_in\":{\"le\":[64,128,1500],\"counts\":[2,2,2,2],\"sum\":13659}"));
+    }
+
+    /// Tests that binding replaces a stale socket but never removes another file.
+    #[test]
+    fn test_bind_keeps_other_files() {
+        let dir = std::env::temp_dir().join(format!("nf_wgobfs-stats-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("stats.sock");
+        let socket = socket.to_str().unwrap();
+        drop(bind(socket).unwrap());
+        // The socket file of the first listener is stale now
+        drop(bind(socket).unwrap());
+
+        let file = dir.join("config");
+        fs::write(&file, b"keep").unwrap();
+        let err = bind(file.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&file).unwrap(), b"keep");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests the JSON re-indentation, including braces inside strings.
+    #[test]
+    fn test_pretty_json() {
+        let pretty = pretty_json("{\"a\":[1,2],\"b\":\"x{,}\"}");
+        assert_eq!(pretty, "{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": \"x{,}\"\n}");
+    }
+}
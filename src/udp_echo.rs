@@ -30,12 +30,147 @@
 //! Usage:
 //! - As a server: `cargo run -- [bind_addr] [port]`
 //! - As a client: `cargo run -- --client [server_ip] [port] [message]`
+//! - As a batch client: `cargo run -- --client [server_ip] [port] [message] --count N
+//!   [--interval MS] [--size BYTES]`
+//!
+//! Addresses may be IPv4 or IPv6 (`::1` or `[::1]`), or host names.
 
 use std::env;
+use std::fmt;
 use std::io::{self, Write};
-use std::net::UdpSocket;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Largest UDP payload over IPv4.
+const MAX_PAYLOAD: usize = 65507;
+/// Length of the sequence number that starts every batch payload.
+const SEQ_LEN: usize = 4;
+/// How long the batch client waits for late replies after the last send.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Settings of the non-interactive batch client.
+#[derive(Debug, PartialEq, Eq)]
+struct Batch {
+    /// Number of packets to send.
+    count: u32,
+    /// Delay between two sends.
+    interval: Duration,
+    /// Payload size in bytes, including the sequence number; the message fills the rest.
+    size: Option<usize>,
+}
+
+/// Round-trip statistics of a batch run.
+#[derive(Debug, Default)]
+struct RttStats {
+    sent: u32,
+    received: u32,
+    min: Option<Duration>,
+    max: Duration,
+    total: Duration,
+}
+
+impl RttStats {
+    /// Records the round-trip time of a reply.
+    fn record(&mut self, rtt: Duration) {
+        self.received += 1;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = self.max.max(rtt);
+        self.total += rtt;
+    }
+
+    /// Percentage of the sent packets that got no reply.
+    fn loss_percent(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => 100.0 * (sent - self.received) as f64 / sent as f64,
+        }
+    }
+}
+
+impl fmt::Display for RttStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{} sent, {} received, {:.1}% loss",
+            self.sent,
+            self.received,
+            self.loss_percent()
+        )?;
+        if let Some(min) = self.min {
+            let avg = self.total / self.received;
+            write!(f, ", rtt min/avg/max = {:.3}/{:.3}/{:.3} ms", ms(min), ms(avg), ms(self.max))?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits the batch flags off the client arguments.
+///
+/// # Returns
+/// * `Ok((positional, batch))` - The remaining arguments, and the batch settings if
+///   `--count` was given.
+/// * `Err(message)` - For unknown flags, missing or invalid values.
+fn parse_batch_flags(args: &[String]) -> Result<(Vec<String>, Option<Batch>), String> {
+    let mut positional = Vec::new();
+    let (mut count, mut interval, mut size) = (None, Duration::from_millis(1000), None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let value = iter.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--count" => {
+                count = Some(value.parse::<u32>().ok().filter(|&n| n > 0).ok_or_else(invalid)?)
+            }
+            "--interval" => interval = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+            "--size" => {
+                let bytes = value.parse::<usize>().map_err(|_| invalid())?;
+                if !(SEQ_LEN..=MAX_PAYLOAD).contains(&bytes) {
+                    return Err(format!("--size must be {}-{} bytes", SEQ_LEN, MAX_PAYLOAD));
+                }
+                size = Some(bytes);
+            }
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    if count.is_none() && size.is_some() {
+        return Err("--size needs --count".to_string());
+    }
+    Ok((positional, count.map(|count| Batch { count, interval, size })))
+}
+
+/// Resolves `host` and `port` to a socket address.
+///
+/// `host` is an IPv4 or IPv6 address, with or without brackets (`[::1]`), or a host name.
+fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("No address found for {}", host))
+    })
+}
+
+/// Binds a UDP socket to an ephemeral port of the address family of `dest`.
+fn bind_for(dest: &SocketAddr) -> UdpSocket {
+    let local: SocketAddr = match dest {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    UdpSocket::bind(local).expect("bind failed")
+}
+
+/// Builds the payload of batch packet `seq`: the sequence number followed by the message,
+/// repeated or cut to fill `size` bytes.
+fn batch_payload(seq: u32, message: &[u8], size: usize) -> Vec<u8> {
+    let mut payload = seq.to_be_bytes().to_vec();
+    payload.extend(message.iter().cycle().take(size - SEQ_LEN));
+    payload.resize(size, 0);
+    payload
+}
 
 /// Prints the contents of a UDP packet in both hexadecimal and ASCII representations.
 ///
@@ -65,12 +200,10 @@ fn print_packet(label: &str, buf: &[u8]) {
 /// and waits for the echoed response. Repeats on each Enter key press.
 ///
 /// # Arguments
-/// * `ip` - The server IP address to send packets to.
-/// * `port` - The server UDP port.
+/// * `dest` - The server address to send packets to.
 /// * `message` - The message to send as a byte slice.
-fn run_client(ip: &str, port: u16, message: &[u8]) {
-    let sock = UdpSocket::bind("0.0.0.0:0").expect("bind failed");
-    let dest = format!("{}:{}", ip, port);
+fn run_client(dest: SocketAddr, message: &[u8]) {
+    let sock = bind_for(&dest);
 
     println!(
         "[client] Ready to send to {}. Press Enter to send (message: {:?}), Ctrl+C to exit.",
@@ -84,7 +217,7 @@ fn run_client(ip: &str, port: u16, message: &[u8]) {
         io::stdout().flush().ok();
         let _ = io::stdin().read_line(&mut String::new());
 
-        sock.send_to(message, &dest).expect("send_to failed");
+        sock.send_to(message, dest).expect("send_to failed");
         println!("[client] sent {} bytes", message.len());
         print_packet("[client] sent", message);
 
@@ -101,20 +234,82 @@ fn run_client(ip: &str, port: u16, message: &[u8]) {
     }
 }
 
+/// Runs the UDP echo client in batch mode.
+///
+/// Sends `batch.count` packets at a fixed rate, each tagged with a sequence number, matches
+/// the echoed replies to their sends and prints round-trip statistics at the end. Replies
+/// are awaited for up to a second after the last send.
+///
+/// # Arguments
+/// * `dest` - The server address to send packets to.
+/// * `message` - The message filling the payload after the sequence number.
+/// * `batch` - Packet count, interval and payload size.
+fn run_batch_client(dest: SocketAddr, message: &[u8], batch: &Batch) -> RttStats {
+    let sock = bind_for(&dest);
+    let size = batch.size.unwrap_or(SEQ_LEN + message.len()).min(MAX_PAYLOAD);
+    println!(
+        "[client] sending {} packets of {} bytes to {} every {} ms",
+        batch.count,
+        size,
+        dest,
+        batch.interval.as_millis()
+    );
+
+    let mut sent_at: Vec<Option<Instant>> = vec![None; batch.count as usize];
+    let mut stats = RttStats::default();
+    let mut buf = vec![0u8; MAX_PAYLOAD];
+    let start = Instant::now();
+    for seq in 0..=batch.count {
+        // Collect replies until the next send is due, or the drain timeout after the last
+        let deadline = match seq {
+            seq if seq < batch.count => start + batch.interval * seq,
+            _ => Instant::now() + DRAIN_TIMEOUT,
+        };
+        while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+            if wait.is_zero() || stats.received == stats.sent && seq == batch.count {
+                break;
+            }
+            sock.set_read_timeout(Some(wait)).expect("set_read_timeout failed");
+            let Ok(len) = sock.recv(&mut buf) else {
+                continue;
+            };
+            let Some(seq) = buf[..len].first_chunk::<SEQ_LEN>().map(|b| u32::from_be_bytes(*b))
+            else {
+                continue;
+            };
+            // Unknown sequence numbers and duplicates are ignored
+            if let Some(sent) = sent_at.get_mut(seq as usize).and_then(Option::take) {
+                stats.record(sent.elapsed());
+            }
+        }
+        if seq < batch.count {
+            // Timestamp before sending: on loopback the reply can arrive before send_to returns
+            let payload = batch_payload(seq, message, size);
+            let now = Instant::now();
+            match sock.send_to(&payload, dest) {
+                Ok(_) => sent_at[seq as usize] = Some(now),
+                Err(e) => eprintln!("[client] send_to failed: {}", e),
+            }
+            stats.sent += 1;
+        }
+    }
+    println!("[client] {}", stats);
+    stats
+}
+
 /// Runs the UDP echo server.
 ///
 /// Binds to the specified address and port, receives UDP packets,
 /// and echoes them back to the sender.
 ///
 /// # Arguments
-/// * `bind_addr` - The local address to bind to (e.g., "0.0.0.0").
-/// * `port` - The UDP port to listen on.
-fn run_server(bind_addr: &str, port: u16) {
-    let addr = format!("{}:{}", bind_addr, port);
-    let sock = UdpSocket::bind(&addr).expect("bind failed");
+/// * `addr` - The local address to bind to (e.g., `0.0.0.0:51820`, or `[::]:51820`, which
+///   also accepts IPv4 unless the system disables dual-stack sockets).
+fn run_server(addr: SocketAddr) {
+    let sock = UdpSocket::bind(addr).expect("bind failed");
     println!("[server] listening on {}", addr);
 
-    let mut buf = [0u8; 1500];
+    let mut buf = vec![0u8; MAX_PAYLOAD];
     loop {
         match sock.recv_from(&mut buf) {
             Ok((len, src)) => {
@@ -134,22 +329,104 @@ fn run_server(bind_addr: &str, port: u16) {
     }
 }
 
+/// Resolves an address given on the command line, exiting with an error if it is invalid.
+fn resolve_or_exit(role: &str, host: &str, port: u16) -> SocketAddr {
+    resolve(host, port).unwrap_or_else(|e| {
+        eprintln!("[{}] {}: {}", role, host, e);
+        std::process::exit(2);
+    })
+}
+
 /// Entry point for the UDP echo utility.
 ///
 /// Parses command-line arguments to determine whether to run as a server or client.
 /// - As a server: `cargo run -- [bind_addr] [port]`
 /// - As a client: `cargo run -- --client [server_ip] [port] [message]`
+/// - As a batch client, with `--count N [--interval MS] [--size BYTES]` after `--client`
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() > 1 && args[1] == "--client" {
-        let ip = args.get(2).map(|s| s.as_str()).unwrap_or("127.0.0.1");
-        let port: u16 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(51820);
-        let message = args.get(4).map(|s| s.as_bytes()).unwrap_or(b"test-packet");
-        run_client(ip, port, message);
+        let (args, batch) = parse_batch_flags(&args[2..]).unwrap_or_else(|e| {
+            eprintln!("[client] {}", e);
+            std::process::exit(2);
+        });
+        let ip = args.first().map(|s| s.as_str()).unwrap_or("127.0.0.1");
+        let port: u16 = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(51820);
+        let message = args.get(2).map(|s| s.as_bytes()).unwrap_or(b"test-packet");
+        let dest = resolve_or_exit("client", ip, port);
+        match batch {
+            Some(batch) => {
+                // Exit with a failure status if every packet was lost
+                if run_batch_client(dest, message, &batch).received == 0 {
+                    std::process::exit(1);
+                }
+            }
+            None => run_client(dest, message),
+        }
     } else {
         let bind_addr = args.get(1).map(|s| s.as_str()).unwrap_or("0.0.0.0");
         let port: u16 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(51820);
-        run_server(bind_addr, port);
+        run_server(resolve_or_exit("server", bind_addr, port));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Tests that batch flags are split off the positional arguments in any position.
+    #[test]
+    fn test_parse_batch_flags() {
+        let (positional, batch) = parse_batch_flags(&args(&["10.0.0.1", "9000"])).unwrap();
+        assert_eq!(positional, args(&["10.0.0.1", "9000"]));
+        assert_eq!(batch, None);
+
+        let list = args(&["--count", "5", "10.0.0.1", "--size", "64", "9000", "--interval", "20"]);
+        let (positional, batch) = parse_batch_flags(&list).unwrap();
+        assert_eq!(positional, args(&["10.0.0.1", "9000"]));
+        let expected = Batch { count: 5, interval: Duration::from_millis(20), size: Some(64) };
+        assert_eq!(batch, Some(expected));
+
+        assert!(parse_batch_flags(&args(&["--count", "0"])).is_err());
+        assert!(parse_batch_flags(&args(&["--count"])).is_err());
+        assert!(parse_batch_flags(&args(&["--count", "1", "--size", "2"])).is_err());
+        assert!(parse_batch_flags(&args(&["--size", "64"])).is_err());
+        assert!(parse_batch_flags(&args(&["--rate", "1"])).is_err());
+    }
+
+    /// Tests that batch payloads carry the sequence number and are filled to the size.
+    #[test]
+    fn test_batch_payload() {
+        assert_eq!(batch_payload(1, b"ab", 9), [0, 0, 0, 1, b'a', b'b', b'a', b'b', b'a']);
+        assert_eq!(batch_payload(2, b"abc", 5), [0, 0, 0, 2, b'a']);
+        assert_eq!(batch_payload(3, b"", 6), [0, 0, 0, 3, 0, 0]);
+    }
+
+    /// Tests that IPv4 and IPv6 literals resolve, with or without brackets.
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("127.0.0.1", 9).unwrap().to_string(), "127.0.0.1:9");
+        assert_eq!(resolve("::1", 9).unwrap().to_string(), "[::1]:9");
+        assert_eq!(resolve("[2001:db8::1]", 9).unwrap().to_string(), "[2001:db8::1]:9");
+        assert!(bind_for(&resolve("::1", 9).unwrap()).local_addr().unwrap().is_ipv6());
+    }
+
+    /// Tests the loss and round-trip summary.
+    #[test]
+    fn test_rtt_stats() {
+        let mut stats = RttStats { sent: 4, ..Default::default() };
+        assert_eq!(stats.to_string(), "4 sent, 0 received, 100.0% loss");
+        stats.record(Duration::from_millis(1));
+        stats.record(Duration::from_millis(3));
+        stats.record(Duration::from_millis(2));
+        assert_eq!(
+            stats.to_string(),
+            "4 sent, 3 received, 25.0% loss, rtt min/avg/max = 1.000/2.000/3.000 ms"
+        );
     }
 }